@@ -0,0 +1,569 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::message_type::MessageType;
+
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Message types that may carry attachments.
+pub const ATTACHMENT_MESSAGE_TYPES: &[&str] = &["image", "file"];
+
+/// Voice clips must be shorter than this.
+pub const MAX_VOICE_DURATION_MS: u64 = 5 * 60 * 1000;
+/// Amplitudes in a voice clip's waveform, one byte each.
+pub const WAVEFORM_SAMPLES: usize = 64;
+
+/// Images smaller than this are left alone by `recompress_attachments`.
+pub const MIN_RECOMPRESS_BYTES: i64 = 100 * 1024;
+
+/// Image types `recompress_attachments` re-encodes. GIFs may be animated and
+/// SVGs aren't raster, so neither is touched.
+const RECOMPRESSIBLE_MIME_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+pub struct AttachmentSettings {
+    max_bytes: AtomicU64,
+}
+
+impl Default for AttachmentSettings {
+    fn default() -> Self {
+        Self {
+            max_bytes: AtomicU64::new(DEFAULT_MAX_ATTACHMENT_BYTES),
+        }
+    }
+}
+
+impl AttachmentSettings {
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Attachment {
+    pub id: i64,
+    pub message_id: i64,
+    pub file_name: String,
+    pub mime_type: String,
+    pub byte_size: i64,
+    pub local_path: Option<String>,
+    pub remote_url: Option<String>,
+    pub uploaded_at: Option<String>,
+    /// Set for voice clips only.
+    pub duration_ms: Option<i64>,
+    /// Base64 of `WAVEFORM_SAMPLES` amplitude bytes; voice clips only.
+    pub waveform: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RecompressReport {
+    pub recompressed: u32,
+    /// Already small enough, or re-encoding wouldn't have made them smaller.
+    pub skipped: u32,
+    /// Missing or unreadable files; their rows are left as they were.
+    pub failed: Vec<i64>,
+    pub bytes_saved: i64,
+}
+
+pub(crate) const ATTACHMENT_COLUMNS: &str = "id, message_id, file_name, mime_type, byte_size,
+    local_path, remote_url, uploaded_at, duration_ms, waveform";
+
+async fn message_type_of(pool: &SqlitePool, message_id: i64) -> Result<String, AppError> {
+    sqlx::query_scalar("SELECT message_type FROM messages WHERE id = ?")
+        .bind(message_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))
+}
+
+fn check_size(byte_size: u64, max_bytes: u64) -> Result<(), AppError> {
+    if byte_size > max_bytes {
+        return Err(AppError::Validation(format!(
+            "attachment is {} bytes, the limit is {} bytes",
+            byte_size, max_bytes
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) async fn insert_attachment(
+    pool: &SqlitePool,
+    max_bytes: u64,
+    message_id: i64,
+    file_name: &str,
+    mime_type: &str,
+    byte_size: u64,
+    local_path: &str,
+) -> Result<Attachment, AppError> {
+    if file_name.trim().is_empty() {
+        return Err(AppError::Validation("file name must not be empty".into()));
+    }
+    check_size(byte_size, max_bytes)?;
+
+    let message_type = message_type_of(pool, message_id).await?;
+    if !ATTACHMENT_MESSAGE_TYPES.contains(&message_type.as_str()) {
+        return Err(AppError::Validation(format!(
+            "attachments can only be added to {} messages, not '{}'",
+            ATTACHMENT_MESSAGE_TYPES.join(" or "),
+            message_type
+        )));
+    }
+
+    sqlx::query_as::<_, Attachment>(&format!(
+        "INSERT INTO attachments (message_id, file_name, mime_type, byte_size, local_path)
+         VALUES (?, ?, ?, ?, ?)
+         RETURNING {}",
+        ATTACHMENT_COLUMNS
+    ))
+    .bind(message_id)
+    .bind(file_name)
+    .bind(mime_type)
+    .bind(byte_size as i64)
+    .bind(local_path)
+    .fetch_one(pool)
+    .await
+    .context("failed to save attachment")
+}
+
+pub(crate) fn validate_voice(duration_ms: u64, waveform: &str) -> Result<(), AppError> {
+    if duration_ms == 0 || duration_ms >= MAX_VOICE_DURATION_MS {
+        return Err(AppError::Validation(format!(
+            "voice clips must be longer than 0 and shorter than {} ms, got {} ms",
+            MAX_VOICE_DURATION_MS, duration_ms
+        )));
+    }
+    let samples = BASE64
+        .decode(waveform)
+        .map_err(|e| AppError::Validation(format!("waveform is not valid base64: {}", e)))?;
+    if samples.len() != WAVEFORM_SAMPLES {
+        return Err(AppError::Validation(format!(
+            "waveform must have {} samples, got {}",
+            WAVEFORM_SAMPLES,
+            samples.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Container types by file extension; the recorder writes Opus in Ogg.
+fn voice_mime_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("m4a") | Some("mp4") => "audio/mp4",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("webm") => "audio/webm",
+        _ => "audio/ogg",
+    }
+}
+
+/// Attaches the recorded clip at `local_path` to a voice message.
+pub(crate) async fn insert_voice(
+    pool: &SqlitePool,
+    max_bytes: u64,
+    message_id: i64,
+    local_path: &str,
+    duration_ms: u64,
+    waveform: &str,
+) -> Result<Attachment, AppError> {
+    validate_voice(duration_ms, waveform)?;
+
+    let message_type = message_type_of(pool, message_id).await?;
+    if message_type != MessageType::Voice.as_str() {
+        return Err(AppError::Validation(format!(
+            "voice clips can only be added to voice messages, not '{}'",
+            message_type
+        )));
+    }
+
+    let path = Path::new(local_path);
+    let byte_size = std::fs::metadata(path)
+        .context(&format!("failed to read {}", local_path))?
+        .len();
+    check_size(byte_size, max_bytes)?;
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| AppError::Validation(format!("'{}' is not a file path", local_path)))?;
+
+    sqlx::query_as::<_, Attachment>(&format!(
+        "INSERT INTO attachments
+             (message_id, file_name, mime_type, byte_size, local_path, duration_ms, waveform)
+         VALUES (?, ?, ?, ?, ?, ?, ?)
+         RETURNING {}",
+        ATTACHMENT_COLUMNS
+    ))
+    .bind(message_id)
+    .bind(file_name)
+    .bind(voice_mime_type(path))
+    .bind(byte_size as i64)
+    .bind(local_path)
+    .bind(duration_ms as i64)
+    .bind(waveform)
+    .fetch_one(pool)
+    .await
+    .context("failed to save voice clip")
+}
+
+pub(crate) async fn list_for_message(
+    pool: &SqlitePool,
+    message_id: i64,
+) -> Result<Vec<Attachment>, AppError> {
+    sqlx::query_as::<_, Attachment>(&format!(
+        "SELECT {} FROM attachments WHERE message_id = ? ORDER BY id",
+        ATTACHMENT_COLUMNS
+    ))
+    .bind(message_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load attachments")
+}
+
+/// Decodes `original`, scales it down to fit in `max_dimension` and
+/// re-encodes it as JPEG.
+fn reencode(original: &[u8], max_dimension: u32, quality: u8) -> Result<Vec<u8>, String> {
+    let mut image = image::load_from_memory(original).map_err(|e| e.to_string())?;
+    if image.width() > max_dimension || image.height() > max_dimension {
+        image = image.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+    }
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode_image(&image.to_rgb8())
+        .map_err(|e| e.to_string())?;
+    Ok(jpeg)
+}
+
+/// Re-encodes one image. Returns the new path and size, or `None` when the
+/// result wouldn't be smaller.
+async fn recompress_one(
+    id: i64,
+    local_path: &str,
+    byte_size: i64,
+    max_dimension: u32,
+    quality: u8,
+) -> Result<Option<(String, i64)>, String> {
+    let original = std::fs::read(local_path).map_err(|e| e.to_string())?;
+    let jpeg =
+        tauri::async_runtime::spawn_blocking(move || reencode(&original, max_dimension, quality))
+            .await
+            .map_err(|e| e.to_string())??;
+    if jpeg.len() as i64 >= byte_size {
+        return Ok(None);
+    }
+
+    // JPEGs are replaced in place. Anything else gets a new name, with the id
+    // in it, so `photo.png` can't overwrite a `photo.jpg` sitting next to it.
+    let original_path = Path::new(local_path);
+    let path = match original_path.extension().and_then(|e| e.to_str()) {
+        Some("jpg") => original_path.to_path_buf(),
+        _ => {
+            let stem = original_path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("attachment");
+            original_path.with_file_name(format!("{}-{}.jpg", stem, id))
+        }
+    };
+    // Written beside the original and renamed into place, so a crash leaves
+    // either the old file or a complete new one.
+    let partial = path.with_extension("jpg.part");
+    std::fs::write(&partial, &jpeg)
+        .and_then(|_| std::fs::rename(&partial, &path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&partial);
+            e.to_string()
+        })?;
+    Ok(Some((path.display().to_string(), jpeg.len() as i64)))
+}
+
+/// Shrinks stored image attachments to at most `max_dimension` pixels a side,
+/// as JPEG at `quality`. A row only points at the new file once it is fully
+/// written, and the original is deleted after that.
+pub(crate) async fn recompress_images(
+    pool: &SqlitePool,
+    max_dimension: u32,
+    quality: u8,
+) -> Result<RecompressReport, AppError> {
+    if max_dimension == 0 {
+        return Err(AppError::Validation(
+            "max dimension must be at least 1 pixel".into(),
+        ));
+    }
+    if !(1..=100).contains(&quality) {
+        return Err(AppError::Validation(format!(
+            "quality must be between 1 and 100, got {}",
+            quality
+        )));
+    }
+
+    let types = serde_json::to_string(RECOMPRESSIBLE_MIME_TYPES).expect("types serialize to JSON");
+    let rows: Vec<(i64, String, i64)> = sqlx::query_as(
+        "SELECT id, local_path, byte_size FROM attachments
+         WHERE local_path IS NOT NULL
+           AND mime_type IN (SELECT value FROM json_each(?))
+           AND byte_size >= ?
+         ORDER BY id",
+    )
+    .bind(types)
+    .bind(MIN_RECOMPRESS_BYTES)
+    .fetch_all(pool)
+    .await
+    .context("failed to load attachments")?;
+
+    let mut report = RecompressReport::default();
+    for (id, local_path, byte_size) in rows {
+        let (new_path, new_size) =
+            match recompress_one(id, &local_path, byte_size, max_dimension, quality).await {
+                Ok(Some(result)) => result,
+                Ok(None) => {
+                    report.skipped += 1;
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("failed to recompress attachment {}: {}", id, e);
+                    report.failed.push(id);
+                    continue;
+                }
+            };
+
+        sqlx::query(
+            "UPDATE attachments
+             SET local_path = ?, byte_size = ?, mime_type = 'image/jpeg'
+             WHERE id = ?",
+        )
+        .bind(&new_path)
+        .bind(new_size)
+        .bind(id)
+        .execute(pool)
+        .await
+        .context("failed to update attachment")?;
+        if new_path != local_path {
+            let _ = std::fs::remove_file(&local_path);
+        }
+
+        report.recompressed += 1;
+        report.bytes_saved += byte_size - new_size;
+    }
+    Ok(report)
+}
+
+#[tauri::command]
+pub async fn attach_file(
+    db: State<'_, DbInstances>,
+    settings: State<'_, AttachmentSettings>,
+    message_id: i64,
+    file_name: String,
+    mime_type: String,
+    byte_size: u64,
+    local_path: String,
+) -> Result<Attachment, AppError> {
+    let pool = db::pool(&db).await?;
+    insert_attachment(
+        &pool,
+        settings.max_bytes(),
+        message_id,
+        &file_name,
+        &mime_type,
+        byte_size,
+        &local_path,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn attach_voice(
+    db: State<'_, DbInstances>,
+    settings: State<'_, AttachmentSettings>,
+    message_id: i64,
+    local_path: String,
+    duration_ms: u64,
+    waveform: String,
+) -> Result<Attachment, AppError> {
+    let pool = db::pool(&db).await?;
+    insert_voice(
+        &pool,
+        settings.max_bytes(),
+        message_id,
+        &local_path,
+        duration_ms,
+        &waveform,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn attachments_for(
+    db: State<'_, DbInstances>,
+    message_id: i64,
+) -> Result<Vec<Attachment>, AppError> {
+    let pool = db::pool(&db).await?;
+    list_for_message(&pool, message_id).await
+}
+
+#[tauri::command]
+pub fn set_max_attachment_bytes(settings: State<'_, AttachmentSettings>, max_bytes: u64) {
+    settings.max_bytes.store(max_bytes, Ordering::Relaxed);
+}
+
+#[tauri::command]
+pub async fn recompress_attachments(
+    db: State<'_, DbInstances>,
+    max_dimension: u32,
+    quality: u8,
+) -> Result<RecompressReport, AppError> {
+    let pool = db::pool(&db).await?;
+    recompress_images(&pool, max_dimension, quality).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::messages::insert_message;
+    use crate::test_support::test_pool;
+
+    const MAX_BYTES: u64 = 1 << 20;
+
+    async fn message_of_type(pool: &SqlitePool, message_type: MessageType) -> i64 {
+        let encryption = EncryptionState::default();
+        insert_message(pool, &encryption, 1, 2, "clip", message_type, None)
+            .await
+            .unwrap()
+            .id
+    }
+
+    #[tokio::test]
+    async fn voice_clips_are_validated_before_they_are_stored() {
+        let pool = test_pool().await;
+        let voice = message_of_type(&pool, MessageType::Voice).await;
+        let text = message_of_type(&pool, MessageType::Text).await;
+        let clip = std::env::temp_dir().join(format!("cereals-voice-{}.opus", std::process::id()));
+        std::fs::write(&clip, [0u8; 321]).unwrap();
+        let clip_path = clip.to_str().unwrap();
+        let wave = BASE64.encode([7u8; WAVEFORM_SAMPLES]);
+        let short_wave = BASE64.encode([7u8; 10]);
+
+        let too_long = MAX_VOICE_DURATION_MS + 1;
+        for (max_bytes, message_id, duration_ms, waveform) in [
+            (MAX_BYTES, voice, too_long, wave.as_str()),
+            (MAX_BYTES, voice, 0, wave.as_str()),
+            (MAX_BYTES, voice, 1000, "!!!"),
+            (MAX_BYTES, voice, 1000, short_wave.as_str()),
+            (MAX_BYTES, text, 1000, wave.as_str()),
+            (100, voice, 1000, wave.as_str()),
+        ] {
+            let err = insert_voice(
+                &pool,
+                max_bytes,
+                message_id,
+                clip_path,
+                duration_ms,
+                waveform,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.code(), "validation");
+        }
+
+        let stored = insert_voice(&pool, MAX_BYTES, voice, clip_path, 12_345, &wave)
+            .await
+            .unwrap();
+        std::fs::remove_file(&clip).unwrap();
+        assert!(stored.file_name.starts_with("cereals-voice-"));
+        assert_eq!(stored.mime_type, "audio/ogg");
+        assert_eq!(stored.byte_size, 321);
+        assert_eq!(stored.duration_ms, Some(12_345));
+        assert_eq!(stored.waveform.as_deref(), Some(wave.as_str()));
+        assert_eq!(list_for_message(&pool, voice).await.unwrap().len(), 1);
+    }
+
+    /// A PNG of noise, so it barely compresses and stays well over
+    /// `MIN_RECOMPRESS_BYTES` at photo sizes.
+    fn noisy_png(width: u32, height: u32) -> Vec<u8> {
+        let mut seed: u32 = 12345;
+        let pixels = image::RgbImage::from_fn(width, height, |_, _| {
+            let mut channel = || {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            };
+            image::Rgb([channel(), channel(), channel()])
+        });
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(pixels)
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[tokio::test]
+    async fn recompressing_shrinks_only_large_images() {
+        let pool = test_pool().await;
+        let message_id = message_of_type(&pool, MessageType::Image).await;
+        let dir = std::env::temp_dir().join(format!("cereals-recompress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let photo = dir.join("photo.png");
+        std::fs::write(&photo, noisy_png(400, 300)).unwrap();
+        let icon = dir.join("icon.png");
+        std::fs::write(&icon, noisy_png(10, 10)).unwrap();
+        let document = dir.join("doc.pdf");
+        std::fs::write(&document, vec![0u8; 200 * 1024]).unwrap();
+        let broken = dir.join("broken.png");
+        std::fs::write(&broken, vec![7u8; 200 * 1024]).unwrap();
+
+        let mut stored = Vec::new();
+        for (path, mime_type) in [
+            (&photo, "image/png"),
+            (&icon, "image/png"),
+            (&document, "application/pdf"),
+            (&broken, "image/png"),
+        ] {
+            let size = std::fs::metadata(path).unwrap().len();
+            let path = path.to_str().unwrap();
+            let attachment =
+                insert_attachment(&pool, u64::MAX, message_id, "f", mime_type, size, path)
+                    .await
+                    .unwrap();
+            stored.push(attachment);
+        }
+
+        let err = recompress_images(&pool, 0, 80).await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        let err = recompress_images(&pool, 100, 0).await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        let report = recompress_images(&pool, 100, 80).await.unwrap();
+        assert_eq!(report.recompressed, 1);
+        assert_eq!(report.skipped, 0);
+        assert_eq!(report.failed, vec![stored[3].id]);
+
+        let after = list_for_message(&pool, message_id).await.unwrap();
+        let shrunk = &after[0];
+        assert_eq!(shrunk.mime_type, "image/jpeg");
+        assert!(shrunk.byte_size < stored[0].byte_size);
+        assert_eq!(report.bytes_saved, stored[0].byte_size - shrunk.byte_size);
+        let new_path = shrunk.local_path.clone().unwrap();
+        assert!(new_path.ends_with(&format!("photo-{}.jpg", shrunk.id)));
+        assert!(!photo.exists());
+        let bytes = std::fs::read(&new_path).unwrap();
+        assert_eq!(bytes.len() as i64, shrunk.byte_size);
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert!(decoded.width() <= 100 && decoded.height() <= 100);
+
+        assert_eq!(after[1].byte_size, stored[1].byte_size);
+        assert_eq!(after[2].local_path, stored[2].local_path);
+        assert!(broken.exists());
+
+        let again = recompress_images(&pool, 100, 80).await.unwrap();
+        assert_eq!(again.recompressed, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
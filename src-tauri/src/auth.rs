@@ -0,0 +1,537 @@
+use std::time::Duration;
+
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+use tauri::{AppHandle, Manager, State};
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::users;
+
+/// How many tokens per user survive `store_tokens`.
+pub const KEEP_TOKENS_PER_USER: i64 = 2;
+/// How often expired tokens are pruned after the run at startup.
+pub const TOKEN_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TokenRow {
+    pub id: i64,
+    pub user_id: i64,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: String,
+    pub created_at: String,
+}
+
+/// A stored token, as shown in the list of signed-in devices. The token
+/// itself never leaves the backend; `fingerprint` is enough to tell two
+/// sessions apart.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub token_id: i64,
+    pub created_at: String,
+    pub expires_at: String,
+    pub expired: bool,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BootstrapResult {
+    pub user_id: i64,
+    pub expires_at: String,
+}
+
+const TOKEN_COLUMNS: &str = "id, user_id, access_token, refresh_token, expires_at, created_at";
+
+pub(crate) async fn valid_token(pool: &SqlitePool, user_id: i64) -> Result<TokenRow, AppError> {
+    let token = sqlx::query_as::<_, TokenRow>(&format!(
+        "SELECT {} FROM auth_tokens
+         WHERE user_id = ? AND datetime(expires_at) > datetime('now')
+         ORDER BY id DESC
+         LIMIT 1",
+        TOKEN_COLUMNS
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(token) = token {
+        return Ok(token);
+    }
+
+    let any: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM auth_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+
+    Err(if any > 0 {
+        AppError::TokenExpired(format!("token for user {} has expired", user_id))
+    } else {
+        AppError::NotFound(format!("no token stored for user {}", user_id))
+    })
+}
+
+/// Inserts a token row and drops all but the newest `KEEP_TOKENS_PER_USER`.
+pub(crate) async fn insert_tokens(
+    pool: &SqlitePool,
+    user_id: i64,
+    access_token: &str,
+    refresh_token: Option<&str>,
+    expires_at: &str,
+) -> Result<TokenRow, AppError> {
+    let mut tx = pool.begin().await?;
+    let row = insert_tokens_in(&mut tx, user_id, access_token, refresh_token, expires_at).await?;
+    tx.commit().await?;
+    Ok(row)
+}
+
+async fn insert_tokens_in(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    access_token: &str,
+    refresh_token: Option<&str>,
+    expires_at: &str,
+) -> Result<TokenRow, AppError> {
+    if access_token.is_empty() {
+        return Err(AppError::Validation(
+            "access token must not be empty".into(),
+        ));
+    }
+
+    let row = sqlx::query_as::<_, TokenRow>(&format!(
+        "INSERT INTO auth_tokens (user_id, access_token, refresh_token, expires_at)
+         SELECT ?, ?, ?, ? WHERE datetime(?4) IS NOT NULL
+         RETURNING {}",
+        TOKEN_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(access_token)
+    .bind(refresh_token)
+    .bind(expires_at)
+    .fetch_optional(&mut *conn)
+    .await
+    .context("failed to store tokens")?
+    .ok_or_else(|| {
+        AppError::Validation(format!("invalid expires_at timestamp '{}'", expires_at))
+    })?;
+
+    sqlx::query(
+        "DELETE FROM auth_tokens
+         WHERE user_id = ?1
+           AND id NOT IN (
+               SELECT id FROM auth_tokens WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2
+           )",
+    )
+    .bind(user_id)
+    .bind(KEEP_TOKENS_PER_USER)
+    .execute(&mut *conn)
+    .await
+    .context("failed to prune old tokens")?;
+
+    Ok(row)
+}
+
+/// Deletes expired tokens, except each user's newest one: a refresh needs
+/// the refresh token stored with it even after the access token has lapsed.
+pub(crate) async fn prune_expired(pool: &SqlitePool) -> Result<u32, AppError> {
+    let result = sqlx::query(
+        "DELETE FROM auth_tokens
+         WHERE datetime(expires_at) < datetime('now')
+           AND id NOT IN (SELECT MAX(id) FROM auth_tokens GROUP BY user_id)",
+    )
+    .execute(pool)
+    .await
+    .context("failed to prune expired tokens")?;
+    Ok(result.rows_affected() as u32)
+}
+
+/// Prunes expired tokens now and then every `TOKEN_PRUNE_INTERVAL`.
+pub fn spawn_token_pruner(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(TOKEN_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let pool = match db::pool(&app.state::<DbInstances>()).await {
+                Ok(pool) => pool,
+                Err(_) => continue,
+            };
+            match prune_expired(&pool).await {
+                Ok(0) => {}
+                Ok(removed) => tracing::info!("pruned {} expired auth tokens", removed),
+                Err(e) => tracing::warn!("token pruning failed: {}", e),
+            }
+        }
+    });
+}
+
+/// The first 48 bits of the access token's SHA-256, as `ab12cd…`.
+fn fingerprint(access_token: &str) -> String {
+    let hex: String = digest(&SHA256, access_token.as_bytes()).as_ref()[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}…", hex)
+}
+
+/// The user's sessions, newest first.
+pub(crate) async fn sessions_of(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<SessionInfo>, AppError> {
+    let rows: Vec<(i64, String, String, String, bool)> = sqlx::query_as(
+        "SELECT id, access_token, created_at, expires_at,
+                datetime(expires_at) <= datetime('now')
+         FROM auth_tokens WHERE user_id = ? ORDER BY id DESC",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load sessions")?;
+
+    Ok(rows
+        .into_iter()
+        .map(
+            |(token_id, access_token, created_at, expires_at, expired)| SessionInfo {
+                token_id,
+                created_at,
+                expires_at,
+                expired,
+                fingerprint: fingerprint(&access_token),
+            },
+        )
+        .collect())
+}
+
+pub(crate) async fn delete_session(
+    pool: &SqlitePool,
+    user_id: i64,
+    token_id: i64,
+) -> Result<(), AppError> {
+    let result = sqlx::query("DELETE FROM auth_tokens WHERE id = ? AND user_id = ?")
+        .bind(token_id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("failed to revoke session")?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!(
+            "session {} not found for user {}",
+            token_id, user_id
+        )));
+    }
+    Ok(())
+}
+
+/// Signs the user out everywhere except `keep_token_id`, which has to be one
+/// of their own sessions: a mistyped id must not sign them out of this
+/// device too. Returns how many sessions were revoked.
+pub(crate) async fn delete_other_sessions(
+    pool: &SqlitePool,
+    user_id: i64,
+    keep_token_id: i64,
+) -> Result<u32, AppError> {
+    let mut tx = pool.begin().await?;
+    let kept: Option<i64> =
+        sqlx::query_scalar("SELECT id FROM auth_tokens WHERE id = ? AND user_id = ?")
+            .bind(keep_token_id)
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    if kept.is_none() {
+        return Err(AppError::NotFound(format!(
+            "session {} not found for user {}",
+            keep_token_id, user_id
+        )));
+    }
+
+    let result = sqlx::query("DELETE FROM auth_tokens WHERE user_id = ? AND id != ?")
+        .bind(user_id)
+        .bind(keep_token_id)
+        .execute(&mut *tx)
+        .await
+        .context("failed to revoke sessions")?;
+    tx.commit().await?;
+    Ok(result.rows_affected() as u32)
+}
+
+/// Creates the user and stores their first tokens in one transaction, so a
+/// failure in either step leaves neither behind.
+pub(crate) async fn create_account(
+    pool: &SqlitePool,
+    username: &str,
+    avatar_url: Option<&str>,
+    access_token: &str,
+    refresh_token: Option<&str>,
+    expires_at: &str,
+) -> Result<BootstrapResult, AppError> {
+    let username = username.trim();
+    users::validate_username(username)?;
+    if let Some(avatar_url) = avatar_url {
+        users::validate_avatar_url(avatar_url)?;
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let user_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO users (username, avatar_url) VALUES (?, ?) RETURNING id",
+    )
+    .bind(username)
+    .bind(avatar_url)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(e) if e.is_unique_violation() => {
+            AppError::UsernameTaken(format!("username '{}' is already taken", username))
+        }
+        e => e.into(),
+    })?;
+
+    let token = insert_tokens_in(&mut tx, user_id, access_token, refresh_token, expires_at).await?;
+
+    tx.commit().await?;
+    Ok(BootstrapResult {
+        user_id,
+        expires_at: token.expires_at,
+    })
+}
+
+#[tauri::command]
+pub async fn get_valid_token(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<TokenRow, AppError> {
+    let pool = db::pool(&db).await?;
+    valid_token(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn store_tokens(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: String,
+) -> Result<TokenRow, AppError> {
+    let pool = db::pool(&db).await?;
+    insert_tokens(
+        &pool,
+        user_id,
+        &access_token,
+        refresh_token.as_deref(),
+        &expires_at,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn bootstrap_account(
+    db: State<'_, DbInstances>,
+    username: String,
+    avatar_url: Option<String>,
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: String,
+) -> Result<BootstrapResult, AppError> {
+    let pool = db::pool(&db).await?;
+    create_account(
+        &pool,
+        &username,
+        avatar_url.as_deref(),
+        &access_token,
+        refresh_token.as_deref(),
+        &expires_at,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn prune_expired_tokens(db: State<'_, DbInstances>) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    prune_expired(&pool).await
+}
+
+#[tauri::command]
+pub async fn list_sessions(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<Vec<SessionInfo>, AppError> {
+    let pool = db::pool(&db).await?;
+    sessions_of(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn revoke_session(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    token_id: i64,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    delete_session(&pool, user_id, token_id).await
+}
+
+#[tauri::command]
+pub async fn revoke_all_other_sessions(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    keep_token_id: i64,
+) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    delete_other_sessions(&pool, user_id, keep_token_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{count, test_pool};
+
+    #[tokio::test]
+    async fn valid_token_skips_expired_ones() {
+        let pool = test_pool().await;
+        assert_eq!(valid_token(&pool, 1).await.unwrap_err().code(), "not_found");
+
+        insert_tokens(&pool, 1, "old", None, "2000-01-01 00:00:00")
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_token(&pool, 1).await.unwrap_err().code(),
+            "token_expired"
+        );
+
+        insert_tokens(&pool, 1, "good", Some("refresh"), "2999-01-01T00:00:00Z")
+            .await
+            .unwrap();
+        // A newer row that has already expired doesn't shadow the valid one.
+        insert_tokens(&pool, 1, "stale", None, "2001-01-01 00:00:00")
+            .await
+            .unwrap();
+        let token = valid_token(&pool, 1).await.unwrap();
+        assert_eq!(token.access_token, "good");
+        assert_eq!(token.refresh_token.as_deref(), Some("refresh"));
+
+        let err = insert_tokens(&pool, 1, "x", None, "garbage")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    const FUTURE: &str = "2999-01-01 00:00:00";
+
+    #[tokio::test]
+    async fn create_account_stores_the_first_token() {
+        let pool = test_pool().await;
+        let created = create_account(&pool, "newbie", None, "tok", Some("ref"), FUTURE)
+            .await
+            .unwrap();
+        assert_eq!(created.expires_at, FUTURE);
+        let token = valid_token(&pool, created.user_id).await.unwrap();
+        assert_eq!(token.access_token, "tok");
+    }
+
+    #[tokio::test]
+    async fn a_failed_bootstrap_leaves_nothing_behind() {
+        let pool = test_pool().await;
+        create_account(&pool, "newbie", None, "tok", None, FUTURE)
+            .await
+            .unwrap();
+        let tokens = "SELECT COUNT(*) FROM auth_tokens";
+        let before = count(&pool, tokens).await;
+
+        let err = create_account(&pool, "newbie", None, "tok2", None, FUTURE)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "username_taken");
+        let err = create_account(&pool, "other", None, "tok3", None, "garbage")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        let other = "SELECT COUNT(*) FROM users WHERE username = 'other'";
+        assert_eq!(count(&pool, other).await, 0);
+        assert_eq!(count(&pool, tokens).await, before);
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_each_users_newest_token() {
+        let pool = test_pool().await;
+        for (user_id, expires_at) in [
+            (1, "2000-01-01 00:00:00"),
+            (1, "2000-01-02 00:00:00"),
+            (2, "2000-01-01 00:00:00"),
+            (2, FUTURE),
+            (3, "2000-01-01 00:00:00"),
+        ] {
+            insert_tokens(&pool, user_id, "a", Some("r"), expires_at)
+                .await
+                .unwrap();
+        }
+
+        // User 1 keeps the newer of two expired tokens, user 2 loses its
+        // expired one, and user 3's only token stays.
+        assert_eq!(prune_expired(&pool).await.unwrap(), 2);
+        let left: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT user_id, id FROM auth_tokens ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(left, [(1, 2), (2, 4), (3, 5)]);
+        assert_eq!(prune_expired(&pool).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn sessions_list_and_revoke() {
+        let pool = test_pool().await;
+        // Inserted directly, since `insert_tokens` only keeps the newest two.
+        let mut ids = Vec::new();
+        for (user_id, token, expires_at) in [
+            (1, "a", "2000-01-01 00:00:00"),
+            (1, "b", FUTURE),
+            (1, "c", FUTURE),
+            (2, "d", FUTURE),
+        ] {
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO auth_tokens (user_id, access_token, expires_at)
+             VALUES (?, ?, ?) RETURNING id",
+            )
+            .bind(user_id)
+            .bind(token)
+            .bind(expires_at)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+        let [old, second, current, other] = ids[..] else {
+            unreachable!()
+        };
+
+        let sessions = sessions_of(&pool, 1).await.unwrap();
+        let ids: Vec<i64> = sessions.iter().map(|s| s.token_id).collect();
+        assert_eq!(ids, [current, second, old]);
+        let expired: Vec<bool> = sessions.iter().map(|s| s.expired).collect();
+        assert_eq!(expired, [false, false, true]);
+        assert!(sessions.iter().all(|s| s.fingerprint.ends_with('…')));
+        assert_ne!(sessions[0].fingerprint, sessions[1].fingerprint);
+        assert!(!serde_json::to_string(&sessions)
+            .unwrap()
+            .contains("access_token"));
+
+        delete_session(&pool, 1, second).await.unwrap();
+        assert_eq!(sessions_of(&pool, 1).await.unwrap().len(), 2);
+        let err = delete_session(&pool, 1, other).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+
+        let err = delete_other_sessions(&pool, 1, other).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+        assert_eq!(sessions_of(&pool, 1).await.unwrap().len(), 2);
+        assert_eq!(delete_other_sessions(&pool, 1, current).await.unwrap(), 1);
+        let left = sessions_of(&pool, 1).await.unwrap();
+        assert_eq!(
+            left.iter().map(|s| s.token_id).collect::<Vec<_>>(),
+            [current]
+        );
+        assert_eq!(sessions_of(&pool, 2).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,337 @@
+//! On-disk cache for remote avatar images.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use ring::digest::{digest, SHA256};
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Manager, State};
+use tauri_plugin_sql::DbInstances;
+use url::{Host, Url};
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::link_preview;
+
+pub const MAX_AVATAR_BYTES: usize = 10 * 1024 * 1024;
+pub const AVATAR_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(15);
+/// Cached files older than this are downloaded again.
+pub const AVATAR_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// `{user_id}-{hash of url}`, so a changed avatar url never reuses the old file.
+fn cache_stem(user_id: i64, url: &str) -> String {
+    let hash = digest(&SHA256, url.as_bytes());
+    let hex: String = hash.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}-{}", user_id, hex)
+}
+
+fn extension_for(content_type: &str) -> &'static str {
+    match content_type {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        _ => "img",
+    }
+}
+
+fn is_fresh(path: &Path, now: SystemTime) -> bool {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| now.duration_since(modified).ok())
+        .is_some_and(|age| age < AVATAR_CACHE_TTL)
+}
+
+fn download_error(e: reqwest::Error) -> AppError {
+    AppError::Io(format!("failed to download avatar: {}", e))
+}
+
+/// Fetches the image from a public host, the way link previews are fetched:
+/// the connection is pinned to the address `link_preview::guard` checked, and
+/// redirects aren't followed since they would skip that check.
+async fn download(url: &Url) -> Result<(Vec<u8>, String), AppError> {
+    let addr = link_preview::guard(url).await?;
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(AVATAR_DOWNLOAD_TIMEOUT);
+    if let Some(Host::Domain(domain)) = url.host() {
+        builder = builder.resolve(domain, addr);
+    }
+    let client = builder.build().map_err(download_error)?;
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(download_error)?;
+    read_image(response).await
+}
+
+/// Reads the image out of `response`, rejecting anything that isn't `image/*`
+/// or is larger than `MAX_AVATAR_BYTES`. The body is read in chunks so an
+/// oversized download is cut off rather than buffered whole.
+async fn read_image(mut response: reqwest::Response) -> Result<(Vec<u8>, String), AppError> {
+    if !response.status().is_success() {
+        return Err(AppError::Io(format!(
+            "failed to download avatar: server returned {}",
+            response.status()
+        )));
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    if !content_type.starts_with("image/") {
+        return Err(AppError::Validation(format!(
+            "avatar url did not return an image (content type '{}')",
+            content_type
+        )));
+    }
+
+    let too_large = || {
+        AppError::Validation(format!(
+            "avatar is larger than {} MB",
+            MAX_AVATAR_BYTES / (1024 * 1024)
+        ))
+    };
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_AVATAR_BYTES as u64)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(download_error)? {
+        if body.len() + chunk.len() > MAX_AVATAR_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, content_type))
+}
+
+/// Returns a local copy of the avatar at `url`, downloading it into `dir`
+/// unless the user's cached file for this url is less than a week old.
+pub(crate) async fn cache_avatar_in(
+    pool: &SqlitePool,
+    dir: &Path,
+    user_id: i64,
+    url: &str,
+) -> Result<String, AppError> {
+    let parsed =
+        Url::parse(url).map_err(|e| AppError::Validation(format!("invalid avatar url: {}", e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(AppError::Validation(
+            "only http(s) avatars can be cached".into(),
+        ));
+    }
+
+    let current: Option<String> =
+        sqlx::query_scalar::<_, Option<String>>("SELECT avatar_local_path FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("user {} not found", user_id)))?;
+
+    let stem = cache_stem(user_id, url);
+    if let Some(current) = &current {
+        let path = Path::new(current);
+        let same_url = path.file_stem().and_then(|s| s.to_str()) == Some(stem.as_str());
+        if same_url && is_fresh(path, SystemTime::now()) {
+            return Ok(current.clone());
+        }
+    }
+
+    let (body, content_type) = download(&parsed).await?;
+    store_avatar(pool, dir, user_id, url, &body, &content_type).await
+}
+
+/// Saves a downloaded avatar as the user's cached copy of `url`, removing the
+/// file it replaces.
+async fn store_avatar(
+    pool: &SqlitePool,
+    dir: &Path,
+    user_id: i64,
+    url: &str,
+    body: &[u8],
+    content_type: &str,
+) -> Result<String, AppError> {
+    let current: Option<String> =
+        sqlx::query_scalar("SELECT avatar_local_path FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+            .context("failed to load avatar path")?;
+
+    let stem = cache_stem(user_id, url);
+    std::fs::create_dir_all(dir)
+        .map_err(|e| AppError::Io(format!("failed to create {}: {}", dir.display(), e)))?;
+    let path: PathBuf = dir.join(format!("{}.{}", stem, extension_for(content_type)));
+    // Write then rename, so a crash never leaves a truncated file behind.
+    let partial = path.with_extension("part");
+    std::fs::write(&partial, body)
+        .and_then(|_| std::fs::rename(&partial, &path))
+        .map_err(|e| AppError::Io(format!("failed to write {}: {}", path.display(), e)))?;
+
+    let local_path = path.display().to_string();
+    sqlx::query("UPDATE users SET avatar_local_path = ? WHERE id = ?")
+        .bind(&local_path)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("failed to store avatar path")?;
+
+    if let Some(previous) = current.filter(|previous| *previous != local_path) {
+        let _ = std::fs::remove_file(previous);
+    }
+    Ok(local_path)
+}
+
+#[tauri::command]
+pub async fn cache_avatar(
+    app: AppHandle,
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    url: String,
+) -> Result<String, AppError> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| AppError::Io(format!("no app data directory: {}", e)))?
+        .join("avatars");
+    let pool = db::pool(&db).await?;
+    cache_avatar_in(&pool, &dir, user_id, &url).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Any download attempt fails, since `guard` refuses loopback hosts.
+    const LOCAL_URL: &str = "http://127.0.0.1/avatar.png";
+
+    fn cache_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cereals-avatars-{}-{}", name, std::process::id()))
+    }
+
+    /// Answers one request on a local port with `response`.
+    async fn serve_once(response: Vec<u8>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            let _ = socket.write_all(&response).await;
+        });
+        format!("http://{}/avatar", addr)
+    }
+
+    async fn fetch(response: Vec<u8>) -> Result<(Vec<u8>, String), AppError> {
+        let url = serve_once(response).await;
+        read_image(reqwest::get(url).await.unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn fresh_avatars_are_not_downloaded_again() {
+        let pool = test_pool().await;
+        let dir = cache_dir("fresh");
+        let path = store_avatar(&pool, &dir, 1, LOCAL_URL, b"png", "image/png")
+            .await
+            .unwrap();
+        assert!(path.ends_with(".png"));
+        assert_eq!(
+            cache_avatar_in(&pool, &dir, 1, LOCAL_URL).await.unwrap(),
+            path
+        );
+
+        // Once a week old it's fetched again, which for this host fails.
+        let week_ago = SystemTime::now() - AVATAR_CACHE_TTL - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(week_ago)
+            .unwrap();
+        let err = cache_avatar_in(&pool, &dir, 1, LOCAL_URL)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_new_avatar_url_gets_a_new_file() {
+        let pool = test_pool().await;
+        let dir = cache_dir("changed");
+        let old = store_avatar(
+            &pool,
+            &dir,
+            1,
+            "https://a.example/1.png",
+            b"one",
+            "image/png",
+        )
+        .await
+        .unwrap();
+        let new = store_avatar(
+            &pool,
+            &dir,
+            1,
+            "https://a.example/2.jpg",
+            b"two",
+            "image/jpeg",
+        )
+        .await
+        .unwrap();
+        assert_ne!(old, new);
+        assert!(!Path::new(&old).exists());
+        assert_eq!(std::fs::read(&new).unwrap(), b"two");
+        let stored: Option<String> =
+            sqlx::query_scalar("SELECT avatar_local_path FROM users WHERE id = 1")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, Some(new));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_images_up_to_the_size_limit_are_accepted() {
+        let image = fetch(
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/PNG; q=1\r\nContent-Length: 3\r\n\r\npng"
+                .to_vec(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(image, (b"png".to_vec(), "image/png".to_string()));
+
+        let html = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 2\r\n\r\nhi";
+        assert_eq!(fetch(html.to_vec()).await.unwrap_err().code(), "validation");
+
+        let declared = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+            MAX_AVATAR_BYTES + 1
+        );
+        assert_eq!(
+            fetch(declared.into_bytes()).await.unwrap_err().code(),
+            "validation"
+        );
+
+        // Without a length the body is cut off once it passes the limit.
+        let mut streamed =
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nConnection: close\r\n\r\n".to_vec();
+        streamed.resize(streamed.len() + MAX_AVATAR_BYTES + 1, 0);
+        assert_eq!(fetch(streamed).await.unwrap_err().code(), "validation");
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Column, Row, SqlitePool, TypeInfo, ValueRef};
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::AppError;
+use crate::export::ensure_parent_dir;
+use crate::maintenance::{user_tables, TableCount};
+
+/// File layout: `MAGIC | version | salt | nonce | ciphertext+tag`. The header
+/// is authenticated as associated data, so tampering with it also fails
+/// decryption.
+const MAGIC: &[u8; 4] = b"CHBK";
+pub const BACKUP_FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupSummary {
+    pub path: String,
+    pub tables: Vec<TableCount>,
+    pub file_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupContents {
+    created_at: String,
+    tables: BTreeMap<String, Vec<Map<String, Value>>>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<LessSafeKey, AppError> {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations are non-zero"),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&AES_256_GCM, &key)
+        .map_err(|_| AppError::Validation("failed to initialise cipher".into()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn encrypt(plaintext: Vec<u8>, passphrase: &str) -> Result<Vec<u8>, AppError> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(BACKUP_FORMAT_VERSION);
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    let mut sealed = plaintext;
+    derive_key(passphrase, &salt)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header),
+            &mut sealed,
+        )
+        .map_err(|_| AppError::Validation("failed to encrypt backup".into()))?;
+
+    header.extend_from_slice(&sealed);
+    Ok(header)
+}
+
+fn decrypt(raw: &[u8], passphrase: &str) -> Result<Vec<u8>, AppError> {
+    if raw.len() < HEADER_LEN || &raw[..MAGIC.len()] != MAGIC {
+        return Err(AppError::Validation("file is not a backup archive".into()));
+    }
+    if raw[MAGIC.len()] != BACKUP_FORMAT_VERSION {
+        return Err(AppError::Validation(format!(
+            "unsupported backup format version {}",
+            raw[MAGIC.len()]
+        )));
+    }
+
+    let (header, body) = raw.split_at(HEADER_LEN);
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce = Nonce::try_assume_unique_for_key(&header[HEADER_LEN - NONCE_LEN..])
+        .map_err(|_| AppError::Validation("backup header is malformed".into()))?;
+
+    let mut opened = body.to_vec();
+    let plaintext = derive_key(passphrase, salt)?
+        .open_in_place(nonce, Aad::from(header), &mut opened)
+        .map_err(|_| {
+            AppError::DecryptionFailed("wrong passphrase or the backup file is corrupted".into())
+        })?;
+    Ok(plaintext.to_vec())
+}
+
+/// Converts a row to JSON using each value's SQLite storage class.
+fn row_to_json(row: &SqliteRow) -> Result<Map<String, Value>, AppError> {
+    let mut object = Map::new();
+    for column in row.columns() {
+        let index = column.ordinal();
+        let raw = row.try_get_raw(index)?;
+        let value = if raw.is_null() {
+            Value::Null
+        } else {
+            match raw.type_info().name() {
+                "INTEGER" | "BOOLEAN" => Value::from(row.try_get::<i64, _>(index)?),
+                "REAL" => Value::from(row.try_get::<f64, _>(index)?),
+                "BLOB" => {
+                    return Err(AppError::Validation(format!(
+                        "column {} holds binary data, which backups do not support",
+                        column.name()
+                    )))
+                }
+                _ => Value::from(row.try_get::<String, _>(index)?),
+            }
+        };
+        object.insert(column.name().to_string(), value);
+    }
+    Ok(object)
+}
+
+pub(crate) async fn write_backup(
+    pool: &SqlitePool,
+    path: &Path,
+    passphrase: &str,
+) -> Result<BackupSummary, AppError> {
+    if passphrase.is_empty() {
+        return Err(AppError::Validation("passphrase must not be empty".into()));
+    }
+    ensure_parent_dir(path)?;
+
+    let table_names = user_tables(pool).await?;
+
+    // A single read transaction keeps every table at the same point in time.
+    let mut tx = pool.begin().await?;
+    let mut tables = BTreeMap::new();
+    let mut counts = Vec::new();
+    for table in table_names {
+        let rows = sqlx::query(&format!("SELECT * FROM \"{}\"", table))
+            .fetch_all(&mut *tx)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(row_to_json)
+            .collect::<Result<Vec<_>, _>>()?;
+        counts.push(TableCount {
+            table: table.clone(),
+            rows: rows.len() as i64,
+        });
+        tables.insert(table, rows);
+    }
+    let created_at: String = sqlx::query_scalar("SELECT strftime('%Y-%m-%dT%H:%M:%SZ', 'now')")
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    let json = serde_json::to_vec(&BackupContents { created_at, tables })
+        .map_err(|e| AppError::Validation(format!("failed to serialize backup: {}", e)))?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&json)
+        .map_err(|e| AppError::Io(format!("failed to compress backup: {}", e)))?;
+    let compressed = encoder
+        .finish()
+        .map_err(|e| AppError::Io(format!("failed to compress backup: {}", e)))?;
+
+    let archive = encrypt(compressed, passphrase)?;
+    std::fs::write(path, &archive)
+        .map_err(|e| AppError::Io(format!("failed to write {}: {}", path.display(), e)))?;
+
+    Ok(BackupSummary {
+        path: path.display().to_string(),
+        tables: counts,
+        file_size: archive.len() as u64,
+    })
+}
+
+fn bind_value<'q>(
+    query: sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>,
+    value: &'q Value,
+) -> sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>> {
+    match value {
+        Value::Null => query.bind(Option::<i64>::None),
+        Value::Bool(b) => query.bind(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => query.bind(i),
+            None => query.bind(n.as_f64()),
+        },
+        Value::String(s) => query.bind(s.as_str()),
+        other => query.bind(other.to_string()),
+    }
+}
+
+/// Replaces the contents of every table with the backup. The archive is fully
+/// decrypted and parsed before the database is touched, and the restore runs
+/// in one transaction, so a bad passphrase or a failed insert leaves the
+/// existing data intact.
+pub(crate) async fn read_backup(
+    pool: &SqlitePool,
+    path: &Path,
+    passphrase: &str,
+) -> Result<BackupSummary, AppError> {
+    let raw = std::fs::read(path)
+        .map_err(|e| AppError::Io(format!("failed to read {}: {}", path.display(), e)))?;
+    let compressed = decrypt(&raw, passphrase)?;
+
+    let mut json = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut json)
+        .map_err(|e| AppError::Validation(format!("backup payload is corrupted: {}", e)))?;
+    let contents: BackupContents = serde_json::from_slice(&json)
+        .map_err(|e| AppError::Validation(format!("backup payload is invalid: {}", e)))?;
+
+    let current_tables = user_tables(pool).await?;
+    if let Some(unknown) = contents.tables.keys().find(|t| !current_tables.contains(t)) {
+        return Err(AppError::Validation(format!(
+            "backup contains unknown table '{}'",
+            unknown
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+    // Foreign keys are checked at commit, so tables can be restored in any order.
+    sqlx::query("PRAGMA defer_foreign_keys = ON")
+        .execute(&mut *tx)
+        .await?;
+
+    for table in &current_tables {
+        sqlx::query(&format!("DELETE FROM \"{}\"", table))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let mut counts = Vec::new();
+    for (table, rows) in &contents.tables {
+        let columns: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM pragma_table_info(?) ORDER BY cid")
+                .bind(table)
+                .fetch_all(&mut *tx)
+                .await?;
+
+        for row in rows {
+            let present: Vec<&String> = columns.iter().filter(|c| row.contains_key(*c)).collect();
+            if present.is_empty() {
+                continue;
+            }
+            let sql = format!(
+                "INSERT INTO \"{}\" ({}) VALUES ({})",
+                table,
+                present
+                    .iter()
+                    .map(|c| format!("\"{}\"", c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                vec!["?"; present.len()].join(", ")
+            );
+            let mut query = sqlx::query(&sql);
+            for column in &present {
+                query = bind_value(query, &row[column.as_str()]);
+            }
+            query.execute(&mut *tx).await?;
+        }
+
+        counts.push(TableCount {
+            table: table.clone(),
+            rows: rows.len() as i64,
+        });
+    }
+
+    tx.commit().await?;
+
+    Ok(BackupSummary {
+        path: path.display().to_string(),
+        tables: counts,
+        file_size: raw.len() as u64,
+    })
+}
+
+#[tauri::command]
+pub async fn export_backup(
+    db: State<'_, DbInstances>,
+    path: String,
+    passphrase: String,
+) -> Result<BackupSummary, AppError> {
+    let pool = db::pool(&db).await?;
+    write_backup(&pool, Path::new(&path), &passphrase).await
+}
+
+#[tauri::command]
+pub async fn import_backup(
+    db: State<'_, DbInstances>,
+    path: String,
+    passphrase: String,
+) -> Result<BackupSummary, AppError> {
+    let pool = db::pool(&db).await?;
+    read_backup(&pool, Path::new(&path), &passphrase).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::groups::insert_group;
+    use crate::message_type::MessageType;
+    use crate::messages::insert_message;
+    use crate::test_support::{count, test_pool};
+
+    async fn seed(pool: &SqlitePool) {
+        let encryption = EncryptionState::default();
+        insert_message(
+            pool,
+            &encryption,
+            1,
+            2,
+            "secret hello",
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap();
+        insert_message(pool, &encryption, 2, 1, "hi back", MessageType::Text, None)
+            .await
+            .unwrap();
+        insert_group(pool, "g", None, 1, &[2, 3]).await.unwrap();
+    }
+
+    async fn table_counts(pool: &SqlitePool) -> Vec<TableCount> {
+        let mut counts = Vec::new();
+        for table in user_tables(pool).await.unwrap() {
+            let rows = count(pool, &format!("SELECT COUNT(*) FROM \"{}\"", table)).await;
+            counts.push(TableCount { table, rows });
+        }
+        counts
+    }
+
+    fn rows_of(counts: &[TableCount]) -> Vec<(String, i64)> {
+        let mut rows: Vec<_> = counts.iter().map(|t| (t.table.clone(), t.rows)).collect();
+        rows.sort();
+        rows
+    }
+
+    #[tokio::test]
+    async fn backups_restore_every_table() {
+        let pool = test_pool().await;
+        seed(&pool).await;
+        let path = std::env::temp_dir().join(format!("cereals-backup-{}.chbk", std::process::id()));
+        let written = write_backup(&pool, &path, "hunter2").await.unwrap();
+        assert_eq!(
+            rows_of(&written.tables),
+            rows_of(&table_counts(&pool).await)
+        );
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+
+        // The restore replaces what was there rather than adding to it.
+        let restored = test_pool().await;
+        insert_group(&restored, "other", None, 4, &[5])
+            .await
+            .unwrap();
+        let read = read_backup(&restored, &path, "hunter2").await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows_of(&read.tables), rows_of(&written.tables));
+        assert_eq!(
+            rows_of(&table_counts(&restored).await),
+            rows_of(&written.tables)
+        );
+        let messages = "SELECT COUNT(*) FROM messages WHERE content = 'secret hello'";
+        assert_eq!(count(&restored, messages).await, 1);
+    }
+
+    #[tokio::test]
+    async fn bad_passphrases_and_tampered_files_change_nothing() {
+        let pool = test_pool().await;
+        seed(&pool).await;
+        let path = std::env::temp_dir().join(format!("cereals-tamper-{}.chbk", std::process::id()));
+        write_backup(&pool, &path, "hunter2").await.unwrap();
+        let raw = std::fs::read(&path).unwrap();
+
+        let target = test_pool().await;
+        insert_group(&target, "keep", None, 4, &[5]).await.unwrap();
+        let before = rows_of(&table_counts(&target).await);
+
+        let err = read_backup(&target, &path, "wrong").await.unwrap_err();
+        assert_eq!(err.code(), "decryption_failed");
+        assert_eq!(rows_of(&table_counts(&target).await), before);
+
+        // One flipped bit in the salt, the nonce or the ciphertext.
+        for index in [MAGIC.len() + 1, HEADER_LEN - 1, raw.len() - 1] {
+            let mut tampered = raw.clone();
+            tampered[index] ^= 1;
+            std::fs::write(&path, &tampered).unwrap();
+            let err = read_backup(&target, &path, "hunter2").await.unwrap_err();
+            assert_eq!(err.code(), "decryption_failed", "byte {}", index);
+        }
+        let mut tampered = raw.clone();
+        tampered[MAGIC.len()] = BACKUP_FORMAT_VERSION + 1;
+        std::fs::write(&path, &tampered).unwrap();
+        let err = read_backup(&target, &path, "hunter2").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows_of(&table_counts(&target).await), before);
+    }
+}
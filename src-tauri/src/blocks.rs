@@ -0,0 +1,167 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct BlockedEntry {
+    pub blocked_id: i64,
+    pub username: String,
+    pub blocked_at: String,
+}
+
+/// Whether `user_id` has blocked `other_id`.
+pub(crate) async fn is_blocked<'e, E>(
+    executor: E,
+    user_id: i64,
+    other_id: i64,
+) -> Result<bool, sqlx::Error>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM blocked_users WHERE user_id = ? AND blocked_id = ?)",
+    )
+    .bind(user_id)
+    .bind(other_id)
+    .fetch_one(executor)
+    .await
+}
+
+pub(crate) async fn insert_block(
+    pool: &SqlitePool,
+    user_id: i64,
+    blocked_id: i64,
+) -> Result<(), AppError> {
+    if user_id == blocked_id {
+        return Err(AppError::Validation("cannot block yourself".into()));
+    }
+
+    sqlx::query("INSERT OR IGNORE INTO blocked_users (user_id, blocked_id) VALUES (?, ?)")
+        .bind(user_id)
+        .bind(blocked_id)
+        .execute(pool)
+        .await
+        .context("failed to block user")?;
+    Ok(())
+}
+
+pub(crate) async fn delete_block(
+    pool: &SqlitePool,
+    user_id: i64,
+    blocked_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM blocked_users WHERE user_id = ? AND blocked_id = ?")
+        .bind(user_id)
+        .bind(blocked_id)
+        .execute(pool)
+        .await
+        .context("failed to unblock user")?;
+    Ok(())
+}
+
+pub(crate) async fn blocked_by(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<BlockedEntry>, AppError> {
+    sqlx::query_as::<_, BlockedEntry>(
+        "SELECT b.blocked_id, u.username, b.blocked_at
+         FROM blocked_users b
+         JOIN users u ON u.id = b.blocked_id
+         WHERE b.user_id = ?
+         ORDER BY b.blocked_at DESC, b.blocked_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load blocked users")
+}
+
+#[tauri::command]
+pub async fn block_user(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    blocked_id: i64,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    insert_block(&pool, user_id, blocked_id).await
+}
+
+#[tauri::command]
+pub async fn unblock_user(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    blocked_id: i64,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    delete_block(&pool, user_id, blocked_id).await
+}
+
+#[tauri::command]
+pub async fn list_blocked(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<Vec<BlockedEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    blocked_by(&pool, user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::message_type::MessageType;
+    use crate::messages::{conversation_page, insert_message};
+    use crate::presence::{statuses, update_status};
+    use crate::test_support::test_pool;
+
+    async fn send(pool: &SqlitePool, sender_id: i64, receiver_id: i64) -> Result<(), AppError> {
+        insert_message(
+            pool,
+            &EncryptionState::default(),
+            sender_id,
+            receiver_id,
+            "hi",
+            MessageType::Text,
+            None,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn history_stays_readable_after_a_block() {
+        let pool = test_pool().await;
+        send(&pool, 1, 2).await.unwrap();
+        update_status(&pool, 2, "online").await.unwrap();
+
+        insert_block(&pool, 2, 1).await.unwrap();
+        insert_block(&pool, 2, 1).await.unwrap();
+        assert_eq!(
+            insert_block(&pool, 2, 2).await.unwrap_err().code(),
+            "validation"
+        );
+        assert_eq!(blocked_by(&pool, 2).await.unwrap().len(), 1);
+
+        assert_eq!(send(&pool, 1, 2).await.unwrap_err().code(), "blocked");
+        send(&pool, 2, 1).await.unwrap();
+        assert_eq!(
+            conversation_page(&pool, 1, 2, None, 0).await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            statuses(&pool, Some(1), &[2]).await.unwrap()[0].status,
+            "offline"
+        );
+        assert_eq!(
+            statuses(&pool, Some(3), &[2]).await.unwrap()[0].status,
+            "online"
+        );
+
+        delete_block(&pool, 2, 1).await.unwrap();
+        send(&pool, 1, 2).await.unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::users::{DELETED_USER_ID, SYSTEM_USER_ID};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ContactView {
+    pub id: i64,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    pub status: String,
+    pub added_at: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportContactsResult {
+    pub added: Vec<String>,
+    pub already_present: Vec<String>,
+    pub not_found: Vec<String>,
+}
+
+/// Contacts who are invisible or have blocked the user show up as offline,
+/// as they do in `presence::statuses`.
+pub(crate) async fn contacts_of(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<ContactView>, AppError> {
+    sqlx::query_as::<_, ContactView>(
+        "SELECT u.id, u.username, u.avatar_url,
+                CASE WHEN u.invisible OR b.user_id IS NOT NULL THEN 'offline'
+                     ELSE COALESCE(u.status, 'offline') END AS status,
+                c.added_at
+         FROM contacts c
+         JOIN users u ON u.id = c.contact_id
+         LEFT JOIN blocked_users b ON b.user_id = u.id AND b.blocked_id = ?1
+         WHERE c.user_id = ?1 AND c.contact_id != ?1
+         ORDER BY u.username COLLATE NOCASE, u.id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load contacts")
+}
+
+/// Adds `contact_id` to `user_id`'s contacts (and the reverse pair when
+/// `reciprocal`), all-or-nothing. Re-adding an existing contact is a no-op.
+pub(crate) async fn insert_contact(
+    pool: &SqlitePool,
+    user_id: i64,
+    contact_id: i64,
+    reciprocal: bool,
+) -> Result<(), AppError> {
+    if user_id == contact_id {
+        return Err(AppError::Validation(
+            "cannot add yourself as a contact".into(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let mut pairs = vec![(user_id, contact_id)];
+    if reciprocal {
+        pairs.push((contact_id, user_id));
+    }
+    for (owner, contact) in pairs {
+        sqlx::query("INSERT OR IGNORE INTO contacts (user_id, contact_id) VALUES (?, ?)")
+            .bind(owner)
+            .bind(contact)
+            .execute(&mut *tx)
+            .await
+            .context("failed to add contact")?;
+    }
+
+    tx.commit().await.map_err(AppError::from)
+}
+
+/// Adds every known username in `usernames` as a contact of `user_id` in
+/// one transaction. Unknown names are reported rather than failing the
+/// batch; the user's own name and repeats are skipped.
+pub(crate) async fn import_usernames(
+    pool: &SqlitePool,
+    user_id: i64,
+    usernames: &[String],
+    reciprocal: bool,
+) -> Result<ImportContactsResult, AppError> {
+    let mut result = ImportContactsResult::default();
+    let mut seen = std::collections::HashSet::new();
+    let mut tx = pool.begin().await?;
+
+    for username in usernames {
+        let username = username.trim();
+        if username.is_empty() || !seen.insert(username) {
+            continue;
+        }
+        let contact_id: Option<i64> =
+            sqlx::query_scalar("SELECT id FROM users WHERE username = ? AND id NOT IN (?, ?)")
+                .bind(username)
+                .bind(SYSTEM_USER_ID)
+                .bind(DELETED_USER_ID)
+                .fetch_optional(&mut *tx)
+                .await
+                .context("failed to look up user")?;
+        let Some(contact_id) = contact_id else {
+            result.not_found.push(username.to_string());
+            continue;
+        };
+        if contact_id == user_id {
+            continue;
+        }
+
+        let inserted =
+            sqlx::query("INSERT OR IGNORE INTO contacts (user_id, contact_id) VALUES (?, ?)")
+                .bind(user_id)
+                .bind(contact_id)
+                .execute(&mut *tx)
+                .await
+                .context("failed to add contact")?
+                .rows_affected();
+        if reciprocal {
+            sqlx::query("INSERT OR IGNORE INTO contacts (user_id, contact_id) VALUES (?, ?)")
+                .bind(contact_id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .context("failed to add contact")?;
+        }
+        if inserted > 0 {
+            result.added.push(username.to_string());
+        } else {
+            result.already_present.push(username.to_string());
+        }
+    }
+
+    tx.commit().await?;
+    Ok(result)
+}
+
+pub(crate) async fn delete_contact(
+    pool: &SqlitePool,
+    user_id: i64,
+    contact_id: i64,
+    reciprocal: bool,
+) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "DELETE FROM contacts
+         WHERE (user_id = ?1 AND contact_id = ?2) OR (?3 AND user_id = ?2 AND contact_id = ?1)",
+    )
+    .bind(user_id)
+    .bind(contact_id)
+    .bind(reciprocal)
+    .execute(&mut *tx)
+    .await
+    .context("failed to remove contact")?;
+
+    tx.commit().await.map_err(AppError::from)
+}
+
+/// Collapses duplicate (`user_id`, `contact_id`) rows for `user_id`, keeping
+/// the earliest `added_at` of each pair. Returns how many rows were removed.
+pub(crate) async fn dedupe_contacts_of(pool: &SqlitePool, user_id: i64) -> Result<u32, AppError> {
+    let result = sqlx::query(
+        "DELETE FROM contacts
+         WHERE user_id = ?1 AND rowid NOT IN (
+             SELECT rowid FROM (
+                 SELECT rowid, ROW_NUMBER() OVER (
+                     PARTITION BY contact_id
+                     ORDER BY added_at IS NULL, added_at, rowid
+                 ) AS rank
+                 FROM contacts
+                 WHERE user_id = ?1
+             )
+             WHERE rank = 1
+         )",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await
+    .context("failed to dedupe contacts")?;
+
+    Ok(result.rows_affected() as u32)
+}
+
+#[tauri::command]
+pub async fn list_contacts(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<Vec<ContactView>, AppError> {
+    let pool = db::pool(&db).await?;
+    contacts_of(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn add_contact(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    contact_id: i64,
+    reciprocal: bool,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    insert_contact(&pool, user_id, contact_id, reciprocal).await
+}
+
+#[tauri::command]
+pub async fn import_contacts(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    usernames: Vec<String>,
+    reciprocal: bool,
+) -> Result<ImportContactsResult, AppError> {
+    let pool = db::pool(&db).await?;
+    import_usernames(&pool, user_id, &usernames, reciprocal).await
+}
+
+#[tauri::command]
+pub async fn remove_contact(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    contact_id: i64,
+    reciprocal: bool,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    delete_contact(&pool, user_id, contact_id, reciprocal).await
+}
+
+#[tauri::command]
+pub async fn dedupe_contacts(db: State<'_, DbInstances>, user_id: i64) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    dedupe_contacts_of(&pool, user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrations;
+    use crate::test_support::{count, test_pool};
+
+    #[tokio::test]
+    async fn contacts_sort_by_name_and_never_include_the_user() {
+        let pool = test_pool().await;
+        sqlx::query(
+            "UPDATE users SET username = CASE id WHEN 2 THEN 'zed' WHEN 3 THEN 'Amy' ELSE 'bob' END
+             WHERE id IN (2, 3, 4)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        // A self-row left over from before self-adds were rejected.
+        for contact_id in [1, 2, 3, 4] {
+            sqlx::query("INSERT INTO contacts (user_id, contact_id) VALUES (1, ?)")
+                .bind(contact_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let contacts = contacts_of(&pool, 1).await.unwrap();
+        assert_eq!(
+            contacts
+                .iter()
+                .map(|c| c.username.as_str())
+                .collect::<Vec<_>>(),
+            ["Amy", "bob", "zed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reciprocal_add_is_all_or_nothing() {
+        let pool = test_pool().await;
+        assert_eq!(
+            insert_contact(&pool, 1, 1, false).await.unwrap_err().code(),
+            "validation"
+        );
+
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM contacts").await, 2);
+
+        // Fail only the reverse row, the way a missing user would.
+        sqlx::query(
+            "CREATE TRIGGER fail_reverse BEFORE INSERT ON contacts WHEN NEW.user_id = 4
+             BEGIN SELECT RAISE(ABORT, 'FOREIGN KEY constraint failed'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert!(insert_contact(&pool, 3, 4, true).await.is_err());
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM contacts WHERE user_id = 3").await,
+            0
+        );
+        assert!(insert_contact(&pool, 3, 99, false).await.is_err());
+
+        delete_contact(&pool, 1, 2, false).await.unwrap();
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM contacts").await, 1);
+        delete_contact(&pool, 1, 2, true).await.unwrap();
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM contacts").await, 0);
+    }
+
+    /// Swaps in the legacy contacts table, which had no key, seeded with
+    /// duplicate pairs.
+    async fn legacy_pool() -> SqlitePool {
+        let pool = test_pool().await;
+        sqlx::raw_sql(
+            "DROP TABLE contacts;
+             CREATE TABLE contacts (
+                 user_id INTEGER NOT NULL,
+                 contact_id INTEGER NOT NULL,
+                 added_at DATETIME DEFAULT CURRENT_TIMESTAMP
+             );
+             INSERT INTO contacts VALUES
+                 (1, 2, '2024-03-01'), (1, 2, '2024-01-01'), (1, 2, '2024-02-01'),
+                 (1, 3, '2024-01-05'), (1, 3, NULL),
+                 (2, 1, '2024-01-01'), (2, 1, '2024-01-02');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn dedupe_keeps_the_earliest_of_each_pair() {
+        let pool = legacy_pool().await;
+        assert_eq!(dedupe_contacts_of(&pool, 1).await.unwrap(), 3);
+        assert_eq!(dedupe_contacts_of(&pool, 1).await.unwrap(), 0);
+
+        let kept: Vec<(i64, Option<String>)> = sqlx::query_as(
+            "SELECT contact_id, added_at FROM contacts WHERE user_id = 1 ORDER BY contact_id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            kept,
+            [
+                (2, Some("2024-01-01".into())),
+                (3, Some("2024-01-05".into()))
+            ]
+        );
+        // Other users' duplicates are left alone.
+        let others = "SELECT COUNT(*) FROM contacts WHERE user_id = 2";
+        assert_eq!(count(&pool, others).await, 2);
+    }
+
+    #[tokio::test]
+    async fn the_rebuild_migration_enforces_the_key() {
+        let pool = legacy_pool().await;
+        let rebuild = migrations()
+            .into_iter()
+            .find(|m| m.description == "rebuild_contacts_with_primary_key")
+            .unwrap();
+        sqlx::raw_sql(rebuild.sql).execute(&pool).await.unwrap();
+
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM contacts").await, 3);
+        let earliest =
+            "SELECT COUNT(*) FROM contacts WHERE user_id = 2 AND added_at = '2024-01-01'";
+        assert_eq!(count(&pool, earliest).await, 1);
+        let duplicate = sqlx::query("INSERT INTO contacts (user_id, contact_id) VALUES (1, 2)")
+            .execute(&pool)
+            .await;
+        assert!(duplicate.is_err());
+    }
+
+    #[tokio::test]
+    async fn import_sorts_names_into_added_present_and_unknown() {
+        let pool = test_pool().await;
+        insert_contact(&pool, 1, 3, false).await.unwrap();
+        let names: Vec<String> = ["u2", "nobody", "u1", "u3", " u4 ", "u2", "~system"]
+            .map(String::from)
+            .to_vec();
+
+        let report = import_usernames(&pool, 1, &names, true).await.unwrap();
+        assert_eq!(report.added, ["u2", "u4"]);
+        assert_eq!(report.already_present, ["u3"]);
+        assert_eq!(report.not_found, ["nobody", "~system"]);
+
+        let mine: Vec<i64> = contacts_of(&pool, 1)
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(mine, [2, 3, 4]);
+        // The reverse rows are filled in for existing contacts too.
+        for contact_id in [2, 3, 4] {
+            assert_eq!(contacts_of(&pool, contact_id).await.unwrap().len(), 1);
+        }
+    }
+}
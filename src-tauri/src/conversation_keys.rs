@@ -0,0 +1,279 @@
+//! Storage for end-to-end encryption keys. The cryptography happens in the
+//! client; this side only checks that keys are well-formed and that they are
+//! exchanged between participants of the conversation.
+//!
+//! A direct conversation's id is the two user ids, smaller first, joined by
+//! `:` (e.g. `3:7`); any other id is taken to be a group id.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::conversations::{self, parse_conversation_id};
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::groups::member_role;
+
+/// Raw length of an X25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Raw length of a wrapped AES-256 key: a 12-byte nonce, the 32-byte key
+/// sealed with AES-GCM, and its 16-byte tag.
+pub const WRAPPED_KEY_LEN: usize = 12 + 32 + 16;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PublicKey {
+    pub user_id: i64,
+    pub public_key: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct WrappedKey {
+    pub conversation_id: String,
+    pub user_id: i64,
+    pub wrapped_key: String,
+    pub wrapped_by: i64,
+    pub created_at: String,
+}
+
+/// Checks that `encoded` is standard base64 of exactly `len` bytes.
+fn validate_key(what: &str, encoded: &str, len: usize) -> Result<(), AppError> {
+    let raw = BASE64
+        .decode(encoded)
+        .map_err(|e| AppError::Validation(format!("{} is not valid base64: {}", what, e)))?;
+    if raw.len() != len {
+        return Err(AppError::Validation(format!(
+            "{} must be {} bytes, got {}",
+            what,
+            len,
+            raw.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `user_id` takes part in the conversation.
+async fn is_participant(
+    pool: &SqlitePool,
+    conversation_id: &str,
+    user_id: i64,
+) -> Result<bool, AppError> {
+    if !conversation_id.contains(':') {
+        return Ok(member_role(pool, conversation_id, user_id).await?.is_some());
+    }
+    let (a, b) = parse_conversation_id(conversation_id)?;
+    // Only the canonical spelling, so each pair has one set of keys.
+    if a == b || conversation_id != conversations::conversation_id(a, b) {
+        return Err(AppError::Validation(format!(
+            "invalid conversation id '{}'",
+            conversation_id
+        )));
+    }
+    Ok(user_id == a || user_id == b)
+}
+
+pub(crate) async fn upsert_public_key(
+    pool: &SqlitePool,
+    user_id: i64,
+    public_key: &str,
+) -> Result<PublicKey, AppError> {
+    validate_key("public key", public_key, PUBLIC_KEY_LEN)?;
+
+    sqlx::query_as::<_, PublicKey>(
+        "INSERT INTO public_keys (user_id, public_key) VALUES (?, ?)
+         ON CONFLICT (user_id) DO UPDATE SET
+             public_key = excluded.public_key,
+             updated_at = CURRENT_TIMESTAMP
+         RETURNING user_id, public_key, updated_at",
+    )
+    .bind(user_id)
+    .bind(public_key)
+    .fetch_one(pool)
+    .await
+    .context("failed to publish public key")
+}
+
+pub(crate) async fn public_key_of(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Option<PublicKey>, AppError> {
+    sqlx::query_as::<_, PublicKey>(
+        "SELECT user_id, public_key, updated_at FROM public_keys WHERE user_id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .context("failed to load public key")
+}
+
+/// Stores the conversation key wrapped for `for_user`. Both `for_user` and
+/// `wrapped_by` must be in the conversation. Storing again replaces the key,
+/// e.g. after a rotation.
+pub(crate) async fn upsert_wrapped_key(
+    pool: &SqlitePool,
+    conversation_id: &str,
+    for_user: i64,
+    wrapped_by: i64,
+    wrapped_key: &str,
+) -> Result<WrappedKey, AppError> {
+    validate_key("wrapped key", wrapped_key, WRAPPED_KEY_LEN)?;
+    for user_id in [wrapped_by, for_user] {
+        if !is_participant(pool, conversation_id, user_id).await? {
+            return Err(AppError::NotAMember(format!(
+                "user {} is not part of conversation {}",
+                user_id, conversation_id
+            )));
+        }
+    }
+
+    sqlx::query_as::<_, WrappedKey>(
+        "INSERT INTO conversation_keys (conversation_id, user_id, wrapped_key, wrapped_by)
+         VALUES (?, ?, ?, ?)
+         ON CONFLICT (conversation_id, user_id) DO UPDATE SET
+             wrapped_key = excluded.wrapped_key,
+             wrapped_by = excluded.wrapped_by,
+             created_at = CURRENT_TIMESTAMP
+         RETURNING conversation_id, user_id, wrapped_key, wrapped_by, created_at",
+    )
+    .bind(conversation_id)
+    .bind(for_user)
+    .bind(wrapped_key)
+    .bind(wrapped_by)
+    .fetch_one(pool)
+    .await
+    .context("failed to store wrapped key")
+}
+
+pub(crate) async fn wrapped_key_for(
+    pool: &SqlitePool,
+    conversation_id: &str,
+    user_id: i64,
+) -> Result<Option<WrappedKey>, AppError> {
+    sqlx::query_as::<_, WrappedKey>(
+        "SELECT conversation_id, user_id, wrapped_key, wrapped_by, created_at
+         FROM conversation_keys
+         WHERE conversation_id = ? AND user_id = ?",
+    )
+    .bind(conversation_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .context("failed to load wrapped key")
+}
+
+#[tauri::command]
+pub async fn publish_public_key(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    pubkey: String,
+) -> Result<PublicKey, AppError> {
+    let pool = db::pool(&db).await?;
+    upsert_public_key(&pool, user_id, &pubkey).await
+}
+
+#[tauri::command]
+pub async fn get_public_key(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<Option<PublicKey>, AppError> {
+    let pool = db::pool(&db).await?;
+    public_key_of(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn store_wrapped_key(
+    db: State<'_, DbInstances>,
+    conversation_id: String,
+    for_user: i64,
+    wrapped_by: i64,
+    wrapped_key: String,
+) -> Result<WrappedKey, AppError> {
+    let pool = db::pool(&db).await?;
+    upsert_wrapped_key(&pool, &conversation_id, for_user, wrapped_by, &wrapped_key).await
+}
+
+#[tauri::command]
+pub async fn get_wrapped_key(
+    db: State<'_, DbInstances>,
+    conversation_id: String,
+    user_id: i64,
+) -> Result<Option<WrappedKey>, AppError> {
+    let pool = db::pool(&db).await?;
+    wrapped_key_for(&pool, &conversation_id, user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::insert_group;
+    use crate::test_support::{count, test_pool};
+
+    fn key_of(len: usize) -> String {
+        BASE64.encode(vec![7u8; len])
+    }
+
+    #[tokio::test]
+    async fn keys_must_be_base64_of_the_right_length() {
+        let pool = test_pool().await;
+        for bad in [
+            "not base64!".to_string(),
+            key_of(PUBLIC_KEY_LEN - 1),
+            key_of(PUBLIC_KEY_LEN + 1),
+            String::new(),
+        ] {
+            let err = upsert_public_key(&pool, 1, &bad).await.unwrap_err();
+            assert_eq!(err.code(), "validation", "{:?}", bad);
+        }
+        let err = upsert_wrapped_key(&pool, "1:2", 2, 1, &key_of(PUBLIC_KEY_LEN))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM public_keys").await, 0);
+
+        let key = upsert_public_key(&pool, 1, &key_of(PUBLIC_KEY_LEN))
+            .await
+            .unwrap();
+        assert_eq!(
+            public_key_of(&pool, 1).await.unwrap().unwrap().public_key,
+            key.public_key
+        );
+    }
+
+    #[tokio::test]
+    async fn wrapped_keys_stay_between_participants() {
+        let pool = test_pool().await;
+        let wrapped = key_of(WRAPPED_KEY_LEN);
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+
+        for conversation_id in ["1:2", group.id.as_str()] {
+            let stored = upsert_wrapped_key(&pool, conversation_id, 2, 1, &wrapped)
+                .await
+                .unwrap();
+            assert_eq!((stored.user_id, stored.wrapped_by), (2, 1));
+            for (for_user, wrapped_by) in [(3, 1), (2, 3)] {
+                let err =
+                    upsert_wrapped_key(&pool, conversation_id, for_user, wrapped_by, &wrapped)
+                        .await
+                        .unwrap_err();
+                assert_eq!(err.code(), "not_a_member", "{}", conversation_id);
+            }
+            assert!(wrapped_key_for(&pool, conversation_id, 3)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        // Only the canonical spelling of a direct conversation is accepted.
+        for conversation_id in ["2:1", "1:1", "01:2", "1:x"] {
+            let err = upsert_wrapped_key(&pool, conversation_id, 2, 1, &wrapped)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "validation", "{}", conversation_id);
+        }
+        let stored = "SELECT COUNT(*) FROM conversation_keys";
+        assert_eq!(count(&pool, stored).await, 2);
+    }
+}
@@ -0,0 +1,594 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::drafts::validate_peer_type;
+use crate::encryption::EncryptionState;
+use crate::error::{AppError, Context};
+use crate::preview::truncate_preview;
+
+/// Shown in place of the last message when it has been soft-deleted.
+pub const DELETED_PREVIEW: &str = "message deleted";
+/// Longest last-message preview in the conversation list, in graphemes.
+pub const CONVERSATION_PREVIEW_CHARS: usize = 100;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ConversationPreview {
+    pub conversation_id: String,
+    pub partner_id: i64,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    pub last_message_id: i64,
+    pub last_message: String,
+    pub last_message_type: String,
+    pub last_timestamp: String,
+    pub last_deleted: bool,
+    pub unread_count: i64,
+    pub archived: bool,
+    /// The unsent draft for this conversation, if any.
+    pub draft: Option<String>,
+    #[serde(skip)]
+    pub last_encrypted: bool,
+}
+
+/// Like drafts, `peer_id` is a user id for `user` peers and a group id for
+/// `group` peers.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ArchivedConversation {
+    pub user_id: i64,
+    pub peer_id: String,
+    pub peer_type: String,
+    /// `conversation_id` of the user and peer, for `user` peers only.
+    pub conversation_id: Option<String>,
+    pub archived_at: String,
+}
+
+/// Approximate space a conversation takes up: its messages' content bytes
+/// plus the size of their attachments.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct StorageEntry {
+    pub peer_id: String,
+    pub peer_type: String,
+    pub message_count: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConversationInsights {
+    pub total_messages: i64,
+    pub messages_by_user_a: i64,
+    pub messages_by_user_b: i64,
+    /// In characters, over messages stored as plaintext; sealed content
+    /// would only measure the ciphertext.
+    pub average_length: f64,
+    /// Hour of day (0-23, UTC) with the most messages; ties go to the earlier
+    /// hour.
+    pub most_active_hour: Option<u32>,
+    /// `YYYY-MM-DD`, UTC.
+    pub first_message_date: Option<String>,
+}
+
+/// The id of the direct conversation between two users: both ids, smaller
+/// first, joined by `:`. The same whichever way round they are given, and
+/// what drafts and archived conversations are keyed on.
+#[tauri::command]
+pub fn conversation_id(user_a: i64, user_b: i64) -> String {
+    format!("{}:{}", user_a.min(user_b), user_a.max(user_b))
+}
+
+/// The two users of a direct conversation id, smaller first. Ids written the
+/// other way round are accepted too.
+#[tauri::command]
+pub fn parse_conversation_id(id: &str) -> Result<(i64, i64), AppError> {
+    let (a, b) = id
+        .split_once(':')
+        .and_then(|(a, b)| Some((a.parse::<i64>().ok()?, b.parse::<i64>().ok()?)))
+        .ok_or_else(|| AppError::Validation(format!("invalid conversation id '{}'", id)))?;
+    Ok((a.min(b), a.max(b)))
+}
+
+/// One row per conversation partner, most recently active first. Archived
+/// conversations are left out unless `include_archived`.
+pub(crate) async fn conversations_of(
+    pool: &SqlitePool,
+    user_id: i64,
+    include_archived: bool,
+) -> Result<Vec<ConversationPreview>, AppError> {
+    sqlx::query_as::<_, ConversationPreview>(
+        "WITH ranked AS (
+             SELECT m.*,
+                    CASE WHEN m.sender_id = ?1 THEN m.receiver_id ELSE m.sender_id END AS partner_id,
+                    MIN(m.sender_id, m.receiver_id) || ':' || MAX(m.sender_id, m.receiver_id)
+                        AS conversation_id,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY CASE WHEN m.sender_id = ?1 THEN m.receiver_id ELSE m.sender_id END
+                        ORDER BY m.timestamp DESC, m.id DESC
+                    ) AS rn
+             FROM messages m
+             WHERE (m.sender_id = ?1 OR m.receiver_id = ?1)
+               AND (m.expires_at IS NULL OR m.expires_at > CURRENT_TIMESTAMP)
+         )
+         SELECT r.conversation_id, r.partner_id, u.username, u.avatar_url,
+                r.id AS last_message_id,
+                CASE WHEN r.deleted_at IS NULL THEN r.content ELSE ?2 END AS last_message,
+                r.message_type AS last_message_type,
+                r.timestamp AS last_timestamp,
+                r.deleted_at IS NOT NULL AS last_deleted,
+                r.encrypted AS last_encrypted,
+                (SELECT COUNT(*) FROM messages
+                 WHERE sender_id = r.partner_id AND receiver_id = ?1
+                   AND is_read = FALSE AND deleted_at IS NULL
+                   AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)) AS unread_count,
+                EXISTS (SELECT 1 FROM archived_conversations a
+                        WHERE a.user_id = ?1 AND a.conversation_id = r.conversation_id)
+                    AS archived,
+                (SELECT d.content FROM drafts d
+                 WHERE d.user_id = ?1 AND d.conversation_id = r.conversation_id) AS draft
+         FROM ranked r
+         JOIN users u ON u.id = r.partner_id
+         WHERE r.rn = 1 AND (?3 OR NOT archived)
+         ORDER BY r.timestamp DESC, r.id DESC",
+    )
+    .bind(user_id)
+    .bind(DELETED_PREVIEW)
+    .bind(include_archived)
+    .fetch_all(pool)
+    .await
+    .context("failed to list conversations")
+}
+
+/// Every direct conversation and group of `user_id`, largest first.
+pub(crate) async fn storage_of(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<StorageEntry>, AppError> {
+    sqlx::query_as::<_, StorageEntry>(
+        "SELECT CAST(CASE WHEN m.sender_id = ?1 THEN m.receiver_id ELSE m.sender_id END AS TEXT)
+                    AS peer_id,
+                'user' AS peer_type,
+                COUNT(*) AS message_count,
+                SUM(LENGTH(CAST(m.content AS BLOB))
+                    + COALESCE((SELECT SUM(a.byte_size) FROM attachments a
+                                WHERE a.message_id = m.id), 0)) AS bytes
+         FROM messages m
+         WHERE m.sender_id = ?1 OR m.receiver_id = ?1
+         GROUP BY 1
+         UNION ALL
+         SELECT gm.group_id, 'group', COUNT(*), SUM(LENGTH(CAST(gm.content AS BLOB)))
+         FROM group_messages gm
+         JOIN group_members mem ON mem.group_id = gm.group_id AND mem.user_id = ?1
+         GROUP BY gm.group_id
+         ORDER BY bytes DESC, peer_type, peer_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to measure conversations")
+}
+
+/// Totals for the direct conversation between `user_a` and `user_b`, not
+/// counting deleted messages. An empty conversation gives zeros.
+pub(crate) async fn insights_of(
+    pool: &SqlitePool,
+    user_a: i64,
+    user_b: i64,
+) -> Result<ConversationInsights, AppError> {
+    // Timestamps are stored as UTC by CURRENT_TIMESTAMP, and SQLite's date
+    // functions read them as UTC, so no offset is applied here.
+    let (
+        total_messages,
+        messages_by_user_a,
+        messages_by_user_b,
+        average_length,
+        first_message_date,
+    ): (i64, i64, i64, f64, Option<String>) = sqlx::query_as(
+        "SELECT COUNT(*),
+                COALESCE(SUM(sender_id = ?1), 0),
+                COALESCE(SUM(sender_id = ?2), 0),
+                COALESCE(AVG(CASE WHEN NOT encrypted THEN LENGTH(content) END), 0.0),
+                date(MIN(datetime(timestamp)))
+         FROM messages
+         WHERE ((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
+           AND deleted_at IS NULL",
+    )
+    .bind(user_a)
+    .bind(user_b)
+    .fetch_one(pool)
+    .await
+    .context("failed to summarize conversation")?;
+
+    let most_active_hour: Option<u32> = sqlx::query_scalar(
+        "SELECT CAST(strftime('%H', timestamp) AS INTEGER) AS hour
+         FROM messages
+         WHERE ((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
+           AND deleted_at IS NULL
+         GROUP BY hour
+         ORDER BY COUNT(*) DESC, hour
+         LIMIT 1",
+    )
+    .bind(user_a)
+    .bind(user_b)
+    .fetch_optional(pool)
+    .await
+    .context("failed to find most active hour")?;
+
+    Ok(ConversationInsights {
+        total_messages,
+        messages_by_user_a,
+        messages_by_user_b,
+        average_length,
+        most_active_hour,
+        first_message_date,
+    })
+}
+
+/// Hides the conversation from the list without touching its messages.
+/// Archiving twice keeps the original `archived_at`.
+pub(crate) async fn archive(
+    pool: &SqlitePool,
+    user_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+) -> Result<ArchivedConversation, AppError> {
+    validate_peer_type(peer_type)?;
+
+    sqlx::query_as::<_, ArchivedConversation>(
+        "INSERT INTO archived_conversations (user_id, peer_id, peer_type) VALUES (?, ?, ?)
+         ON CONFLICT (user_id, peer_id, peer_type) DO UPDATE SET user_id = excluded.user_id
+         RETURNING user_id, peer_id, peer_type, conversation_id, archived_at",
+    )
+    .bind(user_id)
+    .bind(peer_id)
+    .bind(peer_type)
+    .fetch_one(pool)
+    .await
+    .context("failed to archive conversation")
+}
+
+pub(crate) async fn unarchive(
+    pool: &SqlitePool,
+    user_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+) -> Result<(), AppError> {
+    validate_peer_type(peer_type)?;
+
+    sqlx::query(
+        "DELETE FROM archived_conversations
+         WHERE user_id = ? AND peer_id = ? AND peer_type = ?",
+    )
+    .bind(user_id)
+    .bind(peer_id)
+    .bind(peer_type)
+    .execute(pool)
+    .await
+    .context("failed to unarchive conversation")?;
+    Ok(())
+}
+
+/// Brings a direct conversation back for the receiver once the archived
+/// peer writes again.
+pub(crate) async fn unarchive_direct<'e, E>(
+    executor: E,
+    sender_id: i64,
+    receiver_id: i64,
+) -> Result<(), AppError>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    sqlx::query("DELETE FROM archived_conversations WHERE user_id = ? AND conversation_id = ?")
+        .bind(receiver_id)
+        .bind(conversation_id(sender_id, receiver_id))
+        .execute(executor)
+        .await
+        .context("failed to unarchive conversation")?;
+    Ok(())
+}
+
+/// Brings the group back for every member but the sender.
+pub(crate) async fn unarchive_group(
+    pool: &SqlitePool,
+    group_id: &str,
+    sender_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(
+        "DELETE FROM archived_conversations
+         WHERE peer_type = 'group' AND peer_id = ? AND user_id != ?",
+    )
+    .bind(group_id)
+    .bind(sender_id)
+    .execute(pool)
+    .await
+    .context("failed to unarchive conversation")?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_conversations(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_id: i64,
+    include_archived: Option<bool>,
+) -> Result<Vec<ConversationPreview>, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut previews = conversations_of(&pool, user_id, include_archived.unwrap_or(false)).await?;
+    for preview in previews.iter_mut() {
+        if preview.last_encrypted && !preview.last_deleted {
+            preview.last_message =
+                encryption.reveal_content(user_id, preview.partner_id, &preview.last_message);
+        }
+        preview.last_message = truncate_preview(&preview.last_message, CONVERSATION_PREVIEW_CHARS);
+    }
+    Ok(previews)
+}
+
+#[tauri::command]
+pub async fn archive_conversation(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    peer_id: String,
+    peer_type: String,
+) -> Result<ArchivedConversation, AppError> {
+    let pool = db::pool(&db).await?;
+    archive(&pool, user_id, &peer_id, &peer_type).await
+}
+
+#[tauri::command]
+pub async fn unarchive_conversation(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    peer_id: String,
+    peer_type: String,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    unarchive(&pool, user_id, &peer_id, &peer_type).await
+}
+
+#[tauri::command]
+pub async fn storage_breakdown(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<Vec<StorageEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    storage_of(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn conversation_insights(
+    db: State<'_, DbInstances>,
+    user_a: i64,
+    user_b: i64,
+) -> Result<ConversationInsights, AppError> {
+    let pool = db::pool(&db).await?;
+    insights_of(&pool, user_a, user_b).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachments::insert_attachment;
+    use crate::drafts::upsert_draft;
+    use crate::encryption::EncryptionState;
+    use crate::group_messages::insert_group_message;
+    use crate::groups::insert_group;
+    use crate::message_type::MessageType;
+    use crate::messages::insert_message;
+    use crate::test_support::{count, test_pool};
+
+    async fn direct(pool: &SqlitePool, sender_id: i64, receiver_id: i64) {
+        let encryption = EncryptionState::default();
+        insert_message(
+            pool,
+            &encryption,
+            sender_id,
+            receiver_id,
+            "hi",
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn partners(list: &[ConversationPreview]) -> Vec<i64> {
+        list.iter().map(|c| c.partner_id).collect()
+    }
+
+    #[tokio::test]
+    async fn archived_conversations_are_hidden_but_kept() {
+        let pool = test_pool().await;
+        direct(&pool, 2, 1).await;
+        direct(&pool, 3, 1).await;
+        archive(&pool, 1, "2", "user").await.unwrap();
+        let err = archive(&pool, 1, "2", "nope").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        assert_eq!(
+            partners(&conversations_of(&pool, 1, false).await.unwrap()),
+            [3]
+        );
+        let all = conversations_of(&pool, 1, true).await.unwrap();
+        assert!(all.iter().any(|c| c.partner_id == 2 && c.archived));
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 2);
+    }
+
+    #[tokio::test]
+    async fn a_new_message_from_the_peer_unarchives() {
+        let pool = test_pool().await;
+        direct(&pool, 2, 1).await;
+        archive(&pool, 1, "2", "user").await.unwrap();
+
+        // Replying ourselves leaves it archived.
+        direct(&pool, 1, 2).await;
+        assert!(conversations_of(&pool, 1, false).await.unwrap().is_empty());
+        direct(&pool, 2, 1).await;
+        let list = conversations_of(&pool, 1, false).await.unwrap();
+        assert_eq!(partners(&list), [2]);
+        assert!(!list[0].archived);
+    }
+
+    #[tokio::test]
+    async fn a_group_message_unarchives_for_everyone_but_the_sender() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        archive(&pool, 1, &group.id, "group").await.unwrap();
+        archive(&pool, 2, &group.id, "group").await.unwrap();
+
+        insert_group_message(&pool, &group.id, 1, "yo", MessageType::Text, None, None)
+            .await
+            .unwrap();
+        let still_archived: Vec<i64> =
+            sqlx::query_scalar("SELECT user_id FROM archived_conversations")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(still_archived, [1]);
+        unarchive(&pool, 1, &group.id, "group").await.unwrap();
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM archived_conversations").await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn storage_counts_content_and_attachments_largest_first() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        insert_message(&pool, &encryption, 1, 2, "hello", MessageType::Text, None)
+            .await
+            .unwrap();
+        let file = insert_message(&pool, &encryption, 3, 1, "f", MessageType::File, None)
+            .await
+            .unwrap();
+        insert_attachment(&pool, 10_000, file.id, "a.bin", "x/y", 5000, "/a")
+            .await
+            .unwrap();
+        direct(&pool, 4, 5).await;
+        let group = insert_group(&pool, "g", None, 2, &[1]).await.unwrap();
+        insert_group_message(&pool, &group.id, 2, "abc", MessageType::Text, None, None)
+            .await
+            .unwrap();
+        insert_group_message(&pool, &group.id, 1, "d", MessageType::Text, None, None)
+            .await
+            .unwrap();
+
+        let storage = storage_of(&pool, 1).await.unwrap();
+        let entries: Vec<_> = storage
+            .iter()
+            .map(|e| {
+                (
+                    e.peer_id.as_str(),
+                    e.peer_type.as_str(),
+                    e.message_count,
+                    e.bytes,
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("3", "user", 1, 5001),
+                ("2", "user", 1, 5),
+                (group.id.as_str(), "group", 2, 4),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn insights_of_an_empty_conversation_are_zero() {
+        let pool = test_pool().await;
+        let empty = insights_of(&pool, 1, 2).await.unwrap();
+        assert_eq!(
+            empty,
+            ConversationInsights {
+                total_messages: 0,
+                messages_by_user_a: 0,
+                messages_by_user_b: 0,
+                average_length: 0.0,
+                most_active_hour: None,
+                first_message_date: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn insights_summarize_one_conversation() {
+        let pool = test_pool().await;
+        for (sender, receiver, content, timestamp) in [
+            (1, 2, "abcd", "2024-03-01 23:10:00"),
+            (2, 1, "ab", "2024-03-02 09:00:00"),
+            (1, 2, "abcdef", "2024-03-02 23:59:59"),
+            (1, 3, "other", "2020-01-01 00:00:00"),
+        ] {
+            sqlx::query(
+                "INSERT INTO messages (sender_id, receiver_id, content, timestamp) VALUES (?, ?, ?, ?)",
+            )
+            .bind(sender)
+            .bind(receiver)
+            .bind(content)
+            .bind(timestamp)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let insights = insights_of(&pool, 2, 1).await.unwrap();
+        assert_eq!(insights.total_messages, 3);
+        assert_eq!(insights.messages_by_user_a, 1);
+        assert_eq!(insights.messages_by_user_b, 2);
+        assert_eq!(insights.average_length, 4.0);
+        assert_eq!(insights.most_active_hour, Some(23));
+        assert_eq!(insights.first_message_date.as_deref(), Some("2024-03-01"));
+    }
+
+    #[test]
+    fn conversation_ids_ignore_argument_order() {
+        assert_eq!(conversation_id(3, 7), "3:7");
+        assert_eq!(conversation_id(7, 3), conversation_id(3, 7));
+        assert_eq!(
+            parse_conversation_id(&conversation_id(7, 3)).unwrap(),
+            (3, 7)
+        );
+        assert_eq!(parse_conversation_id("7:3").unwrap(), (3, 7));
+        assert_eq!(
+            parse_conversation_id(&conversation_id(-1, 4)).unwrap(),
+            (-1, 4)
+        );
+        for bad in ["", "3", "3:", "a:b", "3:7:9", "group-1"] {
+            let err = parse_conversation_id(bad).unwrap_err();
+            assert_eq!(err.code(), "validation", "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn drafts_and_archives_join_on_the_conversation_id() {
+        let pool = test_pool().await;
+        direct(&pool, 7, 3).await;
+        let draft = upsert_draft(&pool, 3, "7", "user", "later")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(draft.conversation_id.as_deref(), Some("3:7"));
+        let group_draft = upsert_draft(&pool, 3, "7", "group", "x")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(group_draft.conversation_id, None);
+
+        let list = conversations_of(&pool, 3, false).await.unwrap();
+        assert_eq!(list[0].conversation_id, conversation_id(3, 7));
+        assert_eq!(list[0].draft.as_deref(), Some("later"));
+
+        let archived = archive(&pool, 7, "3", "user").await.unwrap();
+        assert_eq!(archived.conversation_id, Some(conversation_id(7, 3)));
+        assert!(conversations_of(&pool, 7, false).await.unwrap().is_empty());
+        assert_eq!(conversations_of(&pool, 3, false).await.unwrap().len(), 1);
+        direct(&pool, 3, 7).await;
+        assert_eq!(
+            partners(&conversations_of(&pool, 7, false).await.unwrap()),
+            [3]
+        );
+    }
+}
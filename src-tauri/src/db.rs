@@ -0,0 +1,153 @@
+//! Access to the database the SQL plugin opens, and the connection tuning
+//! applied on top of it.
+//!
+//! The plugin connects with sqlx defaults, so once it has preloaded and
+//! migrated `DB_URL` the pool is swapped for one built from the same
+//! options plus:
+//!
+//! - `journal_mode=WAL`: readers and the writer no longer block each other,
+//!   so loading a conversation doesn't stall behind an incoming message or a
+//!   scheduled send, and vice versa. Only one writer runs at a time either
+//!   way.
+//! - `synchronous=NORMAL`: in WAL mode this only syncs at checkpoints. A
+//!   power loss can drop the last few commits but never corrupts the file.
+//! - `busy_timeout`: how long a connection waits for the write lock before
+//!   failing with "database is locked".
+//! - `foreign_keys=ON`: SQLite ignores the schema's `REFERENCES` clauses
+//!   unless asked. sqlx turns this on by default; it is set here so the
+//!   guarantee doesn't hinge on that default.
+
+use std::time::Duration;
+
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::{DbInstances, DbPool};
+
+use crate::error::AppError;
+
+pub const DB_URL: &str = "sqlite:cereals.db";
+pub const MAX_CONNECTIONS: u32 = 8;
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5_000;
+pub const MAX_BUSY_TIMEOUT_MS: u64 = 60_000;
+
+/// Returns the pool the SQL plugin opened (and migrated) for `DB_URL`.
+pub async fn pool(instances: &DbInstances) -> Result<SqlitePool, AppError> {
+    let instances = instances.0.read().await;
+    match instances.get(DB_URL) {
+        Some(DbPool::Sqlite(pool)) => Ok(pool.clone()),
+        None => Err(AppError::Database(format!(
+            "database {} is not loaded",
+            DB_URL
+        ))),
+    }
+}
+
+fn tuned_options(base: &SqliteConnectOptions, busy_timeout_ms: u64) -> SqliteConnectOptions {
+    base.clone()
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_millis(busy_timeout_ms))
+        .foreign_keys(true)
+}
+
+/// Replaces the loaded pool with a tuned one. The old pool isn't closed, since
+/// callers may still hold it; it shuts down once the last of them drops it.
+pub async fn configure(instances: &DbInstances, busy_timeout_ms: u64) -> Result<(), AppError> {
+    if busy_timeout_ms > MAX_BUSY_TIMEOUT_MS {
+        return Err(AppError::Validation(format!(
+            "busy timeout must be at most {} ms",
+            MAX_BUSY_TIMEOUT_MS
+        )));
+    }
+
+    let current = pool(instances).await?;
+    let tuned = SqlitePoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .connect_with(tuned_options(&current.connect_options(), busy_timeout_ms))
+        .await?;
+
+    instances
+        .0
+        .write()
+        .await
+        .insert(DB_URL.to_string(), DbPool::Sqlite(tuned));
+    Ok(())
+}
+
+/// Changes how long writes wait on a locked database before giving up.
+#[tauri::command]
+pub async fn set_busy_timeout(db: State<'_, DbInstances>, ms: u64) -> Result<(), AppError> {
+    configure(&db, ms).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn loaded(url: &str) -> DbInstances {
+        let instances = DbInstances::default();
+        let plain = SqlitePool::connect(url).await.unwrap();
+        instances
+            .0
+            .write()
+            .await
+            .insert(DB_URL.to_string(), DbPool::Sqlite(plain));
+        instances
+    }
+
+    #[tokio::test]
+    async fn configure_applies_the_pragmas() {
+        let path = std::env::temp_dir().join(format!("cereals-db-{}.db", std::process::id()));
+        let instances = loaded(&format!("sqlite://{}?mode=rwc", path.display())).await;
+        let old = pool(&instances).await.unwrap();
+        configure(&instances, DEFAULT_BUSY_TIMEOUT_MS)
+            .await
+            .unwrap();
+
+        let tuned = pool(&instances).await.unwrap();
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&tuned)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        // 1 is NORMAL.
+        let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous")
+            .fetch_one(&tuned)
+            .await
+            .unwrap();
+        assert_eq!(synchronous, 1);
+        let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+            .fetch_one(&tuned)
+            .await
+            .unwrap();
+        assert_eq!(busy_timeout, DEFAULT_BUSY_TIMEOUT_MS as i64);
+        let foreign_keys: bool = sqlx::query_scalar("PRAGMA foreign_keys")
+            .fetch_one(&tuned)
+            .await
+            .unwrap();
+        assert!(foreign_keys);
+
+        // Whoever still holds the old pool can keep using it.
+        sqlx::query("SELECT 1").execute(&old).await.unwrap();
+
+        configure(&instances, 1234).await.unwrap();
+        let retuned = pool(&instances).await.unwrap();
+        let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+            .fetch_one(&retuned)
+            .await
+            .unwrap();
+        assert_eq!(busy_timeout, 1234);
+        sqlx::query("SELECT 1").execute(&tuned).await.unwrap();
+
+        let err = configure(&instances, MAX_BUSY_TIMEOUT_MS + 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        drop((old, tuned, retuned, instances));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+
+/// Delivery states in the only order they may be reached.
+pub const DELIVERY_STATUSES: &[&str] = &["sent", "delivered", "read"];
+
+/// `message_delivery.message_id` points at `messages` for `direct` and at
+/// `group_messages` for `group`.
+pub const MESSAGE_SCOPES: &[&str] = &["direct", "group"];
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DeliveryEntry {
+    pub message_id: i64,
+    pub message_scope: String,
+    pub user_id: i64,
+    pub status: String,
+    pub updated_at: String,
+}
+
+fn status_rank(status: &str) -> Result<usize, AppError> {
+    DELIVERY_STATUSES
+        .iter()
+        .position(|s| *s == status)
+        .ok_or_else(|| {
+            AppError::Validation(format!(
+                "invalid delivery status '{}', expected one of: {}",
+                status,
+                DELIVERY_STATUSES.join(", ")
+            ))
+        })
+}
+
+pub(crate) fn validate_scope(scope: &str) -> Result<(), AppError> {
+    if !MESSAGE_SCOPES.contains(&scope) {
+        return Err(AppError::Validation(format!(
+            "invalid message scope '{}', expected one of: {}",
+            scope,
+            MESSAGE_SCOPES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Records `status` for one recipient. Statuses only move forward; repeating
+/// the current status is a no-op and going backwards is an error.
+pub(crate) async fn set_status(
+    pool: &SqlitePool,
+    message_id: i64,
+    scope: &str,
+    user_id: i64,
+    status: &str,
+) -> Result<DeliveryEntry, AppError> {
+    validate_scope(scope)?;
+    let rank = status_rank(status)?;
+
+    let mut tx = pool.begin().await?;
+
+    let current: Option<String> = sqlx::query_scalar(
+        "SELECT status FROM message_delivery
+         WHERE message_id = ? AND message_scope = ? AND user_id = ?",
+    )
+    .bind(message_id)
+    .bind(scope)
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(current) = current.as_deref() {
+        if status_rank(current)? > rank {
+            return Err(AppError::Validation(format!(
+                "cannot change delivery status from '{}' back to '{}'",
+                current, status
+            )));
+        }
+    }
+
+    let entry = sqlx::query_as::<_, DeliveryEntry>(
+        "INSERT INTO message_delivery (message_id, message_scope, user_id, status)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (message_id, message_scope, user_id) DO UPDATE SET
+             status = excluded.status,
+             updated_at = CASE WHEN status = excluded.status THEN updated_at
+                               ELSE CURRENT_TIMESTAMP END
+         RETURNING message_id, message_scope, user_id, status, updated_at",
+    )
+    .bind(message_id)
+    .bind(scope)
+    .bind(user_id)
+    .bind(status)
+    .fetch_one(&mut *tx)
+    .await
+    .context("failed to update delivery status")?;
+
+    tx.commit().await?;
+    Ok(entry)
+}
+
+pub(crate) async fn statuses_for(
+    pool: &SqlitePool,
+    message_id: i64,
+    scope: &str,
+) -> Result<Vec<DeliveryEntry>, AppError> {
+    validate_scope(scope)?;
+
+    sqlx::query_as::<_, DeliveryEntry>(
+        "SELECT message_id, message_scope, user_id, status, updated_at
+         FROM message_delivery
+         WHERE message_id = ? AND message_scope = ?
+         ORDER BY user_id",
+    )
+    .bind(message_id)
+    .bind(scope)
+    .fetch_all(pool)
+    .await
+    .context("failed to load delivery status")
+}
+
+#[tauri::command]
+pub async fn update_delivery_status(
+    db: State<'_, DbInstances>,
+    message_id: i64,
+    user_id: i64,
+    status: String,
+    scope: Option<String>,
+) -> Result<DeliveryEntry, AppError> {
+    let pool = db::pool(&db).await?;
+    let scope = scope.as_deref().unwrap_or("direct");
+    set_status(&pool, message_id, scope, user_id, &status).await
+}
+
+#[tauri::command]
+pub async fn delivery_status_for(
+    db: State<'_, DbInstances>,
+    message_id: i64,
+    scope: Option<String>,
+) -> Result<Vec<DeliveryEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    statuses_for(&pool, message_id, scope.as_deref().unwrap_or("direct")).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn statuses_only_move_forward() {
+        let pool = test_pool().await;
+        set_status(&pool, 1, "direct", 2, "sent").await.unwrap();
+        set_status(&pool, 1, "direct", 2, "read").await.unwrap();
+        let err = set_status(&pool, 1, "direct", 2, "delivered")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        // Repeating the current status leaves its timestamp alone.
+        sqlx::query("UPDATE message_delivery SET updated_at = '2000-01-01 00:00:00'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let repeated = set_status(&pool, 1, "direct", 2, "read").await.unwrap();
+        assert_eq!(repeated.updated_at, "2000-01-01 00:00:00");
+
+        // The same message id in the other scope is tracked separately.
+        set_status(&pool, 1, "group", 2, "delivered").await.unwrap();
+        let direct = statuses_for(&pool, 1, "direct").await.unwrap();
+        assert_eq!(direct.len(), 1);
+        assert_eq!(direct[0].status, "read");
+    }
+
+    #[tokio::test]
+    async fn unknown_statuses_and_scopes_are_rejected() {
+        let pool = test_pool().await;
+        for (scope, status) in [("direct", "seen"), ("direct", "READ"), ("channel", "sent")] {
+            let err = set_status(&pool, 1, scope, 2, status).await.unwrap_err();
+            assert_eq!(err.code(), "validation", "{} {}", scope, status);
+        }
+        let err = statuses_for(&pool, 1, "channel").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        assert!(statuses_for(&pool, 1, "direct").await.unwrap().is_empty());
+    }
+}
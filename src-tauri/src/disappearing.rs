@@ -0,0 +1,424 @@
+//! Disappearing messages. A message sent with a TTL gets an `expires_at`, and
+//! the sweeper deletes it, along with everything hanging off it, once that has
+//! passed. Starred and pinned messages are not spared.
+//!
+//! `conversation_ttls` holds each user's default TTL for what they send in a
+//! conversation; a TTL given with the send wins over it, and a TTL of 0 sends
+//! one message that stays.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::drafts::validate_peer_type;
+use crate::error::{AppError, Context};
+use crate::retention::{
+    self, CLEAR_QUOTES, DIRECT_MESSAGE_CLEANUP, GROUP_MESSAGE_CLEANUP, RETENTION_BATCH_SIZE,
+};
+
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_TTL_SECS: u32 = 30 * 24 * 60 * 60;
+
+/// Like drafts, `peer_id` is a user id for `user` peers and a group id for
+/// `group` peers.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ConversationTtl {
+    pub user_id: i64,
+    pub peer_id: String,
+    pub peer_type: String,
+    pub ttl_secs: i64,
+    pub updated_at: String,
+}
+
+/// Payload of the `message-expired` event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageExpired {
+    pub message_id: i64,
+    /// `direct` or `group`, as in `starred_messages`.
+    pub message_scope: &'static str,
+}
+
+fn validate_ttl(ttl_secs: u32) -> Result<(), AppError> {
+    if ttl_secs > MAX_TTL_SECS {
+        return Err(AppError::Validation(format!(
+            "messages can disappear after at most {} seconds",
+            MAX_TTL_SECS
+        )));
+    }
+    Ok(())
+}
+
+/// The `datetime('now', ?)` modifier for a new message's `expires_at`, or
+/// `None` when it shouldn't expire. Without an explicit `ttl_secs` the
+/// sender's default for the conversation applies.
+pub(crate) async fn expiry_modifier<'e, E>(
+    executor: E,
+    sender_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+    ttl_secs: Option<u32>,
+) -> Result<Option<String>, AppError>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    let ttl_secs = match ttl_secs {
+        Some(ttl_secs) => {
+            validate_ttl(ttl_secs)?;
+            i64::from(ttl_secs)
+        }
+        None => sqlx::query_scalar(
+            "SELECT ttl_secs FROM conversation_ttls
+             WHERE user_id = ? AND peer_id = ? AND peer_type = ?",
+        )
+        .bind(sender_id)
+        .bind(peer_id)
+        .bind(peer_type)
+        .fetch_optional(executor)
+        .await
+        .context("failed to load conversation ttl")?
+        .unwrap_or(0),
+    };
+    Ok((ttl_secs > 0).then(|| format!("+{} seconds", ttl_secs)))
+}
+
+/// Sets the default TTL for messages `user_id` sends to the peer; 0 turns it
+/// off. Returns the setting, or `None` once it's off.
+pub(crate) async fn set_ttl(
+    pool: &SqlitePool,
+    user_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+    ttl_secs: u32,
+) -> Result<Option<ConversationTtl>, AppError> {
+    validate_peer_type(peer_type)?;
+    validate_ttl(ttl_secs)?;
+
+    if ttl_secs == 0 {
+        sqlx::query(
+            "DELETE FROM conversation_ttls WHERE user_id = ? AND peer_id = ? AND peer_type = ?",
+        )
+        .bind(user_id)
+        .bind(peer_id)
+        .bind(peer_type)
+        .execute(pool)
+        .await
+        .context("failed to clear conversation ttl")?;
+        return Ok(None);
+    }
+
+    sqlx::query_as::<_, ConversationTtl>(
+        "INSERT INTO conversation_ttls (user_id, peer_id, peer_type, ttl_secs) VALUES (?, ?, ?, ?)
+         ON CONFLICT (user_id, peer_id, peer_type) DO UPDATE SET
+             ttl_secs = excluded.ttl_secs,
+             updated_at = CURRENT_TIMESTAMP
+         RETURNING user_id, peer_id, peer_type, ttl_secs, updated_at",
+    )
+    .bind(user_id)
+    .bind(peer_id)
+    .bind(peer_type)
+    .bind(ttl_secs)
+    .fetch_one(pool)
+    .await
+    .map(Some)
+    .context("failed to save conversation ttl")
+}
+
+/// Deletes every message whose `expires_at` has passed, in batches like
+/// retention does. Returns what was deleted.
+pub(crate) async fn sweep_expired(pool: &SqlitePool) -> Result<Vec<MessageExpired>, AppError> {
+    // Replies' quotes go too, or the text would outlive the message.
+    let direct_cleanup: Vec<&str> = std::iter::once(CLEAR_QUOTES)
+        .chain(DIRECT_MESSAGE_CLEANUP.iter().copied())
+        .collect();
+    let scopes: [(&str, &'static str, &[&str]); 2] = [
+        ("messages", "direct", &direct_cleanup),
+        ("group_messages", "group", GROUP_MESSAGE_CLEANUP),
+    ];
+
+    let mut expired = Vec::new();
+    for (table, message_scope, cleanup) in scopes {
+        loop {
+            let mut tx = pool.begin().await?;
+            let ids: Vec<i64> = sqlx::query_scalar(&format!(
+                "SELECT id FROM {}
+                 WHERE expires_at IS NOT NULL AND datetime(expires_at) <= datetime('now')
+                 ORDER BY id
+                 LIMIT ?",
+                table
+            ))
+            .bind(RETENTION_BATCH_SIZE)
+            .fetch_all(&mut *tx)
+            .await
+            .context(&format!("failed to select expired {}", table))?;
+            if ids.is_empty() {
+                break;
+            }
+            retention::delete_batch(&mut tx, table, cleanup, &ids).await?;
+            tx.commit().await?;
+
+            expired.extend(ids.into_iter().map(|message_id| MessageExpired {
+                message_id,
+                message_scope,
+            }));
+        }
+    }
+    Ok(expired)
+}
+
+/// Sweeps now and then every `SWEEP_INTERVAL`, emitting `message-expired`
+/// for each message removed.
+pub fn spawn_sweeper(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let pool = match db::pool(&app.state::<DbInstances>()).await {
+                Ok(pool) => pool,
+                Err(_) => continue,
+            };
+            match sweep_expired(&pool).await {
+                Ok(expired) => {
+                    for event in expired {
+                        let _ = app.emit("message-expired", event);
+                    }
+                }
+                Err(e) => tracing::warn!("expired message sweep failed: {}", e),
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn set_conversation_ttl(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    peer_id: String,
+    peer_type: String,
+    ttl_secs: u32,
+) -> Result<Option<ConversationTtl>, AppError> {
+    let pool = db::pool(&db).await?;
+    set_ttl(&pool, user_id, &peer_id, &peer_type, ttl_secs).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::group_messages::{group_page, insert_group_message};
+    use crate::groups::{groups_of, insert_group};
+    use crate::mentions::mentions_of;
+    use crate::message_type::MessageType;
+    use crate::messages::{
+        conversation_page, insert_idempotent, insert_message, messages_around, numbered_page,
+        thread,
+    };
+    use crate::notify::unread_total;
+    use crate::polling::messages_since;
+    use crate::search::{search, search_direct, search_group};
+    use crate::test_support::{count, test_pool};
+
+    async fn send_with_ttl(
+        pool: &SqlitePool,
+        sender_id: i64,
+        receiver_id: i64,
+        content: &str,
+        ttl_secs: Option<u32>,
+    ) -> i64 {
+        let encryption = EncryptionState::default();
+        insert_idempotent(
+            pool,
+            &encryption,
+            sender_id,
+            receiver_id,
+            content,
+            MessageType::Text,
+            None,
+            None,
+            ttl_secs,
+            None,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[tokio::test]
+    async fn messages_disappear_once_their_ttl_passes() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let short = send_with_ttl(&pool, 1, 2, "poof", Some(2)).await;
+        let keep = send_with_ttl(&pool, 1, 2, "stay", None).await;
+        let reply = insert_message(
+            &pool,
+            &encryption,
+            2,
+            1,
+            "re",
+            MessageType::Text,
+            Some(short),
+        )
+        .await
+        .unwrap()
+        .id;
+        sqlx::query(
+            "INSERT INTO starred_messages (user_id, message_id, message_scope)
+             VALUES (1, ?, 'direct')",
+        )
+        .bind(short)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The sender's default applies to their later sends only, and a TTL
+        // of 0 opts a single message out.
+        set_ttl(&pool, 1, "3", "user", 2).await.unwrap().unwrap();
+        let by_default = send_with_ttl(&pool, 1, 3, "default", None).await;
+        let theirs = send_with_ttl(&pool, 3, 1, "theirs", None).await;
+        let exempt = send_with_ttl(&pool, 1, 3, "exempt", Some(0)).await;
+
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let (gone, _) = insert_group_message(
+            &pool,
+            &group.id,
+            1,
+            "gone @u2",
+            MessageType::Text,
+            None,
+            Some(2),
+        )
+        .await
+        .unwrap();
+        let (kept, _) =
+            insert_group_message(&pool, &group.id, 1, "kept", MessageType::Text, None, None)
+                .await
+                .unwrap();
+        assert!(gone.expires_at.is_some());
+        assert!(kept.expires_at.is_none());
+        assert!(sweep_expired(&pool).await.unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(3100)).await;
+
+        // Expired messages are hidden before the sweep gets to them.
+        let page: Vec<i64> = conversation_page(&pool, 1, 2, None, 50)
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(page, [reply, keep]);
+        let err = messages_around(&pool, 1, 2, short, 2).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+        let polled: Vec<i64> = messages_since(&pool, 3, 0)
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(polled, [exempt]);
+        let group_ids: Vec<i64> = group_page(&pool, &group.id, None, 50)
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(group_ids, [kept.id]);
+        let groups = groups_of(&pool, 2, 50, 0).await.unwrap();
+        assert_eq!(groups[0].unread_count, 1);
+        let numbered = numbered_page(&pool, 1, 2, 0, 50).await.unwrap();
+        assert_eq!(numbered.total_count, 2);
+        assert_eq!(numbered.messages.len(), 2);
+        assert_eq!(thread(&pool, short).await.unwrap_err().code(), "not_found");
+        assert!(search(&pool, 1, "poof", false, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(search_direct(&pool, 1, 2, "poof").await.unwrap().is_empty());
+        assert!(search_group(&pool, &group.id, 2, "gone")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(mentions_of(&pool, 2, false).await.unwrap().is_empty());
+        // User 1 has "re" and "theirs" unread; user 2 only "stay", with
+        // "poof" and the mention in "gone" hidden.
+        assert_eq!(unread_total(&pool, 1).await.unwrap(), 2);
+        assert_eq!(unread_total(&pool, 2).await.unwrap(), 1);
+
+        let swept = sweep_expired(&pool).await.unwrap();
+        assert_eq!(
+            swept,
+            [
+                MessageExpired {
+                    message_id: short,
+                    message_scope: "direct"
+                },
+                MessageExpired {
+                    message_id: by_default,
+                    message_scope: "direct"
+                },
+                MessageExpired {
+                    message_id: gone.id,
+                    message_scope: "group"
+                },
+            ]
+        );
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 4);
+        for id in [keep, reply, theirs, exempt] {
+            let query = format!("SELECT COUNT(*) FROM messages WHERE id = {}", id);
+            assert_eq!(count(&pool, &query).await, 1);
+        }
+        let query = format!("SELECT COUNT(*) FROM group_messages WHERE id = {}", kept.id);
+        assert_eq!(count(&pool, &query).await, 1);
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM starred_messages").await,
+            0
+        );
+        let query = format!(
+            "SELECT COUNT(*) FROM messages
+             WHERE id = {} AND reply_to_id IS NULL AND quoted_snippet IS NULL",
+            reply
+        );
+        assert_eq!(count(&pool, &query).await, 1);
+    }
+
+    #[tokio::test]
+    async fn messages_without_a_ttl_are_untouched() {
+        let pool = test_pool().await;
+        for i in 0..5 {
+            send_with_ttl(&pool, 1, 2, &format!("m{}", i), None).await;
+        }
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        insert_group_message(&pool, &group.id, 1, "hi", MessageType::Text, None, None)
+            .await
+            .unwrap();
+
+        assert!(sweep_expired(&pool).await.unwrap().is_empty());
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 5);
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM group_messages").await, 1);
+        let unexpiring = "SELECT COUNT(*) FROM messages WHERE expires_at IS NULL";
+        assert_eq!(count(&pool, unexpiring).await, 5);
+
+        set_ttl(&pool, 1, "2", "user", 60).await.unwrap().unwrap();
+        assert_eq!(
+            set_ttl(&pool, 1, "2", "user", 0)
+                .await
+                .unwrap()
+                .map(|t| t.ttl_secs),
+            None
+        );
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM conversation_ttls").await,
+            0
+        );
+        let err = set_ttl(&pool, 1, "2", "room", 5).await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        let err = set_ttl(&pool, 1, "2", "user", MAX_TTL_SECS + 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+}
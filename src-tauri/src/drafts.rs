@@ -0,0 +1,176 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+
+/// A draft's `peer_id` is a user id for `user` peers and a group id for
+/// `group` peers.
+pub const PEER_TYPES: &[&str] = &["user", "group"];
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Draft {
+    pub user_id: i64,
+    pub peer_id: String,
+    pub peer_type: String,
+    /// `conversations::conversation_id` of the user and peer, for `user`
+    /// peers only.
+    pub conversation_id: Option<String>,
+    pub content: String,
+    pub updated_at: String,
+}
+
+pub(crate) fn validate_peer_type(peer_type: &str) -> Result<(), AppError> {
+    if !PEER_TYPES.contains(&peer_type) {
+        return Err(AppError::Validation(format!(
+            "invalid peer type '{}', expected one of: {}",
+            peer_type,
+            PEER_TYPES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Upserts the draft; saving empty content removes it instead.
+pub(crate) async fn upsert_draft(
+    pool: &SqlitePool,
+    user_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+    content: &str,
+) -> Result<Option<Draft>, AppError> {
+    validate_peer_type(peer_type)?;
+
+    if content.is_empty() {
+        delete_draft(pool, user_id, peer_id, peer_type).await?;
+        return Ok(None);
+    }
+
+    sqlx::query_as::<_, Draft>(
+        "INSERT INTO drafts (user_id, peer_id, peer_type, content) VALUES (?, ?, ?, ?)
+         ON CONFLICT (user_id, peer_id, peer_type) DO UPDATE SET
+             content = excluded.content,
+             updated_at = CURRENT_TIMESTAMP
+         RETURNING user_id, peer_id, peer_type, conversation_id, content, updated_at",
+    )
+    .bind(user_id)
+    .bind(peer_id)
+    .bind(peer_type)
+    .bind(content)
+    .fetch_one(pool)
+    .await
+    .map(Some)
+    .context("failed to save draft")
+}
+
+pub(crate) async fn find_draft(
+    pool: &SqlitePool,
+    user_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+) -> Result<Option<Draft>, AppError> {
+    validate_peer_type(peer_type)?;
+
+    sqlx::query_as::<_, Draft>(
+        "SELECT user_id, peer_id, peer_type, conversation_id, content, updated_at FROM drafts
+         WHERE user_id = ? AND peer_id = ? AND peer_type = ?",
+    )
+    .bind(user_id)
+    .bind(peer_id)
+    .bind(peer_type)
+    .fetch_optional(pool)
+    .await
+    .context("failed to load draft")
+}
+
+pub(crate) async fn delete_draft(
+    pool: &SqlitePool,
+    user_id: i64,
+    peer_id: &str,
+    peer_type: &str,
+) -> Result<(), AppError> {
+    validate_peer_type(peer_type)?;
+
+    sqlx::query("DELETE FROM drafts WHERE user_id = ? AND peer_id = ? AND peer_type = ?")
+        .bind(user_id)
+        .bind(peer_id)
+        .bind(peer_type)
+        .execute(pool)
+        .await
+        .context("failed to clear draft")?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn save_draft(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    peer_id: String,
+    peer_type: String,
+    content: String,
+) -> Result<Option<Draft>, AppError> {
+    let pool = db::pool(&db).await?;
+    upsert_draft(&pool, user_id, &peer_id, &peer_type, &content).await
+}
+
+#[tauri::command]
+pub async fn get_draft(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    peer_id: String,
+    peer_type: String,
+) -> Result<Option<Draft>, AppError> {
+    let pool = db::pool(&db).await?;
+    find_draft(&pool, user_id, &peer_id, &peer_type).await
+}
+
+#[tauri::command]
+pub async fn clear_draft(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    peer_id: String,
+    peer_type: String,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    delete_draft(&pool, user_id, &peer_id, &peer_type).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn user_and_group_drafts_with_the_same_id_are_separate() {
+        let pool = test_pool().await;
+        upsert_draft(&pool, 1, "2", "user", "hello").await.unwrap();
+        upsert_draft(&pool, 1, "2", "group", "to the group")
+            .await
+            .unwrap();
+        upsert_draft(&pool, 1, "2", "user", "hello again")
+            .await
+            .unwrap();
+
+        let user = find_draft(&pool, 1, "2", "user").await.unwrap().unwrap();
+        let group = find_draft(&pool, 1, "2", "group").await.unwrap().unwrap();
+        assert_eq!(
+            (user.content.as_str(), group.content.as_str()),
+            ("hello again", "to the group")
+        );
+
+        // Saving empty content clears only that draft.
+        assert!(upsert_draft(&pool, 1, "2", "user", "")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(find_draft(&pool, 1, "2", "user").await.unwrap().is_none());
+        assert!(find_draft(&pool, 1, "2", "group").await.unwrap().is_some());
+
+        let err = upsert_draft(&pool, 1, "2", "channel", "x")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+}
@@ -0,0 +1,341 @@
+//! Optional at-rest encryption of direct message content.
+//!
+//! Each local user who opts in gets an AES-256-GCM key, derived from their
+//! master key and kept in the OS keychain. A message is sealed with the
+//! sender's key if they have one, otherwise the receiver's, and flagged with
+//! `messages.encrypted` so plaintext rows written before opting in keep
+//! working.
+//!
+//! Encrypted rows are left out of the full-text index, so `search_messages`
+//! only finds messages that were stored as plaintext.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use tauri::State;
+
+use crate::error::AppError;
+use crate::messages::MessageRow;
+
+#[cfg_attr(test, allow(dead_code))]
+const KEYCHAIN_SERVICE: &str = "com.tauri-app.app.message-keys";
+const KEY_LEN: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Shown in place of content that no available key can decrypt.
+pub const UNREADABLE_PLACEHOLDER: &str = "[encrypted message]";
+
+type Key = [u8; KEY_LEN];
+
+/// Where each user's key is kept: the OS keychain, except in tests.
+pub(crate) trait KeyStore: Send + Sync {
+    /// The user's encoded key, or `None` when they have none.
+    fn get(&self, user_id: i64) -> Result<Option<String>, AppError>;
+    fn set(&self, user_id: i64, encoded: &str) -> Result<(), AppError>;
+}
+
+#[cfg_attr(test, allow(dead_code))]
+struct OsKeychain;
+
+#[cfg_attr(test, allow(dead_code))]
+fn keychain_entry(user_id: i64) -> Result<keyring::Entry, AppError> {
+    keyring::Entry::new(KEYCHAIN_SERVICE, &format!("user-{}", user_id))
+        .map_err(|e| AppError::Io(format!("failed to open keychain: {}", e)))
+}
+
+impl KeyStore for OsKeychain {
+    fn get(&self, user_id: i64) -> Result<Option<String>, AppError> {
+        match keychain_entry(user_id)?.get_password() {
+            Ok(encoded) => Ok(Some(encoded)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(AppError::Io(format!(
+                "failed to read key for user {} from keychain: {}",
+                user_id, e
+            ))),
+        }
+    }
+
+    fn set(&self, user_id: i64, encoded: &str) -> Result<(), AppError> {
+        keychain_entry(user_id)?
+            .set_password(encoded)
+            .map_err(|e| AppError::Io(format!("failed to store key in keychain: {}", e)))
+    }
+}
+
+/// Keys loaded from the keychain, cached per user. `None` records that a
+/// user has no key so the keychain isn't asked again for every message.
+pub struct EncryptionState {
+    keys: Mutex<HashMap<i64, Option<Key>>>,
+    store: Box<dyn KeyStore>,
+}
+
+impl Default for EncryptionState {
+    fn default() -> Self {
+        #[cfg(not(test))]
+        let store = Box::new(OsKeychain);
+        #[cfg(test)]
+        let store = Box::new(tests::MemoryKeyStore::default());
+        Self::with_store(store)
+    }
+}
+
+fn derive_key(user_id: i64, master_key: &str) -> Key {
+    // The salt is fixed per user so the same master key always yields the
+    // same message key, e.g. when re-enabling on a fresh keychain.
+    let salt = format!("cereals-hub:messages:{}", user_id);
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations are non-zero"),
+        salt.as_bytes(),
+        master_key.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+fn cipher(key: &Key) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("key has the AES-256 length"))
+}
+
+/// Encrypts `plaintext` to `base64(nonce || ciphertext || tag)`.
+fn seal(key: &Key, plaintext: &str) -> String {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut sealed = plaintext.as_bytes().to_vec();
+    cipher(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .expect("message fits in a single AES-GCM seal");
+
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&sealed);
+    BASE64.encode(out)
+}
+
+fn open(key: &Key, stored: &str) -> Option<String> {
+    let raw = BASE64.decode(stored).ok()?;
+    if raw.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, body) = raw.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut body = body.to_vec();
+    let plaintext = cipher(key)
+        .open_in_place(nonce, Aad::empty(), &mut body)
+        .ok()?;
+    String::from_utf8(plaintext.to_vec()).ok()
+}
+
+impl EncryptionState {
+    pub(crate) fn with_store(store: Box<dyn KeyStore>) -> Self {
+        Self {
+            keys: Mutex::new(HashMap::new()),
+            store,
+        }
+    }
+
+    /// The user's key. A keychain that can't be read is an error rather
+    /// than "no key", so a send fails instead of storing plaintext; it isn't
+    /// cached, so the keychain is asked again next time.
+    fn key_for(&self, user_id: i64) -> Result<Option<Key>, AppError> {
+        if let Some(cached) = self.keys.lock()?.get(&user_id) {
+            return Ok(*cached);
+        }
+
+        let key = self
+            .store
+            .get(user_id)?
+            .and_then(|encoded| BASE64.decode(encoded).ok())
+            .and_then(|bytes| Key::try_from(bytes.as_slice()).ok());
+        self.keys.lock()?.insert(user_id, key);
+        Ok(key)
+    }
+
+    /// Derives the user's key and stores it in the keychain. Enabling twice
+    /// with the same master key is a no-op; a different one is rejected so
+    /// existing ciphertext never becomes unreadable.
+    pub(crate) fn enable(&self, user_id: i64, master_key: &str) -> Result<(), AppError> {
+        if master_key.is_empty() {
+            return Err(AppError::Validation("master key must not be empty".into()));
+        }
+
+        let key = derive_key(user_id, master_key);
+        match self.key_for(user_id)? {
+            Some(existing) if existing == key => return Ok(()),
+            Some(_) => {
+                return Err(AppError::Conflict(
+                    "encryption is already enabled with a different master key".into(),
+                ))
+            }
+            None => {}
+        }
+
+        self.store.set(user_id, &BASE64.encode(key))?;
+        self.keys.lock()?.insert(user_id, Some(key));
+        Ok(())
+    }
+
+    /// Seals content for a new or edited message, or returns `None` when
+    /// neither participant has encryption enabled.
+    pub(crate) fn seal_for(
+        &self,
+        sender_id: i64,
+        receiver_id: i64,
+        plaintext: &str,
+    ) -> Result<Option<String>, AppError> {
+        let key = match self.key_for(sender_id)? {
+            Some(key) => Some(key),
+            None => self.key_for(receiver_id)?,
+        };
+        Ok(key.map(|key| seal(&key, plaintext)))
+    }
+
+    fn open_for(&self, sender_id: i64, receiver_id: i64, stored: &str) -> Option<String> {
+        [sender_id, receiver_id]
+            .into_iter()
+            .filter_map(|user_id| self.key_for(user_id).ok().flatten())
+            .find_map(|key| open(&key, stored))
+    }
+
+    /// Replaces sealed content with plaintext in place. Deleted rows are
+    /// already masked and left alone.
+    pub(crate) fn reveal(&self, row: &mut MessageRow) {
+        if !row.encrypted || row.deleted {
+            return;
+        }
+        row.content = self
+            .open_for(row.sender_id, row.receiver_id, &row.content)
+            .unwrap_or_else(|| UNREADABLE_PLACEHOLDER.to_string());
+        if let Some(snippet) = row.quoted_snippet.as_mut() {
+            *snippet = self.reveal_content(row.sender_id, row.receiver_id, snippet);
+        }
+    }
+
+    pub(crate) fn reveal_all(&self, rows: &mut [MessageRow]) {
+        for row in rows {
+            self.reveal(row);
+        }
+    }
+
+    /// Decrypts a bare content value, for queries that don't return whole rows.
+    pub(crate) fn reveal_content(&self, sender_id: i64, receiver_id: i64, content: &str) -> String {
+        self.open_for(sender_id, receiver_id, content)
+            .unwrap_or_else(|| UNREADABLE_PLACEHOLDER.to_string())
+    }
+}
+
+/// Turns on encryption for messages `user_id` sends and receives from now on.
+/// Existing plaintext rows are left as they are.
+#[tauri::command]
+pub fn enable_encryption(
+    encryption: State<'_, EncryptionState>,
+    user_id: i64,
+    master_key: String,
+) -> Result<(), AppError> {
+    encryption.enable(user_id, &master_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_type::MessageType;
+    use crate::messages::{apply_edit, insert_message};
+    use crate::test_support::{count, test_pool};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    /// Stands in for the keychain so tests never touch the real one.
+    #[derive(Default)]
+    pub(crate) struct MemoryKeyStore(Mutex<HashMap<i64, String>>);
+
+    impl KeyStore for MemoryKeyStore {
+        fn get(&self, user_id: i64) -> Result<Option<String>, AppError> {
+            Ok(self.0.lock()?.get(&user_id).cloned())
+        }
+
+        fn set(&self, user_id: i64, encoded: &str) -> Result<(), AppError> {
+            self.0.lock()?.insert(user_id, encoded.to_string());
+            Ok(())
+        }
+    }
+
+    /// A keychain that is locked for `locked` and fine for everyone else.
+    struct LockedFor {
+        locked: i64,
+        inner: MemoryKeyStore,
+    }
+
+    impl KeyStore for LockedFor {
+        fn get(&self, user_id: i64) -> Result<Option<String>, AppError> {
+            if user_id == self.locked {
+                return Err(AppError::Io("keychain is locked".into()));
+            }
+            self.inner.get(user_id)
+        }
+
+        fn set(&self, user_id: i64, encoded: &str) -> Result<(), AppError> {
+            self.inner.set(user_id, encoded)
+        }
+    }
+
+    #[test]
+    fn a_poisoned_key_cache_is_an_error_not_a_panic() {
+        let state = EncryptionState::default();
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _keys = state.keys.lock().unwrap();
+            panic!("poison the key cache");
+        }));
+        let err = state.seal_for(1, 2, "hi").unwrap_err();
+        assert_eq!(err.code(), "internal");
+        assert_eq!(state.enable(1, "master").unwrap_err().code(), "internal");
+    }
+
+    #[tokio::test]
+    async fn a_locked_keychain_fails_the_send_instead_of_storing_plaintext() {
+        let pool = test_pool().await;
+        let earlier = insert_message(
+            &pool,
+            &EncryptionState::default(),
+            1,
+            2,
+            "before",
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap();
+        let state = EncryptionState::with_store(Box::new(LockedFor {
+            locked: 1,
+            inner: MemoryKeyStore::default(),
+        }));
+        state.enable(3, "master").unwrap();
+        let sent = insert_message(&pool, &state, 3, 4, "sealed", MessageType::Text, None)
+            .await
+            .unwrap();
+        assert!(sent.encrypted);
+
+        assert_eq!(state.seal_for(1, 2, "hi").unwrap_err().code(), "io");
+        let err = insert_message(&pool, &state, 1, 2, "secret", MessageType::Text, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "io");
+        let err = apply_edit(&pool, &state, earlier.id, 1, "edited")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "io");
+        let leaked = "SELECT COUNT(*) FROM messages WHERE content IN ('secret', 'edited')";
+        assert_eq!(count(&pool, leaked).await, 0);
+        // Failures aren't cached as "no key".
+        assert!(state.keys.lock().unwrap().get(&1).is_none());
+    }
+}
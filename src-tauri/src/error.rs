@@ -0,0 +1,167 @@
+//! The error type returned by every command. It serializes as
+//! `{"code": "...", "message": "..."}` (plus `retry_after_ms` for
+//! `rate_limited`), so the frontend can switch on `code` instead of parsing
+//! messages.
+
+use std::fmt;
+use std::sync::PoisonError;
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use sqlx::error::ErrorKind;
+
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    PermissionDenied(String),
+    Validation(String),
+    /// A uniqueness, foreign key or check constraint rejected the write.
+    Conflict(String),
+    Database(String),
+    RateLimited {
+        retry_after_ms: u64,
+    },
+    /// The user is not a member of the group they are acting on.
+    NotAMember(String),
+    /// The receiver has blocked the sender.
+    Blocked(String),
+    TokenExpired(String),
+    UsernameTaken(String),
+    PinLimitReached(String),
+    InviteExpired(String),
+    /// The invite has reached its `max_uses`.
+    InviteExhausted(String),
+    /// Wrong passphrase, or the file was corrupted or tampered with.
+    DecryptionFailed(String),
+    Io(String),
+    /// A bug or broken invariant, e.g. a poisoned lock.
+    Internal(String),
+}
+
+impl AppError {
+    /// Stable, snake_case identifier for the frontend.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::PermissionDenied(_) => "permission_denied",
+            AppError::Validation(_) => "validation",
+            AppError::Conflict(_) => "conflict",
+            AppError::Database(_) => "database",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::NotAMember(_) => "not_a_member",
+            AppError::Blocked(_) => "blocked",
+            AppError::TokenExpired(_) => "token_expired",
+            AppError::UsernameTaken(_) => "username_taken",
+            AppError::PinLimitReached(_) => "pin_limit_reached",
+            AppError::InviteExpired(_) => "invite_expired",
+            AppError::InviteExhausted(_) => "invite_exhausted",
+            AppError::DecryptionFailed(_) => "decryption_failed",
+            AppError::Io(_) => "io",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    fn map_message(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            AppError::NotFound(m) => AppError::NotFound(f(m)),
+            AppError::PermissionDenied(m) => AppError::PermissionDenied(f(m)),
+            AppError::Validation(m) => AppError::Validation(f(m)),
+            AppError::Conflict(m) => AppError::Conflict(f(m)),
+            AppError::Database(m) => AppError::Database(f(m)),
+            AppError::NotAMember(m) => AppError::NotAMember(f(m)),
+            AppError::Blocked(m) => AppError::Blocked(f(m)),
+            AppError::TokenExpired(m) => AppError::TokenExpired(f(m)),
+            AppError::UsernameTaken(m) => AppError::UsernameTaken(f(m)),
+            AppError::PinLimitReached(m) => AppError::PinLimitReached(f(m)),
+            AppError::InviteExpired(m) => AppError::InviteExpired(f(m)),
+            AppError::InviteExhausted(m) => AppError::InviteExhausted(f(m)),
+            AppError::DecryptionFailed(m) => AppError::DecryptionFailed(f(m)),
+            AppError::Io(m) => AppError::Io(f(m)),
+            AppError::Internal(m) => AppError::Internal(f(m)),
+            rate_limited @ AppError::RateLimited { .. } => rate_limited,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::RateLimited { retry_after_ms } => {
+                write!(f, "rate limited, retry in {} ms", retry_after_ms)
+            }
+            AppError::NotFound(m)
+            | AppError::PermissionDenied(m)
+            | AppError::Validation(m)
+            | AppError::Conflict(m)
+            | AppError::Database(m)
+            | AppError::NotAMember(m)
+            | AppError::Blocked(m)
+            | AppError::TokenExpired(m)
+            | AppError::UsernameTaken(m)
+            | AppError::PinLimitReached(m)
+            | AppError::InviteExpired(m)
+            | AppError::InviteExhausted(m)
+            | AppError::DecryptionFailed(m)
+            | AppError::Io(m)
+            | AppError::Internal(m) => f.write_str(m),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let retry_after_ms = match self {
+            AppError::RateLimited { retry_after_ms } => Some(*retry_after_ms),
+            _ => None,
+        };
+        let mut state =
+            serializer.serialize_struct("AppError", 2 + retry_after_ms.is_some() as usize)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        if let Some(retry_after_ms) = retry_after_ms {
+            state.serialize_field("retry_after_ms", &retry_after_ms)?;
+        }
+        state.end()
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => AppError::NotFound("record not found".into()),
+            sqlx::Error::Database(db) => match db.kind() {
+                ErrorKind::UniqueViolation
+                | ErrorKind::ForeignKeyViolation
+                | ErrorKind::NotNullViolation
+                | ErrorKind::CheckViolation => AppError::Conflict(db.message().to_string()),
+                _ => AppError::Database(e.to_string()),
+            },
+            _ => AppError::Database(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Io(e.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for AppError {
+    fn from(e: PoisonError<T>) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+/// Prefixes an error with what was being attempted, keeping its code.
+pub(crate) trait Context<T> {
+    fn context(self, what: &str) -> Result<T, AppError>;
+}
+
+impl<T, E: Into<AppError>> Context<T> for Result<T, E> {
+    fn context(self, what: &str) -> Result<T, AppError> {
+        self.map_err(|e| e.into().map_message(|m| format!("{}: {}", what, m)))
+    }
+}
@@ -0,0 +1,543 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::encryption::EncryptionState;
+use crate::error::{AppError, Context};
+use crate::groups::member_role;
+use crate::integrity::content_hash;
+use crate::message_type::MessageType;
+use crate::messages::validate_message;
+
+/// Bumped whenever the layout of `ConversationExport` changes.
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ExportedMessage {
+    pub sender_id: i64,
+    pub sender_username: String,
+    pub receiver_id: i64,
+    pub receiver_username: String,
+    pub content: String,
+    pub message_type: String,
+    /// ISO-8601, UTC.
+    pub timestamp: String,
+    /// Exports always hold plaintext; this only marks rows that still need
+    /// decrypting after they are loaded.
+    #[serde(skip)]
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub version: u32,
+    pub exported_at: String,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportSummary {
+    pub path: String,
+    pub message_count: usize,
+    pub file_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportSummary {
+    pub inserted: usize,
+    pub skipped: usize,
+}
+
+/// Fails with a readable error instead of letting `fs::write` report a bare
+/// "No such file or directory".
+pub(crate) fn ensure_parent_dir(path: &Path) -> Result<(), AppError> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => Err(AppError::Io(
+            format!("directory {} does not exist", parent.display()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) async fn write_conversation(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    user_a: i64,
+    user_b: i64,
+    path: &Path,
+) -> Result<ExportSummary, AppError> {
+    ensure_parent_dir(path)?;
+
+    let mut messages = sqlx::query_as::<_, ExportedMessage>(
+        "SELECT m.sender_id, s.username AS sender_username,
+                m.receiver_id, r.username AS receiver_username,
+                m.content, m.message_type,
+                strftime('%Y-%m-%dT%H:%M:%SZ', m.timestamp) AS timestamp,
+                m.encrypted
+         FROM messages m
+         JOIN users s ON s.id = m.sender_id
+         JOIN users r ON r.id = m.receiver_id
+         WHERE ((m.sender_id = ?1 AND m.receiver_id = ?2) OR (m.sender_id = ?2 AND m.receiver_id = ?1))
+           AND m.deleted_at IS NULL
+         ORDER BY m.id",
+    )
+    .bind(user_a)
+    .bind(user_b)
+    .fetch_all(pool)
+    .await
+    .context("failed to load conversation")?;
+
+    for message in messages.iter_mut().filter(|m| m.encrypted) {
+        message.content =
+            encryption.reveal_content(message.sender_id, message.receiver_id, &message.content);
+        message.encrypted = false;
+    }
+
+    let exported_at: String = sqlx::query_scalar("SELECT strftime('%Y-%m-%dT%H:%M:%SZ', 'now')")
+        .fetch_one(pool)
+        .await?;
+
+    let export = ConversationExport {
+        version: EXPORT_FORMAT_VERSION,
+        exported_at,
+        messages,
+    };
+    let json = serde_json::to_vec_pretty(&export)
+        .map_err(|e| AppError::Internal(format!("failed to serialize export: {}", e)))?;
+    std::fs::write(path, &json)
+        .map_err(|e| AppError::Io(format!("failed to write {}: {}", path.display(), e)))?;
+
+    Ok(ExportSummary {
+        path: path.display().to_string(),
+        message_count: export.messages.len(),
+        file_size: json.len() as u64,
+    })
+}
+
+/// Quotes a CSV field when it holds a comma, quote or line break, doubling
+/// any quotes inside (RFC 4180).
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes the group's history as CSV with a header row. Deleted messages
+/// are left out, as in `write_conversation`.
+pub(crate) async fn write_group_csv(
+    pool: &SqlitePool,
+    group_id: &str,
+    requester_id: i64,
+    path: &Path,
+) -> Result<ExportSummary, AppError> {
+    if member_role(pool, group_id, requester_id).await?.is_none() {
+        return Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            requester_id, group_id
+        )));
+    }
+    ensure_parent_dir(path)?;
+
+    let rows: Vec<(String, String, String, String)> = sqlx::query_as(
+        "SELECT strftime('%Y-%m-%dT%H:%M:%SZ', m.timestamp), u.username,
+                COALESCE(m.message_type, 'text'), m.content
+         FROM group_messages m
+         JOIN users u ON u.id = m.sender_id
+         WHERE m.group_id = ? AND m.deleted_at IS NULL
+         ORDER BY m.id",
+    )
+    .bind(group_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load group messages")?;
+
+    let mut csv = String::from("timestamp,sender_username,message_type,content\r\n");
+    for (timestamp, sender, message_type, content) in &rows {
+        let fields = [timestamp, sender, message_type, content].map(|f| csv_field(f));
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    std::fs::write(path, &csv)
+        .map_err(|e| AppError::Io(format!("failed to write {}: {}", path.display(), e)))?;
+
+    Ok(ExportSummary {
+        path: path.display().to_string(),
+        message_count: rows.len(),
+        file_size: csv.len() as u64,
+    })
+}
+
+/// Parses and checks an export file up front so a bad file is rejected before
+/// anything is written.
+pub(crate) fn parse_export(raw: &str, owner_id: i64) -> Result<ConversationExport, AppError> {
+    let export: ConversationExport = serde_json::from_str(raw)
+        .map_err(|e| AppError::Validation(format!("malformed export file: {}", e)))?;
+
+    if export.version != EXPORT_FORMAT_VERSION {
+        return Err(AppError::Validation(format!(
+            "unsupported export version {} (expected {})",
+            export.version, EXPORT_FORMAT_VERSION
+        )));
+    }
+
+    for (i, message) in export.messages.iter().enumerate() {
+        message
+            .message_type
+            .parse::<MessageType>()
+            .and_then(|_| validate_message(&message.content))
+            .context(&format!("message {}", i))?;
+        if message.sender_id != owner_id && message.receiver_id != owner_id {
+            return Err(AppError::Validation(format!(
+                "message {} does not belong to a conversation of user {}",
+                i, owner_id
+            )));
+        }
+    }
+
+    Ok(export)
+}
+
+/// Imports an export written by `write_conversation`. A message already
+/// stored with the same sender, receiver, time and plaintext is skipped, so
+/// importing a file twice is harmless even when the stored copy is sealed.
+/// New rows are sealed like any other send.
+pub(crate) async fn read_conversation(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    path: &Path,
+    owner_id: i64,
+) -> Result<ImportSummary, AppError> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| AppError::Io(format!("failed to read {}: {}", path.display(), e)))?;
+    let export = parse_export(&raw, owner_id)?;
+
+    let mut tx = pool.begin().await?;
+    let mut summary = ImportSummary {
+        inserted: 0,
+        skipped: 0,
+    };
+
+    for (i, message) in export.messages.iter().enumerate() {
+        let timestamp: Option<String> = sqlx::query_scalar("SELECT datetime(?)")
+            .bind(&message.timestamp)
+            .fetch_one(&mut *tx)
+            .await?;
+        let timestamp = timestamp.ok_or_else(|| {
+            AppError::Validation(format!(
+                "message {}: invalid timestamp '{}'",
+                i, message.timestamp
+            ))
+        })?;
+
+        let candidates: Vec<(String, bool)> = sqlx::query_as(
+            "SELECT content, encrypted FROM messages
+             WHERE sender_id = ? AND receiver_id = ? AND datetime(timestamp) = ?",
+        )
+        .bind(message.sender_id)
+        .bind(message.receiver_id)
+        .bind(&timestamp)
+        .fetch_all(&mut *tx)
+        .await
+        .context(&format!("message {}: failed to check for duplicates", i))?;
+        let duplicate = candidates.iter().any(|(content, encrypted)| {
+            let plaintext = if *encrypted {
+                encryption.reveal_content(message.sender_id, message.receiver_id, content)
+            } else {
+                content.clone()
+            };
+            plaintext == message.content
+        });
+        if duplicate {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let sealed =
+            encryption.seal_for(message.sender_id, message.receiver_id, &message.content)?;
+        let stored = sealed.as_deref().unwrap_or(&message.content);
+        sqlx::query(
+            "INSERT INTO messages
+                 (sender_id, receiver_id, content, message_type, timestamp, content_hash,
+                  encrypted)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(message.sender_id)
+        .bind(message.receiver_id)
+        .bind(stored)
+        .bind(&message.message_type)
+        .bind(&timestamp)
+        .bind(content_hash(stored))
+        .bind(sealed.is_some())
+        .execute(&mut *tx)
+        .await
+        .context(&format!("message {}: failed to import", i))?;
+        summary.inserted += 1;
+    }
+
+    tx.commit().await?;
+    Ok(summary)
+}
+
+#[tauri::command]
+pub async fn export_conversation(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_a: i64,
+    user_b: i64,
+    path: String,
+) -> Result<ExportSummary, AppError> {
+    let pool = db::pool(&db).await?;
+    write_conversation(&pool, &encryption, user_a, user_b, Path::new(&path)).await
+}
+
+#[tauri::command]
+pub async fn import_conversation(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    path: String,
+    owner_id: i64,
+) -> Result<ImportSummary, AppError> {
+    let pool = db::pool(&db).await?;
+    read_conversation(&pool, &encryption, Path::new(&path), owner_id).await
+}
+
+#[tauri::command]
+pub async fn export_group_csv(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    requester_id: i64,
+    path: String,
+) -> Result<ExportSummary, AppError> {
+    let pool = db::pool(&db).await?;
+    write_group_csv(&pool, &group_id, requester_id, Path::new(&path)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_messages::insert_group_message;
+    use crate::groups::insert_group;
+    use crate::messages::insert_message;
+    use crate::test_support::count;
+    use crate::test_support::test_pool;
+
+    /// Splits RFC 4180 text back into rows of fields.
+    fn parse_csv(raw: &str) -> Vec<Vec<String>> {
+        let (mut rows, mut row, mut field, mut quoted) = (vec![], vec![], String::new(), false);
+        let mut chars = raw.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') => quoted = true,
+                (false, ',') => row.push(std::mem::take(&mut field)),
+                (false, '\r') => {}
+                (false, '\n') => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                (false, c) => field.push(c),
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn group_csv_quotes_awkward_content() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let tricky = "a, \"quoted\"\r\nsecond line";
+        insert_group_message(&pool, &group.id, 2, tricky, MessageType::Text, None, None)
+            .await
+            .unwrap();
+        insert_group_message(&pool, &group.id, 1, "plain", MessageType::File, None, None)
+            .await
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("cereals-group-{}.csv", std::process::id()));
+
+        let summary = write_group_csv(&pool, &group.id, 1, &path).await.unwrap();
+        let raw = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary.file_size, raw.len() as u64);
+
+        let rows = parse_csv(&raw);
+        assert_eq!(
+            rows[0],
+            ["timestamp", "sender_username", "message_type", "content"]
+        );
+        assert_eq!(summary.message_count, rows.len() - 1);
+        let messages: Vec<_> = rows[1..].iter().filter(|r| r[2] != "system").collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0][0].ends_with('Z'));
+        assert_eq!(messages[0][1..], ["u2", "text", tricky]);
+        assert_eq!(messages[1][1..], ["u1", "file", "plain"]);
+    }
+
+    #[tokio::test]
+    async fn only_members_can_export_a_group() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let path = std::env::temp_dir().join(format!("cereals-denied-{}.csv", std::process::id()));
+        let err = write_group_csv(&pool, &group.id, 3, &path)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_a_member");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn encrypted_exports_reimport_without_duplicates() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        encryption.enable(1, "master").unwrap();
+        for (sender, receiver, content) in [(1, 2, "first"), (2, 1, "second")] {
+            insert_message(
+                &pool,
+                &encryption,
+                sender,
+                receiver,
+                content,
+                MessageType::Text,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("cereals-sealed-{}.json", std::process::id()));
+        write_conversation(&pool, &encryption, 1, 2, &path)
+            .await
+            .unwrap();
+
+        let summary = read_conversation(&pool, &encryption, &path, 1)
+            .await
+            .unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (0, 2));
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 2);
+
+        sqlx::query("DELETE FROM messages")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let summary = read_conversation(&pool, &encryption, &path, 1)
+            .await
+            .unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (2, 0));
+        let sealed = "SELECT COUNT(*) FROM messages
+                  WHERE encrypted = 1 AND content NOT IN ('first', 'second')";
+        assert_eq!(count(&pool, sealed).await, 2);
+        let summary = read_conversation(&pool, &encryption, &path, 1)
+            .await
+            .unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (0, 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn exports_import_into_a_fresh_database_once() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        for (sender, receiver, content) in [(1, 2, "hi"), (2, 1, "yo"), (1, 3, "other")] {
+            insert_message(
+                &pool,
+                &encryption,
+                sender,
+                receiver,
+                content,
+                MessageType::Text,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("cereals-export-{}.json", std::process::id()));
+        let exported = write_conversation(&pool, &encryption, 1, 2, &path)
+            .await
+            .unwrap();
+        assert_eq!(exported.message_count, 2);
+
+        let fresh = test_pool().await;
+        let summary = read_conversation(&fresh, &encryption, &path, 2)
+            .await
+            .unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (2, 0));
+        let summary = read_conversation(&fresh, &encryption, &path, 2)
+            .await
+            .unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (0, 2));
+        std::fs::remove_file(&path).unwrap();
+
+        let copied: Vec<(i64, i64, String)> = sqlx::query_as(
+            "SELECT sender_id, receiver_id, content FROM messages ORDER BY timestamp, id",
+        )
+        .fetch_all(&fresh)
+        .await
+        .unwrap();
+        assert_eq!(copied, [(1, 2, "hi".to_string()), (2, 1, "yo".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn bad_export_files_import_nothing() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        insert_message(&pool, &encryption, 1, 2, "kept", MessageType::Text, None)
+            .await
+            .unwrap();
+        let message = |timestamp: &str| {
+            format!(
+                r#"{{"sender_id": 1, "sender_username": "u1", "receiver_id": 2,
+                    "receiver_username": "u2", "content": "new", "message_type": "text",
+                    "timestamp": "{}"}}"#,
+                timestamp
+            )
+        };
+        let file = |version: u32, messages: &[String]| {
+            format!(
+                r#"{{"version": {}, "exported_at": "2024-01-01T00:00:00Z", "messages": [{}]}}"#,
+                version,
+                messages.join(",")
+            )
+        };
+        let path = std::env::temp_dir().join(format!("cereals-bad-{}.json", std::process::id()));
+        for (raw, owner_id) in [
+            ("{ not json".to_string(), 1),
+            (
+                file(
+                    EXPORT_FORMAT_VERSION + 1,
+                    &[message("2024-01-01T00:00:00Z")],
+                ),
+                1,
+            ),
+            // Neither side of the conversation is the importing user.
+            (
+                file(EXPORT_FORMAT_VERSION, &[message("2024-01-01T00:00:00Z")]),
+                3,
+            ),
+            // The first message would go in before the second one fails.
+            (
+                file(
+                    EXPORT_FORMAT_VERSION,
+                    &[message("2024-01-01T00:00:00Z"), message("yesterday")],
+                ),
+                1,
+            ),
+        ] {
+            std::fs::write(&path, &raw).unwrap();
+            let err = read_conversation(&pool, &encryption, &path, owner_id)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "validation", "{}", raw);
+            assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 1);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
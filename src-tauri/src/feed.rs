@@ -0,0 +1,265 @@
+//! A single chronological stream of everything involving a user: direct
+//! messages, messages in their groups, mentions of them, and reactions in
+//! their conversations.
+//!
+//! Entries are ordered by `(timestamp, kind, id)`, newest first. The kind is
+//! part of the key because ids are only unique within one source (a mention
+//! shares its id with the group message it's in), and timestamps have
+//! one-second resolution, so ties are common.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::encryption::EncryptionState;
+use crate::error::{AppError, Context};
+use crate::messages::page_size;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum FeedKind {
+    DirectMessage,
+    GroupMessage,
+    Mention,
+    Reaction,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct FeedEntry {
+    pub kind: FeedKind,
+    /// Message id for message and mention entries, reaction row id for
+    /// reactions.
+    pub id: i64,
+    pub timestamp: String,
+    /// Who sent the message or reacted.
+    pub actor_id: i64,
+    pub message_id: i64,
+    pub receiver_id: Option<i64>,
+    pub group_id: Option<String>,
+    pub content: Option<String>,
+    pub emoji: Option<String>,
+    #[serde(skip)]
+    pub encrypted: bool,
+    #[serde(skip)]
+    pub(crate) kind_rank: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedPage {
+    pub entries: Vec<FeedEntry>,
+    /// Pass back to get the next (older) page; `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+struct Cursor {
+    timestamp: String,
+    kind_rank: i64,
+    id: i64,
+}
+
+fn encode_cursor(entry: &FeedEntry) -> String {
+    URL_SAFE_NO_PAD.encode(format!(
+        "{}|{}|{}",
+        entry.timestamp, entry.kind_rank, entry.id
+    ))
+}
+
+fn decode_cursor(cursor: &str) -> Result<Cursor, AppError> {
+    let invalid = || AppError::Validation("invalid feed cursor".into());
+    let raw = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+    let mut parts = raw.rsplitn(3, '|');
+    let id = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    let kind_rank = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    let timestamp = parts.next().ok_or_else(invalid)?.to_string();
+    Ok(Cursor {
+        timestamp,
+        kind_rank,
+        id,
+    })
+}
+
+pub(crate) async fn feed_page(
+    pool: &SqlitePool,
+    user_id: i64,
+    cursor: Option<&str>,
+    limit: u32,
+) -> Result<FeedPage, AppError> {
+    let cursor = cursor.map(decode_cursor).transpose()?;
+    let limit = page_size(limit);
+
+    let mut entries = sqlx::query_as::<_, FeedEntry>(
+        "WITH feed AS (
+             SELECT 'direct_message' AS kind, 0 AS kind_rank, m.id, datetime(m.timestamp) AS timestamp,
+                    m.sender_id AS actor_id, m.id AS message_id, m.receiver_id, NULL AS group_id,
+                    m.content, NULL AS emoji, m.encrypted
+             FROM messages m
+             WHERE (m.sender_id = ?1 OR m.receiver_id = ?1) AND m.deleted_at IS NULL
+             UNION ALL
+             SELECT 'group_message', 1, g.id, datetime(g.timestamp),
+                    g.sender_id, g.id, NULL, g.group_id,
+                    g.content, NULL, FALSE
+             FROM group_messages g
+             JOIN group_members gm ON gm.group_id = g.group_id AND gm.user_id = ?1
+             WHERE g.deleted_at IS NULL
+             UNION ALL
+             SELECT 'mention', 2, g.id, datetime(g.timestamp),
+                    g.sender_id, g.id, NULL, g.group_id,
+                    g.content, NULL, FALSE
+             FROM mentions mn
+             JOIN group_messages g ON g.id = mn.message_id
+             WHERE mn.mentioned_user_id = ?1 AND g.deleted_at IS NULL
+             UNION ALL
+             SELECT 'reaction', 3, r.rowid, datetime(r.reacted_at),
+                    r.user_id, r.message_id, m.receiver_id, NULL,
+                    NULL, r.emoji, FALSE
+             FROM message_reactions r
+             JOIN messages m ON m.id = r.message_id
+             WHERE (m.sender_id = ?1 OR m.receiver_id = ?1) AND m.deleted_at IS NULL
+         )
+         SELECT * FROM feed
+         WHERE ?2 IS NULL OR (timestamp, kind_rank, id) < (?2, ?3, ?4)
+         ORDER BY timestamp DESC, kind_rank DESC, id DESC
+         LIMIT ?5",
+    )
+    .bind(user_id)
+    .bind(cursor.as_ref().map(|c| c.timestamp.as_str()))
+    .bind(cursor.as_ref().map(|c| c.kind_rank))
+    .bind(cursor.as_ref().map(|c| c.id))
+    .bind(limit + 1)
+    .fetch_all(pool)
+    .await
+    .context("failed to load activity feed")?;
+
+    let next_cursor = if entries.len() > limit as usize {
+        entries.truncate(limit as usize);
+        entries.last().map(encode_cursor)
+    } else {
+        None
+    };
+    Ok(FeedPage {
+        entries,
+        next_cursor,
+    })
+}
+
+#[tauri::command]
+pub async fn activity_feed(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_id: i64,
+    cursor: Option<String>,
+    limit: u32,
+) -> Result<FeedPage, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut page = feed_page(&pool, user_id, cursor.as_deref(), limit).await?;
+    for entry in page.entries.iter_mut().filter(|e| e.encrypted) {
+        if let (Some(content), Some(receiver_id)) = (&entry.content, entry.receiver_id) {
+            entry.content = Some(encryption.reveal_content(entry.actor_id, receiver_id, content));
+        }
+    }
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::insert_group;
+    use crate::test_support::test_pool;
+
+    const TIE: &str = "2024-01-01 10:00:00";
+
+    async fn direct_at(pool: &SqlitePool, sender: i64, receiver: i64, timestamp: &str) -> i64 {
+        sqlx::query_scalar(
+            "INSERT INTO messages (sender_id, receiver_id, content, timestamp)
+             VALUES (?, ?, 'hi', ?) RETURNING id",
+        )
+        .bind(sender)
+        .bind(receiver)
+        .bind(timestamp)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ties_on_the_same_timestamp_page_in_a_stable_order() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 2, &[1]).await.unwrap();
+        let first = direct_at(&pool, 2, 1, TIE).await;
+        let second = direct_at(&pool, 2, 1, TIE).await;
+        let third = direct_at(&pool, 2, 1, TIE).await;
+        direct_at(&pool, 3, 4, TIE).await;
+        let group_message: i64 = sqlx::query_scalar(
+            "INSERT INTO group_messages (group_id, sender_id, content, timestamp)
+             VALUES (?, 2, '@u1', ?) RETURNING id",
+        )
+        .bind(&group.id)
+        .bind(TIE)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO mentions (message_id, mentioned_user_id) VALUES (?, 1)")
+            .bind(group_message)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let reaction: i64 = sqlx::query_scalar(
+            "INSERT INTO message_reactions (message_id, user_id, emoji, reacted_at)
+             VALUES (?, 1, '👍', ?) RETURNING rowid",
+        )
+        .bind(first)
+        .bind(TIE)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let newest = direct_at(&pool, 1, 2, "2024-01-02 00:00:00").await;
+
+        let mut paged = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = feed_page(&pool, 1, cursor.as_deref(), 2).await.unwrap();
+            assert!(page.entries.len() <= 2);
+            paged.extend(page.entries.iter().map(|e| (e.kind, e.id)));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(
+            paged,
+            [
+                (FeedKind::DirectMessage, newest),
+                (FeedKind::Reaction, reaction),
+                (FeedKind::Mention, group_message),
+                (FeedKind::GroupMessage, group_message),
+                (FeedKind::DirectMessage, third),
+                (FeedKind::DirectMessage, second),
+                (FeedKind::DirectMessage, first),
+            ]
+        );
+        let whole = feed_page(&pool, 1, None, 50).await.unwrap();
+        assert!(whole.next_cursor.is_none());
+        let unpaged: Vec<_> = whole.entries.iter().map(|e| (e.kind, e.id)).collect();
+        assert_eq!(unpaged, paged);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_garbled_cursor() {
+        let pool = test_pool().await;
+        let err = feed_page(&pool, 1, Some("!!"), 2).await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+}
@@ -0,0 +1,251 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::AppError;
+use crate::group_messages::{handle_of, post_system_message};
+use crate::groups::{member_role, GroupRow, GROUP_COLUMNS};
+
+pub const INVITE_TOKEN_LEN: usize = 24;
+pub const MAX_INVITE_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GroupInvite {
+    pub token: String,
+    pub group_id: String,
+    pub created_by: i64,
+    pub created_at: String,
+    pub expires_at: String,
+    /// `None` means the invite can be used any number of times until it expires.
+    pub max_uses: Option<i64>,
+    pub use_count: i64,
+}
+
+const INVITE_COLUMNS: &str =
+    "token, group_id, created_by, created_at, expires_at, max_uses, use_count";
+
+fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Only admins and owners may hand out invites.
+pub(crate) async fn insert_invite(
+    pool: &SqlitePool,
+    group_id: &str,
+    creator_id: i64,
+    ttl_secs: u64,
+    max_uses: Option<u32>,
+) -> Result<GroupInvite, AppError> {
+    let ttl_secs = i64::try_from(ttl_secs)
+        .ok()
+        .filter(|ttl| (1..=MAX_INVITE_TTL_SECS).contains(ttl))
+        .ok_or_else(|| {
+            AppError::Validation(format!(
+                "invite lifetime must be between 1 and {} seconds",
+                MAX_INVITE_TTL_SECS
+            ))
+        })?;
+    if max_uses == Some(0) {
+        return Err(AppError::Validation("max_uses must be at least 1".into()));
+    }
+
+    match member_role(pool, group_id, creator_id).await?.as_deref() {
+        Some("owner") | Some("admin") => {}
+        Some(_) => {
+            return Err(AppError::PermissionDenied(
+                "only admins and owners can create invites".into(),
+            ))
+        }
+        None => {
+            return Err(AppError::NotAMember(format!(
+                "user {} is not a member of group {}",
+                creator_id, group_id
+            )))
+        }
+    }
+
+    let invite = sqlx::query_as::<_, GroupInvite>(&format!(
+        "INSERT INTO group_invites (token, group_id, created_by, expires_at, max_uses)
+         VALUES (?, ?, ?, datetime('now', '+' || ? || ' seconds'), ?)
+         RETURNING {}",
+        INVITE_COLUMNS
+    ))
+    .bind(new_token())
+    .bind(group_id)
+    .bind(creator_id)
+    .bind(ttl_secs)
+    .bind(max_uses)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(invite)
+}
+
+/// Adds `user_id` to the invite's group. Users who are already members get
+/// the group back without using up the invite.
+pub(crate) async fn redeem(
+    pool: &SqlitePool,
+    token: &str,
+    user_id: i64,
+) -> Result<GroupRow, AppError> {
+    let mut tx = pool.begin().await?;
+
+    let (group_id, expired, exhausted): (String, bool, bool) = sqlx::query_as(
+        "SELECT group_id,
+                datetime(expires_at) <= datetime('now'),
+                max_uses IS NOT NULL AND use_count >= max_uses
+         FROM group_invites WHERE token = ?",
+    )
+    .bind(token)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("invite not found".into()))?;
+
+    let already_member = member_role(&mut *tx, &group_id, user_id).await?.is_some();
+    if !already_member {
+        if expired {
+            return Err(AppError::InviteExpired("this invite has expired".into()));
+        }
+        if exhausted {
+            return Err(AppError::InviteExhausted(
+                "this invite has already been used the maximum number of times".into(),
+            ));
+        }
+
+        sqlx::query("INSERT INTO group_members (group_id, user_id, role) VALUES (?, ?, 'member')")
+            .bind(&group_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE groups SET member_count = member_count + 1 WHERE id = ?")
+            .bind(&group_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE group_invites SET use_count = use_count + 1 WHERE token = ?")
+            .bind(token)
+            .execute(&mut *tx)
+            .await?;
+
+        let text = format!("{} joined the group", handle_of(&mut tx, user_id).await?);
+        post_system_message(&mut tx, &group_id, &text).await?;
+    }
+
+    let group = sqlx::query_as::<_, GroupRow>(&format!(
+        "SELECT {} FROM groups WHERE id = ?",
+        GROUP_COLUMNS
+    ))
+    .bind(&group_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(group)
+}
+
+#[tauri::command]
+pub async fn create_group_invite(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    creator_id: i64,
+    ttl_secs: u64,
+    max_uses: Option<u32>,
+) -> Result<GroupInvite, AppError> {
+    let pool = db::pool(&db).await?;
+    insert_invite(&pool, &group_id, creator_id, ttl_secs, max_uses).await
+}
+
+#[tauri::command]
+pub async fn redeem_invite(
+    db: State<'_, DbInstances>,
+    token: String,
+    user_id: i64,
+) -> Result<GroupRow, AppError> {
+    let pool = db::pool(&db).await?;
+    redeem(&pool, &token, user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::insert_group;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn redeeming_an_expired_invite_fails() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let invite = insert_invite(&pool, &group.id, 1, 60, None).await.unwrap();
+        sqlx::query(
+            "UPDATE group_invites SET expires_at = datetime('now', '-1 seconds') WHERE token = ?",
+        )
+        .bind(&invite.token)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let err = redeem(&pool, &invite.token, 3).await.unwrap_err();
+        assert_eq!(err.code(), "invite_expired");
+        assert!(member_role(&pool, &group.id, 3).await.unwrap().is_none());
+        // Existing members still get the group back.
+        assert_eq!(redeem(&pool, &invite.token, 2).await.unwrap().id, group.id);
+        assert_eq!(
+            redeem(&pool, "nope", 3).await.unwrap_err().code(),
+            "not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn invite_lifetime_is_bounded() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[]).await.unwrap();
+        for ttl_secs in [0, MAX_INVITE_TTL_SECS as u64 + 1, u64::MAX] {
+            let err = insert_invite(&pool, &group.id, 1, ttl_secs, None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "validation", "ttl {}", ttl_secs);
+        }
+        let invite = insert_invite(&pool, &group.id, 1, MAX_INVITE_TTL_SECS as u64, None)
+            .await
+            .unwrap();
+        let days: i64 =
+            sqlx::query_scalar("SELECT CAST(round(julianday(?) - julianday('now')) AS INTEGER)")
+                .bind(&invite.expires_at)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(days, 30);
+    }
+
+    #[tokio::test]
+    async fn invites_are_limited_to_admins_and_max_uses() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let err = insert_invite(&pool, &group.id, 2, 60, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+
+        let invite = insert_invite(&pool, &group.id, 1, 3600, Some(1))
+            .await
+            .unwrap();
+        assert_eq!(invite.token.len(), INVITE_TOKEN_LEN);
+        assert_eq!(
+            redeem(&pool, &invite.token, 3).await.unwrap().member_count,
+            3
+        );
+        assert_eq!(
+            redeem(&pool, &invite.token, 3).await.unwrap().member_count,
+            3
+        );
+        let err = redeem(&pool, &invite.token, 4).await.unwrap_err();
+        assert_eq!(err.code(), "invite_exhausted");
+    }
+}
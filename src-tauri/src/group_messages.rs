@@ -0,0 +1,764 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+use tauri::{AppHandle, Emitter, State};
+use tauri_plugin_sql::DbInstances;
+
+use crate::error::{AppError, Context};
+use crate::groups::member_role;
+use crate::message_type::MessageType;
+use crate::messages::{page_size, validate_message};
+use crate::protocol::{serialize_ws_frame, GroupMessageEditedEvent, WsEvent};
+use crate::rate_limit::{self, RateLimitSettings, RateLimitState};
+use crate::users::SYSTEM_USER_ID;
+use crate::ws_manager::{self, WsState};
+use crate::{conversations, db, disappearing, mentions, notify, presence};
+
+pub const MAX_PINNED_PER_GROUP: i64 = 50;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GroupMessageRow {
+    pub id: i64,
+    pub group_id: String,
+    pub sender_id: i64,
+    pub content: String,
+    pub message_type: String,
+    pub timestamp: String,
+    pub deleted: bool,
+    pub reply_to_id: Option<i64>,
+    pub edited_at: Option<String>,
+    /// When the message disappears, for messages sent with a TTL.
+    pub expires_at: Option<String>,
+}
+
+/// Column list matching `GroupMessageRow`; deleted rows are masked the same
+/// way as direct messages.
+pub(crate) const GROUP_MESSAGE_COLUMNS: &str = "id, group_id, sender_id,
+    CASE WHEN deleted_at IS NULL THEN content ELSE '' END AS content,
+    message_type, timestamp, deleted_at IS NOT NULL AS deleted, reply_to_id, edited_at,
+    expires_at";
+
+pub(crate) async fn insert_group_message(
+    pool: &SqlitePool,
+    group_id: &str,
+    sender_id: i64,
+    content: &str,
+    message_type: MessageType,
+    reply_to: Option<i64>,
+    ttl_secs: Option<u32>,
+) -> Result<(GroupMessageRow, Vec<i64>), AppError> {
+    validate_message(content)?;
+    if message_type == MessageType::System {
+        return Err(AppError::Validation(
+            "system messages can only be posted by the app".into(),
+        ));
+    }
+
+    if member_role(pool, group_id, sender_id).await?.is_none() {
+        return Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            sender_id, group_id
+        )));
+    }
+
+    if let Some(parent_id) = reply_to {
+        let parent_group: String =
+            sqlx::query_scalar("SELECT group_id FROM group_messages WHERE id = ?")
+                .bind(parent_id)
+                .fetch_optional(pool)
+                .await?
+                .ok_or_else(|| AppError::NotFound(format!("message {} not found", parent_id)))?;
+        if parent_group != group_id {
+            return Err(AppError::Validation(
+                "replies must reference a message in the same group".into(),
+            ));
+        }
+    }
+
+    let expiry =
+        disappearing::expiry_modifier(pool, sender_id, group_id, "group", ttl_secs).await?;
+
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query_as::<_, GroupMessageRow>(&format!(
+        "INSERT INTO group_messages
+             (group_id, sender_id, content, message_type, reply_to_id, expires_at)
+         VALUES (?, ?, ?, ?, ?, datetime('now', ?))
+         RETURNING {}",
+        GROUP_MESSAGE_COLUMNS
+    ))
+    .bind(group_id)
+    .bind(sender_id)
+    .bind(content)
+    .bind(message_type.as_str())
+    .bind(reply_to)
+    .bind(expiry)
+    .fetch_one(&mut *tx)
+    .await?;
+    let mentioned =
+        mentions::record_mentions(&mut tx, group_id, row.id, sender_id, content).await?;
+    tx.commit().await?;
+    presence::touch_last_seen(pool, sender_id).await?;
+    conversations::unarchive_group(pool, group_id, sender_id).await?;
+
+    Ok((row, mentioned))
+}
+
+/// Group messages newest first, keyset-paginated like `fetch_conversation`.
+pub(crate) async fn group_page(
+    pool: &SqlitePool,
+    group_id: &str,
+    before_id: Option<i64>,
+    limit: u32,
+) -> Result<Vec<GroupMessageRow>, AppError> {
+    let rows = sqlx::query_as::<_, GroupMessageRow>(&format!(
+        "SELECT {}
+         FROM group_messages
+         WHERE group_id = ?1 AND (?2 IS NULL OR id < ?2)
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY id DESC
+         LIMIT ?3",
+        GROUP_MESSAGE_COLUMNS
+    ))
+    .bind(group_id)
+    .bind(before_id)
+    .bind(page_size(limit))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows)
+}
+
+/// Replaces a group message's content, keeping the old text in
+/// `group_message_edits`. Only the original sender may edit, and only while
+/// still a member.
+pub(crate) async fn apply_group_edit(
+    pool: &SqlitePool,
+    group_id: &str,
+    message_id: i64,
+    editor_id: i64,
+    new_content: &str,
+) -> Result<GroupMessageRow, AppError> {
+    validate_message(new_content)?;
+    if member_role(pool, group_id, editor_id).await?.is_none() {
+        return Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            editor_id, group_id
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let (sender_id, previous_content, deleted): (i64, String, bool) = sqlx::query_as(
+        "SELECT sender_id, content, deleted_at IS NOT NULL
+         FROM group_messages WHERE id = ? AND group_id = ?",
+    )
+    .bind(message_id)
+    .bind(group_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))?;
+
+    if sender_id != editor_id {
+        return Err(AppError::PermissionDenied(
+            "only the sender can edit this message".into(),
+        ));
+    }
+    if deleted {
+        return Err(AppError::Validation(
+            "deleted messages cannot be edited".into(),
+        ));
+    }
+
+    sqlx::query("INSERT INTO group_message_edits (message_id, previous_content) VALUES (?, ?)")
+        .bind(message_id)
+        .bind(&previous_content)
+        .execute(&mut *tx)
+        .await
+        .context("failed to record edit")?;
+
+    let row = sqlx::query_as::<_, GroupMessageRow>(&format!(
+        "UPDATE group_messages SET content = ?, edited_at = CURRENT_TIMESTAMP
+         WHERE id = ?
+         RETURNING {}",
+        GROUP_MESSAGE_COLUMNS
+    ))
+    .bind(new_content)
+    .bind(message_id)
+    .fetch_one(&mut *tx)
+    .await
+    .context("failed to edit message")?;
+
+    tx.commit().await?;
+    Ok(row)
+}
+
+/// Posts `text` to the group as the system user. Takes the caller's
+/// connection so the announcement commits together with the change it
+/// describes.
+pub(crate) async fn post_system_message(
+    conn: &mut SqliteConnection,
+    group_id: &str,
+    text: &str,
+) -> Result<GroupMessageRow, AppError> {
+    sqlx::query_as::<_, GroupMessageRow>(&format!(
+        "INSERT INTO group_messages (group_id, sender_id, content, message_type)
+         VALUES (?, ?, ?, ?)
+         RETURNING {}",
+        GROUP_MESSAGE_COLUMNS
+    ))
+    .bind(group_id)
+    .bind(SYSTEM_USER_ID)
+    .bind(text)
+    .bind(MessageType::System.as_str())
+    .fetch_one(conn)
+    .await
+    .context("failed to post system message")
+}
+
+/// `@username`, for system message text.
+pub(crate) async fn handle_of(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+) -> Result<String, AppError> {
+    let username: String = sqlx::query_scalar("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {} not found", user_id)))?;
+    Ok(format!("@{}", username))
+}
+
+/// Soft-deletes a group message. Senders may delete their own messages;
+/// admins and owners may delete anyone's.
+pub(crate) async fn mark_group_deleted(
+    pool: &SqlitePool,
+    group_id: &str,
+    message_id: i64,
+    requester_id: i64,
+) -> Result<GroupMessageRow, AppError> {
+    let role = member_role(pool, group_id, requester_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotAMember(format!(
+                "user {} is not a member of group {}",
+                requester_id, group_id
+            ))
+        })?;
+
+    let sender_id: i64 =
+        sqlx::query_scalar("SELECT sender_id FROM group_messages WHERE id = ? AND group_id = ?")
+            .bind(message_id)
+            .bind(group_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))?;
+
+    if sender_id == SYSTEM_USER_ID {
+        return Err(AppError::PermissionDenied(
+            "system messages cannot be deleted".into(),
+        ));
+    }
+    if sender_id != requester_id && !matches!(role.as_str(), "owner" | "admin") {
+        return Err(AppError::PermissionDenied(
+            "only the sender or a group admin can delete this message".into(),
+        ));
+    }
+
+    sqlx::query_as::<_, GroupMessageRow>(&format!(
+        "UPDATE group_messages SET deleted_at = COALESCE(deleted_at, CURRENT_TIMESTAMP)
+         WHERE id = ?
+         RETURNING {}",
+        GROUP_MESSAGE_COLUMNS
+    ))
+    .bind(message_id)
+    .fetch_one(pool)
+    .await
+    .context("failed to delete message")
+}
+
+/// The edit event for `row`, addressed to every other member.
+pub(crate) async fn edited_event(
+    pool: &SqlitePool,
+    row: &GroupMessageRow,
+) -> Result<GroupMessageEditedEvent, AppError> {
+    let recipients: Vec<i64> = sqlx::query_scalar(
+        "SELECT user_id FROM group_members WHERE group_id = ? AND user_id != ? ORDER BY user_id",
+    )
+    .bind(&row.group_id)
+    .bind(row.sender_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(GroupMessageEditedEvent {
+        group_id: row.group_id.clone(),
+        message_id: row.id,
+        editor_id: row.sender_id,
+        content: row.content.clone(),
+        edited_at: row.edited_at.clone(),
+        recipients,
+    })
+}
+
+/// Only admins and owners may change what is pinned in a group.
+async fn require_moderator(
+    pool: &SqlitePool,
+    group_id: &str,
+    actor_id: i64,
+) -> Result<(), AppError> {
+    match member_role(pool, group_id, actor_id).await?.as_deref() {
+        Some("owner") | Some("admin") => Ok(()),
+        Some(_) => Err(AppError::PermissionDenied(
+            "only admins and owners can pin messages".into(),
+        )),
+        None => Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            actor_id, group_id
+        ))),
+    }
+}
+
+pub(crate) async fn set_pinned(
+    pool: &SqlitePool,
+    group_id: &str,
+    message_id: i64,
+    actor_id: i64,
+    pinned: bool,
+) -> Result<GroupMessageRow, AppError> {
+    require_moderator(pool, group_id, actor_id).await?;
+
+    let mut tx = pool.begin().await?;
+
+    let already_pinned: bool = sqlx::query_scalar(
+        "SELECT pinned FROM group_messages WHERE id = ? AND group_id = ? AND deleted_at IS NULL",
+    )
+    .bind(message_id)
+    .bind(group_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))?;
+
+    if pinned && !already_pinned {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM group_messages WHERE group_id = ? AND pinned = 1",
+        )
+        .bind(group_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if count >= MAX_PINNED_PER_GROUP {
+            return Err(AppError::PinLimitReached(format!(
+                "a group can have at most {} pinned messages",
+                MAX_PINNED_PER_GROUP
+            )));
+        }
+    }
+
+    let row = if pinned == already_pinned {
+        sqlx::query_as::<_, GroupMessageRow>(&format!(
+            "SELECT {} FROM group_messages WHERE id = ?",
+            GROUP_MESSAGE_COLUMNS
+        ))
+        .bind(message_id)
+        .fetch_one(&mut *tx)
+        .await?
+    } else {
+        sqlx::query_as::<_, GroupMessageRow>(&format!(
+            "UPDATE group_messages
+             SET pinned = ?1,
+                 pinned_by = CASE WHEN ?1 THEN ?2 ELSE NULL END,
+                 pinned_at = CASE WHEN ?1 THEN CURRENT_TIMESTAMP ELSE NULL END
+             WHERE id = ?3
+             RETURNING {}",
+            GROUP_MESSAGE_COLUMNS
+        ))
+        .bind(pinned)
+        .bind(actor_id)
+        .bind(message_id)
+        .fetch_one(&mut *tx)
+        .await?
+    };
+
+    tx.commit().await?;
+    Ok(row)
+}
+
+/// Pinned messages oldest pin first; the rowid breaks ties between pins made
+/// within the same second.
+pub(crate) async fn pinned_messages(
+    pool: &SqlitePool,
+    group_id: &str,
+) -> Result<Vec<GroupMessageRow>, AppError> {
+    let rows = sqlx::query_as::<_, GroupMessageRow>(&format!(
+        "SELECT {}
+         FROM group_messages
+         WHERE group_id = ? AND pinned = 1 AND deleted_at IS NULL
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY pinned_at ASC, id ASC",
+        GROUP_MESSAGE_COLUMNS
+    ))
+    .bind(group_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows)
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(app, db, ws, limits, buckets, content), err)]
+pub async fn send_group_message(
+    app: AppHandle,
+    db: State<'_, DbInstances>,
+    ws: State<'_, Mutex<WsState>>,
+    limits: State<'_, RateLimitSettings>,
+    buckets: State<'_, RateLimitState>,
+    group_id: String,
+    sender_id: i64,
+    content: String,
+    message_type: Option<String>,
+    reply_to: Option<i64>,
+    ttl_secs: Option<u32>,
+) -> Result<GroupMessageRow, AppError> {
+    rate_limit::check(&buckets, limits.current()?, sender_id, Instant::now())?;
+
+    let pool = db::pool(&db).await?;
+    let message_type = MessageType::parse_or_text(message_type.as_deref())?;
+    let (row, mentioned) = insert_group_message(
+        &pool,
+        &group_id,
+        sender_id,
+        &content,
+        message_type,
+        reply_to,
+        ttl_secs,
+    )
+    .await?;
+
+    if !mentioned.is_empty() {
+        let local_user = ws.lock().ok().and_then(|state| state.user_id());
+        let content = row.content.clone();
+        tauri::async_runtime::spawn(async move {
+            notify::notify_mention(&app, local_user, &group_id, &mentioned, sender_id, &content)
+                .await;
+        });
+    }
+    Ok(row)
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(db), err)]
+pub async fn fetch_group_messages(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    before_id: Option<i64>,
+    limit: u32,
+) -> Result<Vec<GroupMessageRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    group_page(&pool, &group_id, before_id, limit).await
+}
+
+/// Edits the message and tells the other members, over the websocket and as a
+/// `group-message-edited` app event.
+#[tauri::command]
+pub async fn edit_group_message(
+    app: AppHandle,
+    db: State<'_, DbInstances>,
+    ws: State<'_, Mutex<WsState>>,
+    group_id: String,
+    message_id: i64,
+    editor_id: i64,
+    new_content: String,
+) -> Result<GroupMessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    let row = apply_group_edit(&pool, &group_id, message_id, editor_id, &new_content).await?;
+
+    let event = edited_event(&pool, &row).await?;
+    // Like presence, the edit is saved even when the broadcast can't be sent.
+    let _ = ws_manager::send_text(
+        &ws,
+        serialize_ws_frame(&WsEvent::GroupMessageEdited(event.clone())),
+    );
+    let _ = app.emit("group-message-edited", event);
+    Ok(row)
+}
+
+#[tauri::command]
+pub async fn delete_group_message(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    message_id: i64,
+    requester_id: i64,
+) -> Result<GroupMessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    mark_group_deleted(&pool, &group_id, message_id, requester_id).await
+}
+
+#[tauri::command]
+pub async fn pin_group_message(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    message_id: i64,
+    actor_id: i64,
+) -> Result<GroupMessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    set_pinned(&pool, &group_id, message_id, actor_id, true).await
+}
+
+#[tauri::command]
+pub async fn unpin_group_message(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    message_id: i64,
+    actor_id: i64,
+) -> Result<GroupMessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    set_pinned(&pool, &group_id, message_id, actor_id, false).await
+}
+
+#[tauri::command]
+pub async fn list_pinned(
+    db: State<'_, DbInstances>,
+    group_id: String,
+) -> Result<Vec<GroupMessageRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    pinned_messages(&pool, &group_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_invites::{insert_invite, redeem};
+    use crate::groups::change_ownership;
+    use crate::groups::change_role;
+    use crate::groups::{delete_member, insert_group};
+    use crate::messages::MAX_MESSAGE_CHARS;
+    use crate::test_support::count;
+    use crate::test_support::test_pool;
+    use crate::users::merge_accounts;
+
+    async fn post(
+        pool: &SqlitePool,
+        group_id: &str,
+        sender_id: i64,
+        content: &str,
+    ) -> GroupMessageRow {
+        insert_group_message(
+            pool,
+            group_id,
+            sender_id,
+            content,
+            MessageType::Text,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    #[tokio::test]
+    async fn a_removed_member_can_no_longer_post() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        post(&pool, &group.id, 2, "hi").await;
+
+        delete_member(&pool, &group.id, 1, 2).await.unwrap();
+        let err = insert_group_message(&pool, &group.id, 2, "hi", MessageType::Text, None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_a_member");
+        let page = group_page(&pool, &group.id, None, 0).await.unwrap();
+        assert_eq!(page.iter().filter(|m| m.sender_id == 2).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn group_pages_are_newest_first() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[]).await.unwrap();
+        let ids = [
+            post(&pool, &group.id, 1, "m0").await.id,
+            post(&pool, &group.id, 1, "m1").await.id,
+            post(&pool, &group.id, 1, "m2").await.id,
+        ];
+        let first = group_page(&pool, &group.id, None, 2).await.unwrap();
+        let second = group_page(&pool, &group.id, Some(first[1].id), 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            first.iter().map(|m| m.id).collect::<Vec<_>>(),
+            [ids[2], ids[1]]
+        );
+        assert_eq!(second.iter().map(|m| m.id).collect::<Vec<_>>(), [ids[0]]);
+    }
+
+    #[tokio::test]
+    async fn only_admins_pin_and_the_limit_holds() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let first = post(&pool, &group.id, 2, "first").await;
+        let second = post(&pool, &group.id, 2, "second").await;
+
+        let err = set_pinned(&pool, &group.id, first.id, 2, true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+
+        set_pinned(&pool, &group.id, second.id, 1, true)
+            .await
+            .unwrap();
+        set_pinned(&pool, &group.id, first.id, 1, true)
+            .await
+            .unwrap();
+        set_pinned(&pool, &group.id, first.id, 1, true)
+            .await
+            .unwrap();
+        assert_eq!(pinned_messages(&pool, &group.id).await.unwrap().len(), 2);
+        set_pinned(&pool, &group.id, second.id, 1, false)
+            .await
+            .unwrap();
+        assert_eq!(pinned_messages(&pool, &group.id).await.unwrap().len(), 1);
+
+        for i in 1..MAX_PINNED_PER_GROUP {
+            let message = post(&pool, &group.id, 2, &format!("m{}", i)).await;
+            set_pinned(&pool, &group.id, message.id, 1, true)
+                .await
+                .unwrap();
+        }
+        let err = set_pinned(&pool, &group.id, second.id, 1, true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "pin_limit_reached");
+    }
+
+    #[tokio::test]
+    async fn an_admin_can_delete_a_members_message_but_not_the_reverse() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2, 3]).await.unwrap();
+        change_role(&pool, &group.id, 1, 2, "admin").await.unwrap();
+        let by_member = post(&pool, &group.id, 3, "member msg").await;
+        let by_admin = post(&pool, &group.id, 2, "admin msg").await;
+
+        let err = mark_group_deleted(&pool, &group.id, by_admin.id, 3)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        let deleted = mark_group_deleted(&pool, &group.id, by_member.id, 2)
+            .await
+            .unwrap();
+        assert!(deleted.deleted);
+
+        let err = mark_group_deleted(&pool, &group.id, by_admin.id, 7)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_a_member");
+    }
+
+    #[tokio::test]
+    async fn only_the_sender_can_edit_and_edits_are_recorded() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2, 3]).await.unwrap();
+        let message = post(&pool, &group.id, 3, "member msg").await;
+
+        let err = apply_group_edit(&pool, &group.id, message.id, 1, "x")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+
+        let edited = apply_group_edit(&pool, &group.id, message.id, 3, "edited")
+            .await
+            .unwrap();
+        assert_eq!(edited.content, "edited");
+        assert!(edited.edited_at.is_some());
+        let history =
+            "SELECT COUNT(*) FROM group_message_edits WHERE previous_content = 'member msg'";
+        assert_eq!(count(&pool, history).await, 1);
+        let event = edited_event(&pool, &edited).await.unwrap();
+        assert_eq!(event.recipients, [1, 2]);
+
+        mark_group_deleted(&pool, &group.id, message.id, 3)
+            .await
+            .unwrap();
+        let err = apply_group_edit(&pool, &group.id, message.id, 3, "again")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    #[tokio::test]
+    async fn group_edits_share_the_length_cap() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[]).await.unwrap();
+        let message = post(&pool, &group.id, 1, "hi").await;
+
+        let too_long = "a".repeat(MAX_MESSAGE_CHARS + 1);
+        let err = apply_group_edit(&pool, &group.id, message.id, 1, &too_long)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        let at_cap = "a".repeat(MAX_MESSAGE_CHARS);
+        apply_group_edit(&pool, &group.id, message.id, 1, &at_cap)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn membership_changes_post_system_messages() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let invite = insert_invite(&pool, &group.id, 1, 3600, None)
+            .await
+            .unwrap();
+        redeem(&pool, &invite.token, 3).await.unwrap();
+        // Repeats that change nothing announce nothing.
+        redeem(&pool, &invite.token, 3).await.unwrap();
+        change_role(&pool, &group.id, 1, 3, "admin").await.unwrap();
+        change_role(&pool, &group.id, 1, 3, "admin").await.unwrap();
+        change_ownership(&pool, &group.id, 1, 2).await.unwrap();
+        delete_member(&pool, &group.id, 3, 3).await.unwrap();
+        delete_member(&pool, &group.id, 2, 1).await.unwrap();
+
+        let page = group_page(&pool, &group.id, None, 50).await.unwrap();
+        assert!(page
+            .iter()
+            .all(|m| m.sender_id == 0 && m.message_type == "system"));
+        let texts: Vec<_> = page.iter().rev().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "@u3 joined the group",
+                "@u1 made @u3 an admin",
+                "@u1 transferred ownership to @u2",
+                "@u3 left the group",
+                "@u2 removed @u1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn system_messages_cannot_be_forged_or_touched() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        change_role(&pool, &group.id, 1, 2, "admin").await.unwrap();
+        let announcement = group_page(&pool, &group.id, None, 1).await.unwrap()[0].id;
+
+        let err = mark_group_deleted(&pool, &group.id, announcement, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        let err = apply_group_edit(&pool, &group.id, announcement, 2, "x")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        let err =
+            insert_group_message(&pool, &group.id, 2, "fake", MessageType::System, None, None)
+                .await
+                .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        // The system user can't be merged away.
+        assert_eq!(
+            merge_accounts(&pool, 1, 0).await.unwrap_err().code(),
+            "permission_denied"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Per-user notification settings for groups.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::groups::member_role;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GroupSettings {
+    pub user_id: i64,
+    pub group_id: String,
+    pub muted: bool,
+    /// `None` while muted means muted until unmuted.
+    pub muted_until: Option<String>,
+}
+
+/// Mutes the group for `duration_secs`, or indefinitely when `None`.
+/// Muting again replaces the previous duration.
+pub(crate) async fn set_muted(
+    pool: &SqlitePool,
+    user_id: i64,
+    group_id: &str,
+    duration_secs: Option<u64>,
+) -> Result<GroupSettings, AppError> {
+    if member_role(pool, group_id, user_id).await?.is_none() {
+        return Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            user_id, group_id
+        )));
+    }
+
+    sqlx::query_as::<_, GroupSettings>(
+        "INSERT INTO group_settings (user_id, group_id, muted, muted_until)
+         VALUES (?1, ?2, 1, CASE WHEN ?3 IS NULL THEN NULL
+                                 ELSE datetime('now', '+' || ?3 || ' seconds') END)
+         ON CONFLICT (user_id, group_id) DO UPDATE SET
+             muted = 1,
+             muted_until = excluded.muted_until
+         RETURNING user_id, group_id, muted, muted_until",
+    )
+    .bind(user_id)
+    .bind(group_id)
+    .bind(duration_secs.map(|secs| secs as i64))
+    .fetch_one(pool)
+    .await
+    .context("failed to mute group")
+}
+
+pub(crate) async fn clear_muted(
+    pool: &SqlitePool,
+    user_id: i64,
+    group_id: &str,
+) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE group_settings SET muted = 0, muted_until = NULL
+         WHERE user_id = ? AND group_id = ?",
+    )
+    .bind(user_id)
+    .bind(group_id)
+    .execute(pool)
+    .await
+    .context("failed to unmute group")?;
+    Ok(())
+}
+
+/// Whether the group is muted right now; a timed mute lapses on its own once
+/// `muted_until` has passed.
+pub(crate) async fn is_muted(
+    pool: &SqlitePool,
+    user_id: i64,
+    group_id: &str,
+) -> Result<bool, AppError> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (
+             SELECT 1 FROM group_settings
+             WHERE user_id = ? AND group_id = ? AND muted = 1
+               AND (muted_until IS NULL OR muted_until > datetime('now'))
+         )",
+    )
+    .bind(user_id)
+    .bind(group_id)
+    .fetch_one(pool)
+    .await
+    .context("failed to load group settings")
+}
+
+#[tauri::command]
+pub async fn mute_group(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    group_id: String,
+    duration_secs: Option<u64>,
+) -> Result<GroupSettings, AppError> {
+    let pool = db::pool(&db).await?;
+    set_muted(&pool, user_id, &group_id, duration_secs).await
+}
+
+#[tauri::command]
+pub async fn unmute_group(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    group_id: String,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    clear_muted(&pool, user_id, &group_id).await
+}
+
+#[tauri::command]
+pub async fn is_group_muted(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    group_id: String,
+) -> Result<bool, AppError> {
+    let pool = db::pool(&db).await?;
+    is_muted(&pool, user_id, &group_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::insert_group;
+    use crate::test_support::test_pool;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn mute_and_unmute() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        assert!(!is_muted(&pool, 2, &group.id).await.unwrap());
+
+        let settings = set_muted(&pool, 2, &group.id, None).await.unwrap();
+        assert!(settings.muted);
+        assert!(settings.muted_until.is_none());
+        assert!(is_muted(&pool, 2, &group.id).await.unwrap());
+
+        clear_muted(&pool, 2, &group.id).await.unwrap();
+        assert!(!is_muted(&pool, 2, &group.id).await.unwrap());
+
+        let err = set_muted(&pool, 5, &group.id, None).await.unwrap_err();
+        assert_eq!(err.code(), "not_a_member");
+    }
+
+    #[tokio::test]
+    async fn timed_mute_expires_on_its_own() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+
+        let settings = set_muted(&pool, 2, &group.id, Some(3600)).await.unwrap();
+        assert!(settings.muted_until.is_some());
+        assert!(is_muted(&pool, 2, &group.id).await.unwrap());
+
+        // Muting again replaces the hour with a single second.
+        set_muted(&pool, 2, &group.id, Some(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert!(!is_muted(&pool, 2, &group.id).await.unwrap());
+    }
+}
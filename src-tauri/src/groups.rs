@@ -0,0 +1,871 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Emitter, State};
+use tauri_plugin_sql::DbInstances;
+use uuid::Uuid;
+
+use crate::conversations::{CONVERSATION_PREVIEW_CHARS, DELETED_PREVIEW};
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::group_messages::{handle_of, post_system_message};
+use crate::messages::page_size;
+use crate::preview::truncate_preview;
+
+pub const MAX_GROUP_NAME_CHARS: usize = 64;
+pub const GROUP_ROLES: &[&str] = &["owner", "admin", "member"];
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GroupRow {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
+    pub member_count: i64,
+    pub created_by: i64,
+    pub created_at: String,
+}
+
+/// A group in the user's group list. The `last_*` fields are `None` until
+/// the group has a message.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GroupView {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
+    pub member_count: i64,
+    /// The user's own role in the group.
+    pub role: String,
+    pub last_message_id: Option<i64>,
+    pub last_message: Option<String>,
+    pub last_message_type: Option<String>,
+    pub last_timestamp: Option<String>,
+    pub last_deleted: bool,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupIdChange {
+    pub old_id: String,
+    pub new_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationReport {
+    pub groups_changed: usize,
+    pub changes: Vec<GroupIdChange>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GroupMemberView {
+    pub id: i64,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    pub avatar_local_path: Option<String>,
+    pub role: String,
+    pub joined_at: String,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMembers {
+    /// The group's stored `member_count`.
+    pub member_count: i64,
+    pub members: Vec<GroupMemberView>,
+    /// Set when `member_count` disagrees with the members actually listed,
+    /// meaning the counter has gone stale.
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OwnershipChanged {
+    pub group_id: String,
+    pub previous_owner_id: i64,
+    pub new_owner_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RecountEntry {
+    pub group_id: String,
+    pub old_count: i64,
+    pub new_count: i64,
+}
+
+/// Tables that reference a group by id, other than `groups` itself.
+const GROUP_ID_REFERENCES: &[&str] = &[
+    "group_members",
+    "group_contacts",
+    "group_messages",
+    "group_invites",
+    "group_settings",
+];
+
+pub(crate) const GROUP_COLUMNS: &str =
+    "id, name, description, avatar_url, member_count, created_by, created_at";
+
+fn validate_group_name(name: &str) -> Result<(), AppError> {
+    let len = name.trim().chars().count();
+    if len == 0 || len > MAX_GROUP_NAME_CHARS {
+        return Err(AppError::Validation(format!(
+            "group name must be between 1 and {} characters",
+            MAX_GROUP_NAME_CHARS
+        )));
+    }
+    Ok(())
+}
+
+/// Creates a group owned by `created_by`, with everyone in `initial_members`
+/// joining as plain members.
+pub(crate) async fn insert_group(
+    pool: &SqlitePool,
+    name: &str,
+    description: Option<&str>,
+    created_by: i64,
+    initial_members: &[i64],
+) -> Result<GroupRow, AppError> {
+    validate_group_name(name)?;
+
+    let id = Uuid::new_v4().to_string();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("INSERT INTO groups (id, name, description, created_by) VALUES (?, ?, ?, ?)")
+        .bind(&id)
+        .bind(name.trim())
+        .bind(description)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await
+        .context("failed to create group")?;
+
+    sqlx::query("INSERT INTO group_members (group_id, user_id, role) VALUES (?, ?, 'owner')")
+        .bind(&id)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await
+        .context("failed to add group owner")?;
+
+    for member in initial_members.iter().filter(|&&m| m != created_by) {
+        sqlx::query(
+            "INSERT OR IGNORE INTO group_members (group_id, user_id, role) VALUES (?, ?, 'member')",
+        )
+        .bind(&id)
+        .bind(member)
+        .execute(&mut *tx)
+        .await
+        .context(&format!("failed to add group member {}", member))?;
+    }
+
+    let group = sqlx::query_as::<_, GroupRow>(&format!(
+        "UPDATE groups
+         SET member_count = (SELECT COUNT(*) FROM group_members WHERE group_id = ?1)
+         WHERE id = ?1
+         RETURNING {}",
+        GROUP_COLUMNS
+    ))
+    .bind(&id)
+    .fetch_one(&mut *tx)
+    .await
+    .context("failed to update member count")?;
+
+    tx.commit().await?;
+    Ok(group)
+}
+
+pub(crate) async fn member_role<'e, E>(
+    executor: E,
+    group_id: &str,
+    user_id: i64,
+) -> Result<Option<String>, sqlx::Error>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    sqlx::query_scalar("SELECT role FROM group_members WHERE group_id = ? AND user_id = ?")
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(executor)
+        .await
+}
+
+async fn owner_count<'e, E>(executor: E, group_id: &str) -> Result<i64, sqlx::Error>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    sqlx::query_scalar("SELECT COUNT(*) FROM group_members WHERE group_id = ? AND role = 'owner'")
+        .bind(group_id)
+        .fetch_one(executor)
+        .await
+}
+
+/// Owners may change anyone's role; admins may only shuffle non-owners
+/// between `admin` and `member`. The last remaining owner can't be demoted.
+pub(crate) async fn change_role(
+    pool: &SqlitePool,
+    group_id: &str,
+    actor_id: i64,
+    target_id: i64,
+    role: &str,
+) -> Result<(), AppError> {
+    if !GROUP_ROLES.contains(&role) {
+        return Err(AppError::Validation(format!(
+            "invalid role '{}', expected one of: {}",
+            role,
+            GROUP_ROLES.join(", ")
+        )));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let actor_role = member_role(&mut *tx, group_id, actor_id)
+        .await?
+        .ok_or_else(|| AppError::PermissionDenied("you are not a member of this group".into()))?;
+    let target_role = member_role(&mut *tx, group_id, target_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {} is not in this group", target_id)))?;
+
+    match actor_role.as_str() {
+        "owner" => {}
+        "admin" if target_role != "owner" && role != "owner" => {}
+        _ => {
+            return Err(AppError::PermissionDenied(
+                "you are not allowed to change this member's role".into(),
+            ))
+        }
+    }
+
+    if target_role == "owner" && role != "owner" && owner_count(&mut *tx, group_id).await? <= 1 {
+        return Err(AppError::PermissionDenied(
+            "cannot demote the group's only owner".into(),
+        ));
+    }
+
+    sqlx::query("UPDATE group_members SET role = ? WHERE group_id = ? AND user_id = ?")
+        .bind(role)
+        .bind(group_id)
+        .bind(target_id)
+        .execute(&mut *tx)
+        .await?;
+
+    if role != target_role {
+        let text = format!(
+            "{} made {} {}",
+            handle_of(&mut tx, actor_id).await?,
+            handle_of(&mut tx, target_id).await?,
+            match role {
+                "owner" => "an owner",
+                "admin" => "an admin",
+                _ => "a member",
+            }
+        );
+        post_system_message(&mut tx, group_id, &text).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Hands ownership from `current_owner_id` to `new_owner_id`, who must
+/// already be a member. The previous owner stays on as an admin.
+pub(crate) async fn change_ownership(
+    pool: &SqlitePool,
+    group_id: &str,
+    current_owner_id: i64,
+    new_owner_id: i64,
+) -> Result<(), AppError> {
+    if current_owner_id == new_owner_id {
+        return Err(AppError::Validation("you already own this group".into()));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    if member_role(&mut *tx, group_id, current_owner_id)
+        .await?
+        .as_deref()
+        != Some("owner")
+    {
+        return Err(AppError::PermissionDenied(
+            "only the group's owner can transfer ownership".into(),
+        ));
+    }
+    if member_role(&mut *tx, group_id, new_owner_id)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            new_owner_id, group_id
+        )));
+    }
+
+    for (user_id, role) in [(new_owner_id, "owner"), (current_owner_id, "admin")] {
+        sqlx::query("UPDATE group_members SET role = ? WHERE group_id = ? AND user_id = ?")
+            .bind(role)
+            .bind(group_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context("failed to transfer ownership")?;
+    }
+
+    let text = format!(
+        "{} transferred ownership to {}",
+        handle_of(&mut tx, current_owner_id).await?,
+        handle_of(&mut tx, new_owner_id).await?
+    );
+    post_system_message(&mut tx, group_id, &text).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Removes `target_id` from the group. Members may always remove themselves;
+/// removing someone else takes an owner, or an admin when the target isn't an
+/// owner. The last remaining owner can't leave.
+pub(crate) async fn delete_member(
+    pool: &SqlitePool,
+    group_id: &str,
+    actor_id: i64,
+    target_id: i64,
+) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+
+    let actor_role = member_role(&mut *tx, group_id, actor_id)
+        .await?
+        .ok_or_else(|| AppError::PermissionDenied("you are not a member of this group".into()))?;
+    let target_role = member_role(&mut *tx, group_id, target_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {} is not in this group", target_id)))?;
+
+    let allowed = actor_id == target_id
+        || actor_role == "owner"
+        || (actor_role == "admin" && target_role != "owner");
+    if !allowed {
+        return Err(AppError::PermissionDenied(
+            "you are not allowed to remove this member".into(),
+        ));
+    }
+
+    if target_role == "owner" && owner_count(&mut *tx, group_id).await? <= 1 {
+        return Err(AppError::PermissionDenied(
+            "the group's only owner cannot be removed".into(),
+        ));
+    }
+
+    sqlx::query("DELETE FROM group_members WHERE group_id = ? AND user_id = ?")
+        .bind(group_id)
+        .bind(target_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("UPDATE groups SET member_count = MAX(member_count - 1, 0) WHERE id = ?")
+        .bind(group_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let text = if actor_id == target_id {
+        format!("{} left the group", handle_of(&mut tx, target_id).await?)
+    } else {
+        format!(
+            "{} removed {}",
+            handle_of(&mut tx, actor_id).await?,
+            handle_of(&mut tx, target_id).await?
+        )
+    };
+    post_system_message(&mut tx, group_id, &text).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Everyone in the group, owners first, then admins, then members, each
+/// tier by username.
+pub(crate) async fn members_of(
+    pool: &SqlitePool,
+    group_id: &str,
+) -> Result<GroupMembers, AppError> {
+    let member_count: i64 = sqlx::query_scalar("SELECT member_count FROM groups WHERE id = ?")
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("group {} not found", group_id)))?;
+
+    let members = sqlx::query_as::<_, GroupMemberView>(
+        "SELECT u.id, u.username, u.avatar_url, u.avatar_local_path,
+                COALESCE(gm.role, 'member') AS role, gm.joined_at,
+                CASE WHEN u.invisible THEN 'offline' ELSE COALESCE(u.status, 'offline') END AS status
+         FROM group_members gm
+         JOIN users u ON u.id = gm.user_id
+         WHERE gm.group_id = ?
+         ORDER BY CASE gm.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END,
+                  u.username COLLATE NOCASE, u.id",
+    )
+    .bind(group_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load group members")?;
+
+    let warning = (member_count != members.len() as i64).then(|| {
+        format!(
+            "member_count is {} but the group has {} members",
+            member_count,
+            members.len()
+        )
+    });
+
+    Ok(GroupMembers {
+        member_count,
+        members,
+        warning,
+    })
+}
+
+/// A page of the groups `user_id` belongs to, most recently active first and
+/// groups without messages last. A message counts as unread until the user
+/// has a `read` delivery status for it.
+pub(crate) async fn groups_of(
+    pool: &SqlitePool,
+    user_id: i64,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<GroupView>, AppError> {
+    let mut groups = sqlx::query_as::<_, GroupView>(
+        "WITH ranked AS (
+             SELECT m.*,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY m.group_id ORDER BY m.timestamp DESC, m.id DESC
+                    ) AS rn
+             FROM group_messages m
+             JOIN group_members mem ON mem.group_id = m.group_id AND mem.user_id = ?1
+             WHERE m.expires_at IS NULL OR m.expires_at > CURRENT_TIMESTAMP
+         )
+         SELECT g.id, g.name, g.description, g.avatar_url, g.member_count,
+                COALESCE(gm.role, 'member') AS role,
+                r.id AS last_message_id,
+                CASE WHEN r.deleted_at IS NULL THEN r.content ELSE ?2 END AS last_message,
+                r.message_type AS last_message_type,
+                r.timestamp AS last_timestamp,
+                COALESCE(r.deleted_at IS NOT NULL, FALSE) AS last_deleted,
+                (SELECT COUNT(*) FROM group_messages u
+                 WHERE u.group_id = g.id AND u.sender_id != ?1 AND u.deleted_at IS NULL
+                   AND (u.expires_at IS NULL OR u.expires_at > CURRENT_TIMESTAMP)
+                   AND NOT EXISTS (
+                       SELECT 1 FROM message_delivery d
+                       WHERE d.message_id = u.id AND d.message_scope = 'group'
+                         AND d.user_id = ?1 AND d.status = 'read'
+                   )) AS unread_count
+         FROM group_members gm
+         JOIN groups g ON g.id = gm.group_id
+         LEFT JOIN ranked r ON r.group_id = g.id AND r.rn = 1
+         WHERE gm.user_id = ?1
+         ORDER BY r.id IS NULL, r.timestamp DESC, r.id DESC, gm.joined_at DESC, g.id
+         LIMIT ?3 OFFSET ?4",
+    )
+    .bind(user_id)
+    .bind(DELETED_PREVIEW)
+    .bind(page_size(limit))
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+    .context("failed to list groups")?;
+
+    // Group messages are never sealed, so previews can be cut here rather
+    // than after decrypting as direct conversations are.
+    for group in groups.iter_mut() {
+        if let Some(last_message) = group.last_message.as_mut() {
+            *last_message = truncate_preview(last_message, CONVERSATION_PREVIEW_CHARS);
+        }
+    }
+    Ok(groups)
+}
+
+/// Recomputes `member_count` from `group_members` for every group, touching
+/// only the groups whose counter was wrong. Running it again is a no-op.
+pub(crate) async fn recount_members(pool: &SqlitePool) -> Result<Vec<RecountEntry>, AppError> {
+    let mut tx = pool.begin().await?;
+
+    let drifted = sqlx::query_as::<_, RecountEntry>(
+        "SELECT g.id AS group_id, g.member_count AS old_count, COUNT(gm.user_id) AS new_count
+         FROM groups g
+         LEFT JOIN group_members gm ON gm.group_id = g.id
+         GROUP BY g.id
+         HAVING g.member_count IS NOT COUNT(gm.user_id)
+         ORDER BY g.id",
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .context("failed to count group members")?;
+
+    for entry in &drifted {
+        sqlx::query("UPDATE groups SET member_count = ? WHERE id = ?")
+            .bind(entry.new_count)
+            .bind(&entry.group_id)
+            .execute(&mut *tx)
+            .await
+            .context("failed to update member count")?;
+    }
+
+    tx.commit().await?;
+    Ok(drifted)
+}
+
+#[tauri::command]
+pub async fn create_group(
+    db: State<'_, DbInstances>,
+    name: String,
+    description: Option<String>,
+    created_by: i64,
+    initial_members: Vec<i64>,
+) -> Result<GroupRow, AppError> {
+    let pool = db::pool(&db).await?;
+    insert_group(
+        &pool,
+        &name,
+        description.as_deref(),
+        created_by,
+        &initial_members,
+    )
+    .await
+}
+
+/// Gives every group whose id isn't a UUID a fresh one, rewriting all
+/// references in the same transaction. Groups that already have UUIDs are
+/// left alone, so a second run reports no changes.
+pub(crate) async fn migrate_group_ids(pool: &SqlitePool) -> Result<MigrationReport, AppError> {
+    let mut tx = pool.begin().await?;
+    // References are rewritten after the parent row, so check at commit.
+    sqlx::query("PRAGMA defer_foreign_keys = ON")
+        .execute(&mut *tx)
+        .await?;
+
+    let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM groups ORDER BY created_at, id")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut changes = Vec::new();
+    for old_id in ids.into_iter().filter(|id| Uuid::parse_str(id).is_err()) {
+        let new_id = Uuid::new_v4().to_string();
+
+        sqlx::query("UPDATE groups SET id = ? WHERE id = ?")
+            .bind(&new_id)
+            .bind(&old_id)
+            .execute(&mut *tx)
+            .await
+            .context(&format!("failed to rename group {}", old_id))?;
+        for table in GROUP_ID_REFERENCES {
+            sqlx::query(&format!(
+                "UPDATE {} SET group_id = ? WHERE group_id = ?",
+                table
+            ))
+            .bind(&new_id)
+            .bind(&old_id)
+            .execute(&mut *tx)
+            .await
+            .context(&format!("failed to update {}", table))?;
+        }
+        for table in ["drafts", "conversation_ttls", "archived_conversations"] {
+            sqlx::query(&format!(
+                "UPDATE {} SET peer_id = ? WHERE peer_type = 'group' AND peer_id = ?",
+                table
+            ))
+            .bind(&new_id)
+            .bind(&old_id)
+            .execute(&mut *tx)
+            .await
+            .context(&format!("failed to update {}", table))?;
+        }
+
+        changes.push(GroupIdChange { old_id, new_id });
+    }
+
+    tx.commit().await?;
+    Ok(MigrationReport {
+        groups_changed: changes.len(),
+        changes,
+    })
+}
+
+#[tauri::command]
+pub async fn set_member_role(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    actor_id: i64,
+    target_id: i64,
+    role: String,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    change_role(&pool, &group_id, actor_id, target_id, &role).await
+}
+
+#[tauri::command]
+pub async fn transfer_ownership(
+    app: AppHandle,
+    db: State<'_, DbInstances>,
+    group_id: String,
+    current_owner_id: i64,
+    new_owner_id: i64,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    change_ownership(&pool, &group_id, current_owner_id, new_owner_id).await?;
+    let _ = app.emit(
+        "group-ownership-changed",
+        OwnershipChanged {
+            group_id,
+            previous_owner_id: current_owner_id,
+            new_owner_id,
+        },
+    );
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn remove_member(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    actor_id: i64,
+    target_id: i64,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    delete_member(&pool, &group_id, actor_id, target_id).await
+}
+
+#[tauri::command]
+pub async fn list_group_members(
+    db: State<'_, DbInstances>,
+    group_id: String,
+) -> Result<GroupMembers, AppError> {
+    let pool = db::pool(&db).await?;
+    members_of(&pool, &group_id).await
+}
+
+#[tauri::command]
+pub async fn list_user_groups(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<GroupView>, AppError> {
+    let pool = db::pool(&db).await?;
+    groups_of(&pool, user_id, limit, offset).await
+}
+
+#[tauri::command]
+pub async fn recount_group_members(
+    db: State<'_, DbInstances>,
+) -> Result<Vec<RecountEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    recount_members(&pool).await
+}
+
+/// One-off repair for groups created before ids were UUIDs.
+#[tauri::command]
+pub async fn normalize_group_ids(db: State<'_, DbInstances>) -> Result<MigrationReport, AppError> {
+    let pool = db::pool(&db).await?;
+    migrate_group_ids(&pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::archive;
+    use crate::delivery;
+    use crate::group_messages::insert_group_message;
+    use crate::group_settings::{is_muted, set_muted};
+    use crate::message_type::MessageType;
+    use crate::test_support::{count, test_pool};
+
+    #[tokio::test]
+    async fn member_count_matches_membership_after_creation() {
+        let pool = test_pool().await;
+        // The creator and repeats in the list are only counted once.
+        let group = insert_group(&pool, "g", None, 1, &[2, 3, 3, 1])
+            .await
+            .unwrap();
+        assert_eq!(group.member_count, 3);
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM group_members").await, 3);
+
+        assert!(insert_group(&pool, "", None, 1, &[]).await.is_err());
+        assert!(
+            insert_group(&pool, &"x".repeat(MAX_GROUP_NAME_CHARS + 1), None, 1, &[])
+                .await
+                .is_err()
+        );
+        assert!(insert_group(&pool, "g2", None, 1, &[99]).await.is_err());
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM groups").await, 1);
+    }
+
+    #[tokio::test]
+    async fn the_last_owner_cannot_step_down_or_leave() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2, 3]).await.unwrap();
+
+        let denied = |r: Result<(), AppError>| r.unwrap_err().code() == "permission_denied";
+        assert!(denied(change_role(&pool, &group.id, 2, 3, "admin").await));
+        assert!(denied(change_role(&pool, &group.id, 1, 1, "member").await));
+        assert!(denied(delete_member(&pool, &group.id, 1, 1).await));
+        let err = change_role(&pool, &group.id, 1, 2, "boss")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        change_role(&pool, &group.id, 1, 2, "admin").await.unwrap();
+        change_role(&pool, &group.id, 2, 3, "admin").await.unwrap();
+        assert!(denied(change_role(&pool, &group.id, 2, 1, "member").await));
+
+        // With a second owner the first one may step down.
+        change_role(&pool, &group.id, 1, 2, "owner").await.unwrap();
+        change_role(&pool, &group.id, 1, 1, "member").await.unwrap();
+        assert!(denied(delete_member(&pool, &group.id, 2, 2).await));
+
+        delete_member(&pool, &group.id, 2, 3).await.unwrap();
+        assert_eq!(count(&pool, "SELECT member_count FROM groups").await, 2);
+    }
+
+    #[tokio::test]
+    async fn migrating_ids_keeps_a_legacy_groups_mute() {
+        let pool = test_pool().await;
+        let current = insert_group(&pool, "ok", None, 1, &[2]).await.unwrap();
+        sqlx::query(
+            "INSERT INTO groups (id, name, created_by, member_count) VALUES ('legacy-1', 'old', 1, 2)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO group_members (group_id, user_id, role)
+             VALUES ('legacy-1', 1, 'owner'), ('legacy-1', 2, 'member')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        set_muted(&pool, 2, "legacy-1", None).await.unwrap();
+        archive(&pool, 1, "legacy-1", "group").await.unwrap();
+
+        let report = migrate_group_ids(&pool).await.unwrap();
+        assert_eq!(report.groups_changed, 1);
+        assert_eq!(report.changes[0].old_id, "legacy-1");
+        let new_id = &report.changes[0].new_id;
+        assert!(is_muted(&pool, 2, new_id).await.unwrap());
+        let stale = "SELECT COUNT(*) FROM group_settings WHERE group_id = 'legacy-1'";
+        assert_eq!(count(&pool, stale).await, 0);
+        let archived = format!(
+            "SELECT COUNT(*) FROM archived_conversations WHERE peer_type = 'group' AND peer_id = '{}'",
+            new_id
+        );
+        assert_eq!(count(&pool, &archived).await, 1);
+        assert_eq!(
+            member_role(&pool, &current.id, 2).await.unwrap().as_deref(),
+            Some("member")
+        );
+
+        assert_eq!(migrate_group_ids(&pool).await.unwrap().groups_changed, 0);
+    }
+
+    #[tokio::test]
+    async fn recount_fixes_only_stale_counters() {
+        let pool = test_pool().await;
+        let stale = insert_group(&pool, "a", None, 1, &[2]).await.unwrap();
+        let fresh = insert_group(&pool, "b", None, 1, &[2, 3]).await.unwrap();
+        sqlx::query("UPDATE groups SET member_count = 7 WHERE id = ?")
+            .bind(&stale.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let fixed = recount_members(&pool).await.unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fixed[0].group_id, stale.id);
+        assert_eq!((fixed[0].old_count, fixed[0].new_count), (7, 2));
+
+        let counts: Vec<(String, i64)> =
+            sqlx::query_as("SELECT id, member_count FROM groups ORDER BY name")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(counts, [(stale.id, 2), (fresh.id, 3)]);
+        assert!(recount_members(&pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn transferring_ownership_demotes_the_old_owner() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2, 3]).await.unwrap();
+        let err = change_ownership(&pool, &group.id, 1, 9).await.unwrap_err();
+        assert_eq!(err.code(), "not_a_member");
+        let err = change_ownership(&pool, &group.id, 2, 3).await.unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+
+        change_ownership(&pool, &group.id, 1, 2).await.unwrap();
+        assert_eq!(
+            member_role(&pool, &group.id, 1).await.unwrap().as_deref(),
+            Some("admin")
+        );
+        assert_eq!(
+            member_role(&pool, &group.id, 2).await.unwrap().as_deref(),
+            Some("owner")
+        );
+        let owners = "SELECT COUNT(*) FROM group_members WHERE role = 'owner'";
+        assert_eq!(count(&pool, owners).await, 1);
+
+        let err = change_ownership(&pool, &group.id, 1, 3).await.unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+    }
+
+    #[tokio::test]
+    async fn active_groups_sort_first_with_short_previews() {
+        let pool = test_pool().await;
+        let quiet = insert_group(&pool, "quiet", None, 1, &[2]).await.unwrap();
+        let busy = insert_group(&pool, "busy", None, 2, &[1, 3]).await.unwrap();
+        insert_group(&pool, "other", None, 3, &[]).await.unwrap();
+        let send = |sender_id, content: String| {
+            let pool = pool.clone();
+            let group_id = busy.id.clone();
+            async move {
+                insert_group_message(
+                    &pool,
+                    &group_id,
+                    sender_id,
+                    &content,
+                    MessageType::Text,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap()
+                .0
+            }
+        };
+        let first = send(2, "hello".into()).await;
+        let long = "word ".repeat(100);
+        let last = send(3, long.clone()).await;
+        send(1, "mine".into()).await;
+        sqlx::query("UPDATE group_messages SET timestamp = datetime('now', '-1 hour') WHERE content = 'mine'")
+        .execute(&pool)
+        .await
+        .unwrap();
+        delivery::set_status(&pool, first.id, "group", 1, "read")
+            .await
+            .unwrap();
+
+        let groups = groups_of(&pool, 1, 0, 0).await.unwrap();
+        let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["busy", "quiet"]);
+        assert_eq!(groups[0].role, "member");
+        assert_eq!(groups[0].member_count, 3);
+        assert_eq!(groups[0].last_message_id, Some(last.id));
+        assert_eq!(
+            groups[0].last_message.as_deref(),
+            Some(truncate_preview(&long, CONVERSATION_PREVIEW_CHARS).as_str())
+        );
+        assert!(groups[0].last_message.as_ref().unwrap().ends_with('…'));
+        assert_eq!(groups[0].unread_count, 1);
+        assert_eq!(groups[1].role, "owner");
+        assert_eq!(groups[1].last_message, None);
+        assert_eq!(groups[1].last_timestamp, None);
+        assert!(!groups[1].last_deleted);
+        assert_eq!(groups[1].unread_count, 0);
+
+        let second_page = groups_of(&pool, 1, 1, 1).await.unwrap();
+        assert_eq!(second_page[0].id, quiet.id);
+        assert!(groups_of(&pool, 1, 5, 2).await.unwrap().is_empty());
+        assert!(groups_of(&pool, 9, 0, 0).await.unwrap().is_empty());
+    }
+}
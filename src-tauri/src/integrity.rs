@@ -0,0 +1,184 @@
+//! Content checksums for direct messages. `content_hash` is the SHA-256 of
+//! the stored content (ciphertext for sealed messages), written whenever the
+//! content is, so a later mismatch means the row was changed behind the
+//! app's back or damaged in transit.
+//!
+//! Rows from before the column existed are hashed by `backfill_hashes` at
+//! startup; SQLite has no SHA-256 of its own to do it in the migration.
+
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::conversations::parse_conversation_id;
+use crate::db;
+use crate::error::{AppError, Context};
+
+/// Rows hashed per transaction while backfilling.
+pub const BACKFILL_BATCH_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityIssue {
+    pub message_id: i64,
+    /// `None` when the row was never hashed.
+    pub stored_hash: Option<String>,
+    pub computed_hash: String,
+}
+
+/// Lowercase hex SHA-256 of `content`.
+pub fn content_hash(content: &str) -> String {
+    digest(&SHA256, content.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Hashes every message that has no `content_hash` yet. Returns how many
+/// rows were filled in.
+pub(crate) async fn backfill_hashes(pool: &SqlitePool) -> Result<u64, AppError> {
+    let mut filled = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT id, content FROM messages WHERE content_hash IS NULL ORDER BY id LIMIT ?",
+        )
+        .bind(BACKFILL_BATCH_SIZE)
+        .fetch_all(&mut *tx)
+        .await
+        .context("failed to load unhashed messages")?;
+        if rows.is_empty() {
+            return Ok(filled);
+        }
+
+        for (id, content) in &rows {
+            sqlx::query("UPDATE messages SET content_hash = ? WHERE id = ?")
+                .bind(content_hash(content))
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .context("failed to store content hash")?;
+        }
+        tx.commit().await?;
+        filled += rows.len() as u64;
+    }
+}
+
+/// Recomputes the hash of every message between the two users of
+/// `conversation_id` (`a:b`) and reports the rows that don't match.
+pub(crate) async fn verify(
+    pool: &SqlitePool,
+    conversation_id: &str,
+) -> Result<Vec<IntegrityIssue>, AppError> {
+    let (a, b) = parse_conversation_id(conversation_id)?;
+
+    let rows: Vec<(i64, String, Option<String>)> = sqlx::query_as(
+        "SELECT id, content, content_hash FROM messages
+         WHERE (sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1)
+         ORDER BY id",
+    )
+    .bind(a)
+    .bind(b)
+    .fetch_all(pool)
+    .await
+    .context("failed to load conversation")?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(message_id, content, stored_hash)| {
+            let computed_hash = content_hash(&content);
+            (stored_hash.as_deref() != Some(computed_hash.as_str())).then_some(IntegrityIssue {
+                message_id,
+                stored_hash,
+                computed_hash,
+            })
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub async fn verify_message_integrity(
+    db: State<'_, DbInstances>,
+    conversation_id: String,
+) -> Result<Vec<IntegrityIssue>, AppError> {
+    let pool = db::pool(&db).await?;
+    verify(&pool, &conversation_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::message_type::MessageType;
+    use crate::messages::{apply_edit, insert_message};
+    use crate::test_support::test_pool;
+
+    async fn send(pool: &SqlitePool, sender_id: i64, receiver_id: i64, content: &str) -> i64 {
+        let encryption = EncryptionState::default();
+        insert_message(
+            pool,
+            &encryption,
+            sender_id,
+            receiver_id,
+            content,
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[test]
+    fn content_hash_is_hex_sha256() {
+        assert_eq!(
+            content_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_flags_content_changed_behind_the_apps_back() {
+        let pool = test_pool().await;
+        let tampered = send(&pool, 1, 2, "hello").await;
+        let edited = send(&pool, 2, 1, "there").await;
+        assert!(verify(&pool, "1:2").await.unwrap().is_empty());
+
+        // Edits through the app keep the hash current.
+        apply_edit(&pool, &EncryptionState::default(), edited, 2, "there!")
+            .await
+            .unwrap();
+        assert!(verify(&pool, "1:2").await.unwrap().is_empty());
+
+        sqlx::query("UPDATE messages SET content = 'tampered' WHERE id = ?")
+            .bind(tampered)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let issues = verify(&pool, "1:2").await.unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].message_id, tampered);
+        assert_eq!(issues[0].computed_hash, content_hash("tampered"));
+        assert_eq!(issues[0].stored_hash, Some(content_hash("hello")));
+        assert_eq!(verify(&pool, "x").await.unwrap_err().code(), "validation");
+    }
+
+    #[tokio::test]
+    async fn backfill_hashes_rows_written_before_hashing() {
+        let pool = test_pool().await;
+        send(&pool, 1, 2, "hello").await;
+        send(&pool, 2, 1, "there").await;
+        send(&pool, 1, 3, "other").await;
+        sqlx::query("UPDATE messages SET content_hash = NULL")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(verify(&pool, "1:3").await.unwrap().len(), 1);
+
+        assert_eq!(backfill_hashes(&pool).await.unwrap(), 3);
+        assert_eq!(backfill_hashes(&pool).await.unwrap(), 0);
+        assert!(verify(&pool, "1:2").await.unwrap().is_empty());
+    }
+}
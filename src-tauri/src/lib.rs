@@ -1,4 +1,57 @@
-use tauri_plugin_sql::{Migration, MigrationKind};
+mod attachments;
+mod auth;
+mod avatars;
+mod backup;
+mod blocks;
+mod contacts;
+mod conversation_keys;
+mod conversations;
+mod db;
+mod delivery;
+mod disappearing;
+mod drafts;
+mod encryption;
+mod error;
+mod export;
+mod feed;
+mod group_invites;
+mod group_messages;
+mod group_settings;
+mod groups;
+mod integrity;
+mod link_preview;
+mod logging;
+mod maintenance;
+mod mentions;
+mod message_links;
+mod message_type;
+mod messages;
+mod migrations;
+mod notify;
+mod outbox;
+mod polling;
+mod preferences;
+mod presence;
+mod preview;
+mod protocol;
+mod rate_limit;
+mod reactions;
+mod retention;
+mod scheduled;
+mod search;
+mod shortcode;
+mod shutdown;
+mod stars;
+#[cfg(test)]
+mod test_support;
+mod typing;
+mod users;
+mod ws_manager;
+
+use std::sync::Mutex;
+
+use tauri::{Manager, RunEvent};
+use tauri_plugin_sql::DbInstances;
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
@@ -8,111 +61,188 @@ fn greet(name: &str) -> String {
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let migrations = vec![
-        Migration {
-            version: 1,
-            description: "create_initial_tables",
-            sql: "
-                CREATE TABLE IF NOT EXISTS users (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    username TEXT NOT NULL UNIQUE,
-                    avatar_url TEXT,
-                    status TEXT DEFAULT 'offline',
-                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
-                );
-                
-                CREATE TABLE IF NOT EXISTS messages (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    sender_id INTEGER NOT NULL,
-                    receiver_id INTEGER NOT NULL,
-                    content TEXT NOT NULL,
-                    message_type TEXT DEFAULT 'text',
-                    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    is_read BOOLEAN DEFAULT FALSE,
-                    FOREIGN KEY (sender_id) REFERENCES users (id),
-                    FOREIGN KEY (receiver_id) REFERENCES users (id)
-                );
-                
-                CREATE TABLE IF NOT EXISTS contacts (
-                    user_id INTEGER NOT NULL,
-                    contact_id INTEGER NOT NULL,
-                    added_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    PRIMARY KEY (user_id, contact_id),
-                    FOREIGN KEY (user_id) REFERENCES users (id),
-                    FOREIGN KEY (contact_id) REFERENCES users (id)
-                );
-                
-                CREATE TABLE IF NOT EXISTS auth_tokens (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    user_id INTEGER NOT NULL,
-                    access_token TEXT NOT NULL,
-                    refresh_token TEXT,
-                    expires_at DATETIME NOT NULL,
-                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    FOREIGN KEY (user_id) REFERENCES users (id)
-                );
-            ",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 2,
-            description: "create_group_tables",
-            sql: "
-                CREATE TABLE IF NOT EXISTS groups (
-                    id TEXT PRIMARY KEY,
-                    name TEXT NOT NULL,
-                    description TEXT,
-                    avatar_url TEXT,
-                    member_count INTEGER DEFAULT 0,
-                    created_by INTEGER NOT NULL,
-                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    FOREIGN KEY (created_by) REFERENCES users (id)
-                );
-                
-                CREATE TABLE IF NOT EXISTS group_members (
-                    group_id TEXT NOT NULL,
-                    user_id INTEGER NOT NULL,
-                    role TEXT DEFAULT 'member',
-                    joined_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    PRIMARY KEY (group_id, user_id),
-                    FOREIGN KEY (group_id) REFERENCES groups (id),
-                    FOREIGN KEY (user_id) REFERENCES users (id)
-                );
-                
-                CREATE TABLE IF NOT EXISTS group_contacts (
-                    user_id INTEGER NOT NULL,
-                    group_id TEXT NOT NULL,
-                    joined_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    PRIMARY KEY (user_id, group_id),
-                    FOREIGN KEY (user_id) REFERENCES users (id),
-                    FOREIGN KEY (group_id) REFERENCES groups (id)
-                );
-                
-                CREATE TABLE IF NOT EXISTS group_messages (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    group_id TEXT NOT NULL,
-                    sender_id INTEGER NOT NULL,
-                    content TEXT NOT NULL,
-                    message_type TEXT DEFAULT 'text',
-                    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    FOREIGN KEY (group_id) REFERENCES groups (id),
-                    FOREIGN KEY (sender_id) REFERENCES users (id)
-                );
-            ",
-            kind: MigrationKind::Up,
-        }
-    ];
+    let logs = logging::init();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(
             tauri_plugin_sql::Builder::default()
-                .add_migrations("sqlite:cereals.db", migrations)
+                .add_migrations(db::DB_URL, migrations::migrations())
                 .build(),
         )
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_websocket::init())
-        .invoke_handler(tauri::generate_handler![greet])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .manage(Mutex::new(ws_manager::WsState::default()))
+        .manage(typing::TypingState::default())
+        .manage(reactions::ReactionDebounce::default())
+        .manage(notify::NotificationSettings::default())
+        .manage(attachments::AttachmentSettings::default())
+        .manage(rate_limit::RateLimitSettings::default())
+        .manage(rate_limit::RateLimitState::default())
+        .manage(rate_limit::BroadcastRateLimitState::default())
+        .manage(encryption::EncryptionState::default())
+        .manage(shutdown::ShutdownState::default())
+        .manage(logs)
+        .setup(|app| {
+            // The plugin has already preloaded and migrated the database.
+            let instances = app.state::<DbInstances>();
+            if let Err(e) = tauri::async_runtime::block_on(db::configure(
+                &instances,
+                db::DEFAULT_BUSY_TIMEOUT_MS,
+            )) {
+                tracing::error!("failed to configure database: {}", e);
+            }
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let pool = db::pool(&handle.state::<DbInstances>()).await;
+                if let Err(e) = async { integrity::backfill_hashes(&pool?).await }.await {
+                    tracing::error!("failed to hash existing messages: {}", e);
+                }
+            });
+            scheduled::spawn_scheduler(app.handle().clone());
+            auth::spawn_token_pruner(app.handle().clone());
+            disappearing::spawn_sweeper(app.handle().clone());
+            polling::spawn_poller(app.handle().clone());
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            messages::send_message,
+            messages::broadcast_to_contacts,
+            messages::fetch_conversation,
+            messages::fetch_around,
+            messages::edit_message,
+            messages::soft_delete_message,
+            messages::mark_conversation_read,
+            messages::unread_counts,
+            messages::fetch_thread,
+            search::search_messages,
+            ws_manager::ws_connect,
+            ws_manager::ws_disconnect,
+            polling::poll_new_messages,
+            typing::send_typing,
+            auth::get_valid_token,
+            auth::store_tokens,
+            contacts::list_contacts,
+            contacts::add_contact,
+            contacts::remove_contact,
+            groups::create_group,
+            groups::set_member_role,
+            groups::remove_member,
+            group_messages::send_group_message,
+            group_messages::fetch_group_messages,
+            delivery::update_delivery_status,
+            delivery::delivery_status_for,
+            export::export_conversation,
+            export::import_conversation,
+            notify::set_notifications_enabled,
+            maintenance::db_health,
+            maintenance::schema_status,
+            presence::set_user_status,
+            presence::get_statuses,
+            reactions::add_reaction,
+            reactions::remove_reaction,
+            reactions::reactions_for,
+            attachments::attach_file,
+            attachments::attach_voice,
+            attachments::attachments_for,
+            attachments::set_max_attachment_bytes,
+            blocks::block_user,
+            blocks::unblock_user,
+            blocks::list_blocked,
+            drafts::save_draft,
+            drafts::get_draft,
+            drafts::clear_draft,
+            group_messages::pin_group_message,
+            group_messages::unpin_group_message,
+            group_messages::list_pinned,
+            users::update_profile,
+            users::get_users,
+            users::user_stats,
+            scheduled::schedule_message,
+            scheduled::cancel_scheduled,
+            conversations::list_conversations,
+            conversations::conversation_id,
+            conversations::parse_conversation_id,
+            disappearing::set_conversation_ttl,
+            maintenance::run_maintenance,
+            backup::export_backup,
+            backup::import_backup,
+            rate_limit::set_rate_limit,
+            encryption::enable_encryption,
+            users::get_or_create_user,
+            group_invites::create_group_invite,
+            group_invites::redeem_invite,
+            stars::star_message,
+            stars::unstar_message,
+            stars::list_starred,
+            presence::get_last_seen,
+            presence::set_hide_last_seen,
+            presence::set_invisible,
+            groups::normalize_group_ids,
+            users::search_users,
+            db::set_busy_timeout,
+            messages::forward_message,
+            mentions::list_mentions,
+            mentions::mark_mentions_read,
+            shutdown::shutdown,
+            group_settings::mute_group,
+            group_settings::unmute_group,
+            group_settings::is_group_muted,
+            feed::activity_feed,
+            retention::apply_retention,
+            avatars::cache_avatar,
+            ws_manager::ws_last_latency_ms,
+            group_messages::edit_group_message,
+            group_messages::delete_group_message,
+            contacts::dedupe_contacts,
+            messages::validate_message_length,
+            groups::list_group_members,
+            groups::list_user_groups,
+            auth::bootstrap_account,
+            groups::recount_group_members,
+            logging::get_recent_logs,
+            groups::transfer_ownership,
+            messages::fetch_conversation_page,
+            users::merge_users,
+            conversation_keys::publish_public_key,
+            conversation_keys::get_public_key,
+            conversation_keys::store_wrapped_key,
+            conversation_keys::get_wrapped_key,
+            notify::total_unread,
+            conversations::archive_conversation,
+            conversations::unarchive_conversation,
+            maintenance::check_foreign_keys,
+            ws_manager::ws_enqueue,
+            ws_manager::outbox_pending_count,
+            messages::mark_all_read,
+            conversations::storage_breakdown,
+            preferences::set_preference,
+            preferences::get_preferences,
+            search::search_in_conversation,
+            search::search_in_group,
+            message_links::create_message_link,
+            message_links::resolve_message_link,
+            integrity::verify_message_integrity,
+            auth::prune_expired_tokens,
+            link_preview::fetch_link_preview,
+            export::export_group_csv,
+            contacts::import_contacts,
+            conversations::conversation_insights,
+            users::erase_user_data,
+            auth::list_sessions,
+            auth::revoke_session,
+            auth::revoke_all_other_sessions,
+            attachments::recompress_attachments,
+            users::suggest_usernames
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let RunEvent::ExitRequested { .. } = event {
+                if let Err(e) = tauri::async_runtime::block_on(shutdown::flush(app)) {
+                    tracing::error!("shutdown failed: {}", e);
+                }
+            }
+        });
 }
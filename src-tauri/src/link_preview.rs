@@ -0,0 +1,383 @@
+//! Title, description and image for links pasted into a message, read from
+//! the page's Open Graph and `<meta>` tags and cached in `link_previews`.
+//!
+//! Only public http(s) hosts are fetched. Every hop of a redirect is checked
+//! again, and the connection is pinned to the address that was checked, so a
+//! hostname can't be re-resolved to something on the local network in between.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+use url::{Host, Url};
+
+use crate::db;
+use crate::error::{AppError, Context};
+
+pub const LINK_PREVIEW_TIMEOUT: Duration = Duration::from_secs(5);
+/// Only the start of a page is read; the tags we want live in `<head>`.
+pub const MAX_PREVIEW_BYTES: usize = 512 * 1024;
+pub const MAX_PREVIEW_REDIRECTS: usize = 3;
+/// Cached previews older than this are fetched again.
+pub const LINK_PREVIEW_TTL_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, sqlx::FromRow)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80)
+}
+
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+/// Checks that `url` is http(s) and that its host only resolves to public
+/// addresses. Returns the address to connect to.
+pub(crate) async fn guard(url: &Url) -> Result<SocketAddr, AppError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::Validation(
+            "only http(s) links can be previewed".into(),
+        ));
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| AppError::Validation("link has no port".into()))?;
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|e| AppError::Io(format!("failed to resolve {}: {}", domain, e)))?
+            .collect(),
+        None => return Err(AppError::Validation("link has no host".into())),
+    };
+
+    // Every address has to be public, not just the first, since the resolver
+    // order isn't ours to pick.
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(AppError::Validation(
+            "links to private or local addresses can't be previewed".into(),
+        ));
+    }
+    Ok(addrs[0])
+}
+
+fn fetch_error(e: reqwest::Error) -> AppError {
+    AppError::Io(format!("failed to fetch link: {}", e))
+}
+
+/// Follows redirects by hand so each hop goes through `guard`. Returns the
+/// final url, its content type and at most `MAX_PREVIEW_BYTES` of the body.
+async fn download(url: Url) -> Result<(Url, String, Vec<u8>), AppError> {
+    let mut url = url;
+    for _ in 0..=MAX_PREVIEW_REDIRECTS {
+        let addr = guard(&url).await?;
+        let mut builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(LINK_PREVIEW_TIMEOUT);
+        if let Some(Host::Domain(domain)) = url.host() {
+            builder = builder.resolve(domain, addr);
+        }
+        let client = builder.build().map_err(fetch_error)?;
+
+        let mut response = client
+            .get(url.clone())
+            .header(reqwest::header::ACCEPT, "text/html")
+            .send()
+            .await
+            .map_err(fetch_error)?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| AppError::Io("redirect without a location".into()))?;
+            url = url
+                .join(location)
+                .map_err(|e| AppError::Io(format!("invalid redirect location: {}", e)))?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(AppError::Io(format!(
+                "failed to fetch link: server returned {}",
+                response.status()
+            )));
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+            let room = MAX_PREVIEW_BYTES - body.len();
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if body.len() == MAX_PREVIEW_BYTES {
+                break;
+            }
+        }
+        return Ok((url, content_type, body));
+    }
+    Err(AppError::Io("too many redirects".into()))
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Attributes of a single tag body such as `meta property="og:title" content="x"`.
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag;
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq]
+            .rsplit(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let after = rest[eq + 1..].trim_start();
+        let (value, remaining) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => match after[1..].find(quote) {
+                Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                None => (&after[1..], ""),
+            },
+            _ => {
+                let end = after
+                    .find(|c: char| c.is_whitespace())
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        attrs.push((name, decode_entities(value)));
+        rest = remaining;
+    }
+    attrs
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Reads the preview fields out of `html`. Open Graph tags win over plain
+/// `<meta name=...>` and `<title>`; whatever is missing stays `None`.
+pub(crate) fn parse_preview(html: &str, page: &Url) -> LinkPreview {
+    let lower = html.to_ascii_lowercase();
+    let mut og_title = None;
+    let mut og_description = None;
+    let mut og_image = None;
+    let mut description = None;
+
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<meta") {
+        let start = offset + start;
+        let Some(end) = lower[start..].find('>') else {
+            break;
+        };
+        let attrs = attributes(&html[start + "<meta".len()..start + end]);
+        offset = start + end;
+
+        let get = |key: &str| {
+            attrs
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let Some(content) = get("content").and_then(non_empty) else {
+            continue;
+        };
+        let key = get("property")
+            .or_else(|| get("name"))
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let slot = match key.as_str() {
+            "og:title" | "twitter:title" => &mut og_title,
+            "og:description" | "twitter:description" => &mut og_description,
+            "og:image" | "og:image:url" | "twitter:image" => &mut og_image,
+            "description" => &mut description,
+            _ => continue,
+        };
+        slot.get_or_insert(content);
+    }
+
+    let title = og_title.or_else(|| {
+        let start = lower.find("<title")?;
+        let open_end = start + lower[start..].find('>')? + 1;
+        let close = open_end + lower[open_end..].find("</title")?;
+        non_empty(&decode_entities(&html[open_end..close]))
+    });
+    let image_url = og_image
+        .and_then(|image| page.join(&image).ok())
+        .filter(|image| matches!(image.scheme(), "http" | "https"))
+        .map(String::from);
+
+    LinkPreview {
+        url: page.to_string(),
+        title,
+        description: og_description.or(description),
+        image_url,
+    }
+}
+
+async fn cached(pool: &SqlitePool, url: &str) -> Result<Option<LinkPreview>, AppError> {
+    sqlx::query_as::<_, LinkPreview>(
+        "SELECT url, title, description, image_url FROM link_previews
+         WHERE url = ? AND datetime(fetched_at) > datetime('now', ?)",
+    )
+    .bind(url)
+    .bind(format!("-{} seconds", LINK_PREVIEW_TTL_SECS))
+    .fetch_optional(pool)
+    .await
+    .context("failed to read link preview cache")
+}
+
+/// Returns the preview for `url`, from the cache when it's fresh. Pages that
+/// aren't HTML, or have none of the tags, give a preview with empty fields.
+pub(crate) async fn preview_for(pool: &SqlitePool, url: &str) -> Result<LinkPreview, AppError> {
+    let parsed =
+        Url::parse(url).map_err(|e| AppError::Validation(format!("invalid link: {}", e)))?;
+    // Checked up front as well, so a private address is refused even when
+    // an older preview of it is sitting in the cache.
+    guard(&parsed).await?;
+    let key = parsed.to_string();
+    if let Some(preview) = cached(pool, &key).await? {
+        return Ok(preview);
+    }
+
+    let (page, content_type, body) = tokio::time::timeout(LINK_PREVIEW_TIMEOUT, download(parsed))
+        .await
+        .map_err(|_| AppError::Io("timed out fetching link".into()))??;
+    let mut preview = if content_type.is_empty() || content_type == "text/html" {
+        parse_preview(&String::from_utf8_lossy(&body), &page)
+    } else {
+        LinkPreview::default()
+    };
+    preview.url = key;
+
+    sqlx::query(
+        "INSERT INTO link_previews (url, title, description, image_url) VALUES (?, ?, ?, ?)
+         ON CONFLICT (url) DO UPDATE SET
+             title = excluded.title,
+             description = excluded.description,
+             image_url = excluded.image_url,
+             fetched_at = CURRENT_TIMESTAMP",
+    )
+    .bind(&preview.url)
+    .bind(&preview.title)
+    .bind(&preview.description)
+    .bind(&preview.image_url)
+    .execute(pool)
+    .await
+    .context("failed to cache link preview")?;
+    Ok(preview)
+}
+
+#[tauri::command]
+pub async fn fetch_link_preview(
+    db: State<'_, DbInstances>,
+    url: String,
+) -> Result<LinkPreview, AppError> {
+    let pool = db::pool(&db).await?;
+    preview_for(&pool, &url).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn private_and_non_http_targets_are_refused() {
+        let pool = test_pool().await;
+        for url in [
+            "http://127.0.0.1",
+            "http://127.0.0.1:8080/x",
+            "http://localhost/",
+            "http://10.0.0.5",
+            "http://192.168.1.1",
+            "http://169.254.169.254/latest",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://100.64.1.1",
+            "ftp://example.com",
+            "file:///etc/passwd",
+        ] {
+            let err = preview_for(&pool, url).await.unwrap_err();
+            assert_eq!(err.code(), "validation", "{}", url);
+        }
+    }
+
+    #[test]
+    fn is_public_separates_public_from_private_ranges() {
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:4700::1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn open_graph_tags_win_over_plain_ones() {
+        let page = Url::parse("https://ex.com/a/b").unwrap();
+        let html = r#"<html><head><TITLE>Plain &amp; simple</TITLE>
+            <meta name="description" content="meta desc">
+            <meta property='og:image' content="/img.png" />
+            <meta content="OG Title" property="og:title"></head>"#;
+        let preview = parse_preview(html, &page);
+        assert_eq!(preview.title.as_deref(), Some("OG Title"));
+        assert_eq!(preview.description.as_deref(), Some("meta desc"));
+        assert_eq!(preview.image_url.as_deref(), Some("https://ex.com/img.png"));
+
+        let preview = parse_preview("<title>Only &quot;this&quot;</title>", &page);
+        assert_eq!(preview.title.as_deref(), Some("Only \"this\""));
+        assert_eq!(preview.description, None);
+        assert_eq!(preview.image_url, None);
+    }
+}
@@ -0,0 +1,296 @@
+//! Application logging. Events go into an in-memory ring buffer that the
+//! support screen reads back with `get_recent_logs`.
+//!
+//! Values of fields named in `REDACTED_FIELDS`, and anything following
+//! "Bearer ", are replaced before a line is formatted, so credentials never
+//! reach the buffer.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tauri::State;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::error::AppError;
+
+pub const LOG_CAPACITY: usize = 1000;
+pub const REDACTED_FIELDS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "token",
+    "password",
+    "passphrase",
+    "authorization",
+];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    pub timestamp_ms: u64,
+    pub level: String,
+    pub target: String,
+    /// The event's message and fields, prefixed by the spans it happened in.
+    pub message: String,
+}
+
+/// The most recent `LOG_CAPACITY` lines, oldest first.
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<LogLine>>>,
+}
+
+impl LogBuffer {
+    fn push(&self, line: LogLine) {
+        let Ok(mut lines) = self.lines.lock() else {
+            return;
+        };
+        if lines.len() == LOG_CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Lines at `min_level` or more severe, or all of them.
+    pub(crate) fn recent(&self, min_level: Option<Level>) -> Result<Vec<LogLine>, AppError> {
+        let lines = self.lines.lock()?;
+        Ok(lines
+            .iter()
+            .filter(|line| match min_level {
+                Some(min) => Level::from_str(&line.level).is_ok_and(|level| level <= min),
+                None => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+/// Replaces the credential after every "Bearer " in `text`.
+pub(crate) fn redact(text: &str) -> String {
+    const BEARER: &str = "Bearer ";
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(BEARER) {
+        let (before, after) = rest.split_at(start + BEARER.len());
+        out.push_str(before);
+        out.push_str(REDACTED);
+        let end = after
+            .find(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+            .unwrap_or(after.len());
+        rest = &after[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Formats fields as `name=value`, with the event's `message` first.
+#[derive(Default)]
+struct FieldFormatter {
+    message: String,
+    fields: String,
+}
+
+impl FieldFormatter {
+    fn push(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = redact(value);
+            return;
+        }
+        let value = if REDACTED_FIELDS.contains(&field.name()) {
+            REDACTED.to_string()
+        } else {
+            redact(value)
+        };
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        let _ = write!(self.fields, "{}={}", field.name(), value);
+    }
+
+    fn finish(self) -> String {
+        match (self.message.is_empty(), self.fields.is_empty()) {
+            (_, true) => self.message,
+            (true, false) => self.fields,
+            (false, false) => format!("{} {}", self.message, self.fields),
+        }
+    }
+}
+
+impl Visit for FieldFormatter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, &format!("{:?}", value));
+    }
+}
+
+/// A span's formatted fields, kept in its extensions.
+struct SpanFields(String);
+
+struct RingBufferLayer {
+    buffer: LogBuffer,
+}
+
+impl<S> Layer<S> for RingBufferLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = FieldFormatter::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(SpanFields(fields.finish()));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = FieldFormatter::default();
+        values.record(&mut fields);
+        let added = fields.finish();
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<SpanFields>() {
+            Some(SpanFields(existing)) if !existing.is_empty() => {
+                existing.push(' ');
+                existing.push_str(&added);
+            }
+            Some(SpanFields(existing)) => *existing = added,
+            None => extensions.insert(SpanFields(added)),
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                message.push_str(span.name());
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    if !fields.is_empty() {
+                        let _ = write!(message, "{{{}}}", fields);
+                    }
+                }
+                message.push_str(": ");
+            }
+        }
+        let mut fields = FieldFormatter::default();
+        event.record(&mut fields);
+        message.push_str(&fields.finish());
+
+        let metadata = event.metadata();
+        let line = LogLine {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message,
+        };
+        self.buffer.push(line);
+    }
+}
+
+/// Installs the global subscriber and returns the buffer it writes to.
+pub fn init() -> LogBuffer {
+    let buffer = LogBuffer::default();
+    let layer = RingBufferLayer {
+        buffer: buffer.clone(),
+    }
+    .with_filter(LevelFilter::INFO);
+    // Already set means another subscriber owns the process; keep going
+    // without one rather than refusing to start.
+    let _ = tracing_subscriber::registry().with(layer).try_init();
+    buffer
+}
+
+#[tauri::command]
+pub fn get_recent_logs(
+    logs: State<'_, LogBuffer>,
+    level: Option<String>,
+) -> Result<Vec<LogLine>, AppError> {
+    let min_level = level
+        .as_deref()
+        .map(|level| {
+            Level::from_str(level)
+                .map_err(|_| AppError::Validation(format!("unknown log level '{}'", level)))
+        })
+        .transpose()?;
+    logs.recent(min_level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(events: impl FnOnce()) -> LogBuffer {
+        let buffer = LogBuffer::default();
+        let layer = RingBufferLayer {
+            buffer: buffer.clone(),
+        };
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), events);
+        buffer
+    }
+
+    #[test]
+    fn the_buffer_keeps_the_newest_lines() {
+        let buffer = capture(|| {
+            for i in 0..LOG_CAPACITY + 5 {
+                tracing::info!(i, "spam");
+            }
+        });
+        let lines = buffer.recent(None).unwrap();
+        assert_eq!(lines.len(), LOG_CAPACITY);
+        assert_eq!(lines[0].message, "spam i=5");
+    }
+
+    #[test]
+    fn recent_filters_by_severity() {
+        let buffer = capture(|| {
+            tracing::debug!("noise");
+            tracing::info!("hello");
+            tracing::warn!("careful");
+            tracing::error!("bad");
+        });
+        let warnings = buffer.recent(Some(Level::WARN)).unwrap();
+        let messages: Vec<_> = warnings.iter().map(|l| l.message.as_str()).collect();
+        assert_eq!(messages, ["careful", "bad"]);
+    }
+
+    #[test]
+    fn credentials_never_reach_the_buffer() {
+        let buffer = capture(|| {
+            let span = tracing::info_span!("send_message", sender_id = 1, access_token = "sekrit");
+            let _entered = span.enter();
+            tracing::info!(
+                token = "abc",
+                user = 3,
+                "connecting with Bearer xyz.123 now"
+            );
+        });
+        let lines = buffer.recent(None).unwrap();
+        assert_eq!(
+            lines[0].message,
+            "send_message{sender_id=1 access_token=[redacted]}: \
+             connecting with Bearer [redacted] now token=[redacted] user=3"
+        );
+        assert_eq!(
+            redact("a Bearer t1 b Bearer t2"),
+            "a Bearer [redacted] b Bearer [redacted]"
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use std::path::Path;
+use std::time::Instant;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::{DbInstances, Migration};
+
+use crate::error::{AppError, Context};
+use crate::{db, migrations};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableCount {
+    pub table: String,
+    pub rows: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DbHealth {
+    pub integrity_ok: bool,
+    /// Raw `PRAGMA integrity_check` output; just `["ok"]` for a healthy file.
+    pub integrity_messages: Vec<String>,
+    pub schema_version: Option<i64>,
+    pub tables: Vec<TableCount>,
+    pub file_size_bytes: u64,
+}
+
+/// A row whose foreign key points at a missing parent, as reported by
+/// `PRAGMA foreign_key_check`.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct FkViolation {
+    pub table: String,
+    pub rowid: Option<i64>,
+    pub parent: String,
+    /// Index of the violated constraint in `PRAGMA foreign_key_list(table)`.
+    pub fkid: i64,
+    #[sqlx(skip)]
+    pub repaired: bool,
+}
+
+/// Repair passes before giving up; each pass can orphan the children of the
+/// rows it deleted.
+pub const MAX_FK_REPAIR_PASSES: usize = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceReport {
+    pub size_before_bytes: u64,
+    pub size_after_bytes: u64,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PendingMigration {
+    pub version: i64,
+    pub description: String,
+}
+
+/// How the database schema compares with the migrations built into this
+/// binary.
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaStatus {
+    /// Highest migration version this binary knows about.
+    pub binary_version: i64,
+    /// Highest version applied to the database; `None` before the first migration.
+    pub database_version: Option<i64>,
+    /// The database was migrated by a newer build, so this one may not
+    /// understand it: an app downgrade.
+    pub database_newer: bool,
+    /// Migrations this binary has that the database hasn't applied yet.
+    pub pending: Vec<PendingMigration>,
+}
+
+/// Ordinary tables in the main schema, excluding SQLite/sqlx bookkeeping and
+/// FTS shadow tables.
+pub(crate) async fn user_tables(pool: &SqlitePool) -> Result<Vec<String>, AppError> {
+    sqlx::query_scalar(
+        "SELECT name FROM pragma_table_list
+         WHERE schema = 'main' AND type = 'table'
+           AND name NOT LIKE 'sqlite_%' AND name NOT LIKE '_sqlx_%'
+         ORDER BY name",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Size of the main database file, falling back to the page count for
+/// databases that have no file (in-memory).
+pub(crate) async fn file_size(pool: &SqlitePool) -> Result<u64, AppError> {
+    let path: Option<String> =
+        sqlx::query_scalar("SELECT file FROM pragma_database_list WHERE name = 'main'")
+            .fetch_optional(pool)
+            .await?;
+
+    if let Some(path) = path.filter(|p| !p.is_empty()) {
+        if let Ok(meta) = std::fs::metadata(Path::new(&path)) {
+            return Ok(meta.len());
+        }
+    }
+
+    let bytes: i64 = sqlx::query_scalar(
+        "SELECT page_count * page_size FROM pragma_page_count, pragma_page_size",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(bytes as u64)
+}
+
+pub(crate) async fn health(pool: &SqlitePool) -> Result<DbHealth, AppError> {
+    let integrity_messages: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(pool)
+        .await
+        .context("integrity check failed")?;
+    let integrity_ok = integrity_messages.len() == 1 && integrity_messages[0] == "ok";
+
+    let schema_version: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success = TRUE")
+            .fetch_one(pool)
+            .await
+            .context("failed to read schema version")?;
+
+    let mut tables = Vec::new();
+    for table in user_tables(pool).await? {
+        let rows: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM \"{}\"", table))
+            .fetch_one(pool)
+            .await
+            .context(&format!("failed to count {}", table))?;
+        tables.push(TableCount { table, rows });
+    }
+
+    Ok(DbHealth {
+        integrity_ok,
+        integrity_messages,
+        schema_version,
+        tables,
+        file_size_bytes: file_size(pool).await?,
+    })
+}
+
+/// Compares the versions applied to the database with `known`.
+pub(crate) fn compare_schema(known: &[Migration], applied: &[i64]) -> SchemaStatus {
+    let binary_version = known.iter().map(|m| m.version).max().unwrap_or(0);
+    let database_version = applied.iter().copied().max();
+    SchemaStatus {
+        binary_version,
+        database_version,
+        database_newer: database_version.is_some_and(|version| version > binary_version),
+        pending: known
+            .iter()
+            .filter(|m| !applied.contains(&m.version))
+            .map(|m| PendingMigration {
+                version: m.version,
+                description: m.description.to_string(),
+            })
+            .collect(),
+    }
+}
+
+pub(crate) async fn schema_status_of(pool: &SqlitePool) -> Result<SchemaStatus, AppError> {
+    let tracked: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master
+                        WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await
+    .context("failed to look for the migrations table")?;
+    let applied: Vec<i64> = if tracked {
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = TRUE")
+            .fetch_all(pool)
+            .await
+            .context("failed to read applied migrations")?
+    } else {
+        Vec::new()
+    };
+    Ok(compare_schema(&migrations::migrations(), &applied))
+}
+
+async fn fk_violations<'e, E>(executor: E) -> Result<Vec<FkViolation>, AppError>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    sqlx::query_as::<_, FkViolation>(
+        "SELECT \"table\", rowid, parent, fkid FROM pragma_foreign_key_check",
+    )
+    .fetch_all(executor)
+    .await
+    .context("foreign key check failed")
+}
+
+/// Lists orphaned rows. With `repair` they are deleted, along with any rows
+/// that only pointed at them, in one transaction.
+pub(crate) async fn check_fks(
+    pool: &SqlitePool,
+    repair: bool,
+) -> Result<Vec<FkViolation>, AppError> {
+    if !repair {
+        return fk_violations(pool).await;
+    }
+
+    let mut tx = pool.begin().await?;
+    // Deleting an orphan leaves whatever referenced it dangling until the
+    // next pass, so enforcement waits for the commit.
+    sqlx::query("PRAGMA defer_foreign_keys = ON")
+        .execute(&mut *tx)
+        .await?;
+
+    let mut found = Vec::new();
+    for _ in 0..MAX_FK_REPAIR_PASSES {
+        let mut violations = fk_violations(&mut *tx).await?;
+        if violations.iter().all(|v| v.rowid.is_none()) {
+            found.append(&mut violations);
+            break;
+        }
+        for violation in &mut violations {
+            let Some(rowid) = violation.rowid else {
+                continue;
+            };
+            sqlx::query(&format!(
+                "DELETE FROM \"{}\" WHERE rowid = ?",
+                violation.table
+            ))
+            .bind(rowid)
+            .execute(&mut *tx)
+            .await
+            .context(&format!("failed to delete orphan from {}", violation.table))?;
+            violation.repaired = true;
+        }
+        found.append(&mut violations);
+    }
+
+    tx.commit().await?;
+    Ok(found)
+}
+
+/// VACUUM refuses to run inside a transaction, so it gets a dedicated
+/// connection. If that connection was left mid-transaction by an earlier
+/// caller, roll it back and try once more rather than failing outright.
+async fn vacuum(pool: &SqlitePool) -> Result<(), AppError> {
+    let mut conn = pool
+        .acquire()
+        .await
+        .context("failed to acquire connection")?;
+
+    match sqlx::raw_sql("VACUUM").execute(&mut *conn).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.to_string().contains("within a transaction") => {
+            sqlx::raw_sql("ROLLBACK")
+                .execute(&mut *conn)
+                .await
+                .context("failed to leave open transaction")?;
+            sqlx::raw_sql("VACUUM")
+                .execute(&mut *conn)
+                .await
+                .map(|_| ())
+                .context("vacuum failed")
+        }
+        Err(e) => Err(e).context("vacuum failed"),
+    }
+}
+
+pub(crate) async fn maintain(pool: &SqlitePool) -> Result<MaintenanceReport, AppError> {
+    let started = Instant::now();
+    let size_before_bytes = file_size(pool).await?;
+
+    vacuum(pool).await?;
+    sqlx::raw_sql("ANALYZE")
+        .execute(pool)
+        .await
+        .context("analyze failed")?;
+    sqlx::raw_sql("PRAGMA wal_checkpoint(TRUNCATE)")
+        .execute(pool)
+        .await
+        .context("wal checkpoint failed")?;
+
+    Ok(MaintenanceReport {
+        size_before_bytes,
+        size_after_bytes: file_size(pool).await?,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+#[tauri::command]
+pub async fn db_health(db: State<'_, DbInstances>) -> Result<DbHealth, AppError> {
+    let pool = db::pool(&db).await?;
+    health(&pool).await
+}
+
+#[tauri::command]
+pub async fn schema_status(db: State<'_, DbInstances>) -> Result<SchemaStatus, AppError> {
+    let pool = db::pool(&db).await?;
+    schema_status_of(&pool).await
+}
+
+#[tauri::command]
+pub async fn check_foreign_keys(
+    db: State<'_, DbInstances>,
+    repair: Option<bool>,
+) -> Result<Vec<FkViolation>, AppError> {
+    let pool = db::pool(&db).await?;
+    check_fks(&pool, repair.unwrap_or(false)).await
+}
+
+#[tauri::command]
+pub async fn run_maintenance(db: State<'_, DbInstances>) -> Result<MaintenanceReport, AppError> {
+    let pool = db::pool(&db).await?;
+    maintain(&pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrations;
+    use crate::test_support::{count, test_pool};
+
+    /// Leaves message 100 pointing at a deleted user, with an edit hanging
+    /// off it.
+    async fn orphan_a_message(pool: &SqlitePool) {
+        sqlx::raw_sql(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO users (id, username) VALUES (999, 'gone');
+             INSERT INTO messages (id, sender_id, receiver_id, content) VALUES (100, 999, 1, 'orphan');
+             INSERT INTO message_edits (message_id, previous_content) VALUES (100, 'x');
+             DELETE FROM users WHERE id = 999;
+             PRAGMA foreign_keys = ON;",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_reports_orphans_without_touching_them() {
+        let pool = test_pool().await;
+        assert!(check_fks(&pool, false).await.unwrap().is_empty());
+        orphan_a_message(&pool).await;
+
+        let violations = check_fks(&pool, false).await.unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].table, "messages");
+        assert_eq!(violations[0].rowid, Some(100));
+        assert_eq!(violations[0].parent, "users");
+        assert!(!violations[0].repaired);
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 1);
+    }
+
+    #[tokio::test]
+    async fn repair_removes_orphans_and_what_hangs_off_them() {
+        let pool = test_pool().await;
+        sqlx::query("INSERT INTO messages (sender_id, receiver_id, content) VALUES (1, 2, 'ok')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        orphan_a_message(&pool).await;
+
+        let repaired = check_fks(&pool, true).await.unwrap();
+        assert_eq!(repaired.len(), 2);
+        assert!(repaired.iter().all(|v| v.repaired));
+        assert!(check_fks(&pool, false).await.unwrap().is_empty());
+        let kept = "SELECT COUNT(*) FROM messages WHERE content = 'ok'";
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 1);
+        assert_eq!(count(&pool, kept).await, 1);
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM message_edits").await, 0);
+    }
+
+    #[tokio::test]
+    async fn schema_status_reads_the_migrations_table() {
+        let pool = test_pool().await;
+        let known = migrations();
+        let latest = known.iter().map(|m| m.version).max().unwrap();
+        let status = schema_status_of(&pool).await.unwrap();
+        assert_eq!(status.binary_version, latest);
+        assert_eq!(status.database_version, None);
+        assert!(!status.database_newer);
+        assert_eq!(status.pending.len(), known.len());
+
+        sqlx::raw_sql(
+            "CREATE TABLE _sqlx_migrations (version BIGINT PRIMARY KEY, success BOOLEAN)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let record = |version: i64, success: bool| {
+            sqlx::query("INSERT INTO _sqlx_migrations VALUES (?, ?)")
+                .bind(version)
+                .bind(success)
+                .execute(&pool)
+        };
+        for version in 1..=latest - 2 {
+            record(version, true).await.unwrap();
+        }
+        record(latest - 1, false).await.unwrap();
+        let status = schema_status_of(&pool).await.unwrap();
+        assert_eq!(status.database_version, Some(latest - 2));
+        assert!(!status.database_newer);
+        let pending: Vec<i64> = status.pending.iter().map(|m| m.version).collect();
+        assert_eq!(pending, [latest - 1, latest]);
+        assert_eq!(
+            status.pending[1].description,
+            known.last().unwrap().description
+        );
+
+        sqlx::query("UPDATE _sqlx_migrations SET success = TRUE")
+            .execute(&pool)
+            .await
+            .unwrap();
+        record(latest, true).await.unwrap();
+        record(latest + 1, true).await.unwrap();
+        let status = schema_status_of(&pool).await.unwrap();
+        assert_eq!(status.database_version, Some(latest + 1));
+        assert!(status.database_newer);
+        assert!(status.pending.is_empty());
+
+        let applied: Vec<i64> = (1..=latest).collect();
+        let status = compare_schema(&known, &applied);
+        assert!(!status.database_newer);
+        assert!(status.pending.is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+//! `@userid` / `@username` mentions in group messages.
+
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct MentionEntry {
+    pub message_id: i64,
+    pub group_id: String,
+    pub sender_id: i64,
+    pub content: String,
+    pub timestamp: String,
+    pub is_read: bool,
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// The distinct `@token`s in `content`. An `@` directly after a word
+/// character is treated as part of an email address and skipped.
+pub(crate) fn mention_tokens(content: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut prev: Option<char> = None;
+    for (i, c) in content.char_indices() {
+        let after_word = prev.is_some_and(|p| is_token_char(p) || p == '.' || p == '+');
+        prev = Some(c);
+        if c != '@' || after_word {
+            continue;
+        }
+        let token: String = content[i + 1..]
+            .chars()
+            .take_while(|&c| is_token_char(c))
+            .collect();
+        if !token.is_empty() && !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+/// Resolves the mentions in a new message against the group's members and
+/// records them. Tokens that match no member, and self-mentions, are
+/// ignored. Returns the mentioned user ids.
+pub(crate) async fn record_mentions(
+    tx: &mut Transaction<'_, Sqlite>,
+    group_id: &str,
+    message_id: i64,
+    sender_id: i64,
+    content: &str,
+) -> Result<Vec<i64>, AppError> {
+    let tokens = mention_tokens(content);
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let members: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT u.id, u.username
+         FROM group_members gm
+         JOIN users u ON u.id = gm.user_id
+         WHERE gm.group_id = ?",
+    )
+    .bind(group_id)
+    .fetch_all(&mut **tx)
+    .await
+    .context("failed to load group members")?;
+
+    let mentioned: BTreeSet<i64> = tokens
+        .iter()
+        .filter_map(|token| {
+            members
+                .iter()
+                .find(|(id, username)| {
+                    username.eq_ignore_ascii_case(token) || token.parse() == Ok(*id)
+                })
+                .map(|(id, _)| *id)
+        })
+        .filter(|&id| id != sender_id)
+        .collect();
+
+    for user_id in &mentioned {
+        sqlx::query("INSERT OR IGNORE INTO mentions (message_id, mentioned_user_id) VALUES (?, ?)")
+            .bind(message_id)
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await
+            .context("failed to record mention")?;
+    }
+    Ok(mentioned.into_iter().collect())
+}
+
+/// Messages mentioning `user_id`, newest first. Deleted messages are left out.
+pub(crate) async fn mentions_of(
+    pool: &SqlitePool,
+    user_id: i64,
+    unread_only: bool,
+) -> Result<Vec<MentionEntry>, AppError> {
+    sqlx::query_as::<_, MentionEntry>(
+        "SELECT gm.id AS message_id, gm.group_id, gm.sender_id, gm.content, gm.timestamp,
+                mn.is_read
+         FROM mentions mn
+         JOIN group_messages gm ON gm.id = mn.message_id
+         WHERE mn.mentioned_user_id = ?1 AND gm.deleted_at IS NULL
+           AND (gm.expires_at IS NULL OR gm.expires_at > CURRENT_TIMESTAMP)
+           AND (?2 = 0 OR mn.is_read = 0)
+         ORDER BY gm.id DESC",
+    )
+    .bind(user_id)
+    .bind(unread_only)
+    .fetch_all(pool)
+    .await
+    .context("failed to load mentions")
+}
+
+pub(crate) async fn mark_read(
+    pool: &SqlitePool,
+    user_id: i64,
+    group_id: Option<&str>,
+) -> Result<u32, AppError> {
+    let result = sqlx::query(
+        "UPDATE mentions SET is_read = 1
+         WHERE mentioned_user_id = ?1 AND is_read = 0
+           AND (?2 IS NULL
+                OR message_id IN (SELECT id FROM group_messages WHERE group_id = ?2))",
+    )
+    .bind(user_id)
+    .bind(group_id)
+    .execute(pool)
+    .await
+    .context("failed to mark mentions read")?;
+    Ok(result.rows_affected() as u32)
+}
+
+#[tauri::command]
+pub async fn list_mentions(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    unread_only: bool,
+) -> Result<Vec<MentionEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    mentions_of(&pool, user_id, unread_only).await
+}
+
+/// Marks mentions of `user_id` as read, in one group or everywhere.
+#[tauri::command]
+pub async fn mark_mentions_read(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    group_id: Option<String>,
+) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    mark_read(&pool, user_id, group_id.as_deref()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_messages::insert_group_message;
+    use crate::groups::insert_group;
+    use crate::message_type::MessageType;
+    use crate::test_support::{count, test_pool};
+
+    #[test]
+    fn emails_and_repeats_are_not_extra_mentions() {
+        assert_eq!(mention_tokens("a@b.com @u2 @u2"), ["u2"]);
+        assert_eq!(mention_tokens("(@u3) x.y@u4 a+b@u5"), ["u3"]);
+        assert!(mention_tokens("no one @ all").is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_other_members_are_recorded() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2, 3]).await.unwrap();
+        let (message, mentioned) = insert_group_message(
+            &pool,
+            &group.id,
+            1,
+            "@u9 @u2 @u1 a@u3.com",
+            MessageType::Text,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(mentioned, [2]);
+
+        let rows = format!(
+            "SELECT COUNT(*) FROM mentions WHERE message_id = {}",
+            message.id
+        );
+        assert_eq!(count(&pool, &rows).await, 1);
+        assert!(mentions_of(&pool, 9, false).await.unwrap().is_empty());
+        assert_eq!(mentions_of(&pool, 2, false).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,200 @@
+//! Shareable links to a single direct message, `cereals://message/<token>`.
+//! The token is random and only means something to this database; resolving
+//! it is limited to the two people in the conversation.
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::conversations::conversation_id;
+use crate::db;
+use crate::error::{AppError, Context};
+
+pub const MESSAGE_LINK_PREFIX: &str = "cereals://message/";
+pub const LINK_TOKEN_LEN: usize = 32;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageRef {
+    pub message_id: i64,
+    /// The two participants, smaller id first, as `a:b`.
+    pub conversation_id: String,
+    pub sender_id: i64,
+    pub receiver_id: i64,
+}
+
+fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(LINK_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+async fn message_ref(pool: &SqlitePool, message_id: i64) -> Result<MessageRef, AppError> {
+    let (sender_id, receiver_id): (i64, i64) = sqlx::query_as(
+        "SELECT sender_id, receiver_id FROM messages WHERE id = ? AND deleted_at IS NULL",
+    )
+    .bind(message_id)
+    .fetch_optional(pool)
+    .await
+    .context("failed to load message")?
+    .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))?;
+    Ok(MessageRef {
+        message_id,
+        conversation_id: conversation_id(sender_id, receiver_id),
+        sender_id,
+        receiver_id,
+    })
+}
+
+fn is_participant(message: &MessageRef, user_id: i64) -> bool {
+    message.sender_id == user_id || message.receiver_id == user_id
+}
+
+/// Returns the requester's link to the message, creating it the first time.
+pub(crate) async fn link_for(
+    pool: &SqlitePool,
+    message_id: i64,
+    requester_id: i64,
+) -> Result<String, AppError> {
+    let message = message_ref(pool, message_id).await?;
+    if !is_participant(&message, requester_id) {
+        return Err(AppError::PermissionDenied(
+            "only participants can link to a message".into(),
+        ));
+    }
+
+    let token: String = sqlx::query_scalar(
+        "INSERT INTO message_links (token, message_id, created_by) VALUES (?, ?, ?)
+         ON CONFLICT (message_id, created_by) DO UPDATE SET token = token
+         RETURNING token",
+    )
+    .bind(new_token())
+    .bind(message_id)
+    .bind(requester_id)
+    .fetch_one(pool)
+    .await
+    .context("failed to create link")?;
+    Ok(format!("{}{}", MESSAGE_LINK_PREFIX, token))
+}
+
+/// Accepts either the whole link or just its token.
+pub(crate) async fn resolve(
+    pool: &SqlitePool,
+    token: &str,
+    resolver_id: i64,
+) -> Result<MessageRef, AppError> {
+    let token = token.strip_prefix(MESSAGE_LINK_PREFIX).unwrap_or(token);
+    let message_id: i64 =
+        sqlx::query_scalar("SELECT message_id FROM message_links WHERE token = ?")
+            .bind(token)
+            .fetch_optional(pool)
+            .await
+            .context("failed to look up link")?
+            .ok_or_else(|| AppError::NotFound("unknown message link".into()))?;
+
+    let message = message_ref(pool, message_id).await?;
+    if !is_participant(&message, resolver_id) {
+        return Err(AppError::PermissionDenied(
+            "this link belongs to a conversation you are not part of".into(),
+        ));
+    }
+    Ok(message)
+}
+
+#[tauri::command]
+pub async fn create_message_link(
+    db: State<'_, DbInstances>,
+    message_id: i64,
+    requester_id: i64,
+) -> Result<String, AppError> {
+    let pool = db::pool(&db).await?;
+    link_for(&pool, message_id, requester_id).await
+}
+
+#[tauri::command]
+pub async fn resolve_message_link(
+    db: State<'_, DbInstances>,
+    token: String,
+    resolver_id: i64,
+) -> Result<MessageRef, AppError> {
+    let pool = db::pool(&db).await?;
+    resolve(&pool, &token, resolver_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retention::purge_older_than;
+    use crate::test_support::{count, test_pool};
+
+    async fn message_from_3_to_2(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar(
+            "INSERT INTO messages (sender_id, receiver_id, content) VALUES (3, 2, 'hi') RETURNING id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn links_are_stable_per_user_and_limited_to_participants() {
+        let pool = test_pool().await;
+        let message_id = message_from_3_to_2(&pool).await;
+
+        let link = link_for(&pool, message_id, 2).await.unwrap();
+        let token = link.strip_prefix(MESSAGE_LINK_PREFIX).unwrap();
+        assert_eq!(token.len(), LINK_TOKEN_LEN);
+        assert_eq!(link_for(&pool, message_id, 2).await.unwrap(), link);
+        assert_ne!(link_for(&pool, message_id, 3).await.unwrap(), link);
+
+        let err = link_for(&pool, message_id, 4).await.unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        assert_eq!(
+            link_for(&pool, 999, 2).await.unwrap_err().code(),
+            "not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_accepts_the_link_or_its_bare_token() {
+        let pool = test_pool().await;
+        let message_id = message_from_3_to_2(&pool).await;
+        let link = link_for(&pool, message_id, 2).await.unwrap();
+
+        let target = resolve(&pool, &link, 3).await.unwrap();
+        assert_eq!(target.message_id, message_id);
+        assert_eq!(target.conversation_id, "2:3");
+        assert_eq!(target.sender_id, 3);
+        let token = link.strip_prefix(MESSAGE_LINK_PREFIX).unwrap();
+        assert_eq!(
+            resolve(&pool, token, 2).await.unwrap().message_id,
+            message_id
+        );
+
+        assert_eq!(
+            resolve(&pool, &link, 4).await.unwrap_err().code(),
+            "permission_denied"
+        );
+        assert_eq!(
+            resolve(&pool, "nope", 2).await.unwrap_err().code(),
+            "not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn purging_a_message_removes_its_links() {
+        let pool = test_pool().await;
+        let message_id = message_from_3_to_2(&pool).await;
+        link_for(&pool, message_id, 2).await.unwrap();
+        sqlx::query("UPDATE messages SET timestamp = '2000-01-01'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        purge_older_than(&pool, 1, false).await.unwrap();
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM message_links").await, 0);
+    }
+}
@@ -0,0 +1,134 @@
+//! The kinds of message a conversation can hold. Commands take the type as a
+//! string and parse it here, so an unknown type is rejected before anything
+//! is stored.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    Text,
+    Image,
+    File,
+    System,
+    Voice,
+}
+
+impl MessageType {
+    pub const ALL: &'static [MessageType] = &[
+        MessageType::Text,
+        MessageType::Image,
+        MessageType::File,
+        MessageType::System,
+        MessageType::Voice,
+    ];
+
+    /// The name stored in `message_type` columns.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageType::Text => "text",
+            MessageType::Image => "image",
+            MessageType::File => "file",
+            MessageType::System => "system",
+            MessageType::Voice => "voice",
+        }
+    }
+
+    /// Parses an optional command argument, defaulting to `Text`.
+    pub(crate) fn parse_or_text(message_type: Option<&str>) -> Result<Self, AppError> {
+        message_type.map_or(Ok(MessageType::Text), str::parse)
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MessageType {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MessageType::ALL
+            .iter()
+            .copied()
+            .find(|t| t.as_str() == s)
+            .ok_or_else(|| {
+                let expected: Vec<&str> = MessageType::ALL.iter().map(|t| t.as_str()).collect();
+                AppError::Validation(format!(
+                    "invalid message type '{}', expected one of: {}",
+                    s,
+                    expected.join(", ")
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::insert_group;
+    use crate::test_support::test_pool;
+
+    #[test]
+    fn every_type_round_trips_through_its_name() {
+        for message_type in MessageType::ALL {
+            let parsed: MessageType = message_type.to_string().parse().unwrap();
+            assert_eq!(parsed, *message_type);
+        }
+        assert_eq!(MessageType::parse_or_text(None).unwrap(), MessageType::Text);
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        for name in ["Text", "", "bogus"] {
+            assert_eq!(
+                name.parse::<MessageType>().unwrap_err().code(),
+                "validation"
+            );
+        }
+        let err = MessageType::parse_or_text(Some("bogus")).unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    #[tokio::test]
+    async fn the_schema_rejects_unknown_types() {
+        let pool = test_pool().await;
+        let err = sqlx::query(
+            "INSERT INTO messages (sender_id, receiver_id, content, message_type)
+             VALUES (1, 2, 'x', 'bogus')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid message_type"));
+
+        sqlx::query(
+            "INSERT INTO messages (sender_id, receiver_id, content, message_type)
+             VALUES (1, 2, 'x', 'voice')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let update = sqlx::query("UPDATE messages SET message_type = 'nope'")
+            .execute(&pool)
+            .await;
+        assert!(update.is_err());
+        let group = insert_group(&pool, "g", None, 1, &[]).await.unwrap();
+        let err = sqlx::query(
+            "INSERT INTO group_messages (group_id, sender_id, content, message_type)
+             VALUES (?, 1, 'x', 'bad')",
+        )
+        .bind(&group.id)
+        .execute(&pool)
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid message_type"));
+    }
+}
@@ -0,0 +1,1521 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::encryption::{EncryptionState, UNREADABLE_PLACEHOLDER};
+use crate::error::{AppError, Context};
+use crate::integrity::content_hash;
+use crate::message_type::MessageType;
+use crate::protocol::{serialize_ws_frame, ReadReceiptEvent, WsEvent};
+use crate::rate_limit::{
+    self, BroadcastRateLimitState, RateLimit, RateLimitSettings, RateLimitState,
+};
+use crate::ws_manager::{self, WsState};
+use crate::{blocks, conversations, db, disappearing, presence, shortcode};
+
+/// Column list matching `MessageRow`, for `SELECT`/`RETURNING` clauses on `messages`.
+/// Soft-deleted rows come back with empty content and `deleted` set.
+pub(crate) const MESSAGE_COLUMNS: &str = "id, sender_id, receiver_id,
+    CASE WHEN deleted_at IS NULL THEN content ELSE '' END AS content,
+    message_type, timestamp, is_read, edited_at, deleted_at IS NOT NULL AS deleted, reply_to_id,
+    CASE WHEN deleted_at IS NULL THEN quoted_snippet END AS quoted_snippet,
+    encrypted, forwarded_from, client_msg_id, expires_at";
+
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 200;
+/// Numbered pages are capped lower than keyset pages, since each one also
+/// pays for an `OFFSET` scan.
+pub const MAX_NUMBERED_PAGE_SIZE: u32 = 100;
+/// Longest message accepted, in user-perceived characters.
+pub const MAX_MESSAGE_CHARS: usize = 4000;
+/// Length of the quote stored with a reply, in user-perceived characters.
+pub const QUOTED_SNIPPET_CHARS: usize = 120;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PagedMessages {
+    pub messages: Vec<MessageRow>,
+    pub total_count: i64,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LengthInfo {
+    /// Grapheme clusters, so an emoji or a letter with combining accents
+    /// counts once.
+    pub chars: usize,
+    pub bytes: usize,
+    pub exceeds_limit: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BroadcastReport {
+    pub delivered: u32,
+    /// Contacts who have blocked the sender.
+    pub skipped: u32,
+}
+
+/// The start of a quoted message, kept on the reply so the quote survives
+/// the original being deleted.
+pub(crate) fn quoted_snippet(content: &str) -> String {
+    content.graphemes(true).take(QUOTED_SNIPPET_CHARS).collect()
+}
+
+pub(crate) fn length_info(content: &str, max_chars: usize) -> LengthInfo {
+    let chars = content.graphemes(true).count();
+    LengthInfo {
+        chars,
+        bytes: content.len(),
+        exceeds_limit: chars > max_chars,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct MessageRow {
+    pub id: i64,
+    pub sender_id: i64,
+    pub receiver_id: i64,
+    pub content: String,
+    pub message_type: String,
+    pub timestamp: String,
+    pub is_read: bool,
+    pub edited_at: Option<String>,
+    pub deleted: bool,
+    pub reply_to_id: Option<i64>,
+    /// The start of the replied-to message as it was when this reply was
+    /// sent; still there after the original is deleted.
+    pub quoted_snippet: Option<String>,
+    /// Whether the stored content is ciphertext. Rows returned from commands
+    /// have already been decrypted.
+    pub encrypted: bool,
+    /// The message this one was forwarded from, if any.
+    pub forwarded_from: Option<i64>,
+    /// Temporary id the sending client assigned, echoed back for reconciling
+    /// optimistic sends.
+    pub client_msg_id: Option<String>,
+    /// When the message disappears, for messages sent with a TTL.
+    pub expires_at: Option<String>,
+}
+
+pub(crate) fn validate_message(content: &str) -> Result<(), AppError> {
+    if content.trim().is_empty() {
+        return Err(AppError::Validation(
+            "message content must not be empty".into(),
+        ));
+    }
+    if length_info(content, MAX_MESSAGE_CHARS).exceeds_limit {
+        return Err(AppError::Validation(format!(
+            "message is longer than {} characters",
+            MAX_MESSAGE_CHARS
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) async fn insert_message(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    sender_id: i64,
+    receiver_id: i64,
+    content: &str,
+    message_type: MessageType,
+    reply_to: Option<i64>,
+) -> Result<MessageRow, AppError> {
+    insert_with_origin(
+        pool,
+        encryption,
+        sender_id,
+        receiver_id,
+        content,
+        message_type,
+        reply_to,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+}
+
+async fn find_by_client_id(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    sender_id: i64,
+    client_msg_id: &str,
+) -> Result<Option<MessageRow>, AppError> {
+    let mut row = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {} FROM messages WHERE sender_id = ? AND client_msg_id = ?",
+        MESSAGE_COLUMNS
+    ))
+    .bind(sender_id)
+    .bind(client_msg_id)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(row) = row.as_mut() {
+        encryption.reveal(row);
+    }
+    Ok(row)
+}
+
+/// The local id of the message `sender_id` sent as `client_msg_id`, if this
+/// database has it.
+pub(crate) async fn local_id_for(
+    pool: &SqlitePool,
+    sender_id: i64,
+    client_msg_id: &str,
+) -> Result<Option<i64>, AppError> {
+    sqlx::query_scalar("SELECT id FROM messages WHERE sender_id = ? AND client_msg_id = ?")
+        .bind(sender_id)
+        .bind(client_msg_id)
+        .fetch_optional(pool)
+        .await
+        .context("failed to look up message")
+}
+
+/// Like `insert_message`, but a repeated `client_msg_id` from the same sender
+/// returns the message already stored instead of inserting a duplicate, so
+/// clients can safely retry a send. With `rate_limit`, the sender is charged
+/// only once the message is known to be new and valid; retries and rejected
+/// sends are free.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn insert_idempotent(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    sender_id: i64,
+    receiver_id: i64,
+    content: &str,
+    message_type: MessageType,
+    reply_to: Option<i64>,
+    client_msg_id: Option<&str>,
+    ttl_secs: Option<u32>,
+    rate_limit: Option<(&RateLimitState, RateLimit)>,
+) -> Result<MessageRow, AppError> {
+    let Some(client_msg_id) = client_msg_id else {
+        return insert_with_origin(
+            pool,
+            encryption,
+            sender_id,
+            receiver_id,
+            content,
+            message_type,
+            reply_to,
+            None,
+            None,
+            ttl_secs,
+            rate_limit,
+        )
+        .await;
+    };
+
+    if let Some(existing) = find_by_client_id(pool, encryption, sender_id, client_msg_id).await? {
+        return Ok(existing);
+    }
+    let inserted = insert_with_origin(
+        pool,
+        encryption,
+        sender_id,
+        receiver_id,
+        content,
+        message_type,
+        reply_to,
+        None,
+        Some(client_msg_id),
+        ttl_secs,
+        rate_limit,
+    )
+    .await;
+    match inserted {
+        // Lost a race with a concurrent retry of the same send.
+        Err(AppError::Conflict(_)) => find_by_client_id(pool, encryption, sender_id, client_msg_id)
+            .await?
+            .ok_or_else(|| AppError::Conflict("duplicate client message id".into())),
+        other => other,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn insert_with_origin(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    sender_id: i64,
+    receiver_id: i64,
+    content: &str,
+    message_type: MessageType,
+    reply_to: Option<i64>,
+    forwarded_from: Option<i64>,
+    client_msg_id: Option<&str>,
+    ttl_secs: Option<u32>,
+    rate_limit: Option<(&RateLimitState, RateLimit)>,
+) -> Result<MessageRow, AppError> {
+    validate_message(content)?;
+    let expiry =
+        disappearing::expiry_modifier(pool, sender_id, &receiver_id.to_string(), "user", ttl_secs)
+            .await?;
+
+    if blocks::is_blocked(pool, receiver_id, sender_id).await? {
+        return Err(AppError::Blocked(format!(
+            "user {} is not accepting messages from you",
+            receiver_id
+        )));
+    }
+
+    let mut snippet = None;
+    if let Some(parent_id) = reply_to {
+        let parent: Option<(bool, String, bool, bool)> = sqlx::query_as(
+            "SELECT (sender_id = ?2 AND receiver_id = ?3) OR (sender_id = ?3 AND receiver_id = ?2),
+                    content, encrypted, deleted_at IS NOT NULL
+             FROM messages WHERE id = ?1",
+        )
+        .bind(parent_id)
+        .bind(sender_id)
+        .bind(receiver_id)
+        .fetch_optional(pool)
+        .await?;
+
+        match parent {
+            None => {
+                return Err(AppError::NotFound(format!(
+                    "message {} not found",
+                    parent_id
+                )))
+            }
+            Some((false, ..)) => {
+                return Err(AppError::Validation(
+                    "replies must reference a message in the same conversation".into(),
+                ))
+            }
+            Some((true, _, _, true)) => {}
+            Some((true, parent_content, encrypted, false)) => {
+                let parent_content = if encrypted {
+                    encryption.reveal_content(sender_id, receiver_id, &parent_content)
+                } else {
+                    parent_content
+                };
+                snippet = Some(quoted_snippet(&parent_content));
+            }
+        }
+    }
+
+    let sealed = encryption.seal_for(sender_id, receiver_id, content)?;
+    let stored = sealed.as_deref().unwrap_or(content);
+    // The quote is stored the same way as the reply itself.
+    let stored_snippet = match snippet.as_deref() {
+        Some(snippet) => Some(
+            encryption
+                .seal_for(sender_id, receiver_id, snippet)?
+                .unwrap_or_else(|| snippet.to_string()),
+        ),
+        None => None,
+    };
+
+    if let Some((buckets, limit)) = rate_limit {
+        rate_limit::check(buckets, limit, sender_id, Instant::now())?;
+    }
+    let mut row = sqlx::query_as::<_, MessageRow>(&format!(
+        "INSERT INTO messages
+             (sender_id, receiver_id, content, content_hash, message_type, reply_to_id,
+              quoted_snippet, encrypted, forwarded_from, client_msg_id, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, datetime('now', ?))
+         RETURNING {}",
+        MESSAGE_COLUMNS
+    ))
+    .bind(sender_id)
+    .bind(receiver_id)
+    .bind(stored)
+    .bind(content_hash(stored))
+    .bind(message_type.as_str())
+    .bind(reply_to)
+    .bind(stored_snippet)
+    .bind(sealed.is_some())
+    .bind(forwarded_from)
+    .bind(client_msg_id)
+    .bind(expiry)
+    .fetch_one(pool)
+    .await?;
+    presence::touch_last_seen(pool, sender_id).await?;
+    conversations::unarchive_direct(pool, sender_id, receiver_id).await?;
+
+    row.content = content.to_string();
+    row.quoted_snippet = snippet;
+    Ok(row)
+}
+
+/// Sends `content` to each of the sender's contacts as its own direct
+/// message, all in one transaction. Contacts who blocked the sender are
+/// skipped rather than failing the whole broadcast.
+pub(crate) async fn broadcast(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    sender_id: i64,
+    content: &str,
+) -> Result<BroadcastReport, AppError> {
+    validate_message(content)?;
+
+    let mut tx = pool.begin().await?;
+    let contacts: Vec<(i64, bool)> = sqlx::query_as(
+        "SELECT c.contact_id,
+                EXISTS (SELECT 1 FROM blocked_users b
+                        WHERE b.user_id = c.contact_id AND b.blocked_id = c.user_id)
+         FROM contacts c
+         WHERE c.user_id = ? AND c.contact_id != c.user_id
+         ORDER BY c.contact_id",
+    )
+    .bind(sender_id)
+    .fetch_all(&mut *tx)
+    .await
+    .context("failed to load contacts")?;
+
+    let mut report = BroadcastReport {
+        delivered: 0,
+        skipped: 0,
+    };
+    for (receiver_id, blocked) in contacts {
+        if blocked {
+            report.skipped += 1;
+            continue;
+        }
+        let sealed = encryption.seal_for(sender_id, receiver_id, content)?;
+        let stored = sealed.as_deref().unwrap_or(content);
+        let expiry = disappearing::expiry_modifier(
+            &mut *tx,
+            sender_id,
+            &receiver_id.to_string(),
+            "user",
+            None,
+        )
+        .await?;
+        sqlx::query(
+            "INSERT INTO messages
+                 (sender_id, receiver_id, content, content_hash, message_type, encrypted,
+                  expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, datetime('now', ?))",
+        )
+        .bind(sender_id)
+        .bind(receiver_id)
+        .bind(stored)
+        .bind(content_hash(stored))
+        .bind(MessageType::Text.as_str())
+        .bind(sealed.is_some())
+        .bind(expiry)
+        .execute(&mut *tx)
+        .await
+        .context("failed to send broadcast")?;
+        conversations::unarchive_direct(&mut *tx, sender_id, receiver_id).await?;
+        report.delivered += 1;
+    }
+    tx.commit().await?;
+
+    if report.delivered > 0 {
+        presence::touch_last_seen(pool, sender_id).await?;
+    }
+    Ok(report)
+}
+
+/// Sends a copy of `original_id` from `from_user` to `to_receiver`. The
+/// forwarder must be a participant of the original conversation. Text
+/// messages can get a "Forwarded from @username" line; other types are copied
+/// as-is since their content is a path or URL.
+pub(crate) async fn forward(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    original_id: i64,
+    from_user: i64,
+    to_receiver: i64,
+    include_attribution: bool,
+) -> Result<MessageRow, AppError> {
+    let mut original = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {} FROM messages WHERE id = ?",
+        MESSAGE_COLUMNS
+    ))
+    .bind(original_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("message {} not found", original_id)))?;
+
+    if original.sender_id != from_user && original.receiver_id != from_user {
+        return Err(AppError::PermissionDenied(
+            "you can only forward messages from your own conversations".into(),
+        ));
+    }
+    if original.deleted {
+        return Err(AppError::Validation(
+            "deleted messages cannot be forwarded".into(),
+        ));
+    }
+
+    encryption.reveal(&mut original);
+    if original.encrypted && original.content == UNREADABLE_PLACEHOLDER {
+        return Err(AppError::DecryptionFailed(format!(
+            "message {} could not be decrypted",
+            original_id
+        )));
+    }
+
+    let message_type: MessageType = original.message_type.parse()?;
+    let content = if include_attribution && message_type == MessageType::Text {
+        let username: String = sqlx::query_scalar("SELECT username FROM users WHERE id = ?")
+            .bind(original.sender_id)
+            .fetch_one(pool)
+            .await
+            .context("failed to load original sender")?;
+        format!("Forwarded from @{}\n{}", username, original.content)
+    } else {
+        original.content
+    };
+
+    insert_with_origin(
+        pool,
+        encryption,
+        from_user,
+        to_receiver,
+        &content,
+        message_type,
+        None,
+        Some(original_id),
+        None,
+        None,
+        None,
+    )
+    .await
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct UnreadEntry {
+    pub partner_id: i64,
+    pub count: i64,
+}
+
+/// Maps a requested page size onto `1..=MAX_PAGE_SIZE`, treating 0 as "use the default".
+pub(crate) fn page_size(limit: u32) -> u32 {
+    match limit {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n.min(MAX_PAGE_SIZE),
+    }
+}
+
+/// Page `page` (0-based, newest first) of the conversation between two users,
+/// for UIs that show page numbers. Pages past the end come back empty.
+pub(crate) async fn numbered_page(
+    pool: &SqlitePool,
+    user_a: i64,
+    user_b: i64,
+    page: u32,
+    page_size: u32,
+) -> Result<PagedMessages, AppError> {
+    let page_size = match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n,
+    }
+    .min(MAX_NUMBERED_PAGE_SIZE);
+    let offset = i64::from(page) * i64::from(page_size);
+
+    let total_count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM messages
+         WHERE ((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+    )
+    .bind(user_a)
+    .bind(user_b)
+    .fetch_one(pool)
+    .await
+    .context("failed to count messages")?;
+
+    let messages = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {}
+         FROM messages
+         WHERE ((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY id DESC
+         LIMIT ?3 OFFSET ?4",
+        MESSAGE_COLUMNS
+    ))
+    .bind(user_a)
+    .bind(user_b)
+    .bind(page_size)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+    .context("failed to load conversation")?;
+
+    Ok(PagedMessages {
+        has_more: offset + (messages.len() as i64) < total_count,
+        messages,
+        total_count,
+    })
+}
+
+/// Messages between two users in either direction, newest first. Pass the
+/// smallest id of the previous page as `before_id` to get the next older page.
+/// The statement behind `conversation_page`; `?1` and `?2` are the two users,
+/// `?3` the optional `before_id` and `?4` the page size.
+pub(crate) fn conversation_page_sql() -> String {
+    format!(
+        "SELECT {}
+         FROM messages
+         WHERE ((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
+           AND (?3 IS NULL OR id < ?3)
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY id DESC
+         LIMIT ?4",
+        MESSAGE_COLUMNS
+    )
+}
+
+pub(crate) async fn conversation_page(
+    pool: &SqlitePool,
+    user_a: i64,
+    user_b: i64,
+    before_id: Option<i64>,
+    limit: u32,
+) -> Result<Vec<MessageRow>, AppError> {
+    sqlx::query_as::<_, MessageRow>(&conversation_page_sql())
+        .bind(user_a)
+        .bind(user_b)
+        .bind(before_id)
+        .bind(page_size(limit))
+        .fetch_all(pool)
+        .await
+        .context("failed to load conversation")
+}
+
+/// The message `message_id` with up to `context` messages either side of it,
+/// oldest first, for jumping to a message from search. Near the start or end
+/// of the conversation that side simply comes back shorter.
+pub(crate) async fn messages_around(
+    pool: &SqlitePool,
+    user_a: i64,
+    user_b: i64,
+    message_id: i64,
+    context: u32,
+) -> Result<Vec<MessageRow>, AppError> {
+    let context = context.min(MAX_PAGE_SIZE);
+    let in_conversation =
+        "((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
+         AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)";
+
+    let target = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {} FROM messages WHERE {} AND id = ?3",
+        MESSAGE_COLUMNS, in_conversation
+    ))
+    .bind(user_a)
+    .bind(user_b)
+    .bind(message_id)
+    .fetch_optional(pool)
+    .await
+    .context("failed to load message")?
+    .ok_or_else(|| {
+        AppError::NotFound(format!(
+            "message {} not found in this conversation",
+            message_id
+        ))
+    })?;
+
+    let mut before = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {} FROM messages WHERE {} AND id < ?3 ORDER BY id DESC LIMIT ?4",
+        MESSAGE_COLUMNS, in_conversation
+    ))
+    .bind(user_a)
+    .bind(user_b)
+    .bind(message_id)
+    .bind(context)
+    .fetch_all(pool)
+    .await
+    .context("failed to load earlier messages")?;
+
+    let after = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {} FROM messages WHERE {} AND id > ?3 ORDER BY id LIMIT ?4",
+        MESSAGE_COLUMNS, in_conversation
+    ))
+    .bind(user_a)
+    .bind(user_b)
+    .bind(message_id)
+    .bind(context)
+    .fetch_all(pool)
+    .await
+    .context("failed to load later messages")?;
+
+    before.reverse();
+    before.push(target);
+    before.extend(after);
+    Ok(before)
+}
+
+/// Replaces a message's content, keeping the old text in `message_edits`.
+/// Only the original sender may edit.
+pub(crate) async fn apply_edit(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+    message_id: i64,
+    editor_id: i64,
+    new_content: &str,
+) -> Result<MessageRow, AppError> {
+    validate_message(new_content)?;
+
+    let mut tx = pool.begin().await?;
+
+    let (sender_id, receiver_id, previous_content, deleted, was_sealed, stored_snippet): (
+        i64,
+        i64,
+        String,
+        bool,
+        bool,
+        Option<String>,
+    ) = sqlx::query_as(
+        "SELECT sender_id, receiver_id, content, deleted_at IS NOT NULL, encrypted,
+                quoted_snippet
+         FROM messages WHERE id = ?",
+    )
+    .bind(message_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))?;
+
+    if sender_id != editor_id {
+        return Err(AppError::PermissionDenied(
+            "only the sender can edit this message".into(),
+        ));
+    }
+    if deleted {
+        return Err(AppError::Validation(
+            "deleted messages cannot be edited".into(),
+        ));
+    }
+
+    sqlx::query("INSERT INTO message_edits (message_id, previous_content) VALUES (?, ?)")
+        .bind(message_id)
+        .bind(&previous_content)
+        .execute(&mut *tx)
+        .await
+        .context("failed to record edit")?;
+
+    // The edit history keeps whatever was stored, so sealed content stays
+    // sealed there too.
+    let sealed = encryption.seal_for(sender_id, receiver_id, new_content)?;
+    let stored = sealed.as_deref().unwrap_or(new_content);
+    // Re-stored alongside the content, in case encryption was switched on
+    // since the reply was sent.
+    let snippet = stored_snippet.map(|snippet| {
+        if was_sealed {
+            encryption.reveal_content(sender_id, receiver_id, &snippet)
+        } else {
+            snippet
+        }
+    });
+    let stored_snippet = match snippet.as_deref() {
+        Some(snippet) => Some(
+            encryption
+                .seal_for(sender_id, receiver_id, snippet)?
+                .unwrap_or_else(|| snippet.to_string()),
+        ),
+        None => None,
+    };
+
+    let mut row = sqlx::query_as::<_, MessageRow>(&format!(
+        "UPDATE messages
+         SET content = ?, content_hash = ?, quoted_snippet = ?, encrypted = ?,
+             edited_at = CURRENT_TIMESTAMP
+         WHERE id = ?
+         RETURNING {}",
+        MESSAGE_COLUMNS
+    ))
+    .bind(stored)
+    .bind(content_hash(stored))
+    .bind(stored_snippet)
+    .bind(sealed.is_some())
+    .bind(message_id)
+    .fetch_one(&mut *tx)
+    .await
+    .context("failed to edit message")?;
+
+    tx.commit().await?;
+    row.content = new_content.to_string();
+    row.quoted_snippet = snippet;
+    Ok(row)
+}
+
+/// Marks a message as deleted without removing the row, so replies and
+/// timestamps stay intact. Only the original sender may delete.
+pub(crate) async fn mark_deleted(
+    pool: &SqlitePool,
+    message_id: i64,
+    requester_id: i64,
+) -> Result<MessageRow, AppError> {
+    let sender_id: i64 = sqlx::query_scalar("SELECT sender_id FROM messages WHERE id = ?")
+        .bind(message_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("message {} not found", message_id)))?;
+
+    if sender_id != requester_id {
+        return Err(AppError::PermissionDenied(
+            "only the sender can delete this message".into(),
+        ));
+    }
+
+    sqlx::query_as::<_, MessageRow>(&format!(
+        "UPDATE messages SET deleted_at = COALESCE(deleted_at, CURRENT_TIMESTAMP)
+         WHERE id = ?
+         RETURNING {}",
+        MESSAGE_COLUMNS
+    ))
+    .bind(message_id)
+    .fetch_one(pool)
+    .await
+    .context("failed to delete message")
+}
+
+pub(crate) async fn mark_read(
+    pool: &SqlitePool,
+    reader_id: i64,
+    partner_id: i64,
+) -> Result<u32, AppError> {
+    let result = sqlx::query(
+        "UPDATE messages SET is_read = TRUE
+         WHERE receiver_id = ? AND sender_id = ? AND is_read = FALSE",
+    )
+    .bind(reader_id)
+    .bind(partner_id)
+    .execute(pool)
+    .await
+    .context("failed to mark conversation read")?;
+
+    Ok(result.rows_affected() as u32)
+}
+
+/// What `mark_all_read` changed: the number of messages, and one receipt
+/// per conversation for its newest newly-read message.
+pub(crate) struct AllRead {
+    pub count: u32,
+    pub receipts: Vec<ReadReceiptEvent>,
+}
+
+pub(crate) async fn mark_all_read_for(
+    pool: &SqlitePool,
+    reader_id: i64,
+) -> Result<AllRead, AppError> {
+    let read: Vec<(i64, i64)> = sqlx::query_as(
+        "UPDATE messages SET is_read = TRUE
+         WHERE receiver_id = ? AND is_read = FALSE
+         RETURNING id, sender_id",
+    )
+    .bind(reader_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to mark messages read")?;
+
+    let mut newest: BTreeMap<i64, i64> = BTreeMap::new();
+    for &(id, sender_id) in &read {
+        let latest = newest.entry(sender_id).or_insert(id);
+        *latest = (*latest).max(id);
+    }
+    Ok(AllRead {
+        count: read.len() as u32,
+        receipts: newest
+            .into_values()
+            .map(|message_id| ReadReceiptEvent {
+                message_id,
+                reader_id,
+            })
+            .collect(),
+    })
+}
+
+pub(crate) async fn unread_by_partner(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<UnreadEntry>, AppError> {
+    sqlx::query_as::<_, UnreadEntry>(
+        "SELECT sender_id AS partner_id, COUNT(*) AS count
+         FROM messages
+         WHERE receiver_id = ? AND is_read = FALSE AND deleted_at IS NULL
+         GROUP BY sender_id
+         ORDER BY sender_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to count unread messages")
+}
+
+/// The root message followed by its direct replies, oldest first.
+pub(crate) async fn thread(
+    pool: &SqlitePool,
+    root_message_id: i64,
+) -> Result<Vec<MessageRow>, AppError> {
+    let rows = sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {}
+         FROM messages
+         WHERE (id = ?1 OR reply_to_id = ?1)
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY id = ?1 DESC, timestamp, id",
+        MESSAGE_COLUMNS
+    ))
+    .bind(root_message_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load thread")?;
+
+    if rows.first().map(|row| row.id) != Some(root_message_id) {
+        return Err(AppError::NotFound(format!(
+            "message {} not found",
+            root_message_id
+        )));
+    }
+    Ok(rows)
+}
+
+#[tauri::command]
+pub fn validate_message_length(content: String, max_chars: usize) -> LengthInfo {
+    length_info(&content, max_chars)
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(db, limits, buckets, encryption, content), err)]
+pub async fn send_message(
+    db: State<'_, DbInstances>,
+    limits: State<'_, RateLimitSettings>,
+    buckets: State<'_, RateLimitState>,
+    encryption: State<'_, EncryptionState>,
+    sender_id: i64,
+    receiver_id: i64,
+    content: String,
+    message_type: Option<String>,
+    reply_to: Option<i64>,
+    client_msg_id: Option<String>,
+    expand_shortcodes: Option<bool>,
+    ttl_secs: Option<u32>,
+) -> Result<MessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    let message_type = MessageType::parse_or_text(message_type.as_deref())?;
+    let content = if message_type == MessageType::Text && expand_shortcodes.unwrap_or(true) {
+        shortcode::expand_shortcodes(&content)
+    } else {
+        content
+    };
+    insert_idempotent(
+        &pool,
+        &encryption,
+        sender_id,
+        receiver_id,
+        &content,
+        message_type,
+        reply_to,
+        client_msg_id.as_deref(),
+        ttl_secs,
+        Some((&buckets, limits.current()?)),
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn broadcast_to_contacts(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    buckets: State<'_, BroadcastRateLimitState>,
+    sender_id: i64,
+    content: String,
+) -> Result<BroadcastReport, AppError> {
+    rate_limit::check(
+        &buckets.0,
+        rate_limit::BROADCAST_LIMIT,
+        sender_id,
+        Instant::now(),
+    )?;
+
+    let pool = db::pool(&db).await?;
+    broadcast(&pool, &encryption, sender_id, &content).await
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(db, encryption), err)]
+pub async fn fetch_conversation(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_a: i64,
+    user_b: i64,
+    before_id: Option<i64>,
+    limit: u32,
+) -> Result<Vec<MessageRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut rows = conversation_page(&pool, user_a, user_b, before_id, limit).await?;
+    encryption.reveal_all(&mut rows);
+    Ok(rows)
+}
+
+/// `conversation_id` is the two participants as `a:b`.
+#[tauri::command]
+pub async fn fetch_around(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    conversation_id: String,
+    message_id: i64,
+    context: u32,
+) -> Result<Vec<MessageRow>, AppError> {
+    let (user_a, user_b) = conversations::parse_conversation_id(&conversation_id)?;
+    let pool = db::pool(&db).await?;
+    let mut rows = messages_around(&pool, user_a, user_b, message_id, context).await?;
+    encryption.reveal_all(&mut rows);
+    Ok(rows)
+}
+
+#[tauri::command]
+pub async fn fetch_conversation_page(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_a: i64,
+    user_b: i64,
+    page: u32,
+    page_size: u32,
+) -> Result<PagedMessages, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut paged = numbered_page(&pool, user_a, user_b, page, page_size).await?;
+    encryption.reveal_all(&mut paged.messages);
+    Ok(paged)
+}
+
+#[tauri::command]
+pub async fn edit_message(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    message_id: i64,
+    editor_id: i64,
+    new_content: String,
+) -> Result<MessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    apply_edit(&pool, &encryption, message_id, editor_id, &new_content).await
+}
+
+#[tauri::command]
+pub async fn soft_delete_message(
+    db: State<'_, DbInstances>,
+    message_id: i64,
+    requester_id: i64,
+) -> Result<MessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    mark_deleted(&pool, message_id, requester_id).await
+}
+
+#[tauri::command]
+pub async fn mark_conversation_read(
+    db: State<'_, DbInstances>,
+    reader_id: i64,
+    partner_id: i64,
+) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    mark_read(&pool, reader_id, partner_id).await
+}
+
+/// Marks everything `user_id` has received as read, sending one read receipt
+/// per conversation.
+#[tauri::command]
+pub async fn mark_all_read(
+    db: State<'_, DbInstances>,
+    ws: State<'_, Mutex<WsState>>,
+    user_id: i64,
+) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    let all_read = mark_all_read_for(&pool, user_id).await?;
+    // Receipts are best-effort, like `mark_conversation_read` locally.
+    for receipt in all_read.receipts {
+        let _ = ws_manager::send_text(&ws, serialize_ws_frame(&WsEvent::ReadReceipt(receipt)));
+    }
+    Ok(all_read.count)
+}
+
+#[tauri::command]
+pub async fn unread_counts(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<Vec<UnreadEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    unread_by_partner(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn fetch_thread(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    root_message_id: i64,
+) -> Result<Vec<MessageRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut rows = thread(&pool, root_message_id).await?;
+    encryption.reveal_all(&mut rows);
+    Ok(rows)
+}
+
+#[tauri::command]
+pub async fn forward_message(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    original_id: i64,
+    from_user: i64,
+    to_receiver: i64,
+    include_attribution: bool,
+) -> Result<MessageRow, AppError> {
+    let pool = db::pool(&db).await?;
+    forward(
+        &pool,
+        &encryption,
+        original_id,
+        from_user,
+        to_receiver,
+        include_attribution,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::count;
+    use crate::test_support::test_pool;
+
+    async fn send(
+        pool: &SqlitePool,
+        sender_id: i64,
+        receiver_id: i64,
+        content: &str,
+    ) -> MessageRow {
+        insert_message(
+            pool,
+            &EncryptionState::default(),
+            sender_id,
+            receiver_id,
+            content,
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn successive_pages_are_disjoint_and_newest_first() {
+        let pool = test_pool().await;
+        for i in 0..300 {
+            let (sender, receiver) = if i % 2 == 0 { (1, 2) } else { (2, 1) };
+            send(&pool, sender, receiver, &format!("m{}", i)).await;
+        }
+        send(&pool, 1, 3, "elsewhere").await;
+
+        let mut seen = Vec::new();
+        let mut before_id = None;
+        for _ in 0..3 {
+            let page = conversation_page(&pool, 1, 2, before_id, 100)
+                .await
+                .unwrap();
+            assert_eq!(page.len(), 100);
+            assert!(page.windows(2).all(|w| w[0].id > w[1].id));
+            before_id = page.last().map(|m| m.id);
+            seen.extend(page.into_iter().map(|m| m.id));
+        }
+        assert_eq!(seen, (1..=300).rev().collect::<Vec<i64>>());
+        assert!(conversation_page(&pool, 2, 1, before_id, 100)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn page_size_falls_back_to_default_and_is_capped() {
+        let pool = test_pool().await;
+        for i in 0..250 {
+            send(&pool, 1, 2, &format!("m{}", i)).await;
+        }
+        let default = conversation_page(&pool, 1, 2, None, 0).await.unwrap();
+        assert_eq!(default.len(), DEFAULT_PAGE_SIZE as usize);
+        let capped = conversation_page(&pool, 1, 2, None, 1000).await.unwrap();
+        assert_eq!(capped.len(), MAX_PAGE_SIZE as usize);
+    }
+
+    #[tokio::test]
+    async fn only_the_sender_can_delete_and_the_timestamp_is_kept() {
+        let pool = test_pool().await;
+        let message = send(&pool, 1, 2, "hi").await;
+
+        let err = mark_deleted(&pool, message.id, 2).await.unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+
+        let deleted = mark_deleted(&pool, message.id, 1).await.unwrap();
+        assert!(deleted.deleted);
+        assert_eq!(deleted.content, "");
+        assert_eq!(deleted.timestamp, message.timestamp);
+
+        let page = conversation_page(&pool, 1, 2, None, 0).await.unwrap();
+        assert_eq!(page[0].timestamp, message.timestamp);
+        assert!(page[0].deleted);
+    }
+
+    #[tokio::test]
+    async fn marking_one_conversation_read_leaves_others_unread() {
+        let pool = test_pool().await;
+        send(&pool, 2, 1, "a").await;
+        send(&pool, 2, 1, "b").await;
+        send(&pool, 3, 1, "c").await;
+        send(&pool, 1, 2, "mine").await;
+
+        let counts = |entries: Vec<UnreadEntry>| {
+            entries
+                .into_iter()
+                .map(|e| (e.partner_id, e.count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            counts(unread_by_partner(&pool, 1).await.unwrap()),
+            [(2, 2), (3, 1)]
+        );
+        assert_eq!(mark_read(&pool, 1, 2).await.unwrap(), 2);
+        assert_eq!(counts(unread_by_partner(&pool, 1).await.unwrap()), [(3, 1)]);
+        // User 2 still hasn't read what user 1 sent them.
+        assert_eq!(counts(unread_by_partner(&pool, 2).await.unwrap()), [(1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn forwarding_a_deleted_message_is_rejected() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let original = send(&pool, 1, 2, "hello").await;
+
+        let forwarded = forward(&pool, &encryption, original.id, 2, 3, true)
+            .await
+            .unwrap();
+        assert_eq!((forwarded.sender_id, forwarded.receiver_id), (2, 3));
+        assert_eq!(forwarded.forwarded_from, Some(original.id));
+        assert_eq!(forwarded.content, "Forwarded from @u1\nhello");
+        let plain = forward(&pool, &encryption, original.id, 1, 3, false)
+            .await
+            .unwrap();
+        assert_eq!(plain.content, "hello");
+
+        let err = forward(&pool, &encryption, original.id, 4, 3, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        let err = forward(&pool, &encryption, 9999, 1, 3, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_found");
+
+        mark_deleted(&pool, original.id, 1).await.unwrap();
+        let err = forward(&pool, &encryption, original.id, 1, 3, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    async fn send_once(
+        pool: &SqlitePool,
+        sender_id: i64,
+        receiver_id: i64,
+        client_msg_id: Option<&str>,
+    ) -> MessageRow {
+        insert_idempotent(
+            pool,
+            &EncryptionState::default(),
+            sender_id,
+            receiver_id,
+            "hi",
+            MessageType::Text,
+            None,
+            client_msg_id,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resubmitting_a_client_msg_id_returns_the_first_row() {
+        let pool = test_pool().await;
+        let first = send_once(&pool, 1, 2, Some("tmp-1")).await;
+        let retry = send_once(&pool, 1, 2, Some("tmp-1")).await;
+        assert_eq!(retry.id, first.id);
+        assert_eq!(retry.client_msg_id.as_deref(), Some("tmp-1"));
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 1);
+
+        // The id is only unique per sender.
+        let other_sender = send_once(&pool, 2, 1, Some("tmp-1")).await;
+        assert_ne!(other_sender.id, first.id);
+
+        send_once(&pool, 1, 2, None).await;
+        send_once(&pool, 1, 2, None).await;
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 4);
+    }
+
+    #[tokio::test]
+    async fn content_over_the_cap_is_rejected_on_send_and_edit() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let too_long = "a".repeat(MAX_MESSAGE_CHARS + 1);
+        let err = insert_message(&pool, &encryption, 1, 2, &too_long, MessageType::Text, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        let message = send(&pool, 1, 2, "hi").await;
+        let err = apply_edit(&pool, &encryption, message.id, 1, &too_long)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    #[tokio::test]
+    async fn the_cap_counts_graphemes_not_bytes() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        // A skin-toned thumbs up is one grapheme but two code points and 8 bytes.
+        let emoji = "👍🏽".repeat(MAX_MESSAGE_CHARS);
+        let message = send(&pool, 1, 2, &emoji).await;
+        assert_eq!(message.content, emoji);
+
+        let edited = apply_edit(&pool, &encryption, message.id, 1, &emoji)
+            .await
+            .unwrap();
+        assert_eq!(edited.content, emoji);
+    }
+
+    #[test]
+    fn length_info_reports_graphemes_and_bytes() {
+        let emoji = "👍🏽".repeat(MAX_MESSAGE_CHARS);
+        let info = length_info(&emoji, MAX_MESSAGE_CHARS);
+        assert_eq!(info.chars, MAX_MESSAGE_CHARS);
+        assert_eq!(info.bytes, 8 * MAX_MESSAGE_CHARS);
+        assert!(!info.exceeds_limit);
+        assert_eq!(length_info("e\u{301}", 1).chars, 1);
+    }
+
+    #[tokio::test]
+    async fn numbered_pages_report_the_total_and_what_is_left() {
+        let pool = test_pool().await;
+        for i in 0..25 {
+            let (sender, receiver) = if i % 2 == 0 { (1, 2) } else { (2, 1) };
+            send(&pool, sender, receiver, &format!("m{}", i)).await;
+        }
+        send(&pool, 1, 3, "elsewhere").await;
+
+        let first = numbered_page(&pool, 1, 2, 0, 10).await.unwrap();
+        assert_eq!(first.messages.len(), 10);
+        assert_eq!(first.total_count, 25);
+        assert!(first.has_more);
+        assert_eq!(first.messages[0].content, "m24");
+
+        // Either side of the conversation sees the same pages.
+        let last = numbered_page(&pool, 2, 1, 2, 10).await.unwrap();
+        assert_eq!(last.messages.len(), 5);
+        assert!(!last.has_more);
+
+        let exact = numbered_page(&pool, 1, 2, 0, 25).await.unwrap();
+        assert!(!exact.has_more);
+        let past_the_end = numbered_page(&pool, 1, 2, 1, 25).await.unwrap();
+        assert!(past_the_end.messages.is_empty());
+        assert!(!past_the_end.has_more);
+        let far = numbered_page(&pool, 1, 2, u32::MAX, 100).await.unwrap();
+        assert!(far.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn mark_all_read_only_touches_messages_to_the_user() {
+        let pool = test_pool().await;
+        for (sender, receiver) in [(2, 1), (2, 1), (3, 1), (1, 2), (1, 3), (4, 5)] {
+            send(&pool, sender, receiver, "hi").await;
+        }
+
+        let marked = mark_all_read_for(&pool, 1).await.unwrap();
+        assert_eq!(marked.count, 3);
+        let receipts: Vec<_> = marked
+            .receipts
+            .iter()
+            .map(|r| (r.message_id, r.reader_id))
+            .collect();
+        // One receipt per sender, for their latest message.
+        assert_eq!(receipts, [(2, 1), (3, 1)]);
+
+        let unread: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT sender_id, receiver_id FROM messages WHERE is_read = 0 ORDER BY id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(unread, [(1, 2), (1, 3), (4, 5)]);
+        assert_eq!(mark_all_read_for(&pool, 1).await.unwrap().count, 0);
+    }
+
+    #[tokio::test]
+    async fn replies_keep_their_quote_after_the_original_is_deleted() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let original = send(&pool, 1, 2, &"a".repeat(150)).await;
+        assert_eq!(original.quoted_snippet, None);
+        let reply = insert_message(
+            &pool,
+            &encryption,
+            2,
+            1,
+            "agreed",
+            MessageType::Text,
+            Some(original.id),
+        )
+        .await
+        .unwrap();
+        let quote = "a".repeat(QUOTED_SNIPPET_CHARS);
+        assert_eq!(reply.quoted_snippet.as_deref(), Some(quote.as_str()));
+
+        mark_deleted(&pool, original.id, 1).await.unwrap();
+        let rows = conversation_page(&pool, 1, 2, None, 10).await.unwrap();
+        let reply_row = rows.iter().find(|r| r.id == reply.id).unwrap();
+        assert_eq!(reply_row.quoted_snippet.as_deref(), Some(quote.as_str()));
+        let edited = apply_edit(&pool, &encryption, reply.id, 2, "agreed!")
+            .await
+            .unwrap();
+        assert_eq!(edited.quoted_snippet.as_deref(), Some(quote.as_str()));
+
+        // A reply to an already deleted message quotes nothing.
+        let late = insert_message(
+            &pool,
+            &encryption,
+            2,
+            1,
+            "late",
+            MessageType::Text,
+            Some(original.id),
+        )
+        .await
+        .unwrap();
+        assert_eq!(late.quoted_snippet, None);
+    }
+
+    #[test]
+    fn quoted_snippet_never_splits_a_grapheme() {
+        assert_eq!(quoted_snippet("👍🏽ab"), "👍🏽ab");
+        let emoji = "👍🏽".repeat(QUOTED_SNIPPET_CHARS + 5);
+        let snippet = quoted_snippet(&emoji);
+        assert!(emoji.starts_with(&snippet));
+        assert_eq!(
+            length_info(&snippet, usize::MAX).chars,
+            QUOTED_SNIPPET_CHARS
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_around_is_clamped_to_the_conversation() {
+        let pool = test_pool().await;
+        let mut ids = Vec::new();
+        for i in 0..10 {
+            let (sender, receiver) = if i % 2 == 0 { (1, 2) } else { (2, 1) };
+            ids.push(send(&pool, sender, receiver, &format!("m{}", i)).await.id);
+            send(&pool, 1, 3, "other").await;
+        }
+        let around = |user, partner, id, context| {
+            let pool = pool.clone();
+            async move {
+                messages_around(&pool, user, partner, id, context)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|m| m.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(around(2, 1, ids[0], 3).await, ids[..4]);
+        assert_eq!(around(1, 2, ids[5], 2).await, ids[3..8]);
+        assert_eq!(around(1, 2, ids[9], 3).await, ids[6..]);
+        assert_eq!(around(1, 2, ids[4], 0).await, [ids[4]]);
+
+        let err = messages_around(&pool, 1, 3, ids[4], 2).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn broadcasts_reach_contacts_that_accept_them() {
+        let pool = test_pool().await;
+        for (user_id, contact_id) in [(1, 2), (1, 3), (1, 4), (5, 1)] {
+            sqlx::query("INSERT INTO contacts (user_id, contact_id) VALUES (?, ?)")
+                .bind(user_id)
+                .bind(contact_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        for (user_id, blocked_id) in [(3, 1), (6, 1)] {
+            sqlx::query("INSERT INTO blocked_users (user_id, blocked_id) VALUES (?, ?)")
+                .bind(user_id)
+                .bind(blocked_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query(
+        "INSERT INTO archived_conversations (user_id, peer_id, peer_type) VALUES (2, '1', 'user')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+        let encryption = EncryptionState::default();
+
+        let report = broadcast(&pool, &encryption, 1, "news").await.unwrap();
+        assert_eq!((report.delivered, report.skipped), (2, 1));
+        let sent = "SELECT COUNT(*) FROM messages WHERE sender_id = 1 AND content = 'news'";
+        assert_eq!(count(&pool, sent).await, 2);
+        let skipped = "SELECT COUNT(*) FROM messages WHERE receiver_id IN (3, 5)";
+        assert_eq!(count(&pool, skipped).await, 0);
+        let unhashed = "SELECT COUNT(*) FROM messages WHERE content_hash IS NULL";
+        assert_eq!(count(&pool, unhashed).await, 0);
+        let archived = "SELECT COUNT(*) FROM archived_conversations";
+        assert_eq!(count(&pool, archived).await, 0);
+
+        let err = broadcast(&pool, &encryption, 1, "  ").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        let report = broadcast(&pool, &encryption, 7, "nobody").await.unwrap();
+        assert_eq!((report.delivered, report.skipped), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn only_new_messages_count_towards_the_rate_limit() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let buckets = RateLimitState::default();
+        let limit = RateLimit {
+            per_window: 2,
+            window: std::time::Duration::from_secs(3600),
+        };
+        sqlx::query("INSERT INTO blocked_users (user_id, blocked_id) VALUES (3, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let send = |receiver_id: i64, content: &'static str, client_msg_id: &'static str| {
+            insert_idempotent(
+                &pool,
+                &encryption,
+                1,
+                receiver_id,
+                content,
+                MessageType::Text,
+                None,
+                Some(client_msg_id),
+                None,
+                Some((&buckets, limit)),
+            )
+        };
+
+        let first = send(2, "hi", "a").await.unwrap();
+        // Retries, invalid messages and blocked sends are free.
+        for _ in 0..3 {
+            assert_eq!(send(2, "hi", "a").await.unwrap().id, first.id);
+        }
+        assert_eq!(send(2, "", "b").await.unwrap_err().code(), "validation");
+        assert_eq!(send(3, "hi", "c").await.unwrap_err().code(), "blocked");
+
+        send(2, "again", "d").await.unwrap();
+        let err = send(2, "one too many", "e").await.unwrap_err();
+        assert_eq!(err.code(), "rate_limited");
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 2);
+    }
+}
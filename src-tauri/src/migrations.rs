@@ -0,0 +1,804 @@
+use tauri_plugin_sql::{Migration, MigrationKind};
+
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: "create_initial_tables",
+            sql: "
+                CREATE TABLE IF NOT EXISTS users (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    username TEXT NOT NULL UNIQUE,
+                    avatar_url TEXT,
+                    status TEXT DEFAULT 'offline',
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                );
+                
+                CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    sender_id INTEGER NOT NULL,
+                    receiver_id INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    message_type TEXT DEFAULT 'text',
+                    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    is_read BOOLEAN DEFAULT FALSE,
+                    FOREIGN KEY (sender_id) REFERENCES users (id),
+                    FOREIGN KEY (receiver_id) REFERENCES users (id)
+                );
+                
+                CREATE TABLE IF NOT EXISTS contacts (
+                    user_id INTEGER NOT NULL,
+                    contact_id INTEGER NOT NULL,
+                    added_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, contact_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id),
+                    FOREIGN KEY (contact_id) REFERENCES users (id)
+                );
+                
+                CREATE TABLE IF NOT EXISTS auth_tokens (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id INTEGER NOT NULL,
+                    access_token TEXT NOT NULL,
+                    refresh_token TEXT,
+                    expires_at DATETIME NOT NULL,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 2,
+            description: "create_group_tables",
+            sql: "
+                CREATE TABLE IF NOT EXISTS groups (
+                    id TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    description TEXT,
+                    avatar_url TEXT,
+                    member_count INTEGER DEFAULT 0,
+                    created_by INTEGER NOT NULL,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (created_by) REFERENCES users (id)
+                );
+                
+                CREATE TABLE IF NOT EXISTS group_members (
+                    group_id TEXT NOT NULL,
+                    user_id INTEGER NOT NULL,
+                    role TEXT DEFAULT 'member',
+                    joined_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (group_id, user_id),
+                    FOREIGN KEY (group_id) REFERENCES groups (id),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+                
+                CREATE TABLE IF NOT EXISTS group_contacts (
+                    user_id INTEGER NOT NULL,
+                    group_id TEXT NOT NULL,
+                    joined_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, group_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id),
+                    FOREIGN KEY (group_id) REFERENCES groups (id)
+                );
+                
+                CREATE TABLE IF NOT EXISTS group_messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    group_id TEXT NOT NULL,
+                    sender_id INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    message_type TEXT DEFAULT 'text',
+                    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (group_id) REFERENCES groups (id),
+                    FOREIGN KEY (sender_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 3,
+            description: "create_message_edits",
+            sql: "
+                ALTER TABLE messages ADD COLUMN edited_at DATETIME;
+
+                CREATE TABLE IF NOT EXISTS message_edits (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    message_id INTEGER NOT NULL,
+                    previous_content TEXT NOT NULL,
+                    edited_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 4,
+            description: "add_message_soft_delete",
+            sql: "
+                ALTER TABLE messages ADD COLUMN deleted_at DATETIME;
+                ALTER TABLE group_messages ADD COLUMN deleted_at DATETIME;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 5,
+            description: "create_messages_fts",
+            sql: "
+                CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+                    content,
+                    content = 'messages',
+                    content_rowid = 'id'
+                );
+
+                INSERT INTO messages_fts (rowid, content) SELECT id, content FROM messages;
+
+                CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                    VALUES ('delete', old.id, old.content);
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                    VALUES ('delete', old.id, old.content);
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 6,
+            description: "create_message_delivery",
+            sql: "
+                CREATE TABLE IF NOT EXISTS message_delivery (
+                    message_id INTEGER NOT NULL,
+                    message_scope TEXT NOT NULL DEFAULT 'direct',
+                    user_id INTEGER NOT NULL,
+                    status TEXT NOT NULL DEFAULT 'sent',
+                    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (message_id, message_scope, user_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 7,
+            description: "create_message_reactions",
+            sql: "
+                CREATE TABLE IF NOT EXISTS message_reactions (
+                    message_id INTEGER NOT NULL,
+                    user_id INTEGER NOT NULL,
+                    emoji TEXT NOT NULL,
+                    reacted_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (message_id, user_id, emoji),
+                    FOREIGN KEY (message_id) REFERENCES messages (id),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 8,
+            description: "add_reply_threading",
+            sql: "
+                ALTER TABLE messages ADD COLUMN reply_to_id INTEGER REFERENCES messages (id);
+                ALTER TABLE group_messages ADD COLUMN reply_to_id INTEGER REFERENCES group_messages (id);
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 9,
+            description: "add_query_indexes",
+            sql: "
+                CREATE INDEX IF NOT EXISTS idx_messages_conversation
+                    ON messages (sender_id, receiver_id, id);
+                CREATE INDEX IF NOT EXISTS idx_messages_unread
+                    ON messages (receiver_id, is_read);
+                CREATE INDEX IF NOT EXISTS idx_group_messages_group
+                    ON group_messages (group_id, id);
+                CREATE INDEX IF NOT EXISTS idx_auth_tokens_user
+                    ON auth_tokens (user_id, expires_at);
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 10,
+            description: "create_attachments",
+            sql: "
+                CREATE TABLE IF NOT EXISTS attachments (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    message_id INTEGER NOT NULL,
+                    file_name TEXT NOT NULL,
+                    mime_type TEXT NOT NULL,
+                    byte_size INTEGER NOT NULL,
+                    local_path TEXT,
+                    remote_url TEXT,
+                    uploaded_at DATETIME,
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_attachments_message ON attachments (message_id);
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 11,
+            description: "create_blocked_users",
+            sql: "
+                CREATE TABLE IF NOT EXISTS blocked_users (
+                    user_id INTEGER NOT NULL,
+                    blocked_id INTEGER NOT NULL,
+                    blocked_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, blocked_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id),
+                    FOREIGN KEY (blocked_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 12,
+            description: "create_drafts",
+            sql: "
+                CREATE TABLE IF NOT EXISTS drafts (
+                    user_id INTEGER NOT NULL,
+                    peer_id TEXT NOT NULL,
+                    peer_type TEXT NOT NULL CHECK (peer_type IN ('user', 'group')),
+                    content TEXT NOT NULL,
+                    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, peer_id, peer_type),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 13,
+            description: "add_group_message_pins",
+            sql: "
+                ALTER TABLE group_messages ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0;
+                ALTER TABLE group_messages ADD COLUMN pinned_by INTEGER REFERENCES users (id);
+                ALTER TABLE group_messages ADD COLUMN pinned_at DATETIME;
+                CREATE INDEX IF NOT EXISTS idx_group_messages_pinned
+                    ON group_messages (group_id, pinned_at) WHERE pinned = 1;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 14,
+            description: "create_scheduled_messages",
+            sql: "
+                CREATE TABLE IF NOT EXISTS scheduled_messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    sender_id INTEGER NOT NULL,
+                    receiver_id INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    message_type TEXT DEFAULT 'text',
+                    reply_to_id INTEGER,
+                    send_at DATETIME NOT NULL,
+                    status TEXT NOT NULL DEFAULT 'pending'
+                        CHECK (status IN ('pending', 'sent', 'cancelled')),
+                    message_id INTEGER,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (sender_id) REFERENCES users (id),
+                    FOREIGN KEY (receiver_id) REFERENCES users (id),
+                    FOREIGN KEY (reply_to_id) REFERENCES messages (id),
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_scheduled_messages_due
+                    ON scheduled_messages (status, send_at);
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 15,
+            description: "add_message_encryption_flag",
+            sql: "
+                ALTER TABLE messages ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT 0;
+
+                -- Ciphertext is meaningless to the tokenizer, so only plaintext
+                -- rows are kept in the full-text index.
+                DROP TRIGGER IF EXISTS messages_fts_insert;
+                DROP TRIGGER IF EXISTS messages_fts_delete;
+                DROP TRIGGER IF EXISTS messages_fts_update;
+
+                CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages
+                WHEN new.encrypted = 0 BEGIN
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+
+                CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages
+                WHEN old.encrypted = 0 BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                    VALUES ('delete', old.id, old.content);
+                END;
+
+                CREATE TRIGGER messages_fts_update AFTER UPDATE OF content, encrypted ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                    SELECT 'delete', old.id, old.content WHERE old.encrypted = 0;
+                    INSERT INTO messages_fts (rowid, content)
+                    SELECT new.id, new.content WHERE new.encrypted = 0;
+                END;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 16,
+            description: "create_group_invites",
+            sql: "
+                CREATE TABLE IF NOT EXISTS group_invites (
+                    token TEXT PRIMARY KEY,
+                    group_id TEXT NOT NULL,
+                    created_by INTEGER NOT NULL,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    expires_at DATETIME NOT NULL,
+                    max_uses INTEGER,
+                    use_count INTEGER NOT NULL DEFAULT 0,
+                    FOREIGN KEY (group_id) REFERENCES groups (id),
+                    FOREIGN KEY (created_by) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 17,
+            description: "create_starred_messages",
+            sql: "
+                CREATE TABLE IF NOT EXISTS starred_messages (
+                    user_id INTEGER NOT NULL,
+                    message_id INTEGER NOT NULL,
+                    message_scope TEXT NOT NULL DEFAULT 'direct',
+                    starred_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, message_id, message_scope),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 18,
+            description: "add_users_last_seen",
+            sql: "
+                ALTER TABLE users ADD COLUMN last_seen_at DATETIME;
+                ALTER TABLE users ADD COLUMN hide_last_seen BOOLEAN NOT NULL DEFAULT 0;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 19,
+            description: "add_messages_forwarded_from",
+            sql: "
+                ALTER TABLE messages ADD COLUMN forwarded_from INTEGER REFERENCES messages (id);
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 20,
+            description: "create_mentions",
+            sql: "
+                CREATE TABLE IF NOT EXISTS mentions (
+                    message_id INTEGER NOT NULL,
+                    mentioned_user_id INTEGER NOT NULL,
+                    is_read BOOLEAN NOT NULL DEFAULT 0,
+                    PRIMARY KEY (message_id, mentioned_user_id),
+                    FOREIGN KEY (message_id) REFERENCES group_messages (id),
+                    FOREIGN KEY (mentioned_user_id) REFERENCES users (id)
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_mentions_user
+                    ON mentions (mentioned_user_id, is_read);
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 21,
+            description: "create_group_settings",
+            sql: "
+                CREATE TABLE IF NOT EXISTS group_settings (
+                    user_id INTEGER NOT NULL,
+                    group_id TEXT NOT NULL,
+                    muted BOOLEAN NOT NULL DEFAULT 0,
+                    muted_until DATETIME,
+                    PRIMARY KEY (user_id, group_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id),
+                    FOREIGN KEY (group_id) REFERENCES groups (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 22,
+            description: "add_messages_client_msg_id",
+            sql: "
+                ALTER TABLE messages ADD COLUMN client_msg_id TEXT;
+
+                CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_client_msg_id
+                    ON messages (sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 23,
+            description: "add_users_avatar_local_path",
+            sql: "
+                ALTER TABLE users ADD COLUMN avatar_local_path TEXT;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 24,
+            description: "create_group_message_edits",
+            sql: "
+                ALTER TABLE group_messages ADD COLUMN edited_at DATETIME;
+
+                CREATE TABLE IF NOT EXISTS group_message_edits (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    message_id INTEGER NOT NULL,
+                    previous_content TEXT NOT NULL,
+                    edited_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (message_id) REFERENCES group_messages (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 25,
+            description: "rebuild_contacts_with_primary_key",
+            // Databases created before the schema settled can have a contacts
+            // table without its primary key, and with it duplicate pairs.
+            // Rebuild it, keeping the earliest row of each pair.
+            sql: "
+                CREATE TABLE contacts_rebuilt (
+                    user_id INTEGER NOT NULL,
+                    contact_id INTEGER NOT NULL,
+                    added_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, contact_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id),
+                    FOREIGN KEY (contact_id) REFERENCES users (id)
+                );
+
+                INSERT INTO contacts_rebuilt (user_id, contact_id, added_at)
+                SELECT user_id, contact_id, MIN(added_at)
+                FROM contacts
+                GROUP BY user_id, contact_id;
+
+                DROP TABLE contacts;
+                ALTER TABLE contacts_rebuilt RENAME TO contacts;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 26,
+            description: "create_conversation_keys",
+            sql: "
+                CREATE TABLE IF NOT EXISTS public_keys (
+                    user_id INTEGER PRIMARY KEY,
+                    public_key TEXT NOT NULL,
+                    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+
+                CREATE TABLE IF NOT EXISTS conversation_keys (
+                    conversation_id TEXT NOT NULL,
+                    user_id INTEGER NOT NULL,
+                    wrapped_key TEXT NOT NULL,
+                    wrapped_by INTEGER NOT NULL,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (conversation_id, user_id),
+                    FOREIGN KEY (user_id) REFERENCES users (id),
+                    FOREIGN KEY (wrapped_by) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 27,
+            description: "create_archived_conversations",
+            sql: "
+                CREATE TABLE IF NOT EXISTS archived_conversations (
+                    user_id INTEGER NOT NULL,
+                    peer_id TEXT NOT NULL,
+                    peer_type TEXT NOT NULL CHECK (peer_type IN ('user', 'group')),
+                    archived_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, peer_id, peer_type),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 28,
+            description: "create_outbox",
+            sql: "
+                CREATE TABLE IF NOT EXISTS outbox (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    frame TEXT NOT NULL,
+                    status TEXT NOT NULL DEFAULT 'pending'
+                        CHECK (status IN ('pending', 'failed')),
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    last_attempt_at DATETIME
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_outbox_status ON outbox (status, id);
+            ",
+            kind: MigrationKind::Up,
+        },
+        // SQLite can't add a CHECK constraint to an existing table, and
+        // rebuilding `messages` would mean rebuilding everything that
+        // references it; triggers enforce the same rule on every database.
+        // Keep the list in step with `MessageType`.
+        Migration {
+            version: 29,
+            description: "check_message_types",
+            sql: "
+                CREATE TRIGGER IF NOT EXISTS messages_type_check_insert
+                BEFORE INSERT ON messages
+                WHEN NEW.message_type NOT IN ('text', 'image', 'file', 'system', 'voice')
+                BEGIN
+                    SELECT RAISE(ABORT, 'invalid message_type');
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS messages_type_check_update
+                BEFORE UPDATE OF message_type ON messages
+                WHEN NEW.message_type NOT IN ('text', 'image', 'file', 'system', 'voice')
+                BEGIN
+                    SELECT RAISE(ABORT, 'invalid message_type');
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS group_messages_type_check_insert
+                BEFORE INSERT ON group_messages
+                WHEN NEW.message_type NOT IN ('text', 'image', 'file', 'system', 'voice')
+                BEGIN
+                    SELECT RAISE(ABORT, 'invalid message_type');
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS group_messages_type_check_update
+                BEFORE UPDATE OF message_type ON group_messages
+                WHEN NEW.message_type NOT IN ('text', 'image', 'file', 'system', 'voice')
+                BEGIN
+                    SELECT RAISE(ABORT, 'invalid message_type');
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS scheduled_messages_type_check_insert
+                BEFORE INSERT ON scheduled_messages
+                WHEN NEW.message_type NOT IN ('text', 'image', 'file', 'system', 'voice')
+                BEGIN
+                    SELECT RAISE(ABORT, 'invalid message_type');
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS scheduled_messages_type_check_update
+                BEFORE UPDATE OF message_type ON scheduled_messages
+                WHEN NEW.message_type NOT IN ('text', 'image', 'file', 'system', 'voice')
+                BEGIN
+                    SELECT RAISE(ABORT, 'invalid message_type');
+                END;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 30,
+            description: "create_user_preferences",
+            sql: "
+                CREATE TABLE IF NOT EXISTS user_preferences (
+                    user_id INTEGER NOT NULL,
+                    key TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, key),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 31,
+            description: "add_voice_attachment_metadata",
+            sql: "
+                ALTER TABLE attachments ADD COLUMN duration_ms INTEGER;
+                ALTER TABLE attachments ADD COLUMN waveform TEXT;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 32,
+            description: "create_group_messages_fts",
+            sql: "
+                CREATE VIRTUAL TABLE IF NOT EXISTS group_messages_fts USING fts5(
+                    content,
+                    content = 'group_messages',
+                    content_rowid = 'id'
+                );
+
+                INSERT INTO group_messages_fts (rowid, content)
+                SELECT id, content FROM group_messages;
+
+                CREATE TRIGGER IF NOT EXISTS group_messages_fts_insert AFTER INSERT ON group_messages
+                BEGIN
+                    INSERT INTO group_messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS group_messages_fts_delete AFTER DELETE ON group_messages
+                BEGIN
+                    INSERT INTO group_messages_fts (group_messages_fts, rowid, content)
+                    VALUES ('delete', old.id, old.content);
+                END;
+
+                CREATE TRIGGER IF NOT EXISTS group_messages_fts_update
+                AFTER UPDATE OF content ON group_messages BEGIN
+                    INSERT INTO group_messages_fts (group_messages_fts, rowid, content)
+                    VALUES ('delete', old.id, old.content);
+                    INSERT INTO group_messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 33,
+            description: "create_message_links",
+            sql: "
+                CREATE TABLE IF NOT EXISTS message_links (
+                    token TEXT PRIMARY KEY,
+                    message_id INTEGER NOT NULL,
+                    created_by INTEGER NOT NULL,
+                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    UNIQUE (message_id, created_by),
+                    FOREIGN KEY (message_id) REFERENCES messages (id),
+                    FOREIGN KEY (created_by) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        // Existing rows are hashed by `integrity::backfill_hashes`.
+        Migration {
+            version: 34,
+            description: "add_message_content_hash",
+            sql: "
+                ALTER TABLE messages ADD COLUMN content_hash TEXT;
+            ",
+            kind: MigrationKind::Up,
+        },
+        // Keep in step with `users::SYSTEM_USER_ID`.
+        Migration {
+            version: 35,
+            description: "create_system_user",
+            sql: "
+                INSERT OR IGNORE INTO users (id, username, status) VALUES (0, '~system', 'offline');
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 36,
+            description: "add_message_quoted_snippet",
+            sql: "
+                ALTER TABLE messages ADD COLUMN quoted_snippet TEXT;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 37,
+            description: "create_link_previews",
+            sql: "
+                CREATE TABLE IF NOT EXISTS link_previews (
+                    url TEXT PRIMARY KEY,
+                    title TEXT,
+                    description TEXT,
+                    image_url TEXT,
+                    fetched_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        // Keep in step with `users::DELETED_USER_ID`.
+        Migration {
+            version: 38,
+            description: "create_deleted_user",
+            sql: "
+                INSERT OR IGNORE INTO users (id, username, status) VALUES (-1, '~deleted', 'offline');
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 39,
+            description: "add_message_expiry",
+            sql: "
+                ALTER TABLE messages ADD COLUMN expires_at DATETIME;
+                ALTER TABLE group_messages ADD COLUMN expires_at DATETIME;
+                CREATE INDEX IF NOT EXISTS idx_messages_expires_at
+                    ON messages (expires_at) WHERE expires_at IS NOT NULL;
+                CREATE INDEX IF NOT EXISTS idx_group_messages_expires_at
+                    ON group_messages (expires_at) WHERE expires_at IS NOT NULL;
+                CREATE TABLE IF NOT EXISTS conversation_ttls (
+                    user_id INTEGER NOT NULL,
+                    peer_id TEXT NOT NULL,
+                    peer_type TEXT NOT NULL CHECK (peer_type IN ('user', 'group')),
+                    ttl_secs INTEGER NOT NULL CHECK (ttl_secs > 0),
+                    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (user_id, peer_id, peer_type),
+                    FOREIGN KEY (user_id) REFERENCES users (id)
+                );
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 40,
+            description: "add_users_invisible",
+            sql: "
+                ALTER TABLE users ADD COLUMN invisible BOOLEAN NOT NULL DEFAULT 0;
+            ",
+            kind: MigrationKind::Up,
+        },
+        Migration {
+            version: 41,
+            description: "add_conversation_ids",
+            sql: "
+                ALTER TABLE drafts ADD COLUMN conversation_id TEXT GENERATED ALWAYS AS (
+                    CASE WHEN peer_type = 'user' THEN
+                        MIN(user_id, CAST(peer_id AS INTEGER)) || ':'
+                            || MAX(user_id, CAST(peer_id AS INTEGER))
+                    END
+                ) VIRTUAL;
+                ALTER TABLE archived_conversations ADD COLUMN conversation_id TEXT GENERATED ALWAYS AS (
+                    CASE WHEN peer_type = 'user' THEN
+                        MIN(user_id, CAST(peer_id AS INTEGER)) || ':'
+                            || MAX(user_id, CAST(peer_id AS INTEGER))
+                    END
+                ) VIRTUAL;
+                CREATE INDEX IF NOT EXISTS idx_drafts_conversation
+                    ON drafts (user_id, conversation_id);
+                CREATE INDEX IF NOT EXISTS idx_archived_conversations_conversation
+                    ON archived_conversations (user_id, conversation_id);
+            ",
+            kind: MigrationKind::Up,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{conversation_page, conversation_page_sql};
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn conversation_fetch_uses_the_conversation_index() {
+        let pool = test_pool().await;
+        // 100k messages spread over the ten conversations between users 1 to
+        // 5, sent both ways.
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i < 99999)
+             INSERT INTO messages (sender_id, receiver_id, content)
+             SELECT 1 + i % 5, 1 + (i % 5 + 1 + i / 5 % 4) % 5, 'message ' || i FROM n",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let plan: Vec<(i64, i64, i64, String)> =
+            sqlx::query_as(&format!("EXPLAIN QUERY PLAN {}", conversation_page_sql()))
+                .bind(1)
+                .bind(2)
+                .bind(None::<i64>)
+                .bind(50)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert!(
+            plan.iter()
+                .any(|(_, _, _, detail)| detail.contains("idx_messages_conversation")),
+            "{:?}",
+            plan
+        );
+
+        let page = conversation_page(&pool, 1, 2, None, 50).await.unwrap();
+        assert_eq!(page.len(), 50);
+        assert!(page.iter().any(|m| m.sender_id == 1 && m.receiver_id == 2));
+        assert!(page.iter().any(|m| m.sender_id == 2 && m.receiver_id == 1));
+        assert!(page
+            .iter()
+            .all(|m| [(1, 2), (2, 1)].contains(&(m.sender_id, m.receiver_id))));
+    }
+}
@@ -0,0 +1,214 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Manager, Runtime, State};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_sql::DbInstances;
+
+use crate::error::{AppError, Context};
+use crate::preview::truncate_preview;
+use crate::{db, group_settings};
+
+/// Longest message preview shown in a notification body, in graphemes.
+pub const PREVIEW_CHARS: usize = 80;
+
+pub struct NotificationSettings {
+    enabled: AtomicBool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+        }
+    }
+}
+
+impl NotificationSettings {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+async fn sender_name<R: Runtime>(app: &AppHandle<R>, sender_id: i64) -> Option<String> {
+    let pool = db::pool(&app.state::<DbInstances>()).await.ok()?;
+    sqlx::query_scalar("SELECT username FROM users WHERE id = ?")
+        .bind(sender_id)
+        .fetch_optional(&pool)
+        .await
+        .ok()?
+}
+
+/// Shows a native notification for an inbound direct message, unless
+/// notifications are muted or the message came from the local user.
+pub(crate) async fn notify_new_message<R: Runtime>(
+    app: &AppHandle<R>,
+    local_user: Option<i64>,
+    sender_id: i64,
+    sender_username: Option<String>,
+    content: &str,
+) {
+    if !app.state::<NotificationSettings>().enabled() || local_user == Some(sender_id) {
+        return;
+    }
+
+    let title = match sender_username {
+        Some(name) => name,
+        None => sender_name(app, sender_id)
+            .await
+            .unwrap_or_else(|| format!("User {}", sender_id)),
+    };
+
+    let _ = app
+        .notification()
+        .builder()
+        .title(title)
+        .body(truncate_preview(content, PREVIEW_CHARS))
+        .show();
+}
+
+/// Shows a native notification when a group message mentions the local user,
+/// unless they have muted the group.
+pub(crate) async fn notify_mention<R: Runtime>(
+    app: &AppHandle<R>,
+    local_user: Option<i64>,
+    group_id: &str,
+    mentioned: &[i64],
+    sender_id: i64,
+    content: &str,
+) {
+    let Some(local_user) = local_user else {
+        return;
+    };
+    if !app.state::<NotificationSettings>().enabled()
+        || local_user == sender_id
+        || !mentioned.contains(&local_user)
+    {
+        return;
+    }
+    let Ok(pool) = db::pool(&app.state::<DbInstances>()).await else {
+        return;
+    };
+    if group_settings::is_muted(&pool, local_user, group_id)
+        .await
+        .unwrap_or(false)
+    {
+        return;
+    }
+
+    let sender = sender_name(app, sender_id)
+        .await
+        .unwrap_or_else(|| format!("User {}", sender_id));
+
+    let _ = app
+        .notification()
+        .builder()
+        .title(format!("{} mentioned you", sender))
+        .body(truncate_preview(content, PREVIEW_CHARS))
+        .show();
+}
+
+/// The badge number: unread direct messages plus unread mentions in groups
+/// the user hasn't muted.
+pub(crate) async fn unread_total(pool: &SqlitePool, user_id: i64) -> Result<u32, AppError> {
+    let total: i64 = sqlx::query_scalar(
+        "SELECT
+             (SELECT COUNT(*) FROM messages
+              WHERE receiver_id = ?1 AND is_read = 0 AND deleted_at IS NULL
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP))
+           + (SELECT COUNT(*) FROM mentions mn
+              JOIN group_messages gm ON gm.id = mn.message_id
+              WHERE mn.mentioned_user_id = ?1 AND mn.is_read = 0 AND gm.deleted_at IS NULL
+                AND (gm.expires_at IS NULL OR gm.expires_at > CURRENT_TIMESTAMP)
+                AND NOT EXISTS (
+                    SELECT 1 FROM group_settings s
+                    WHERE s.user_id = ?1 AND s.group_id = gm.group_id AND s.muted = 1
+                      AND (s.muted_until IS NULL OR s.muted_until > datetime('now'))
+                ))",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .context("failed to count unread messages")?;
+    Ok(total as u32)
+}
+
+/// Sets the dock/taskbar badge to the local user's unread total, clearing it
+/// at zero.
+pub(crate) async fn refresh_badge<R: Runtime>(app: &AppHandle<R>, user_id: i64) {
+    let Ok(pool) = db::pool(&app.state::<DbInstances>()).await else {
+        return;
+    };
+    let Ok(total) = unread_total(&pool, user_id).await else {
+        return;
+    };
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.set_badge_count((total > 0).then_some(i64::from(total)));
+    }
+}
+
+#[tauri::command]
+pub async fn total_unread(db: State<'_, DbInstances>, user_id: i64) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    unread_total(&pool, user_id).await
+}
+
+#[tauri::command]
+pub fn set_notifications_enabled(settings: State<'_, NotificationSettings>, enabled: bool) {
+    settings.enabled.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::group_messages::insert_group_message;
+    use crate::groups::insert_group;
+    use crate::message_type::MessageType;
+    use crate::messages::insert_message;
+    use crate::test_support::test_pool;
+
+    async fn direct(pool: &SqlitePool, sender_id: i64, receiver_id: i64) {
+        let encryption = EncryptionState::default();
+        insert_message(
+            pool,
+            &encryption,
+            sender_id,
+            receiver_id,
+            "hi",
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn in_group(pool: &SqlitePool, group_id: &str, content: &str) {
+        insert_group_message(pool, group_id, 2, content, MessageType::Text, None, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unread_total_counts_direct_messages_and_unmuted_mentions() {
+        let pool = test_pool().await;
+        for _ in 0..3 {
+            direct(&pool, 2, 1).await;
+        }
+        direct(&pool, 1, 2).await;
+        assert_eq!(unread_total(&pool, 1).await.unwrap(), 3);
+        assert_eq!(unread_total(&pool, 2).await.unwrap(), 1);
+
+        let loud = insert_group(&pool, "loud", None, 2, &[1]).await.unwrap();
+        let quiet = insert_group(&pool, "quiet", None, 2, &[1]).await.unwrap();
+        in_group(&pool, &loud.id, "hey @u1").await;
+        in_group(&pool, &loud.id, "no mention").await;
+        in_group(&pool, &quiet.id, "@u1 in here too").await;
+        assert_eq!(unread_total(&pool, 1).await.unwrap(), 5);
+
+        group_settings::set_muted(&pool, 1, &quiet.id, None)
+            .await
+            .unwrap();
+        assert_eq!(unread_total(&pool, 1).await.unwrap(), 4);
+    }
+}
@@ -0,0 +1,140 @@
+//! Frames waiting to go out over the websocket. They are kept in the
+//! database until the server acknowledges them, so a dropped connection (or
+//! a restart) doesn't lose them.
+//!
+//! A frame is sent with its outbox id added as a top-level `id` field, and
+//! the server answers with an `ack` frame carrying that id. Unacknowledged
+//! frames are resent after `ACK_TIMEOUT` and on every reconnect; once
+//! `MAX_OUTBOX_ATTEMPTS` sends have gone unanswered the frame is marked
+//! `failed`.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::SqlitePool;
+
+use crate::error::{AppError, Context};
+
+pub const MAX_OUTBOX_ATTEMPTS: i64 = 5;
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct OutboxFrame {
+    pub id: i64,
+    pub frame: String,
+    pub status: String,
+    pub attempts: i64,
+    pub created_at: String,
+    pub last_attempt_at: Option<String>,
+}
+
+const OUTBOX_COLUMNS: &str = "id, frame, status, attempts, created_at, last_attempt_at";
+
+/// `ACK_TIMEOUT` as an SQLite date modifier.
+fn ack_cutoff() -> String {
+    format!("-{} seconds", ACK_TIMEOUT.as_secs())
+}
+
+/// Queues `frame`, which must be a JSON object with a `type`.
+pub(crate) async fn enqueue(pool: &SqlitePool, frame: &str) -> Result<i64, AppError> {
+    match serde_json::from_str::<Value>(frame) {
+        Ok(Value::Object(fields)) if fields.get("type").is_some_and(Value::is_string) => {}
+        _ => {
+            return Err(AppError::Validation(
+                "a frame must be a JSON object with a string 'type'".into(),
+            ))
+        }
+    }
+
+    sqlx::query_scalar("INSERT INTO outbox (frame) VALUES (?) RETURNING id")
+        .bind(frame)
+        .fetch_one(pool)
+        .await
+        .context("failed to queue frame")
+}
+
+/// The frame as sent, with its outbox id stamped in.
+pub(crate) fn stamped(frame: &OutboxFrame) -> String {
+    match serde_json::from_str::<Value>(&frame.frame) {
+        Ok(Value::Object(mut fields)) => {
+            fields.insert("id".into(), Value::from(frame.id));
+            Value::Object(fields).to_string()
+        }
+        _ => frame.frame.clone(),
+    }
+}
+
+/// Pending frames that have never been sent, or whose last send went
+/// unacknowledged for `ACK_TIMEOUT`, oldest first.
+pub(crate) async fn due(pool: &SqlitePool) -> Result<Vec<OutboxFrame>, AppError> {
+    sqlx::query_as::<_, OutboxFrame>(&format!(
+        "SELECT {} FROM outbox
+         WHERE status = 'pending' AND attempts < ?1
+           AND (last_attempt_at IS NULL OR last_attempt_at <= datetime('now', ?2))
+         ORDER BY id",
+        OUTBOX_COLUMNS
+    ))
+    .bind(MAX_OUTBOX_ATTEMPTS)
+    .bind(ack_cutoff())
+    .fetch_all(pool)
+    .await
+    .context("failed to load outbox")
+}
+
+pub(crate) async fn mark_attempted(pool: &SqlitePool, id: i64) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE outbox SET attempts = attempts + 1, last_attempt_at = CURRENT_TIMESTAMP
+         WHERE id = ?",
+    )
+    .bind(id)
+    .execute(pool)
+    .await
+    .context("failed to update outbox")?;
+    Ok(())
+}
+
+/// Makes every pending frame due again. Called on connect: whatever was
+/// sent on the previous connection and not acknowledged may never have
+/// arrived.
+pub(crate) async fn make_all_due(pool: &SqlitePool) -> Result<(), AppError> {
+    sqlx::query("UPDATE outbox SET last_attempt_at = NULL WHERE status = 'pending'")
+        .execute(pool)
+        .await
+        .context("failed to update outbox")?;
+    Ok(())
+}
+
+/// Removes an acknowledged frame.
+pub(crate) async fn ack(pool: &SqlitePool, id: i64) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM outbox WHERE id = ? AND status = 'pending'")
+        .bind(id)
+        .execute(pool)
+        .await
+        .context("failed to acknowledge frame")?;
+    Ok(())
+}
+
+/// Marks frames that are out of attempts as `failed` and returns them.
+pub(crate) async fn fail_exhausted(pool: &SqlitePool) -> Result<Vec<OutboxFrame>, AppError> {
+    sqlx::query_as::<_, OutboxFrame>(&format!(
+        "UPDATE outbox SET status = 'failed'
+         WHERE status = 'pending' AND attempts >= ?1
+           AND (last_attempt_at IS NULL OR last_attempt_at <= datetime('now', ?2))
+         RETURNING {}",
+        OUTBOX_COLUMNS
+    ))
+    .bind(MAX_OUTBOX_ATTEMPTS)
+    .bind(ack_cutoff())
+    .fetch_all(pool)
+    .await
+    .context("failed to update outbox")
+}
+
+pub(crate) async fn pending_count(pool: &SqlitePool) -> Result<u32, AppError> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM outbox WHERE status = 'pending'")
+        .fetch_one(pool)
+        .await
+        .context("failed to count outbox")?;
+    Ok(count as u32)
+}
@@ -0,0 +1,146 @@
+//! Fallback for networks that block the websocket. Once the socket has been
+//! down for `POLL_FALLBACK_AFTER`, new direct messages are read from the
+//! database every `POLL_INTERVAL` and fed through the same handler as socket
+//! frames, so the frontend sees the same `ws-message` events either way.
+//!
+//! `WsState` keeps the highest local message id delivered by either path,
+//! which is what stops polling and a reconnected socket from repeating each
+//! other. Socket frames are matched to their local row by client id.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Manager, Runtime, State};
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::encryption::EncryptionState;
+use crate::error::{AppError, Context};
+use crate::messages::{MessageRow, MAX_PAGE_SIZE, MESSAGE_COLUMNS};
+use crate::protocol::{serialize_ws_frame, NewMessageEvent, WsEvent};
+use crate::ws_manager::{self, WsState};
+
+pub const POLL_FALLBACK_AFTER: Duration = Duration::from_secs(60);
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Messages received by `user_id` after `since_id`, oldest first.
+pub(crate) async fn messages_since(
+    pool: &SqlitePool,
+    user_id: i64,
+    since_id: i64,
+) -> Result<Vec<MessageRow>, AppError> {
+    sqlx::query_as::<_, MessageRow>(&format!(
+        "SELECT {} FROM messages
+         WHERE receiver_id = ? AND id > ? AND deleted_at IS NULL
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY id
+         LIMIT ?",
+        MESSAGE_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(since_id)
+    .bind(MAX_PAGE_SIZE)
+    .fetch_all(pool)
+    .await
+    .context("failed to load new messages")
+}
+
+async fn latest_received_id(pool: &SqlitePool, user_id: i64) -> Result<i64, AppError> {
+    sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM messages WHERE receiver_id = ?")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .context("failed to load latest message id")
+}
+
+/// The `message` frame the server would have sent for `row`.
+pub(crate) fn frame_for(row: &MessageRow) -> String {
+    serialize_ws_frame(&WsEvent::NewMessage(NewMessageEvent {
+        id: Some(row.id),
+        sender_id: row.sender_id,
+        sender_username: None,
+        receiver_id: Some(row.receiver_id),
+        content: row.content.clone(),
+        message_type: Some(row.message_type.clone()),
+        client_msg_id: row.client_msg_id.clone(),
+    }))
+}
+
+/// Where polling picks up for the signed-in user.
+struct PollCursor {
+    user_id: i64,
+    since_id: i64,
+}
+
+async fn poll_once<R: Runtime>(
+    app: &AppHandle<R>,
+    cursor: &mut Option<PollCursor>,
+) -> Result<(), AppError> {
+    let (user_id, offline_for, last_delivered) = {
+        let state = app.state::<Mutex<WsState>>();
+        let state = state.lock()?;
+        let Some(user_id) = state.user_id() else {
+            return Ok(());
+        };
+        (
+            user_id,
+            state.offline_for(Instant::now()),
+            state.last_delivered_id(),
+        )
+    };
+    let pool = db::pool(&app.state::<DbInstances>()).await?;
+
+    // While the socket is up it does the delivering, so the cursor follows
+    // the newest message and a later fallback starts from there rather than
+    // from history. It stays put once the socket drops, so whatever arrives
+    // during the grace period is still picked up.
+    let stale = cursor.as_ref().is_none_or(|c| c.user_id != user_id);
+    if stale || offline_for.is_none() {
+        let since_id = latest_received_id(&pool, user_id).await?;
+        *cursor = Some(PollCursor { user_id, since_id });
+        return Ok(());
+    }
+    if offline_for.is_some_and(|down| down < POLL_FALLBACK_AFTER) {
+        return Ok(());
+    }
+    let Some(cursor) = cursor.as_mut() else {
+        return Ok(());
+    };
+
+    let since_id = cursor.since_id.max(last_delivered.unwrap_or(0));
+    let mut rows = messages_since(&pool, user_id, since_id).await?;
+    app.state::<EncryptionState>().reveal_all(&mut rows);
+    for row in &rows {
+        ws_manager::handle_polled(app, row.id, &frame_for(row));
+        cursor.since_id = row.id;
+    }
+    Ok(())
+}
+
+/// Checks every `POLL_INTERVAL` whether the fallback should be polling.
+pub fn spawn_poller(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut cursor = None;
+        loop {
+            interval.tick().await;
+            if let Err(e) = poll_once(&app, &mut cursor).await {
+                tracing::warn!("message polling failed: {}", e);
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn poll_new_messages(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_id: i64,
+    since_id: i64,
+) -> Result<Vec<MessageRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut rows = messages_since(&pool, user_id, since_id).await?;
+    encryption.reveal_all(&mut rows);
+    Ok(rows)
+}
@@ -0,0 +1,175 @@
+//! Per-user settings such as theme, notification sound and language. Values
+//! are arbitrary JSON, stored as text.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+
+pub const MAX_PREFERENCES_PER_USER: i64 = 200;
+pub const MAX_PREFERENCE_KEY_LEN: usize = 64;
+
+fn validate_key(key: &str) -> Result<(), AppError> {
+    if key.trim().is_empty() {
+        return Err(AppError::Validation(
+            "preference key must not be empty".into(),
+        ));
+    }
+    if key.len() > MAX_PREFERENCE_KEY_LEN {
+        return Err(AppError::Validation(format!(
+            "preference key must be at most {} bytes",
+            MAX_PREFERENCE_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Sets `key` to the JSON in `value`, replacing any earlier value. A new key
+/// is refused once the user has `MAX_PREFERENCES_PER_USER` of them.
+pub(crate) async fn upsert_preference(
+    pool: &SqlitePool,
+    user_id: i64,
+    key: &str,
+    value: &str,
+) -> Result<Value, AppError> {
+    validate_key(key)?;
+    let parsed: Value = serde_json::from_str(value)
+        .map_err(|e| AppError::Validation(format!("preference value is not valid JSON: {}", e)))?;
+
+    let mut tx = pool.begin().await?;
+    let (count, exists): (i64, bool) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(key = ?2), 0) > 0
+         FROM user_preferences WHERE user_id = ?1",
+    )
+    .bind(user_id)
+    .bind(key)
+    .fetch_one(&mut *tx)
+    .await
+    .context("failed to count preferences")?;
+    if !exists && count >= MAX_PREFERENCES_PER_USER {
+        return Err(AppError::Validation(format!(
+            "a user can have at most {} preferences",
+            MAX_PREFERENCES_PER_USER
+        )));
+    }
+
+    sqlx::query(
+        "INSERT INTO user_preferences (user_id, key, value) VALUES (?, ?, ?)
+         ON CONFLICT (user_id, key) DO UPDATE SET
+             value = excluded.value,
+             updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(parsed.to_string())
+    .execute(&mut *tx)
+    .await
+    .context("failed to save preference")?;
+    tx.commit().await?;
+
+    Ok(parsed)
+}
+
+pub(crate) async fn preferences_of(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<HashMap<String, Value>, AppError> {
+    let rows: Vec<(String, String)> =
+        sqlx::query_as("SELECT key, value FROM user_preferences WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+            .context("failed to load preferences")?;
+
+    rows.into_iter()
+        .map(|(key, value)| {
+            let value = serde_json::from_str(&value).map_err(|e| {
+                AppError::Internal(format!("stored preference '{}' is corrupt: {}", key, e))
+            })?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub async fn set_preference(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    key: String,
+    value: String,
+) -> Result<Value, AppError> {
+    let pool = db::pool(&db).await?;
+    upsert_preference(&pool, user_id, &key, &value).await
+}
+
+#[tauri::command]
+pub async fn get_preferences(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+) -> Result<HashMap<String, Value>, AppError> {
+    let pool = db::pool(&db).await?;
+    preferences_of(&pool, user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn nested_values_round_trip_and_overwrite() {
+        let pool = test_pool().await;
+        let nested = r#"{"theme":{"name":"dark","accent":[1,2,3]},"enabled":true}"#;
+        upsert_preference(&pool, 1, "appearance", nested)
+            .await
+            .unwrap();
+        upsert_preference(&pool, 1, "language", r#""en""#)
+            .await
+            .unwrap();
+        upsert_preference(&pool, 1, "language", r#""fr""#)
+            .await
+            .unwrap();
+        upsert_preference(&pool, 2, "language", r#""de""#)
+            .await
+            .unwrap();
+
+        let prefs = preferences_of(&pool, 1).await.unwrap();
+        assert_eq!(prefs.len(), 2);
+        assert_eq!(
+            prefs["appearance"],
+            serde_json::from_str::<Value>(nested).unwrap()
+        );
+        assert_eq!(prefs["language"], "fr");
+    }
+
+    #[tokio::test]
+    async fn bad_keys_and_values_are_rejected() {
+        let pool = test_pool().await;
+        let err = upsert_preference(&pool, 1, "x", "{not json")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        let err = upsert_preference(&pool, 1, " ", "1").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    #[tokio::test]
+    async fn new_keys_stop_at_the_cap_but_overwrites_still_work() {
+        let pool = test_pool().await;
+        for i in 0..MAX_PREFERENCES_PER_USER {
+            upsert_preference(&pool, 1, &format!("k{}", i), "1")
+                .await
+                .unwrap();
+        }
+        let err = upsert_preference(&pool, 1, "one_more", "1")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        upsert_preference(&pool, 1, "k0", "null").await.unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::protocol::{serialize_ws_frame, WsEvent};
+use crate::ws_manager::{self, WsState};
+
+pub const USER_STATUSES: &[&str] = &["online", "away", "busy", "offline"];
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PresenceEntry {
+    pub user_id: i64,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceChanged {
+    pub user_id: i64,
+    pub status: String,
+    /// Users the relay should forward this update to.
+    #[serde(default)]
+    pub recipients: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LastSeenEntry {
+    pub user_id: i64,
+    /// `None` if the user has never been active or hides their last-seen time.
+    pub last_seen_at: Option<String>,
+}
+
+fn validate_status(status: &str) -> Result<(), AppError> {
+    if !USER_STATUSES.contains(&status) {
+        return Err(AppError::Validation(format!(
+            "invalid status '{}', expected one of: {}",
+            status,
+            USER_STATUSES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Stores the new status and returns the presence event to broadcast; only
+/// the user's contacts, less anyone they've blocked, are listed as recipients,
+/// and an invisible user's event always says `offline`.
+pub(crate) async fn update_status(
+    pool: &SqlitePool,
+    user_id: i64,
+    status: &str,
+) -> Result<PresenceChanged, AppError> {
+    validate_status(status)?;
+
+    let updated =
+        sqlx::query("UPDATE users SET status = ?, last_seen_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(status)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .context("failed to update status")?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!("user {} not found", user_id)));
+    }
+
+    presence_event(pool, user_id).await
+}
+
+/// The presence event for the user's status as their contacts should see it.
+async fn presence_event(pool: &SqlitePool, user_id: i64) -> Result<PresenceChanged, AppError> {
+    let status: String = sqlx::query_scalar(
+        "SELECT CASE WHEN invisible THEN 'offline' ELSE COALESCE(status, 'offline') END
+         FROM users WHERE id = ?",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .context("failed to load status")?;
+
+    let recipients: Vec<i64> = sqlx::query_scalar(
+        "SELECT contact_id FROM contacts
+         WHERE user_id = ?1 AND contact_id != ?1
+           AND contact_id NOT IN (SELECT blocked_id FROM blocked_users WHERE user_id = ?1)
+         ORDER BY contact_id",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(PresenceChanged {
+        user_id,
+        status,
+        recipients,
+    })
+}
+
+/// Statuses as seen by `viewer_id`: anyone who has blocked the viewer or is
+/// invisible shows up as offline. An invisible viewer still sees their own
+/// status.
+pub(crate) async fn statuses(
+    pool: &SqlitePool,
+    viewer_id: Option<i64>,
+    user_ids: &[i64],
+) -> Result<Vec<PresenceEntry>, AppError> {
+    if user_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let placeholders = vec!["?"; user_ids.len()].join(", ");
+    let sql = format!(
+        "SELECT u.id AS user_id,
+                CASE WHEN b.user_id IS NOT NULL THEN 'offline'
+                     WHEN u.invisible AND u.id IS NOT ? THEN 'offline'
+                     ELSE COALESCE(u.status, 'offline') END AS status
+         FROM users u
+         LEFT JOIN blocked_users b ON b.user_id = u.id AND b.blocked_id = ?
+         WHERE u.id IN ({})
+         ORDER BY u.id",
+        placeholders
+    );
+    let mut query = sqlx::query_as::<_, PresenceEntry>(&sql)
+        .bind(viewer_id)
+        .bind(viewer_id);
+    for id in user_ids {
+        query = query.bind(id);
+    }
+    query
+        .fetch_all(pool)
+        .await
+        .context("failed to load statuses")
+}
+
+/// Records that the user was just active.
+pub(crate) async fn touch_last_seen(pool: &SqlitePool, user_id: i64) -> Result<(), AppError> {
+    sqlx::query("UPDATE users SET last_seen_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("failed to update last seen")?;
+    Ok(())
+}
+
+/// Last-seen times in `user_ids` order; unknown ids are omitted.
+pub(crate) async fn last_seen(
+    pool: &SqlitePool,
+    user_ids: &[i64],
+) -> Result<Vec<LastSeenEntry>, AppError> {
+    if user_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let placeholders = vec!["?"; user_ids.len()].join(", ");
+    let sql = format!(
+        "SELECT id AS user_id,
+                CASE WHEN hide_last_seen THEN NULL ELSE last_seen_at END AS last_seen_at
+         FROM users
+         WHERE id IN ({})",
+        placeholders
+    );
+    let mut query = sqlx::query_as::<_, LastSeenEntry>(&sql);
+    for id in user_ids {
+        query = query.bind(id);
+    }
+    let rows = query
+        .fetch_all(pool)
+        .await
+        .context("failed to load last seen")?;
+
+    Ok(user_ids
+        .iter()
+        .filter_map(|id| rows.iter().find(|row| row.user_id == *id).cloned())
+        .collect())
+}
+
+pub(crate) async fn set_last_seen_hidden(
+    pool: &SqlitePool,
+    user_id: i64,
+    hidden: bool,
+) -> Result<(), AppError> {
+    let updated = sqlx::query("UPDATE users SET hide_last_seen = ? WHERE id = ?")
+        .bind(hidden)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!("user {} not found", user_id)));
+    }
+    Ok(())
+}
+
+/// Turns invisible mode on or off and returns the presence event that tells
+/// the user's contacts, who only ever see `offline` while it's on.
+pub(crate) async fn set_invisible_mode(
+    pool: &SqlitePool,
+    user_id: i64,
+    invisible: bool,
+) -> Result<PresenceChanged, AppError> {
+    let updated = sqlx::query("UPDATE users SET invisible = ? WHERE id = ?")
+        .bind(invisible)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("failed to update invisible mode")?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound(format!("user {} not found", user_id)));
+    }
+    presence_event(pool, user_id).await
+}
+
+pub(crate) fn broadcast(ws: &Mutex<WsState>, event: &PresenceChanged) -> Result<(), AppError> {
+    let frame = serialize_ws_frame(&WsEvent::Presence(event.clone()));
+    ws_manager::send_text(ws, frame)
+}
+
+#[tauri::command]
+pub async fn set_user_status(
+    db: State<'_, DbInstances>,
+    ws: State<'_, Mutex<WsState>>,
+    user_id: i64,
+    status: String,
+) -> Result<PresenceEntry, AppError> {
+    let pool = db::pool(&db).await?;
+    let event = update_status(&pool, user_id, &status).await?;
+    // Presence is best-effort: the new status is saved even when offline.
+    let _ = broadcast(&ws, &event);
+    Ok(PresenceEntry { user_id, status })
+}
+
+#[tauri::command]
+pub async fn get_statuses(
+    db: State<'_, DbInstances>,
+    user_ids: Vec<i64>,
+    viewer_id: Option<i64>,
+) -> Result<Vec<PresenceEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    statuses(&pool, viewer_id, &user_ids).await
+}
+
+#[tauri::command]
+pub async fn get_last_seen(
+    db: State<'_, DbInstances>,
+    user_ids: Vec<i64>,
+) -> Result<Vec<LastSeenEntry>, AppError> {
+    let pool = db::pool(&db).await?;
+    last_seen(&pool, &user_ids).await
+}
+
+/// Opts the user in or out of sharing their last-seen time with everyone.
+#[tauri::command]
+pub async fn set_hide_last_seen(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    hidden: bool,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    set_last_seen_hidden(&pool, user_id, hidden).await
+}
+
+#[tauri::command]
+pub async fn set_invisible(
+    db: State<'_, DbInstances>,
+    ws: State<'_, Mutex<WsState>>,
+    user_id: i64,
+    invisible: bool,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    let event = set_invisible_mode(&pool, user_id, invisible).await?;
+    let _ = broadcast(&ws, &event);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::insert_block;
+    use crate::contacts::contacts_of;
+    use crate::contacts::insert_contact;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    async fn status_updates_only_go_to_contacts() {
+        let pool = test_pool().await;
+        insert_contact(&pool, 1, 2, false).await.unwrap();
+        insert_contact(&pool, 1, 4, false).await.unwrap();
+        // User 3 has user 1 as a contact, but not the other way round.
+        insert_contact(&pool, 3, 1, false).await.unwrap();
+
+        let event = update_status(&pool, 1, "busy").await.unwrap();
+        assert_eq!(
+            (event.status.as_str(), event.recipients),
+            ("busy", vec![2, 4])
+        );
+        let err = update_status(&pool, 1, "sleepy").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        assert_eq!(
+            update_status(&pool, 99, "busy").await.unwrap_err().code(),
+            "not_found"
+        );
+
+        let statuses = statuses(&pool, None, &[1, 2, 99]).await.unwrap();
+        assert_eq!(
+            statuses
+                .iter()
+                .map(|s| (s.user_id, s.status.as_str()))
+                .collect::<Vec<_>>(),
+            [(1, "busy"), (2, "offline")]
+        );
+    }
+
+    #[tokio::test]
+    async fn hidden_last_seen_is_hidden_from_contacts_too() {
+        let pool = test_pool().await;
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        let never = last_seen(&pool, &[1, 2, 99]).await.unwrap();
+        assert_eq!(never.len(), 2);
+        assert!(never.iter().all(|e| e.last_seen_at.is_none()));
+
+        update_status(&pool, 1, "online").await.unwrap();
+        update_status(&pool, 2, "away").await.unwrap();
+        let seen = last_seen(&pool, &[2, 1]).await.unwrap();
+        assert_eq!(seen.iter().map(|e| e.user_id).collect::<Vec<_>>(), [2, 1]);
+        assert!(seen.iter().all(|e| e.last_seen_at.is_some()));
+
+        set_last_seen_hidden(&pool, 1, true).await.unwrap();
+        assert!(last_seen(&pool, &[1]).await.unwrap()[0]
+            .last_seen_at
+            .is_none());
+        set_last_seen_hidden(&pool, 1, false).await.unwrap();
+        assert!(last_seen(&pool, &[1]).await.unwrap()[0]
+            .last_seen_at
+            .is_some());
+        let err = set_last_seen_hidden(&pool, 99, true).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn invisible_users_look_offline_to_everyone_else() {
+        let pool = test_pool().await;
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        update_status(&pool, 1, "online").await.unwrap();
+
+        let changed = set_invisible_mode(&pool, 1, true).await.unwrap();
+        assert_eq!(changed.status, "offline");
+        assert_eq!(changed.recipients, vec![2]);
+        assert_eq!(
+            statuses(&pool, Some(2), &[1]).await.unwrap()[0].status,
+            "offline"
+        );
+        assert_eq!(
+            statuses(&pool, None, &[1]).await.unwrap()[0].status,
+            "offline"
+        );
+        assert_eq!(
+            statuses(&pool, Some(1), &[1]).await.unwrap()[0].status,
+            "online"
+        );
+        assert_eq!(contacts_of(&pool, 2).await.unwrap()[0].status, "offline");
+
+        // Changing status while invisible still shows offline to others.
+        assert_eq!(
+            update_status(&pool, 1, "busy").await.unwrap().status,
+            "offline"
+        );
+        let own: Vec<String> = statuses(&pool, Some(1), &[1, 2])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| s.status)
+            .collect();
+        assert_eq!(own, ["busy", "offline"]);
+
+        assert_eq!(
+            set_invisible_mode(&pool, 1, false).await.unwrap().status,
+            "busy"
+        );
+        assert_eq!(
+            statuses(&pool, Some(2), &[1]).await.unwrap()[0].status,
+            "busy"
+        );
+        let err = set_invisible_mode(&pool, 99, true).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn blocked_contacts_get_no_presence_and_see_no_status() {
+        let pool = test_pool().await;
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        insert_contact(&pool, 1, 3, true).await.unwrap();
+        insert_block(&pool, 1, 2).await.unwrap();
+
+        let event = update_status(&pool, 1, "online").await.unwrap();
+        assert_eq!(event.recipients, [3]);
+
+        let seen_by = |contacts: Vec<crate::contacts::ContactView>| {
+            contacts
+                .into_iter()
+                .find(|c| c.id == 1)
+                .map(|c| c.status)
+                .unwrap()
+        };
+        assert_eq!(seen_by(contacts_of(&pool, 2).await.unwrap()), "offline");
+        assert_eq!(seen_by(contacts_of(&pool, 3).await.unwrap()), "online");
+    }
+}
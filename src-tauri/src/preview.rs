@@ -0,0 +1,58 @@
+//! One-line previews of message content, shared by notifications and the
+//! conversation list so both cut text the same way.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Flattens `content` onto one line and cuts it to `max_graphemes`, adding
+/// `…` only when something was cut. Counting grapheme clusters means an emoji
+/// or an accented letter is never split in half.
+pub fn truncate_preview(content: &str, max_graphemes: usize) -> String {
+    let flat = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut graphemes = flat.graphemes(true);
+    let head: String = graphemes.by_ref().take(max_graphemes).collect();
+    if graphemes.next().is_some() {
+        format!("{}…", head.trim_end())
+    } else {
+        head
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAG: &str = "🇫🇷";
+    const FAMILY: &str = "👨‍👩‍👧";
+
+    #[test]
+    fn short_content_is_untouched() {
+        assert_eq!(truncate_preview("hello", 5), "hello");
+    }
+
+    #[test]
+    fn lines_are_flattened_and_trimmed() {
+        assert_eq!(truncate_preview("  hi\r\n\n there  ", 20), "hi there");
+    }
+
+    #[test]
+    fn emoji_sequences_are_never_split() {
+        let content = format!("{0}{1}{0}{1}", FLAG, FAMILY);
+        assert_eq!(
+            truncate_preview(&content, 3),
+            format!("{}{}{}…", FLAG, FAMILY, FLAG)
+        );
+        assert_eq!(truncate_preview(&content, 4), content);
+        assert_eq!(truncate_preview("é́é́é́", 2), "é́é́…");
+    }
+
+    #[test]
+    fn trailing_space_before_the_ellipsis_is_dropped() {
+        assert_eq!(truncate_preview("ab cd", 3), "ab…");
+    }
+}
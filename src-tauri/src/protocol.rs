@@ -0,0 +1,245 @@
+//! Typed websocket frames. Every frame is `{"type": ..., "data": ...}`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::presence::PresenceChanged;
+use crate::typing::TypingEvent;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewMessageEvent {
+    /// The message's id, when the sender knows it. Only meaningful to
+    /// whoever assigned it, so it isn't used to match copies of a message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub sender_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiver_id: Option<i64>,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<String>,
+    /// The sender's id for the message, the same on every copy of it. Ties a
+    /// frame to the local `messages` row.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadReceiptEvent {
+    pub message_id: i64,
+    pub reader_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionEvent {
+    pub message_id: i64,
+    pub user_id: i64,
+    pub emoji: String,
+    /// `false` when the reaction was removed.
+    pub added: bool,
+    /// How many users have reacted to the message with `emoji` now.
+    #[serde(default)]
+    pub count: usize,
+    /// Participants the relay should forward this update to.
+    #[serde(default)]
+    pub recipients: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMessageEditedEvent {
+    pub group_id: String,
+    pub message_id: i64,
+    pub editor_id: i64,
+    pub content: String,
+    pub edited_at: Option<String>,
+    /// Group members the relay should forward this update to.
+    #[serde(default)]
+    pub recipients: Vec<i64>,
+}
+
+/// The server's acknowledgement of an outbox frame, by the `id` it was sent
+/// with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckEvent {
+    pub id: i64,
+}
+
+/// Sent by the client straight after connecting; nothing else is exchanged
+/// until the server answers with `auth-ok` or `auth-failed`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthEvent {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthFailedEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WsEvent {
+    #[serde(rename = "message")]
+    NewMessage(NewMessageEvent),
+    Typing(TypingEvent),
+    #[serde(rename = "user_status")]
+    Presence(PresenceChanged),
+    ReadReceipt(ReadReceiptEvent),
+    Reaction(ReactionEvent),
+    GroupMessageEdited(GroupMessageEditedEvent),
+    Ack(AckEvent),
+    Auth(AuthEvent),
+    #[serde(rename = "auth-ok")]
+    AuthOk,
+    /// `data` is optional; servers may send the bare type.
+    #[serde(rename = "auth-failed")]
+    AuthFailed(Option<AuthFailedEvent>),
+    /// A frame type this build doesn't know about, kept verbatim so newer
+    /// servers don't break older clients.
+    #[serde(skip)]
+    Unknown {
+        event_type: String,
+        data: Value,
+    },
+}
+
+/// Wire names of the known variants; anything else parses as `Unknown`.
+const KNOWN_EVENT_TYPES: &[&str] = &[
+    "message",
+    "typing",
+    "user_status",
+    "read_receipt",
+    "reaction",
+    "group_message_edited",
+    "ack",
+    "auth",
+    "auth-ok",
+    "auth-failed",
+];
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum ProtocolError {
+    /// Not JSON, or missing the `type` field.
+    InvalidJson(String),
+    /// A known event type whose `data` doesn't match its schema.
+    InvalidPayload(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawFrame {
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(default)]
+    data: Value,
+}
+
+pub fn parse_ws_frame(raw: &str) -> Result<WsEvent, ProtocolError> {
+    let frame: RawFrame =
+        serde_json::from_str(raw).map_err(|e| ProtocolError::InvalidJson(e.to_string()))?;
+
+    if !KNOWN_EVENT_TYPES.contains(&frame.event_type.as_str()) {
+        return Ok(WsEvent::Unknown {
+            event_type: frame.event_type,
+            data: frame.data,
+        });
+    }
+
+    serde_json::from_str(raw).map_err(|e| {
+        ProtocolError::InvalidPayload(format!("invalid '{}' frame: {}", frame.event_type, e))
+    })
+}
+
+pub fn serialize_ws_frame(event: &WsEvent) -> String {
+    let result = match event {
+        WsEvent::Unknown { event_type, data } => serde_json::to_string(&RawFrame {
+            event_type: event_type.clone(),
+            data: data.clone(),
+        }),
+        known => serde_json::to_string(known),
+    };
+    result.expect("websocket frames contain only JSON-safe values")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: &str) -> WsEvent {
+        let event = parse_ws_frame(frame).unwrap();
+        let expected: Value = serde_json::from_str(frame).unwrap();
+        let actual: Value = serde_json::from_str(&serialize_ws_frame(&event)).unwrap();
+        assert_eq!(actual, expected, "{}", frame);
+        event
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let cases = [
+            r#"{"type":"message","data":{"id":7,"sender_id":1,"receiver_id":2,"content":"hi","message_type":"text"}}"#,
+            r#"{"type":"typing","data":{"sender_id":1,"receiver_id":2,"is_typing":true,"seq":3}}"#,
+            r#"{"type":"user_status","data":{"user_id":1,"status":"online","recipients":[2]}}"#,
+            r#"{"type":"read_receipt","data":{"message_id":1,"reader_id":2}}"#,
+            r#"{"type":"reaction","data":{"message_id":1,"user_id":2,"emoji":"👍","added":true,"count":1,"recipients":[1]}}"#,
+            r#"{"type":"group_message_edited","data":{"group_id":"g","message_id":1,"editor_id":2,"content":"new","edited_at":null,"recipients":[3]}}"#,
+            r#"{"type":"ack","data":{"id":4}}"#,
+            r#"{"type":"auth","data":{"token":"t"}}"#,
+            r#"{"type":"auth-ok"}"#,
+            r#"{"type":"auth-failed","data":{"reason":"expired"}}"#,
+            r#"{"type":"future_thing","data":{"x":1}}"#,
+        ];
+        let events: Vec<WsEvent> = cases.iter().map(|frame| round_trip(frame)).collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                WsEvent::NewMessage(_),
+                WsEvent::Typing(_),
+                WsEvent::Presence(_),
+                WsEvent::ReadReceipt(_),
+                WsEvent::Reaction(_),
+                WsEvent::GroupMessageEdited(_),
+                WsEvent::Ack(_),
+                WsEvent::Auth(_),
+                WsEvent::AuthOk,
+                WsEvent::AuthFailed(Some(_)),
+                WsEvent::Unknown { .. },
+            ]
+        ));
+        assert!(matches!(
+            parse_ws_frame(r#"{"type":"auth-failed"}"#).unwrap(),
+            WsEvent::AuthFailed(None)
+        ));
+    }
+
+    #[test]
+    fn bad_frames_are_reported() {
+        assert!(matches!(
+            parse_ws_frame(r#"{"type":"future_thing"}"#).unwrap(),
+            WsEvent::Unknown { .. }
+        ));
+        assert!(matches!(
+            parse_ws_frame("nope"),
+            Err(ProtocolError::InvalidJson(_))
+        ));
+        assert!(matches!(
+            parse_ws_frame(r#"{"type":"typing","data":{}}"#),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn auth_frames_tolerate_missing_or_null_data() {
+        assert!(matches!(
+            parse_ws_frame(r#"{"type":"auth-ok","data":null}"#),
+            Ok(WsEvent::AuthOk)
+        ));
+        let auth = WsEvent::Auth(AuthEvent { token: "t".into() });
+        assert_eq!(
+            serialize_ws_frame(&auth),
+            r#"{"type":"auth","data":{"token":"t"}}"#
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tauri::State;
+
+use crate::error::AppError;
+
+pub const DEFAULT_PER_WINDOW: u32 = 20;
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Per-sender buckets, managed as Tauri state.
+pub type RateLimitState = Mutex<HashMap<i64, Bucket>>;
+
+/// Broadcasts to every contact, kept apart from ordinary sends.
+pub const BROADCAST_LIMIT: RateLimit = RateLimit {
+    per_window: 5,
+    window: Duration::from_secs(60 * 60),
+};
+
+/// Per-sender buckets for `BROADCAST_LIMIT`.
+#[derive(Default)]
+pub struct BroadcastRateLimitState(pub(crate) RateLimitState);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_window: u32,
+    pub window: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_window: DEFAULT_PER_WINDOW,
+            window: DEFAULT_WINDOW,
+        }
+    }
+}
+
+impl RateLimit {
+    fn tokens_per_sec(&self) -> f64 {
+        self.per_window as f64 / self.window.as_secs_f64()
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimitSettings {
+    limit: Mutex<RateLimit>,
+}
+
+impl RateLimitSettings {
+    pub fn current(&self) -> Result<RateLimit, AppError> {
+        Ok(*self.limit.lock()?)
+    }
+}
+
+/// A token bucket holding up to `per_window` tokens, refilled continuously so
+/// that a full bucket takes one window to regenerate.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.per_window as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes one token, or reports how long until one becomes available.
+    pub fn take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let rate = limit.tokens_per_sec();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(limit.per_window as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// Charges one message to `sender_id`, failing with `RateLimited` and the wait
+/// in milliseconds when the sender is over the limit.
+pub(crate) fn check(
+    buckets: &RateLimitState,
+    limit: RateLimit,
+    sender_id: i64,
+    now: Instant,
+) -> Result<(), AppError> {
+    let mut buckets = buckets.lock()?;
+    buckets
+        .entry(sender_id)
+        .or_insert_with(|| Bucket::full(limit, now))
+        .take(limit, now)
+        .map_err(|wait| AppError::RateLimited {
+            retry_after_ms: (wait.as_millis() as u64).max(1),
+        })
+}
+
+/// Changes the limit for all senders. Existing buckets are dropped so the new
+/// limit applies from a full bucket.
+#[tauri::command]
+pub fn set_rate_limit(
+    settings: State<'_, RateLimitSettings>,
+    buckets: State<'_, RateLimitState>,
+    per_window: u32,
+    window_secs: u64,
+) -> Result<(), AppError> {
+    if per_window == 0 || window_secs == 0 {
+        return Err(AppError::Validation(
+            "rate limit and window must both be greater than zero".into(),
+        ));
+    }
+    *settings.limit.lock()? = RateLimit {
+        per_window,
+        window: Duration::from_secs(window_secs),
+    };
+    buckets.lock()?.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let buckets = RateLimitState::default();
+        let limit = RateLimit::default();
+        let start = Instant::now();
+        for _ in 0..limit.per_window {
+            check(&buckets, limit, 1, start).unwrap();
+        }
+        // One token comes back every window / per_window.
+        let err = check(&buckets, limit, 1, start).unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::RateLimited {
+                retry_after_ms: 500
+            }
+        ));
+        check(&buckets, limit, 2, start).unwrap();
+
+        assert!(check(&buckets, limit, 1, start + Duration::from_millis(400)).is_err());
+        check(&buckets, limit, 1, start + Duration::from_millis(900)).unwrap();
+
+        let later = start + limit.window * 2;
+        for _ in 0..limit.per_window {
+            check(&buckets, limit, 1, later).unwrap();
+        }
+        assert!(check(&buckets, limit, 1, later).is_err());
+    }
+
+    #[test]
+    fn broadcasts_have_their_own_buckets() {
+        let broadcasts = BroadcastRateLimitState::default();
+        let sends = RateLimitState::default();
+        let now = Instant::now();
+        for _ in 0..BROADCAST_LIMIT.per_window {
+            check(&broadcasts.0, BROADCAST_LIMIT, 1, now).unwrap();
+        }
+        assert!(check(&broadcasts.0, BROADCAST_LIMIT, 1, now).is_err());
+        check(&sends, RateLimit::default(), 1, now).unwrap();
+    }
+
+    #[test]
+    fn a_poisoned_lock_is_an_error_not_a_panic() {
+        let buckets = RateLimitState::default();
+        let settings = RateLimitSettings::default();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _buckets = buckets.lock().unwrap();
+                let _limit = settings.limit.lock().unwrap();
+                panic!("poison both locks");
+            })
+            .join()
+            .unwrap_err();
+        });
+        let err = check(&buckets, RateLimit::default(), 1, Instant::now()).unwrap_err();
+        assert_eq!(err.code(), "internal");
+        assert_eq!(settings.current().unwrap_err().code(), "internal");
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+use tauri_plugin_sql::DbInstances;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::protocol::{serialize_ws_frame, ReactionEvent, WsEvent};
+use crate::ws_manager::{self, WsState};
+
+/// Changes to one reaction closer together than this go out as a single
+/// event, carrying the state once things settle.
+pub const REACTION_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// A single user's reaction: message, user, emoji.
+type ReactionKey = (i64, i64, String);
+
+/// The latest change to each reaction with a broadcast pending, and whether
+/// the reaction was there before the burst of changes began.
+#[derive(Default)]
+pub struct ReactionDebounce {
+    seq: AtomicU64,
+    pending: Mutex<HashMap<ReactionKey, (u64, bool)>>,
+}
+
+impl ReactionDebounce {
+    /// Notes a change and returns its sequence number. `was_present` only
+    /// counts for the first change of a burst.
+    pub(crate) fn record(&self, key: ReactionKey, was_present: bool) -> Result<u64, AppError> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        self.pending
+            .lock()?
+            .entry(key)
+            .and_modify(|(latest, _)| *latest = seq)
+            .or_insert((seq, was_present));
+        Ok(seq)
+    }
+
+    /// Ends the burst if `seq` is still its latest change, returning whether
+    /// the reaction was present when it began.
+    pub(crate) fn settle(&self, key: &ReactionKey, seq: u64) -> Option<bool> {
+        let mut pending = self.pending.lock().ok()?;
+        let &(latest, was_present) = pending.get(key)?;
+        if latest != seq {
+            return None;
+        }
+        pending.remove(key);
+        Some(was_present)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: usize,
+    pub user_ids: Vec<i64>,
+}
+
+/// A reaction must be exactly one user-perceived character, and not a plain
+/// ASCII letter, digit or symbol.
+pub(crate) fn validate_emoji(emoji: &str) -> Result<(), AppError> {
+    let mut graphemes = emoji.graphemes(true);
+    match (graphemes.next(), graphemes.next()) {
+        (Some(g), None) if !g.is_ascii() && !g.chars().any(char::is_whitespace) => Ok(()),
+        _ => Err(AppError::Validation(
+            "a reaction must be a single emoji".into(),
+        )),
+    }
+}
+
+pub(crate) async fn summaries(
+    pool: &SqlitePool,
+    message_id: i64,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT emoji, user_id FROM message_reactions
+         WHERE message_id = ?
+         ORDER BY reacted_at, rowid",
+    )
+    .bind(message_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load reactions")?;
+
+    let mut out: Vec<ReactionSummary> = Vec::new();
+    for (emoji, user_id) in rows {
+        match out.iter_mut().find(|s| s.emoji == emoji) {
+            Some(summary) => {
+                summary.count += 1;
+                summary.user_ids.push(user_id);
+            }
+            None => out.push(ReactionSummary {
+                emoji,
+                count: 1,
+                user_ids: vec![user_id],
+            }),
+        }
+    }
+    Ok(out)
+}
+
+async fn is_present(pool: &SqlitePool, key: &ReactionKey) -> Result<bool, AppError> {
+    let (message_id, user_id, emoji) = key;
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM message_reactions
+                        WHERE message_id = ? AND user_id = ? AND emoji = ?)",
+    )
+    .bind(message_id)
+    .bind(user_id)
+    .bind(emoji)
+    .fetch_one(pool)
+    .await
+    .context("failed to load reaction")
+}
+
+/// The event for a settled reaction, read from the database so it reflects
+/// where the changes ended up. `None` when they cancelled out.
+pub(crate) async fn settled_event(
+    pool: &SqlitePool,
+    key: &ReactionKey,
+    was_present: bool,
+) -> Result<Option<ReactionEvent>, AppError> {
+    let added = is_present(pool, key).await?;
+    if added == was_present {
+        return Ok(None);
+    }
+    let (message_id, user_id, emoji) = key;
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM message_reactions WHERE message_id = ? AND emoji = ?",
+    )
+    .bind(message_id)
+    .bind(emoji)
+    .fetch_one(pool)
+    .await
+    .context("failed to count reactions")?;
+    let recipients: Vec<i64> = sqlx::query_scalar(
+        "SELECT DISTINCT p.value
+         FROM messages m, json_each(json_array(m.sender_id, m.receiver_id)) p
+         WHERE m.id = ?1 AND p.value != ?2",
+    )
+    .bind(message_id)
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .context("failed to load participants")?;
+
+    Ok(Some(ReactionEvent {
+        message_id: *message_id,
+        user_id: *user_id,
+        emoji: emoji.clone(),
+        added,
+        count: count as usize,
+        recipients,
+    }))
+}
+
+/// Broadcasts the change once the reaction has been left alone for
+/// `REACTION_DEBOUNCE`. Like edits, the change is saved even when the
+/// broadcast can't be sent.
+fn broadcast_later<R: Runtime>(app: AppHandle<R>, key: ReactionKey, seq: u64) {
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(REACTION_DEBOUNCE).await;
+        let Some(was_present) = app.state::<ReactionDebounce>().settle(&key, seq) else {
+            return;
+        };
+        let Ok(pool) = db::pool(&app.state::<DbInstances>()).await else {
+            return;
+        };
+        let Ok(Some(event)) = settled_event(&pool, &key, was_present).await else {
+            return;
+        };
+        let _ = ws_manager::send_text(
+            &app.state::<Mutex<WsState>>(),
+            serialize_ws_frame(&WsEvent::Reaction(event.clone())),
+        );
+        let _ = app.emit("reaction-changed", event);
+    });
+}
+
+pub(crate) async fn insert_reaction(
+    pool: &SqlitePool,
+    message_id: i64,
+    user_id: i64,
+    emoji: &str,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    validate_emoji(emoji)?;
+
+    sqlx::query(
+        "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji) VALUES (?, ?, ?)",
+    )
+    .bind(message_id)
+    .bind(user_id)
+    .bind(emoji)
+    .execute(pool)
+    .await
+    .context("failed to add reaction")?;
+
+    summaries(pool, message_id).await
+}
+
+pub(crate) async fn delete_reaction(
+    pool: &SqlitePool,
+    message_id: i64,
+    user_id: i64,
+    emoji: &str,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    sqlx::query("DELETE FROM message_reactions WHERE message_id = ? AND user_id = ? AND emoji = ?")
+        .bind(message_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(pool)
+        .await
+        .context("failed to remove reaction")?;
+
+    summaries(pool, message_id).await
+}
+
+#[tauri::command]
+pub async fn add_reaction(
+    app: AppHandle,
+    db: State<'_, DbInstances>,
+    debounce: State<'_, ReactionDebounce>,
+    message_id: i64,
+    user_id: i64,
+    emoji: String,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    let pool = db::pool(&db).await?;
+    let key = (message_id, user_id, emoji);
+    let was_present = is_present(&pool, &key).await?;
+    let summaries = insert_reaction(&pool, message_id, user_id, &key.2).await?;
+    let seq = debounce.record(key.clone(), was_present)?;
+    broadcast_later(app, key, seq);
+    Ok(summaries)
+}
+
+#[tauri::command]
+pub async fn remove_reaction(
+    app: AppHandle,
+    db: State<'_, DbInstances>,
+    debounce: State<'_, ReactionDebounce>,
+    message_id: i64,
+    user_id: i64,
+    emoji: String,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    let pool = db::pool(&db).await?;
+    let key = (message_id, user_id, emoji);
+    let was_present = is_present(&pool, &key).await?;
+    let summaries = delete_reaction(&pool, message_id, user_id, &key.2).await?;
+    let seq = debounce.record(key.clone(), was_present)?;
+    broadcast_later(app, key, seq);
+    Ok(summaries)
+}
+
+#[tauri::command]
+pub async fn reactions_for(
+    db: State<'_, DbInstances>,
+    message_id: i64,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    let pool = db::pool(&db).await?;
+    summaries(&pool, message_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::message_type::MessageType;
+    use crate::messages::insert_message;
+    use crate::test_support::{count, test_pool};
+
+    async fn message(pool: &SqlitePool) -> i64 {
+        insert_message(
+            pool,
+            &EncryptionState::default(),
+            1,
+            2,
+            "hi",
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[tokio::test]
+    async fn reacting_twice_with_the_same_emoji_is_a_no_op() {
+        let pool = test_pool().await;
+        let id = message(&pool).await;
+        insert_reaction(&pool, id, 1, "👍").await.unwrap();
+        let summary = insert_reaction(&pool, id, 1, "👍").await.unwrap();
+        assert_eq!((summary.len(), summary[0].count), (1, 1));
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM message_reactions").await,
+            1
+        );
+
+        insert_reaction(&pool, id, 2, "👍").await.unwrap();
+        let summary = insert_reaction(&pool, id, 2, "👨‍👩‍👧").await.unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].user_ids, [1, 2]);
+
+        let summary = delete_reaction(&pool, id, 1, "👍").await.unwrap();
+        assert_eq!(summary[0].count, 1);
+    }
+
+    #[tokio::test]
+    async fn a_reaction_is_exactly_one_emoji() {
+        let pool = test_pool().await;
+        let id = message(&pool).await;
+        for emoji in ["👍👍", "a", ""] {
+            let err = insert_reaction(&pool, id, 2, emoji).await.unwrap_err();
+            assert_eq!(err.code(), "validation");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_changes_settles_once() {
+        let pool = test_pool().await;
+        let id = message(&pool).await;
+        insert_reaction(&pool, id, 3, "🎉").await.unwrap();
+        let debounce = ReactionDebounce::default();
+        let key = (id, 2, "🎉".to_string());
+
+        let first = debounce.record(key.clone(), false).unwrap();
+        insert_reaction(&pool, id, 2, "🎉").await.unwrap();
+        let second = debounce.record(key.clone(), true).unwrap();
+        delete_reaction(&pool, id, 2, "🎉").await.unwrap();
+        let last = debounce.record(key.clone(), false).unwrap();
+        insert_reaction(&pool, id, 2, "🎉").await.unwrap();
+
+        assert_eq!(debounce.settle(&key, first), None);
+        assert_eq!(debounce.settle(&key, second), None);
+        let was_present = debounce.settle(&key, last).unwrap();
+        assert!(!was_present);
+        assert_eq!(debounce.settle(&key, last), None);
+
+        let event = settled_event(&pool, &key, was_present)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(event.added);
+        assert_eq!(event.count, 2);
+        assert_eq!(event.recipients, vec![1]);
+        let summary = summaries(&pool, id).await.unwrap();
+        assert_eq!(summary.iter().find(|s| s.emoji == "🎉").unwrap().count, 2);
+    }
+
+    #[tokio::test]
+    async fn changes_that_cancel_out_send_nothing() {
+        let pool = test_pool().await;
+        let id = message(&pool).await;
+        insert_reaction(&pool, id, 2, "👍").await.unwrap();
+        let debounce = ReactionDebounce::default();
+        let key = (id, 2, "👍".to_string());
+
+        debounce.record(key.clone(), true).unwrap();
+        delete_reaction(&pool, id, 2, "👍").await.unwrap();
+        let last = debounce.record(key.clone(), false).unwrap();
+        insert_reaction(&pool, id, 2, "👍").await.unwrap();
+
+        let was_present = debounce.settle(&key, last).unwrap();
+        assert!(was_present);
+        assert!(settled_event(&pool, &key, was_present)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,274 @@
+//! Purging old messages. Starred direct and group messages, and pinned group
+//! messages, are always kept.
+
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::maintenance::TableCount;
+
+/// Rows deleted per transaction, so other writers get the lock in between.
+pub const RETENTION_BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    /// Messages removed (or that would be removed) from each table.
+    pub tables: Vec<TableCount>,
+}
+
+struct Scope {
+    table: &'static str,
+    /// Selects purgeable ids; `?1` is the age modifier, e.g. `-30 days`.
+    candidates: &'static str,
+    /// Statements run for each batch before the rows are deleted; `?1` is a
+    /// JSON array of the batch's ids.
+    cleanup: &'static [&'static str],
+}
+
+/// Statements that detach everything hanging off a batch of direct messages
+/// before the messages themselves are deleted; `?1` is a JSON array of ids.
+pub(crate) const DIRECT_MESSAGE_CLEANUP: &[&str] = &[
+    "DELETE FROM message_edits WHERE message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM message_reactions WHERE message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM attachments WHERE message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM message_delivery
+     WHERE message_scope = 'direct' AND message_id IN (SELECT value FROM json_each(?1))",
+    "UPDATE messages SET reply_to_id = NULL
+     WHERE reply_to_id IN (SELECT value FROM json_each(?1))",
+    "UPDATE messages SET forwarded_from = NULL
+     WHERE forwarded_from IN (SELECT value FROM json_each(?1))",
+    "UPDATE scheduled_messages SET reply_to_id = NULL
+     WHERE reply_to_id IN (SELECT value FROM json_each(?1))",
+    "UPDATE scheduled_messages SET message_id = NULL
+     WHERE message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM starred_messages
+     WHERE message_scope = 'direct' AND message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM message_links WHERE message_id IN (SELECT value FROM json_each(?1))",
+];
+
+/// Drops the copy replies keep of the direct messages they quote, for when
+/// their text has to go too; `?1` is a JSON array of ids. Must run before
+/// `DIRECT_MESSAGE_CLEANUP` detaches the replies.
+pub(crate) const CLEAR_QUOTES: &str = "UPDATE messages SET quoted_snippet = NULL
+     WHERE reply_to_id IN (SELECT value FROM json_each(?1))";
+
+/// The same for a batch of group messages.
+pub(crate) const GROUP_MESSAGE_CLEANUP: &[&str] = &[
+    "DELETE FROM mentions WHERE message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM group_message_edits WHERE message_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM message_delivery
+     WHERE message_scope = 'group' AND message_id IN (SELECT value FROM json_each(?1))",
+    "UPDATE group_messages SET reply_to_id = NULL
+     WHERE reply_to_id IN (SELECT value FROM json_each(?1))",
+    "DELETE FROM starred_messages
+     WHERE message_scope = 'group' AND message_id IN (SELECT value FROM json_each(?1))",
+];
+
+const SCOPES: &[Scope] = &[
+    Scope {
+        table: "messages",
+        candidates: "SELECT m.id FROM messages m
+                     WHERE datetime(m.timestamp) < datetime('now', ?1)
+                       AND NOT EXISTS (
+                           SELECT 1 FROM starred_messages s
+                           WHERE s.message_id = m.id AND s.message_scope = 'direct'
+                       )",
+        cleanup: DIRECT_MESSAGE_CLEANUP,
+    },
+    Scope {
+        table: "group_messages",
+        candidates: "SELECT g.id FROM group_messages g
+                     WHERE datetime(g.timestamp) < datetime('now', ?1) AND g.pinned = 0
+                       AND NOT EXISTS (
+                           SELECT 1 FROM starred_messages s
+                           WHERE s.message_id = g.id AND s.message_scope = 'group'
+                       )",
+        cleanup: GROUP_MESSAGE_CLEANUP,
+    },
+];
+
+/// Runs `cleanup` for the batch, then deletes the rows themselves from `table`.
+pub(crate) async fn delete_batch(
+    conn: &mut SqliteConnection,
+    table: &str,
+    cleanup: &[&str],
+    ids: &[i64],
+) -> Result<(), AppError> {
+    let batch = serde_json::to_string(ids).expect("ids serialize to JSON");
+    for statement in cleanup {
+        sqlx::query(statement)
+            .bind(&batch)
+            .execute(&mut *conn)
+            .await
+            .context(&format!("failed to clean up {}", table))?;
+    }
+    sqlx::query(&format!(
+        "DELETE FROM {} WHERE id IN (SELECT value FROM json_each(?1))",
+        table
+    ))
+    .bind(&batch)
+    .execute(&mut *conn)
+    .await
+    .context(&format!("failed to purge {}", table))?;
+    Ok(())
+}
+
+async fn purge_scope(pool: &SqlitePool, scope: &Scope, age: &str) -> Result<i64, AppError> {
+    let mut removed = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let ids: Vec<i64> = sqlx::query_scalar(&format!("{} LIMIT ?2", scope.candidates))
+            .bind(age)
+            .bind(RETENTION_BATCH_SIZE)
+            .fetch_all(&mut *tx)
+            .await
+            .context(&format!("failed to select {} to purge", scope.table))?;
+        if ids.is_empty() {
+            return Ok(removed);
+        }
+
+        delete_batch(&mut tx, scope.table, scope.cleanup, &ids).await?;
+        tx.commit().await?;
+
+        removed += ids.len() as i64;
+    }
+}
+
+/// Deletes messages older than `days`, or only counts them when `dry_run`.
+pub(crate) async fn purge_older_than(
+    pool: &SqlitePool,
+    days: u32,
+    dry_run: bool,
+) -> Result<RetentionReport, AppError> {
+    if days == 0 {
+        return Err(AppError::Validation(
+            "retention must be at least 1 day".into(),
+        ));
+    }
+    let age = format!("-{} days", days);
+
+    let mut tables = Vec::new();
+    for scope in SCOPES {
+        let rows = if dry_run {
+            sqlx::query_scalar(&format!("SELECT COUNT(*) FROM ({})", scope.candidates))
+                .bind(&age)
+                .fetch_one(pool)
+                .await
+                .context(&format!("failed to count {}", scope.table))?
+        } else {
+            purge_scope(pool, scope, &age).await?
+        };
+        tables.push(TableCount {
+            table: scope.table.to_string(),
+            rows,
+        });
+    }
+
+    Ok(RetentionReport { dry_run, tables })
+}
+
+#[tauri::command]
+pub async fn apply_retention(
+    db: State<'_, DbInstances>,
+    days: u32,
+    dry_run: bool,
+) -> Result<RetentionReport, AppError> {
+    let pool = db::pool(&db).await?;
+    purge_older_than(&pool, days, dry_run).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::insert_group;
+    use crate::stars::insert_star;
+    use crate::test_support::{count, test_pool};
+
+    const OLD: &str = "2000-01-01 00:00:00";
+
+    async fn seed(pool: &SqlitePool) {
+        for _ in 0..5 {
+            sqlx::query(
+                "INSERT INTO messages (sender_id, receiver_id, content, timestamp) VALUES (1, 2, 'old', ?)",
+            )
+            .bind(OLD)
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+        sqlx::query(
+            "INSERT INTO messages (sender_id, receiver_id, content, reply_to_id) VALUES (2, 1, 'reply', 2)",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO message_reactions (message_id, user_id, emoji) VALUES (2, 2, '👍')",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        insert_star(pool, 1, 1, "direct").await.unwrap();
+
+        let group = insert_group(pool, "g", None, 1, &[2]).await.unwrap();
+        for pinned in [true, false, false] {
+            sqlx::query(
+                "INSERT INTO group_messages (group_id, sender_id, content, timestamp, pinned)
+                 VALUES (?, 1, 'old', ?, ?)",
+            )
+            .bind(&group.id)
+            .bind(OLD)
+            .bind(pinned)
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_counts_without_deleting() {
+        let pool = test_pool().await;
+        seed(&pool).await;
+        let report = purge_older_than(&pool, 30, true).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.tables[0].rows, 4);
+        assert_eq!(report.tables[1].rows, 2);
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 6);
+    }
+
+    #[tokio::test]
+    async fn starred_and_pinned_messages_survive_purging() {
+        let pool = test_pool().await;
+        seed(&pool).await;
+        let report = purge_older_than(&pool, 30, false).await.unwrap();
+        assert_eq!(report.tables[0].rows, 4);
+        assert_eq!(report.tables[1].rows, 2);
+
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ids, [1, 6]);
+        let dangling = "SELECT COUNT(*) FROM messages WHERE reply_to_id IS NOT NULL";
+        assert_eq!(count(&pool, dangling).await, 0);
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM message_reactions").await,
+            0
+        );
+        let survivors = "SELECT COUNT(*) FROM group_messages WHERE content = 'old'";
+        assert_eq!(count(&pool, survivors).await, 1);
+        let pinned = "SELECT COUNT(*) FROM group_messages WHERE pinned = 1";
+        assert_eq!(count(&pool, pinned).await, 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_zero_day_policy() {
+        let pool = test_pool().await;
+        let err = purge_older_than(&pool, 0, true).await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+}
@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::encryption::EncryptionState;
+use crate::error::{AppError, Context};
+use crate::message_type::MessageType;
+use crate::messages::{insert_message, validate_message, MessageRow};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub sender_id: i64,
+    pub receiver_id: i64,
+    pub content: String,
+    pub message_type: String,
+    pub reply_to_id: Option<i64>,
+    pub send_at: String,
+    pub status: String,
+    pub message_id: Option<i64>,
+    pub created_at: String,
+}
+
+/// Payload of the `scheduled-sent` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledSent {
+    pub scheduled_id: i64,
+    pub message: MessageRow,
+}
+
+const SCHEDULED_COLUMNS: &str = "id, sender_id, receiver_id, content, message_type, reply_to_id,
+    send_at, status, message_id, created_at";
+
+pub(crate) async fn insert_scheduled(
+    pool: &SqlitePool,
+    sender_id: i64,
+    receiver_id: i64,
+    content: &str,
+    message_type: MessageType,
+    reply_to: Option<i64>,
+    send_at: &str,
+) -> Result<ScheduledMessage, AppError> {
+    validate_message(content)?;
+
+    // datetime() normalises the input so it compares correctly against
+    // datetime('now') when polling; it yields NULL for unparseable input.
+    sqlx::query_as::<_, ScheduledMessage>(&format!(
+        "INSERT INTO scheduled_messages
+             (sender_id, receiver_id, content, message_type, reply_to_id, send_at)
+         SELECT ?1, ?2, ?3, ?4, ?5, datetime(?6)
+         WHERE datetime(?6) IS NOT NULL
+         RETURNING {}",
+        SCHEDULED_COLUMNS
+    ))
+    .bind(sender_id)
+    .bind(receiver_id)
+    .bind(content)
+    .bind(message_type.as_str())
+    .bind(reply_to)
+    .bind(send_at)
+    .fetch_optional(pool)
+    .await
+    .context("failed to schedule message")?
+    .ok_or_else(|| AppError::Validation(format!("invalid send_at timestamp '{}'", send_at)))
+}
+
+/// Cancels a pending scheduled message; only its sender may do so.
+pub(crate) async fn mark_cancelled(
+    pool: &SqlitePool,
+    id: i64,
+    requester_id: i64,
+) -> Result<(), AppError> {
+    let status: Option<(i64, String)> =
+        sqlx::query_as("SELECT sender_id, status FROM scheduled_messages WHERE id = ?")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+            .context("failed to load scheduled message")?;
+
+    match status {
+        None => {
+            return Err(AppError::NotFound(format!(
+                "scheduled message {} not found",
+                id
+            )))
+        }
+        Some((sender_id, _)) if sender_id != requester_id => {
+            return Err(AppError::PermissionDenied(
+                "only the sender can cancel a scheduled message".into(),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    let cancelled = sqlx::query(
+        "UPDATE scheduled_messages SET status = 'cancelled' WHERE id = ? AND status = 'pending'",
+    )
+    .bind(id)
+    .execute(pool)
+    .await
+    .context("failed to cancel scheduled message")?
+    .rows_affected();
+
+    if cancelled == 0 {
+        return Err(AppError::Conflict(format!(
+            "scheduled message {} is no longer pending",
+            id
+        )));
+    }
+    Ok(())
+}
+
+/// Moves every due pending row into `messages`. Each row is claimed with a
+/// guarded update first so a concurrent cancel can't race the delivery.
+pub(crate) async fn deliver_due(
+    pool: &SqlitePool,
+    encryption: &EncryptionState,
+) -> Result<Vec<ScheduledSent>, AppError> {
+    let due = sqlx::query_as::<_, ScheduledMessage>(&format!(
+        "SELECT {} FROM scheduled_messages
+         WHERE status = 'pending' AND send_at <= datetime('now')
+         ORDER BY send_at ASC, id ASC",
+        SCHEDULED_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+    .context("failed to load due messages")?;
+
+    let mut sent = Vec::new();
+    for scheduled in due {
+        let claimed = sqlx::query(
+            "UPDATE scheduled_messages SET status = 'sent' WHERE id = ? AND status = 'pending'",
+        )
+        .bind(scheduled.id)
+        .execute(pool)
+        .await
+        .context("failed to claim scheduled message")?
+        .rows_affected();
+        if claimed == 0 {
+            continue;
+        }
+
+        let result = match scheduled.message_type.parse() {
+            Ok(message_type) => {
+                insert_message(
+                    pool,
+                    encryption,
+                    scheduled.sender_id,
+                    scheduled.receiver_id,
+                    &scheduled.content,
+                    message_type,
+                    scheduled.reply_to_id,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(message) => {
+                sqlx::query("UPDATE scheduled_messages SET message_id = ? WHERE id = ?")
+                    .bind(message.id)
+                    .bind(scheduled.id)
+                    .execute(pool)
+                    .await
+                    .context("failed to record scheduled delivery")?;
+                sent.push(ScheduledSent {
+                    scheduled_id: scheduled.id,
+                    message,
+                });
+            }
+            Err(e) => {
+                // Database errors are retried on the next poll; anything else
+                // (blocked, invalid reply target) will never succeed.
+                let status = match e {
+                    AppError::Database(_) => "pending",
+                    _ => "cancelled",
+                };
+                sqlx::query("UPDATE scheduled_messages SET status = ? WHERE id = ?")
+                    .bind(status)
+                    .bind(scheduled.id)
+                    .execute(pool)
+                    .await
+                    .context("failed to release scheduled message")?;
+            }
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Starts the background poller. Ticks before the frontend has loaded the
+/// database are skipped.
+pub fn spawn_scheduler(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let pool = match db::pool(&app.state::<DbInstances>()).await {
+                Ok(pool) => pool,
+                Err(_) => continue,
+            };
+            match deliver_due(&pool, &app.state::<EncryptionState>()).await {
+                Ok(sent) => {
+                    for event in sent {
+                        let _ = app.emit("scheduled-sent", event);
+                    }
+                }
+                Err(e) => tracing::warn!("scheduled delivery failed: {}", e),
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn schedule_message(
+    db: State<'_, DbInstances>,
+    sender_id: i64,
+    receiver_id: i64,
+    content: String,
+    send_at: String,
+    message_type: Option<String>,
+    reply_to: Option<i64>,
+) -> Result<ScheduledMessage, AppError> {
+    let pool = db::pool(&db).await?;
+    let message_type = MessageType::parse_or_text(message_type.as_deref())?;
+    insert_scheduled(
+        &pool,
+        sender_id,
+        receiver_id,
+        &content,
+        message_type,
+        reply_to,
+        &send_at,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn cancel_scheduled(
+    db: State<'_, DbInstances>,
+    id: i64,
+    requester_id: i64,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    mark_cancelled(&pool, id, requester_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{count, test_pool};
+
+    #[tokio::test]
+    async fn cancelling_before_send_at_prevents_delivery() {
+        let pool = test_pool().await;
+        let due = insert_scheduled(
+            &pool,
+            1,
+            2,
+            "due",
+            MessageType::Text,
+            None,
+            "2000-01-01 00:00:00",
+        )
+        .await
+        .unwrap();
+        let cancelled = insert_scheduled(
+            &pool,
+            1,
+            2,
+            "cancel me",
+            MessageType::Text,
+            None,
+            "2000-01-01T00:00:00Z",
+        )
+        .await
+        .unwrap();
+        insert_scheduled(
+            &pool,
+            1,
+            2,
+            "future",
+            MessageType::Text,
+            None,
+            "2999-01-01 00:00:00",
+        )
+        .await
+        .unwrap();
+        let err = insert_scheduled(&pool, 1, 2, "x", MessageType::Text, None, "garbage")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+
+        assert!(mark_cancelled(&pool, cancelled.id, 2).await.is_err());
+        mark_cancelled(&pool, cancelled.id, 1).await.unwrap();
+        assert!(mark_cancelled(&pool, cancelled.id, 1).await.is_err());
+
+        let encryption = EncryptionState::default();
+        let sent = deliver_due(&pool, &encryption).await.unwrap();
+        assert_eq!(
+            sent.iter().map(|s| s.scheduled_id).collect::<Vec<_>>(),
+            [due.id]
+        );
+        assert_eq!(count(&pool, "SELECT COUNT(*) FROM messages").await, 1);
+        assert!(deliver_due(&pool, &encryption).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,456 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::groups::member_role;
+use crate::messages::{page_size, MAX_PAGE_SIZE};
+
+pub const HIGHLIGHT_OPEN: &str = "<mark>";
+pub const HIGHLIGHT_CLOSE: &str = "</mark>";
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct MessageHit {
+    pub message_id: i64,
+    /// HTML: the message text escaped, with matches wrapped in
+    /// `HIGHLIGHT_OPEN`/`HIGHLIGHT_CLOSE`.
+    pub snippet: String,
+    pub partner_id: i64,
+}
+
+/// A matched term, as UTF-16 offsets into `content` so the UI can slice the
+/// JavaScript string directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MatchSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A hit from searching one conversation: the whole message, with every
+/// match marked.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationHit {
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub timestamp: String,
+    pub content: String,
+    pub matches: Vec<MatchSpan>,
+}
+
+#[derive(sqlx::FromRow)]
+struct MarkedRow {
+    message_id: i64,
+    sender_id: i64,
+    timestamp: String,
+    marked: String,
+}
+
+/// Delimiters passed to FTS5 `highlight()`; control characters that don't
+/// occur in typed text.
+const MARK_OPEN: char = '\u{2}';
+const MARK_CLOSE: char = '\u{3}';
+
+/// Strips the highlight delimiters from `marked`, returning the original
+/// text and where the delimiters were.
+pub(crate) fn split_marks(marked: &str) -> (String, Vec<MatchSpan>) {
+    let mut content = String::with_capacity(marked.len());
+    let mut matches = Vec::new();
+    let mut offset = 0;
+    let mut start = None;
+    for c in marked.chars() {
+        match c {
+            MARK_OPEN => start = Some(offset),
+            MARK_CLOSE => {
+                if let Some(start) = start.take() {
+                    matches.push(MatchSpan { start, end: offset });
+                }
+            }
+            c => {
+                content.push(c);
+                offset += c.len_utf16();
+            }
+        }
+    }
+    (content, matches)
+}
+
+/// Escapes `marked` for HTML and turns the highlight delimiters into
+/// `<mark>` tags, so message text can't inject markup into a snippet.
+pub(crate) fn snippet_html(marked: &str) -> String {
+    let mut html = String::with_capacity(marked.len());
+    for c in marked.chars() {
+        match c {
+            MARK_OPEN => html.push_str(HIGHLIGHT_OPEN),
+            MARK_CLOSE => html.push_str(HIGHLIGHT_CLOSE),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+impl From<MarkedRow> for ConversationHit {
+    fn from(row: MarkedRow) -> Self {
+        let (content, matches) = split_marks(&row.marked);
+        ConversationHit {
+            message_id: row.message_id,
+            sender_id: row.sender_id,
+            timestamp: row.timestamp,
+            content,
+            matches,
+        }
+    }
+}
+
+/// Turns user input into an FTS5 query. Unless `use_operators` is set, every
+/// whitespace-separated word is quoted so `AND`, `*`, `"` and friends are
+/// matched literally.
+pub(crate) fn fts_query(query: &str, use_operators: bool) -> Result<String, AppError> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(AppError::Validation(
+            "search query must not be empty".into(),
+        ));
+    }
+    if use_operators {
+        return Ok(query.to_string());
+    }
+    Ok(query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Only plaintext messages are indexed, so content stored encrypted (see
+/// `encryption`) never shows up in results.
+pub(crate) async fn search(
+    pool: &SqlitePool,
+    user_id: i64,
+    query: &str,
+    use_operators: bool,
+    limit: u32,
+) -> Result<Vec<MessageHit>, AppError> {
+    let fts = fts_query(query, use_operators)?;
+
+    let mut hits = sqlx::query_as::<_, MessageHit>(
+        "SELECT m.id AS message_id,
+                snippet(messages_fts, 0, ?3, ?4, '…', 12) AS snippet,
+                CASE WHEN m.sender_id = ?1 THEN m.receiver_id ELSE m.sender_id END AS partner_id
+         FROM messages_fts
+         JOIN messages m ON m.id = messages_fts.rowid
+         WHERE messages_fts MATCH ?2
+           AND (m.sender_id = ?1 OR m.receiver_id = ?1)
+           AND m.deleted_at IS NULL
+           AND (m.expires_at IS NULL OR m.expires_at > CURRENT_TIMESTAMP)
+         ORDER BY rank
+         LIMIT ?5",
+    )
+    .bind(user_id)
+    .bind(&fts)
+    .bind(MARK_OPEN.to_string())
+    .bind(MARK_CLOSE.to_string())
+    .bind(page_size(limit))
+    .fetch_all(pool)
+    .await
+    .context("search failed")?;
+    for hit in hits.iter_mut() {
+        hit.snippet = snippet_html(&hit.snippet);
+    }
+    Ok(hits)
+}
+
+/// Matches in the direct conversation between `user_a` and `user_b`, newest
+/// first.
+pub(crate) async fn search_direct(
+    pool: &SqlitePool,
+    user_a: i64,
+    user_b: i64,
+    query: &str,
+) -> Result<Vec<ConversationHit>, AppError> {
+    let fts = fts_query(query, false)?;
+
+    let rows = sqlx::query_as::<_, MarkedRow>(
+        "SELECT m.id AS message_id, m.sender_id, m.timestamp,
+                highlight(messages_fts, 0, ?4, ?5) AS marked
+         FROM messages_fts
+         JOIN messages m ON m.id = messages_fts.rowid
+         WHERE messages_fts MATCH ?3
+           AND ((m.sender_id = ?1 AND m.receiver_id = ?2)
+                OR (m.sender_id = ?2 AND m.receiver_id = ?1))
+           AND m.deleted_at IS NULL
+           AND (m.expires_at IS NULL OR m.expires_at > CURRENT_TIMESTAMP)
+         ORDER BY m.timestamp DESC, m.id DESC
+         LIMIT ?6",
+    )
+    .bind(user_a)
+    .bind(user_b)
+    .bind(&fts)
+    .bind(MARK_OPEN.to_string())
+    .bind(MARK_CLOSE.to_string())
+    .bind(MAX_PAGE_SIZE)
+    .fetch_all(pool)
+    .await
+    .context("search failed")?;
+    Ok(rows.into_iter().map(ConversationHit::from).collect())
+}
+
+/// Matches in `group_id`, newest first. Only members can search a group.
+pub(crate) async fn search_group(
+    pool: &SqlitePool,
+    group_id: &str,
+    user_id: i64,
+    query: &str,
+) -> Result<Vec<ConversationHit>, AppError> {
+    let fts = fts_query(query, false)?;
+    if member_role(pool, group_id, user_id).await?.is_none() {
+        return Err(AppError::NotAMember(format!(
+            "user {} is not a member of group {}",
+            user_id, group_id
+        )));
+    }
+
+    let rows = sqlx::query_as::<_, MarkedRow>(
+        "SELECT g.id AS message_id, g.sender_id, g.timestamp,
+                highlight(group_messages_fts, 0, ?3, ?4) AS marked
+         FROM group_messages_fts
+         JOIN group_messages g ON g.id = group_messages_fts.rowid
+         WHERE group_messages_fts MATCH ?2
+           AND g.group_id = ?1
+           AND g.deleted_at IS NULL
+           AND (g.expires_at IS NULL OR g.expires_at > CURRENT_TIMESTAMP)
+         ORDER BY g.timestamp DESC, g.id DESC
+         LIMIT ?5",
+    )
+    .bind(group_id)
+    .bind(&fts)
+    .bind(MARK_OPEN.to_string())
+    .bind(MARK_CLOSE.to_string())
+    .bind(MAX_PAGE_SIZE)
+    .fetch_all(pool)
+    .await
+    .context("search failed")?;
+    Ok(rows.into_iter().map(ConversationHit::from).collect())
+}
+
+#[tauri::command]
+pub async fn search_messages(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    query: String,
+    limit: u32,
+    use_operators: Option<bool>,
+) -> Result<Vec<MessageHit>, AppError> {
+    let pool = db::pool(&db).await?;
+    search(
+        &pool,
+        user_id,
+        &query,
+        use_operators.unwrap_or(false),
+        limit,
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn search_in_conversation(
+    db: State<'_, DbInstances>,
+    user_a: i64,
+    user_b: i64,
+    query: String,
+) -> Result<Vec<ConversationHit>, AppError> {
+    let pool = db::pool(&db).await?;
+    search_direct(&pool, user_a, user_b, &query).await
+}
+
+#[tauri::command]
+pub async fn search_in_group(
+    db: State<'_, DbInstances>,
+    group_id: String,
+    user_id: i64,
+    query: String,
+) -> Result<Vec<ConversationHit>, AppError> {
+    let pool = db::pool(&db).await?;
+    search_group(&pool, &group_id, user_id, &query).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::group_messages::insert_group_message;
+    use crate::groups::insert_group;
+    use crate::message_type::MessageType;
+    use crate::messages::insert_message;
+    use crate::test_support::test_pool;
+
+    async fn send(pool: &SqlitePool, sender_id: i64, receiver_id: i64, content: &str) -> i64 {
+        insert_message(
+            pool,
+            &EncryptionState::default(),
+            sender_id,
+            receiver_id,
+            content,
+            MessageType::Text,
+            None,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[tokio::test]
+    async fn snippets_escape_message_html() {
+        let pool = test_pool().await;
+        let id = send(&pool, 1, 2, "look <img src=x onerror=alert(1)> here").await;
+        let hits = search(&pool, 1, "look", false, 0).await.unwrap();
+        assert_eq!(hits[0].message_id, id);
+        assert_eq!(
+            hits[0].snippet,
+            "<mark>look</mark> &lt;img src=x onerror=alert(1)&gt; here"
+        );
+    }
+
+    #[tokio::test]
+    async fn operators_are_literal_unless_enabled() {
+        assert_eq!(
+            fts_query("foo AND bar", false).unwrap(),
+            "\"foo\" \"AND\" \"bar\""
+        );
+        assert_eq!(fts_query("say \"hi", false).unwrap(), "\"say\" \"\"\"hi\"");
+        assert_eq!(fts_query(" foo AND bar ", true).unwrap(), "foo AND bar");
+        assert_eq!(fts_query("  ", false).unwrap_err().code(), "validation");
+
+        let pool = test_pool().await;
+        let both = send(&pool, 1, 2, "foo and bar").await;
+        let foo = send(&pool, 1, 2, "just foo").await;
+        let literal = search(&pool, 1, "foo AND bar", false, 0).await.unwrap();
+        assert_eq!(
+            literal.iter().map(|h| h.message_id).collect::<Vec<_>>(),
+            [both]
+        );
+        let mut any = search(&pool, 1, "foo OR bar", true, 0)
+            .await
+            .unwrap()
+            .iter()
+            .map(|h| h.message_id)
+            .collect::<Vec<_>>();
+        any.sort();
+        assert_eq!(any, [both, foo]);
+        // An unbalanced quote is a syntax error as an operator query.
+        assert!(search(&pool, 1, "\"foo", true, 0).await.is_err());
+        assert_eq!(search(&pool, 1, "\"foo", false, 0).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn results_only_cover_the_users_conversations() {
+        let pool = test_pool().await;
+        let mine = send(&pool, 2, 1, "secret plans").await;
+        send(&pool, 2, 3, "secret plans").await;
+        send(&pool, 3, 4, "secret plans").await;
+
+        let hits = search(&pool, 1, "secret", false, 0).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].message_id, hits[0].partner_id), (mine, 2));
+        assert!(search(&pool, 5, "secret", false, 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    fn spans(ranges: &[(usize, usize)]) -> Vec<MatchSpan> {
+        ranges
+            .iter()
+            .map(|&(start, end)| MatchSpan { start, end })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn search_direct_returns_match_offsets_newest_first() {
+        let pool = test_pool().await;
+        let first = send(&pool, 1, 2, "lunch tomorrow? Lunch!").await;
+        send(&pool, 1, 3, "lunch with someone else").await;
+        let second = send(&pool, 2, 1, "é lunch").await;
+
+        let hits = search_direct(&pool, 2, 1, "lunch").await.unwrap();
+        assert_eq!(
+            hits.iter().map(|h| h.message_id).collect::<Vec<_>>(),
+            [second, first]
+        );
+        assert_eq!(hits[1].content, "lunch tomorrow? Lunch!");
+        assert_eq!(hits[1].matches, spans(&[(0, 5), (16, 21)]));
+        // Offsets count UTF-16 units, like the frontend's string indices.
+        assert_eq!(hits[0].matches, spans(&[(2, 7)]));
+
+        assert!(search_direct(&pool, 2, 3, "lunch")
+            .await
+            .unwrap()
+            .is_empty());
+        let err = search_direct(&pool, 1, 2, " ").await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    #[test]
+    fn split_marks_measures_in_utf16_units() {
+        let (content, matches) = split_marks("\u{2}😀\u{3}x");
+        assert_eq!(content, "😀x");
+        assert_eq!(matches, spans(&[(0, 2)]));
+    }
+
+    #[tokio::test]
+    async fn search_group_is_scoped_to_one_group_of_the_member() {
+        let pool = test_pool().await;
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let other = insert_group(&pool, "h", None, 1, &[]).await.unwrap();
+        let (hit, _) = insert_group_message(
+            &pool,
+            &group.id,
+            2,
+            "group lunch",
+            MessageType::Text,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        insert_group_message(
+            &pool,
+            &other.id,
+            1,
+            "other lunch",
+            MessageType::Text,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let hits = search_group(&pool, &group.id, 1, "lunch").await.unwrap();
+        assert_eq!(
+            hits.iter().map(|h| h.message_id).collect::<Vec<_>>(),
+            [hit.id]
+        );
+        assert_eq!(hits[0].matches, spans(&[(6, 11)]));
+        let err = search_group(&pool, &other.id, 2, "lunch")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_a_member");
+
+        // The index follows content updates.
+        sqlx::query("UPDATE group_messages SET content = 'dinner' WHERE id = ?")
+            .bind(hit.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(search_group(&pool, &group.id, 1, "lunch")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
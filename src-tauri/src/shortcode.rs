@@ -0,0 +1,130 @@
+//! `:name:` emoji shortcodes, expanded when a text message is sent.
+//!
+//! Unknown names are left as typed. A doubled colon is never part of a
+//! shortcode, so `::` and things like `std::io` pass through untouched.
+
+/// Shortcode names and their emoji, sorted by name for binary search.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("blush", "😊"),
+    ("broken_heart", "💔"),
+    ("clap", "👏"),
+    ("cold_sweat", "😰"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("hugs", "🤗"),
+    ("joy", "😂"),
+    ("kiss", "😘"),
+    ("laughing", "😆"),
+    ("neutral_face", "😐"),
+    ("ok_hand", "👌"),
+    ("open_mouth", "😮"),
+    ("party", "🥳"),
+    ("pensive", "😔"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("sad", "😞"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("sleeping", "😴"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("upside_down_face", "🙃"),
+    ("wave", "👋"),
+    ("white_check_mark", "✅"),
+    ("wink", "😉"),
+    ("x", "❌"),
+    ("yum", "😋"),
+];
+
+fn lookup(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by(|(code, _)| code.cmp(&name))
+        .ok()
+        .map(|i| SHORTCODES[i].1)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')
+}
+
+/// Replaces every known `:name:` in `content` with its emoji.
+pub fn expand_shortcodes(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(escaped) = after.strip_prefix(':') {
+            out.push_str("::");
+            rest = escaped;
+            continue;
+        }
+
+        let name_len = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+        let name = &after[..name_len];
+        let closed = after[name_len..].starts_with(':') && !after[name_len + 1..].starts_with(':');
+        match lookup(name).filter(|_| closed) {
+            Some(emoji) => {
+                out.push_str(emoji);
+                rest = &after[name_len + 1..];
+            }
+            None => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_codes_expand() {
+        assert_eq!(expand_shortcodes("hi :smile: :heart:"), "hi 😄 ❤️");
+        assert_eq!(expand_shortcodes(":+1: :tada:"), "👍 🎉");
+        assert_eq!(expand_shortcodes("ünï :fire:!"), "ünï 🔥!");
+    }
+
+    #[test]
+    fn anything_else_is_left_alone() {
+        for text in [
+            ":+1::tada:",
+            "a :notareal: b",
+            "std::io and ::smile::",
+            "time 10:30 :",
+            ":smile",
+        ] {
+            assert_eq!(expand_shortcodes(text), text);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Flushes state before the app quits: the signed-in user goes offline,
+//! contacts are told, the websocket is closed and the WAL is folded back
+//! into the database file.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_sql::DbInstances;
+
+use crate::error::{AppError, Context};
+use crate::ws_manager::{self, WsState};
+use crate::{db, presence};
+
+/// Time given to the socket task to send its close frame before exiting.
+const CLOSE_GRACE: Duration = Duration::from_millis(250);
+
+/// Set once shutdown has run, so the frontend calling `shutdown` and the
+/// exit event that follows don't do the work twice.
+#[derive(Default)]
+pub struct ShutdownState {
+    done: AtomicBool,
+}
+
+pub(crate) async fn flush<R: Runtime>(app: &AppHandle<R>) -> Result<(), AppError> {
+    if app
+        .state::<ShutdownState>()
+        .done
+        .swap(true, Ordering::SeqCst)
+    {
+        return Ok(());
+    }
+
+    let local_user = app.state::<Mutex<WsState>>().lock()?.user_id();
+    let pool = db::pool(&app.state::<DbInstances>()).await?;
+
+    if let Some(user_id) = local_user {
+        let event = presence::update_status(&pool, user_id, "offline").await?;
+        // Best-effort, like any other presence update.
+        let _ = presence::broadcast(&app.state::<Mutex<WsState>>(), &event);
+    }
+
+    ws_manager::disconnect(app)?;
+    tokio::time::sleep(CLOSE_GRACE).await;
+
+    sqlx::raw_sql("PRAGMA wal_checkpoint(TRUNCATE)")
+        .execute(&pool)
+        .await
+        .context("wal checkpoint failed")?;
+    Ok(())
+}
+
+/// Call before quitting. Also runs on OS-initiated quit via
+/// `RunEvent::ExitRequested`, so calling it is optional.
+#[tauri::command]
+pub async fn shutdown(app: AppHandle) -> Result<(), AppError> {
+    flush(&app).await
+}
@@ -0,0 +1,286 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+
+use crate::db;
+use crate::delivery::validate_scope;
+use crate::encryption::EncryptionState;
+use crate::error::{AppError, Context};
+use crate::messages::page_size;
+
+/// A starred message with enough context to render it; direct messages carry
+/// `receiver_id`, group messages carry `group_id`.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct StarredMessage {
+    pub message_id: i64,
+    pub message_scope: String,
+    pub sender_id: i64,
+    pub receiver_id: Option<i64>,
+    pub group_id: Option<String>,
+    pub content: String,
+    pub message_type: String,
+    pub timestamp: String,
+    pub starred_at: String,
+    #[serde(skip)]
+    pub encrypted: bool,
+}
+
+/// Users can only star messages they can see: their own conversations, or
+/// groups they belong to.
+async fn can_see(
+    pool: &SqlitePool,
+    user_id: i64,
+    message_id: i64,
+    scope: &str,
+) -> Result<bool, AppError> {
+    let sql = if scope == "group" {
+        "SELECT EXISTS (
+             SELECT 1 FROM group_messages gm
+             JOIN group_members mem ON mem.group_id = gm.group_id AND mem.user_id = ?1
+             WHERE gm.id = ?2 AND gm.deleted_at IS NULL
+         )"
+    } else {
+        "SELECT EXISTS (
+             SELECT 1 FROM messages
+             WHERE id = ?2 AND (sender_id = ?1 OR receiver_id = ?1) AND deleted_at IS NULL
+         )"
+    };
+    sqlx::query_scalar(sql)
+        .bind(user_id)
+        .bind(message_id)
+        .fetch_one(pool)
+        .await
+        .context("failed to look up message")
+}
+
+pub(crate) async fn insert_star(
+    pool: &SqlitePool,
+    user_id: i64,
+    message_id: i64,
+    scope: &str,
+) -> Result<(), AppError> {
+    validate_scope(scope)?;
+    if !can_see(pool, user_id, message_id, scope).await? {
+        return Err(AppError::NotFound(format!(
+            "message {} not found",
+            message_id
+        )));
+    }
+
+    sqlx::query(
+        "INSERT OR IGNORE INTO starred_messages (user_id, message_id, message_scope)
+         VALUES (?, ?, ?)",
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .bind(scope)
+    .execute(pool)
+    .await
+    .context("failed to star message")?;
+    Ok(())
+}
+
+pub(crate) async fn delete_star(
+    pool: &SqlitePool,
+    user_id: i64,
+    message_id: i64,
+    scope: &str,
+) -> Result<(), AppError> {
+    validate_scope(scope)?;
+    sqlx::query(
+        "DELETE FROM starred_messages WHERE user_id = ? AND message_id = ? AND message_scope = ?",
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .bind(scope)
+    .execute(pool)
+    .await
+    .context("failed to unstar message")?;
+    Ok(())
+}
+
+/// Newest star first. Stars on messages deleted since are kept but hidden, so
+/// they don't count towards the page.
+pub(crate) async fn starred_page(
+    pool: &SqlitePool,
+    user_id: i64,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<StarredMessage>, AppError> {
+    sqlx::query_as::<_, StarredMessage>(
+        "SELECT s.rowid AS star_seq, s.message_id, s.message_scope,
+                m.sender_id, m.receiver_id, NULL AS group_id,
+                m.content, m.message_type, m.timestamp, s.starred_at, m.encrypted
+         FROM starred_messages s
+         JOIN messages m ON m.id = s.message_id
+         WHERE s.user_id = ?1 AND s.message_scope = 'direct' AND m.deleted_at IS NULL
+         UNION ALL
+         SELECT s.rowid AS star_seq, s.message_id, s.message_scope,
+                g.sender_id, NULL AS receiver_id, g.group_id,
+                g.content, g.message_type, g.timestamp, s.starred_at, FALSE AS encrypted
+         FROM starred_messages s
+         JOIN group_messages g ON g.id = s.message_id
+         WHERE s.user_id = ?1 AND s.message_scope = 'group' AND g.deleted_at IS NULL
+         ORDER BY starred_at DESC, star_seq DESC
+         LIMIT ?2 OFFSET ?3",
+    )
+    .bind(user_id)
+    .bind(page_size(limit))
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+    .context("failed to list starred messages")
+}
+
+#[tauri::command]
+pub async fn star_message(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    message_id: i64,
+    scope: Option<String>,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    insert_star(
+        &pool,
+        user_id,
+        message_id,
+        scope.as_deref().unwrap_or("direct"),
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn unstar_message(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    message_id: i64,
+    scope: Option<String>,
+) -> Result<(), AppError> {
+    let pool = db::pool(&db).await?;
+    delete_star(
+        &pool,
+        user_id,
+        message_id,
+        scope.as_deref().unwrap_or("direct"),
+    )
+    .await
+}
+
+#[tauri::command]
+pub async fn list_starred(
+    db: State<'_, DbInstances>,
+    encryption: State<'_, EncryptionState>,
+    user_id: i64,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<StarredMessage>, AppError> {
+    let pool = db::pool(&db).await?;
+    let mut starred = starred_page(&pool, user_id, limit, offset).await?;
+    for message in starred.iter_mut().filter(|m| m.encrypted) {
+        message.content = encryption.reveal_content(
+            message.sender_id,
+            message.receiver_id.unwrap_or_default(),
+            &message.content,
+        );
+    }
+    Ok(starred)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_messages::{insert_group_message, mark_group_deleted};
+    use crate::groups::insert_group;
+    use crate::message_type::MessageType;
+    use crate::messages::{insert_message, mark_deleted};
+    use crate::test_support::{count, test_pool};
+
+    #[tokio::test]
+    async fn stars_list_newest_first_and_hide_deleted_messages() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let first = insert_message(&pool, &encryption, 1, 2, "one", MessageType::Text, None)
+            .await
+            .unwrap();
+        let second = insert_message(&pool, &encryption, 2, 1, "two", MessageType::Text, None)
+            .await
+            .unwrap();
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let (grouped, _) =
+            insert_group_message(&pool, &group.id, 2, "grp", MessageType::Text, None, None)
+                .await
+                .unwrap();
+
+        insert_star(&pool, 1, first.id, "direct").await.unwrap();
+        insert_star(&pool, 1, grouped.id, "group").await.unwrap();
+        insert_star(&pool, 1, second.id, "direct").await.unwrap();
+        // Starring twice keeps the original star.
+        insert_star(&pool, 1, first.id, "direct").await.unwrap();
+        let ids = |starred: Vec<StarredMessage>| -> Vec<(i64, String)> {
+            starred
+                .into_iter()
+                .map(|s| (s.message_id, s.message_scope))
+                .collect()
+        };
+        assert_eq!(
+            ids(starred_page(&pool, 1, 0, 0).await.unwrap()),
+            [
+                (second.id, "direct".to_string()),
+                (grouped.id, "group".to_string()),
+                (first.id, "direct".to_string()),
+            ]
+        );
+
+        mark_deleted(&pool, second.id, 2).await.unwrap();
+        mark_group_deleted(&pool, &group.id, grouped.id, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            ids(starred_page(&pool, 1, 0, 0).await.unwrap()),
+            [(first.id, "direct".to_string())]
+        );
+        // The stars themselves are kept.
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM starred_messages").await,
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn only_visible_messages_can_be_starred() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let direct = insert_message(&pool, &encryption, 1, 2, "one", MessageType::Text, None)
+            .await
+            .unwrap();
+        let group = insert_group(&pool, "g", None, 1, &[2]).await.unwrap();
+        let (grouped, _) =
+            insert_group_message(&pool, &group.id, 1, "grp", MessageType::Text, None, None)
+                .await
+                .unwrap();
+
+        for (message_id, scope) in [(direct.id, "direct"), (grouped.id, "group")] {
+            let err = insert_star(&pool, 3, message_id, scope).await.unwrap_err();
+            assert_eq!(err.code(), "not_found", "{}", scope);
+        }
+        // Unknown and deleted messages are refused too.
+        let err = insert_star(&pool, 1, grouped.id + 100, "group")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_found");
+        mark_deleted(&pool, direct.id, 1).await.unwrap();
+        let err = insert_star(&pool, 2, direct.id, "direct")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_found");
+        let err = insert_star(&pool, 1, direct.id, "pinned")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM starred_messages").await,
+            0
+        );
+    }
+}
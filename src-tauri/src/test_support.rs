@@ -0,0 +1,71 @@
+//! Setup shared by the in-module tests.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::SqlitePool;
+
+use crate::migrations::migrations;
+
+/// A fresh in-memory database with every migration applied and users 1 to
+/// 10, named `u1` to `u10`, on top of the ones the migrations seed.
+pub(crate) async fn test_pool() -> SqlitePool {
+    // One connection, since each in-memory connection is its own database.
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    seed(&pool).await;
+    pool
+}
+
+/// Like `test_pool`, but a WAL database file named after `name` with several
+/// connections, for tests that need writers racing each other. Returns the
+/// path so the test can remove the file.
+pub(crate) async fn file_pool(name: &str) -> (SqlitePool, PathBuf) {
+    let path = std::env::temp_dir().join(format!("cereals-{}-{}.db", name, std::process::id()));
+    remove_db(&path);
+    let options = SqliteConnectOptions::new()
+        .filename(&path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::from_secs(10));
+    let pool = SqlitePoolOptions::new()
+        .max_connections(8)
+        .connect_with(options)
+        .await
+        .unwrap();
+    seed(&pool).await;
+    (pool, path)
+}
+
+/// Deletes a database file made by `file_pool`, along with its WAL files.
+pub(crate) fn remove_db(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
+
+async fn seed(pool: &SqlitePool) {
+    for migration in migrations() {
+        sqlx::raw_sql(migration.sql)
+            .execute(pool)
+            .await
+            .unwrap_or_else(|e| panic!("migration {} failed: {}", migration.version, e));
+    }
+    for id in 1..=10 {
+        sqlx::query("INSERT INTO users (id, username) VALUES (?, ?)")
+            .bind(id)
+            .bind(format!("u{}", id))
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+}
+
+/// Runs a `SELECT COUNT(*)`-style query.
+pub(crate) async fn count(pool: &SqlitePool, sql: &str) -> i64 {
+    sqlx::query_scalar(sql).fetch_one(pool).await.unwrap()
+}
@@ -0,0 +1,100 @@
+//! Typing indicators. These are fire-and-forget websocket frames and are never
+//! written to the `messages` table.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+
+use crate::error::AppError;
+use crate::protocol::{serialize_ws_frame, WsEvent};
+use crate::ws_manager::{self, WsState};
+
+/// How long a "typing" signal stays valid without being refreshed.
+pub const TYPING_TTL: Duration = Duration::from_secs(6);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub sender_id: i64,
+    pub receiver_id: i64,
+    pub is_typing: bool,
+    pub seq: u64,
+}
+
+/// Sequence counter plus the latest sequence number per (sender, receiver)
+/// pair, so a pending expiry can tell whether it has been superseded.
+#[derive(Default)]
+pub struct TypingState {
+    seq: AtomicU64,
+    latest: Mutex<HashMap<(i64, i64), u64>>,
+}
+
+impl TypingState {
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+fn expire_later<R: Runtime>(app: AppHandle<R>, event: TypingEvent) {
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(TYPING_TTL).await;
+
+        let typing = app.state::<TypingState>();
+        let key = (event.sender_id, event.receiver_id);
+        {
+            let mut latest = match typing.latest.lock() {
+                Ok(latest) => latest,
+                Err(_) => return,
+            };
+            if latest.get(&key) != Some(&event.seq) {
+                return;
+            }
+            latest.remove(&key);
+        }
+
+        let _ = app.emit(
+            "typing-expired",
+            TypingEvent {
+                is_typing: false,
+                ..event
+            },
+        );
+    });
+}
+
+#[tauri::command]
+pub fn send_typing(
+    app: AppHandle,
+    ws: State<'_, Mutex<WsState>>,
+    typing: State<'_, TypingState>,
+    sender_id: i64,
+    receiver_id: i64,
+    is_typing: bool,
+) -> Result<(), AppError> {
+    let event = TypingEvent {
+        sender_id,
+        receiver_id,
+        is_typing,
+        seq: typing.next_seq(),
+    };
+
+    let frame = serialize_ws_frame(&WsEvent::Typing(event.clone()));
+    ws_manager::send_text(&ws, frame)?;
+
+    {
+        let mut latest = typing.latest.lock()?;
+        if is_typing {
+            latest.insert((sender_id, receiver_id), event.seq);
+        } else {
+            latest.remove(&(sender_id, receiver_id));
+        }
+    }
+
+    if is_typing {
+        expire_later(app, event);
+    }
+    Ok(())
+}
@@ -0,0 +1,1436 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::State;
+use tauri_plugin_sql::DbInstances;
+use url::Url;
+
+use crate::db;
+use crate::error::{AppError, Context};
+use crate::retention::{CLEAR_QUOTES, DIRECT_MESSAGE_CLEANUP};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct UserRow {
+    pub id: i64,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    /// Cached copy of `avatar_url`, see `cache_avatar`.
+    pub avatar_local_path: Option<String>,
+    pub status: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct UserStats {
+    pub user_id: i64,
+    pub messages_sent: i64,
+    pub messages_received: i64,
+    pub contacts: i64,
+    pub groups_joined: i64,
+    /// Newest direct or group message the user sent, if any.
+    pub last_activity_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MergeCount {
+    pub table: String,
+    /// Rows moved from the duplicate to the primary user.
+    pub reassigned: i64,
+    /// Rows dropped because the primary already had an equivalent one, or
+    /// because they would have pointed the primary at itself.
+    pub removed: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MergeReport {
+    pub primary_id: i64,
+    pub duplicate_id: i64,
+    /// Direct messages between the two accounts, deleted rather than turned
+    /// into a conversation with oneself.
+    pub self_messages_removed: i64,
+    pub tables: Vec<MergeCount>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EraseCount {
+    pub table: String,
+    pub removed: i64,
+    /// Rows kept for other users but moved to the `~deleted` tombstone.
+    pub anonymized: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EraseReport {
+    pub user_id: i64,
+    /// Nothing was changed; the counts are what erasing would do.
+    pub dry_run: bool,
+    pub tables: Vec<EraseCount>,
+}
+
+/// How one table's references to a user move during a merge. In every
+/// statement `?1` is the primary user and `?2` the duplicate.
+struct MergeStep {
+    table: &'static str,
+    reassign: &'static [&'static str],
+    cleanup: &'static [&'static str],
+}
+
+/// Where a table's primary key includes the user, the reassigning updates
+/// skip rows the primary already has and the cleanup drops what is left.
+const MERGE_STEPS: &[MergeStep] = &[
+    MergeStep {
+        table: "messages",
+        reassign: &[
+            "UPDATE messages SET sender_id = ?1 WHERE sender_id = ?2",
+            "UPDATE messages SET receiver_id = ?1 WHERE receiver_id = ?2",
+        ],
+        cleanup: &[],
+    },
+    MergeStep {
+        table: "scheduled_messages",
+        reassign: &[
+            "UPDATE scheduled_messages SET sender_id = ?1 WHERE sender_id = ?2",
+            "UPDATE scheduled_messages SET receiver_id = ?1 WHERE receiver_id = ?2",
+        ],
+        cleanup: &[],
+    },
+    MergeStep {
+        table: "contacts",
+        reassign: &[
+            "UPDATE OR IGNORE contacts SET user_id = ?1 WHERE user_id = ?2",
+            "UPDATE OR IGNORE contacts SET contact_id = ?1 WHERE contact_id = ?2",
+        ],
+        cleanup: &["DELETE FROM contacts
+                    WHERE ?2 IN (user_id, contact_id) OR (user_id = ?1 AND contact_id = ?1)"],
+    },
+    MergeStep {
+        table: "blocked_users",
+        reassign: &[
+            "UPDATE OR IGNORE blocked_users SET user_id = ?1 WHERE user_id = ?2",
+            "UPDATE OR IGNORE blocked_users SET blocked_id = ?1 WHERE blocked_id = ?2",
+        ],
+        cleanup: &["DELETE FROM blocked_users
+                    WHERE ?2 IN (user_id, blocked_id) OR (user_id = ?1 AND blocked_id = ?1)"],
+    },
+    MergeStep {
+        table: "group_members",
+        reassign: &["UPDATE OR IGNORE group_members SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM group_members WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "group_contacts",
+        reassign: &["UPDATE OR IGNORE group_contacts SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM group_contacts WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "group_settings",
+        reassign: &["UPDATE OR IGNORE group_settings SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM group_settings WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "message_reactions",
+        reassign: &["UPDATE OR IGNORE message_reactions SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM message_reactions WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "message_delivery",
+        reassign: &["UPDATE OR IGNORE message_delivery SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM message_delivery WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "starred_messages",
+        reassign: &["UPDATE OR IGNORE starred_messages SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM starred_messages WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "mentions",
+        reassign: &[
+            "UPDATE OR IGNORE mentions SET mentioned_user_id = ?1 WHERE mentioned_user_id = ?2",
+        ],
+        cleanup: &["DELETE FROM mentions WHERE mentioned_user_id = ?2"],
+    },
+    MergeStep {
+        table: "drafts",
+        reassign: &[
+            "UPDATE OR IGNORE drafts SET user_id = ?1 WHERE user_id = ?2",
+            "UPDATE OR IGNORE drafts SET peer_id = CAST(?1 AS TEXT)
+             WHERE peer_type = 'user' AND peer_id = CAST(?2 AS TEXT)",
+        ],
+        cleanup: &["DELETE FROM drafts
+                    WHERE user_id = ?2
+                       OR (peer_type = 'user' AND peer_id = CAST(?2 AS TEXT))
+                       OR (user_id = ?1 AND peer_type = 'user' AND peer_id = CAST(?1 AS TEXT))"],
+    },
+    MergeStep {
+        table: "archived_conversations",
+        reassign: &[
+            "UPDATE OR IGNORE archived_conversations SET user_id = ?1 WHERE user_id = ?2",
+            "UPDATE OR IGNORE archived_conversations SET peer_id = CAST(?1 AS TEXT)
+             WHERE peer_type = 'user' AND peer_id = CAST(?2 AS TEXT)",
+        ],
+        cleanup: &["DELETE FROM archived_conversations
+                    WHERE user_id = ?2
+                       OR (peer_type = 'user' AND peer_id = CAST(?2 AS TEXT))
+                       OR (user_id = ?1 AND peer_type = 'user' AND peer_id = CAST(?1 AS TEXT))"],
+    },
+    MergeStep {
+        table: "conversation_ttls",
+        reassign: &[
+            "UPDATE OR IGNORE conversation_ttls SET user_id = ?1 WHERE user_id = ?2",
+            "UPDATE OR IGNORE conversation_ttls SET peer_id = CAST(?1 AS TEXT)
+             WHERE peer_type = 'user' AND peer_id = CAST(?2 AS TEXT)",
+        ],
+        cleanup: &["DELETE FROM conversation_ttls
+                    WHERE user_id = ?2
+                       OR (peer_type = 'user' AND peer_id = CAST(?2 AS TEXT))
+                       OR (user_id = ?1 AND peer_type = 'user' AND peer_id = CAST(?1 AS TEXT))"],
+    },
+    MergeStep {
+        table: "message_links",
+        reassign: &["UPDATE OR IGNORE message_links SET created_by = ?1 WHERE created_by = ?2"],
+        cleanup: &["DELETE FROM message_links WHERE created_by = ?2"],
+    },
+    MergeStep {
+        table: "user_preferences",
+        reassign: &["UPDATE OR IGNORE user_preferences SET user_id = ?1 WHERE user_id = ?2"],
+        cleanup: &["DELETE FROM user_preferences WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "groups",
+        reassign: &["UPDATE groups SET created_by = ?1 WHERE created_by = ?2"],
+        cleanup: &[],
+    },
+    MergeStep {
+        table: "group_messages",
+        reassign: &[
+            "UPDATE group_messages SET sender_id = ?1 WHERE sender_id = ?2",
+            "UPDATE group_messages SET pinned_by = ?1 WHERE pinned_by = ?2",
+        ],
+        cleanup: &[],
+    },
+    MergeStep {
+        table: "group_invites",
+        reassign: &["UPDATE group_invites SET created_by = ?1 WHERE created_by = ?2"],
+        cleanup: &[],
+    },
+    // Tokens and encryption keys belong to the duplicate's sign-in, not to
+    // the person.
+    MergeStep {
+        table: "auth_tokens",
+        reassign: &[],
+        cleanup: &["DELETE FROM auth_tokens WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "public_keys",
+        reassign: &[],
+        cleanup: &["DELETE FROM public_keys WHERE user_id = ?2"],
+    },
+    MergeStep {
+        table: "conversation_keys",
+        reassign: &[],
+        cleanup: &["DELETE FROM conversation_keys WHERE ?2 IN (user_id, wrapped_by)"],
+    },
+];
+
+/// How one table's references to a user go when the account is erased. In
+/// every statement `?1` is the user and `?2` is `DELETED_USER_ID`. The
+/// user's direct messages, sent group messages and the account itself are
+/// handled in `erase_user`.
+struct EraseStep {
+    table: &'static str,
+    anonymize: &'static [&'static str],
+    remove: &'static [&'static str],
+}
+
+const ERASE_STEPS: &[EraseStep] = &[
+    EraseStep {
+        table: "scheduled_messages",
+        anonymize: &[],
+        remove: &["DELETE FROM scheduled_messages WHERE ?1 IN (sender_id, receiver_id)"],
+    },
+    EraseStep {
+        table: "contacts",
+        anonymize: &[],
+        remove: &["DELETE FROM contacts WHERE ?1 IN (user_id, contact_id)"],
+    },
+    EraseStep {
+        table: "blocked_users",
+        anonymize: &[],
+        remove: &["DELETE FROM blocked_users WHERE ?1 IN (user_id, blocked_id)"],
+    },
+    EraseStep {
+        table: "group_members",
+        anonymize: &[],
+        remove: &["DELETE FROM group_members WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "group_contacts",
+        anonymize: &[],
+        remove: &["DELETE FROM group_contacts WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "group_settings",
+        anonymize: &[],
+        remove: &["DELETE FROM group_settings WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "message_reactions",
+        anonymize: &[],
+        remove: &["DELETE FROM message_reactions WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "message_delivery",
+        anonymize: &[],
+        remove: &["DELETE FROM message_delivery WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "starred_messages",
+        anonymize: &[],
+        remove: &["DELETE FROM starred_messages WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "mentions",
+        anonymize: &[],
+        remove: &["DELETE FROM mentions WHERE mentioned_user_id = ?1"],
+    },
+    EraseStep {
+        table: "drafts",
+        anonymize: &[],
+        remove: &["DELETE FROM drafts
+                   WHERE user_id = ?1 OR (peer_type = 'user' AND peer_id = CAST(?1 AS TEXT))"],
+    },
+    EraseStep {
+        table: "archived_conversations",
+        anonymize: &[],
+        remove: &["DELETE FROM archived_conversations
+                   WHERE user_id = ?1 OR (peer_type = 'user' AND peer_id = CAST(?1 AS TEXT))"],
+    },
+    EraseStep {
+        table: "conversation_ttls",
+        anonymize: &[],
+        remove: &["DELETE FROM conversation_ttls
+                   WHERE user_id = ?1 OR (peer_type = 'user' AND peer_id = CAST(?1 AS TEXT))"],
+    },
+    EraseStep {
+        table: "message_links",
+        anonymize: &[],
+        remove: &["DELETE FROM message_links WHERE created_by = ?1"],
+    },
+    EraseStep {
+        table: "user_preferences",
+        anonymize: &[],
+        remove: &["DELETE FROM user_preferences WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "auth_tokens",
+        anonymize: &[],
+        remove: &["DELETE FROM auth_tokens WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "public_keys",
+        anonymize: &[],
+        remove: &["DELETE FROM public_keys WHERE user_id = ?1"],
+    },
+    EraseStep {
+        table: "conversation_keys",
+        anonymize: &[],
+        remove: &["DELETE FROM conversation_keys WHERE ?1 IN (user_id, wrapped_by)"],
+    },
+    EraseStep {
+        table: "groups",
+        anonymize: &["UPDATE groups SET created_by = ?2 WHERE created_by = ?1"],
+        remove: &[],
+    },
+    EraseStep {
+        table: "group_messages",
+        anonymize: &[
+            "UPDATE group_messages SET sender_id = ?2 WHERE sender_id = ?1",
+            "UPDATE group_messages SET pinned_by = ?2 WHERE pinned_by = ?1",
+        ],
+        remove: &[],
+    },
+    EraseStep {
+        table: "group_invites",
+        anonymize: &["UPDATE group_invites SET created_by = ?2 WHERE created_by = ?1"],
+        remove: &[],
+    },
+];
+
+/// Messages between the two accounts, which would become a conversation
+/// with oneself.
+const SELF_CONVERSATION: &str = "(sender_id = ?2 AND receiver_id IN (?1, ?2))
+    OR (sender_id = ?1 AND receiver_id = ?2)";
+
+/// Author of the app's own messages, such as membership announcements in
+/// groups. Created by a migration as `~system`, a name `validate_username`
+/// never accepts.
+pub const SYSTEM_USER_ID: i64 = 0;
+
+/// Stands in for erased accounts wherever their rows are kept for other
+/// people's sake, like group history. Created by a migration as `~deleted`.
+pub const DELETED_USER_ID: i64 = -1;
+
+pub const MAX_USER_LOOKUP: usize = 500;
+pub const DEFAULT_USER_SEARCH_LIMIT: u32 = 10;
+pub const MAX_USER_SEARCH_LIMIT: u32 = 50;
+pub const MIN_USERNAME_CHARS: usize = 3;
+pub const MAX_USERNAME_CHARS: usize = 32;
+pub const MAX_USERNAME_SUGGESTIONS: u32 = 20;
+
+/// Invisible users read as offline, as they do in presence events.
+pub(crate) const USER_COLUMNS: &str = "id, username, avatar_url, avatar_local_path,
+     CASE WHEN invisible THEN 'offline' ELSE COALESCE(status, 'offline') END AS status,
+     created_at";
+
+pub(crate) fn validate_username(username: &str) -> Result<(), AppError> {
+    let len = username.chars().count();
+    if !(MIN_USERNAME_CHARS..=MAX_USERNAME_CHARS).contains(&len) {
+        return Err(AppError::Validation(format!(
+            "username must be between {} and {} characters",
+            MIN_USERNAME_CHARS, MAX_USERNAME_CHARS
+        )));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(AppError::Validation(
+            "username may only contain letters, digits, '_' and '-'".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Variations on `base` in the order they're offered: numbered ones first,
+/// then a few random ones so popular names still get something short.
+/// `base` is shortened where needed to leave room for the suffix.
+fn username_candidates(base: &str, wanted: usize) -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    let mut suffixes: Vec<String> = (1..=wanted).map(|n| n.to_string()).collect();
+    suffixes.extend((0..wanted * 2).map(|_| format!("_{}", rng.gen_range(100..10_000))));
+    suffixes.extend(["_app", "_hq", "-official"].map(String::from));
+
+    let mut candidates: Vec<String> = Vec::new();
+    for suffix in suffixes {
+        let room = MAX_USERNAME_CHARS.saturating_sub(suffix.len());
+        let candidate = format!("{}{}", &base[..base.len().min(room)], suffix);
+        if candidate != base
+            && validate_username(&candidate).is_ok()
+            && !candidates.contains(&candidate)
+        {
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+/// Up to `count` usernames based on `base` that nobody has. Checked
+/// case-insensitively, so a suggestion never differs from an existing name
+/// by case alone.
+pub(crate) async fn username_suggestions(
+    pool: &SqlitePool,
+    base: &str,
+    count: u32,
+) -> Result<Vec<String>, AppError> {
+    let base = base.trim();
+    validate_username(base)?;
+    let count = count.min(MAX_USERNAME_SUGGESTIONS);
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let candidates = username_candidates(base, count as usize);
+    sqlx::query_scalar(
+        "SELECT c.value FROM json_each(?1) c
+         WHERE NOT EXISTS (SELECT 1 FROM users u WHERE u.username = c.value COLLATE NOCASE)
+         ORDER BY c.key
+         LIMIT ?2",
+    )
+    .bind(serde_json::to_string(&candidates).expect("names serialize to JSON"))
+    .bind(count)
+    .fetch_all(pool)
+    .await
+    .context("failed to check username suggestions")
+}
+
+/// Avatars may point at the web or at a file already on this machine.
+pub(crate) fn validate_avatar_url(avatar_url: &str) -> Result<(), AppError> {
+    let parsed = Url::parse(avatar_url)
+        .map_err(|e| AppError::Validation(format!("invalid avatar url: {}", e)))?;
+    match parsed.scheme() {
+        "http" | "https" if parsed.host_str().is_some() => Ok(()),
+        "file" => Ok(()),
+        _ => Err(AppError::Validation(
+            "avatar url must be an http(s) url or a file:// path".into(),
+        )),
+    }
+}
+
+/// Applies whichever fields are provided and returns the resulting row.
+pub(crate) async fn apply_profile_update(
+    pool: &SqlitePool,
+    user_id: i64,
+    username: Option<&str>,
+    avatar_url: Option<&str>,
+) -> Result<UserRow, AppError> {
+    let username = username.map(str::trim);
+    if let Some(username) = username {
+        validate_username(username)?;
+    }
+    if let Some(avatar_url) = avatar_url {
+        validate_avatar_url(avatar_url)?;
+    }
+
+    let result = sqlx::query_as::<_, UserRow>(&format!(
+        "UPDATE users
+         SET username = COALESCE(?, username), avatar_url = COALESCE(?, avatar_url)
+         WHERE id = ?
+         RETURNING {}",
+        USER_COLUMNS
+    ))
+    .bind(username)
+    .bind(avatar_url)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(Some(row)) => Ok(row),
+        Ok(None) => Err(AppError::NotFound(format!("user {} not found", user_id))),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Err(AppError::UsernameTaken(format!(
+                "username '{}' is already taken",
+                username.unwrap_or_default()
+            )))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Escapes `%`, `_` and the backslash escape character for `LIKE`.
+fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Usernames containing `query`, case-insensitively. Prefix matches come
+/// first, then other substring matches, each alphabetically.
+pub(crate) async fn search_usernames(
+    pool: &SqlitePool,
+    query: &str,
+    limit: u32,
+    self_id: Option<i64>,
+) -> Result<Vec<UserRow>, AppError> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let limit = match limit {
+        0 => DEFAULT_USER_SEARCH_LIMIT,
+        n => n.min(MAX_USER_SEARCH_LIMIT),
+    };
+    let escaped = escape_like(query);
+
+    sqlx::query_as::<_, UserRow>(&format!(
+        "SELECT {}
+         FROM users
+         WHERE username LIKE '%' || ?1 || '%' ESCAPE '\\'
+           AND (?3 IS NULL OR id != ?3)
+           AND id NOT IN (?4, ?5)
+         ORDER BY CASE WHEN username LIKE ?1 || '%' ESCAPE '\\' THEN 0 ELSE 1 END,
+                  username COLLATE NOCASE
+         LIMIT ?2",
+        USER_COLUMNS
+    ))
+    .bind(&escaped)
+    .bind(limit)
+    .bind(self_id)
+    .bind(SYSTEM_USER_ID)
+    .bind(DELETED_USER_ID)
+    .fetch_all(pool)
+    .await
+    .context("failed to search users")
+}
+
+/// Looks up many users in one query. Results follow the order of `ids`, with
+/// duplicates collapsed to their first occurrence and unknown ids omitted.
+pub(crate) async fn users_by_ids(pool: &SqlitePool, ids: &[i64]) -> Result<Vec<UserRow>, AppError> {
+    if ids.len() > MAX_USER_LOOKUP {
+        return Err(AppError::Validation(format!(
+            "cannot look up more than {} users at once",
+            MAX_USER_LOOKUP
+        )));
+    }
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let sql = format!(
+        "SELECT {} FROM users WHERE id IN ({})",
+        USER_COLUMNS, placeholders
+    );
+    let mut query = sqlx::query_as::<_, UserRow>(&sql);
+    for id in ids {
+        query = query.bind(id);
+    }
+    let rows = query
+        .fetch_all(pool)
+        .await
+        .context("failed to load users")?;
+
+    let mut by_id: HashMap<i64, UserRow> = rows.into_iter().map(|row| (row.id, row)).collect();
+    Ok(ids.iter().filter_map(|id| by_id.remove(id)).collect())
+}
+
+/// Gathers all profile stats in a single statement. Sent counts cover both
+/// direct and group messages; received counts are direct messages only.
+pub(crate) async fn stats_for(pool: &SqlitePool, user_id: i64) -> Result<UserStats, AppError> {
+    sqlx::query_as::<_, UserStats>(
+        "SELECT
+             u.id AS user_id,
+             (SELECT COUNT(*) FROM messages WHERE sender_id = u.id)
+               + (SELECT COUNT(*) FROM group_messages WHERE sender_id = u.id) AS messages_sent,
+             (SELECT COUNT(*) FROM messages WHERE receiver_id = u.id) AS messages_received,
+             (SELECT COUNT(*) FROM contacts WHERE user_id = u.id) AS contacts,
+             (SELECT COUNT(*) FROM group_members WHERE user_id = u.id) AS groups_joined,
+             (SELECT MAX(ts) FROM (
+                  SELECT timestamp AS ts FROM messages WHERE sender_id = u.id
+                  UNION ALL
+                  SELECT timestamp FROM group_messages WHERE sender_id = u.id
+             )) AS last_activity_at
+         FROM users u
+         WHERE u.id = ?",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .context("failed to load user stats")?
+    .ok_or_else(|| AppError::NotFound(format!("user {} not found", user_id)))
+}
+
+/// Returns the user called `username`, creating it first if needed. The
+/// insert and the read share a transaction, so concurrent callers all end up
+/// with the same row. `avatar_url` only applies when the user is created.
+pub(crate) async fn find_or_create_user(
+    pool: &SqlitePool,
+    username: &str,
+    avatar_url: Option<&str>,
+) -> Result<UserRow, AppError> {
+    let username = username.trim();
+    validate_username(username)?;
+    if let Some(avatar_url) = avatar_url {
+        validate_avatar_url(avatar_url)?;
+    }
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "INSERT INTO users (username, avatar_url) VALUES (?, ?)
+         ON CONFLICT (username) DO NOTHING",
+    )
+    .bind(username)
+    .bind(avatar_url)
+    .execute(&mut *tx)
+    .await?;
+
+    let row = sqlx::query_as::<_, UserRow>(&format!(
+        "SELECT {} FROM users WHERE username = ?",
+        USER_COLUMNS
+    ))
+    .bind(username)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(row)
+}
+
+/// Folds `duplicate_id` into `primary_id` and deletes the duplicate, all in
+/// one transaction. Where both were in the same group the primary keeps the
+/// higher of the two roles.
+pub(crate) async fn merge_accounts(
+    pool: &SqlitePool,
+    primary_id: i64,
+    duplicate_id: i64,
+) -> Result<MergeReport, AppError> {
+    if primary_id == duplicate_id {
+        return Err(AppError::Validation(
+            "cannot merge a user into itself".into(),
+        ));
+    }
+    if [primary_id, duplicate_id]
+        .iter()
+        .any(|id| [SYSTEM_USER_ID, DELETED_USER_ID].contains(id))
+    {
+        return Err(AppError::PermissionDenied(
+            "the system user cannot be merged".into(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    for id in [primary_id, duplicate_id] {
+        let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM users WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if exists.is_none() {
+            return Err(AppError::NotFound(format!("user {} not found", id)));
+        }
+    }
+
+    let self_ids: Vec<i64> = sqlx::query_scalar(&format!(
+        "SELECT id FROM messages WHERE {}",
+        SELF_CONVERSATION
+    ))
+    .bind(primary_id)
+    .bind(duplicate_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let batch = serde_json::to_string(&self_ids).expect("ids serialize to JSON");
+    for statement in DIRECT_MESSAGE_CLEANUP {
+        sqlx::query(statement)
+            .bind(&batch)
+            .execute(&mut *tx)
+            .await
+            .context("failed to clean up self-conversation")?;
+    }
+    sqlx::query("DELETE FROM messages WHERE id IN (SELECT value FROM json_each(?1))")
+        .bind(&batch)
+        .execute(&mut *tx)
+        .await
+        .context("failed to remove self-conversation")?;
+    sqlx::query(&format!(
+        "DELETE FROM scheduled_messages WHERE {}",
+        SELF_CONVERSATION
+    ))
+    .bind(primary_id)
+    .bind(duplicate_id)
+    .execute(&mut *tx)
+    .await
+    .context("failed to remove self-addressed scheduled messages")?;
+
+    // Retried sends are keyed on (sender, client id); drop the duplicate's
+    // key where the primary already used it.
+    sqlx::query(
+        "UPDATE messages SET client_msg_id = NULL
+         WHERE sender_id = ?2 AND client_msg_id IN (
+             SELECT client_msg_id FROM messages WHERE sender_id = ?1 AND client_msg_id IS NOT NULL
+         )",
+    )
+    .bind(primary_id)
+    .bind(duplicate_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        "UPDATE group_members SET role = (
+             SELECT d.role FROM group_members d
+             WHERE d.group_id = group_members.group_id AND d.user_id = ?2
+         )
+         WHERE user_id = ?1 AND EXISTS (
+             SELECT 1 FROM group_members d
+             WHERE d.group_id = group_members.group_id AND d.user_id = ?2
+               AND CASE d.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END
+                 < CASE group_members.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END
+         )",
+    )
+    .bind(primary_id)
+    .bind(duplicate_id)
+    .execute(&mut *tx)
+    .await
+    .context("failed to merge group roles")?;
+
+    let mut tables = Vec::with_capacity(MERGE_STEPS.len());
+    for step in MERGE_STEPS {
+        let mut count = MergeCount {
+            table: step.table.to_string(),
+            reassigned: 0,
+            removed: 0,
+        };
+        for (statements, total) in [
+            (step.reassign, &mut count.reassigned),
+            (step.cleanup, &mut count.removed),
+        ] {
+            for statement in statements {
+                *total += sqlx::query(statement)
+                    .bind(primary_id)
+                    .bind(duplicate_id)
+                    .execute(&mut *tx)
+                    .await
+                    .context(&format!("failed to merge {}", step.table))?
+                    .rows_affected() as i64;
+            }
+        }
+        tables.push(count);
+    }
+
+    // Groups both accounts were in have lost a member.
+    sqlx::query(
+        "UPDATE groups
+         SET member_count = (SELECT COUNT(*) FROM group_members WHERE group_id = groups.id)
+         WHERE id IN (SELECT group_id FROM group_members WHERE user_id = ?)",
+    )
+    .bind(primary_id)
+    .execute(&mut *tx)
+    .await
+    .context("failed to update member counts")?;
+
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(duplicate_id)
+        .execute(&mut *tx)
+        .await
+        .context("failed to delete duplicate user")?;
+
+    tx.commit().await?;
+    Ok(MergeReport {
+        primary_id,
+        duplicate_id,
+        self_messages_removed: self_ids.len() as i64,
+        tables,
+    })
+}
+
+/// Deletes the account and everything that is only the user's. Their direct
+/// messages go; messages others sent them and their group messages stay,
+/// credited to `DELETED_USER_ID`. Groups they solely owned pass to the
+/// senior remaining member, admins first.
+///
+/// Unless `confirm`, the same work is done and rolled back, so the dry-run
+/// report is exactly what a real erase would do.
+pub(crate) async fn erase_user(
+    pool: &SqlitePool,
+    user_id: i64,
+    confirm: bool,
+) -> Result<EraseReport, AppError> {
+    if [SYSTEM_USER_ID, DELETED_USER_ID].contains(&user_id) {
+        return Err(AppError::PermissionDenied(
+            "the system user cannot be erased".into(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+    let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if exists.is_none() {
+        return Err(AppError::NotFound(format!("user {} not found", user_id)));
+    }
+
+    let sent: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages WHERE sender_id = ?")
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await
+        .context("failed to load messages to erase")?;
+    let batch = serde_json::to_string(&sent).expect("ids serialize to JSON");
+    // Replies keep a copy of what they quote; it goes with the original.
+    sqlx::query(CLEAR_QUOTES)
+        .bind(&batch)
+        .execute(&mut *tx)
+        .await
+        .context("failed to clear quotes")?;
+    for statement in DIRECT_MESSAGE_CLEANUP {
+        sqlx::query(statement)
+            .bind(&batch)
+            .execute(&mut *tx)
+            .await
+            .context("failed to clean up messages")?;
+    }
+    let removed = sqlx::query("DELETE FROM messages WHERE id IN (SELECT value FROM json_each(?1))")
+        .bind(&batch)
+        .execute(&mut *tx)
+        .await
+        .context("failed to erase messages")?
+        .rows_affected() as i64;
+    let anonymized = sqlx::query("UPDATE messages SET receiver_id = ?2 WHERE receiver_id = ?1")
+        .bind(user_id)
+        .bind(DELETED_USER_ID)
+        .execute(&mut *tx)
+        .await
+        .context("failed to anonymize messages")?
+        .rows_affected() as i64;
+    let mut tables = vec![EraseCount {
+        table: "messages".to_string(),
+        removed,
+        anonymized,
+    }];
+
+    let groups: Vec<String> =
+        sqlx::query_scalar("SELECT group_id FROM group_members WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&mut *tx)
+            .await?;
+    sqlx::query(
+        "UPDATE group_members SET role = 'owner'
+         WHERE rowid IN (
+             SELECT (SELECT m.rowid FROM group_members m
+                     WHERE m.group_id = o.group_id AND m.user_id != ?1
+                     ORDER BY m.role = 'admin' DESC, m.joined_at, m.user_id
+                     LIMIT 1)
+             FROM group_members o
+             WHERE o.user_id = ?1 AND o.role = 'owner'
+               AND NOT EXISTS (SELECT 1 FROM group_members x
+                               WHERE x.group_id = o.group_id AND x.role = 'owner'
+                                 AND x.user_id != ?1)
+         )",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .context("failed to hand over group ownership")?;
+
+    for step in ERASE_STEPS {
+        let mut count = EraseCount {
+            table: step.table.to_string(),
+            removed: 0,
+            anonymized: 0,
+        };
+        for (statements, total) in [
+            (step.anonymize, &mut count.anonymized),
+            (step.remove, &mut count.removed),
+        ] {
+            for statement in statements {
+                *total += sqlx::query(statement)
+                    .bind(user_id)
+                    .bind(DELETED_USER_ID)
+                    .execute(&mut *tx)
+                    .await
+                    .context(&format!("failed to erase {}", step.table))?
+                    .rows_affected() as i64;
+            }
+        }
+        tables.push(count);
+    }
+
+    sqlx::query(
+        "UPDATE groups
+         SET member_count = (SELECT COUNT(*) FROM group_members WHERE group_id = groups.id)
+         WHERE id IN (SELECT value FROM json_each(?))",
+    )
+    .bind(serde_json::to_string(&groups).expect("ids serialize to JSON"))
+    .execute(&mut *tx)
+    .await
+    .context("failed to update member counts")?;
+
+    let removed = sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .context("failed to delete user")?
+        .rows_affected() as i64;
+    tables.push(EraseCount {
+        table: "users".to_string(),
+        removed,
+        anonymized: 0,
+    });
+
+    if confirm {
+        tx.commit().await?;
+    } else {
+        tx.rollback().await?;
+    }
+    Ok(EraseReport {
+        user_id,
+        dry_run: !confirm,
+        tables,
+    })
+}
+
+#[tauri::command]
+pub async fn get_users(
+    db: State<'_, DbInstances>,
+    ids: Vec<i64>,
+) -> Result<Vec<UserRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    users_by_ids(&pool, &ids).await
+}
+
+#[tauri::command]
+pub async fn update_profile(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    username: Option<String>,
+    avatar_url: Option<String>,
+) -> Result<UserRow, AppError> {
+    let pool = db::pool(&db).await?;
+    apply_profile_update(&pool, user_id, username.as_deref(), avatar_url.as_deref()).await
+}
+
+#[tauri::command]
+pub async fn user_stats(db: State<'_, DbInstances>, user_id: i64) -> Result<UserStats, AppError> {
+    let pool = db::pool(&db).await?;
+    stats_for(&pool, user_id).await
+}
+
+#[tauri::command]
+pub async fn get_or_create_user(
+    db: State<'_, DbInstances>,
+    username: String,
+    avatar_url: Option<String>,
+) -> Result<UserRow, AppError> {
+    let pool = db::pool(&db).await?;
+    find_or_create_user(&pool, &username, avatar_url.as_deref()).await
+}
+
+#[tauri::command]
+pub async fn merge_users(
+    db: State<'_, DbInstances>,
+    primary_id: i64,
+    duplicate_id: i64,
+) -> Result<MergeReport, AppError> {
+    let pool = db::pool(&db).await?;
+    merge_accounts(&pool, primary_id, duplicate_id).await
+}
+
+/// Autocomplete for the "new chat" dialog.
+#[tauri::command]
+pub async fn search_users(
+    db: State<'_, DbInstances>,
+    query: String,
+    limit: u32,
+    self_id: Option<i64>,
+) -> Result<Vec<UserRow>, AppError> {
+    let pool = db::pool(&db).await?;
+    search_usernames(&pool, &query, limit, self_id).await
+}
+
+#[tauri::command]
+pub async fn erase_user_data(
+    db: State<'_, DbInstances>,
+    user_id: i64,
+    confirm: bool,
+) -> Result<EraseReport, AppError> {
+    let pool = db::pool(&db).await?;
+    erase_user(&pool, user_id, confirm).await
+}
+
+#[tauri::command]
+pub async fn suggest_usernames(
+    db: State<'_, DbInstances>,
+    base: String,
+    count: u32,
+) -> Result<Vec<String>, AppError> {
+    let pool = db::pool(&db).await?;
+    username_suggestions(&pool, &base, count).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contacts::insert_contact;
+    use crate::encryption::EncryptionState;
+    use crate::group_messages::insert_group_message;
+    use crate::groups::{insert_group, member_role};
+    use crate::message_type::MessageType;
+    use crate::messages::insert_message;
+    use crate::preferences::upsert_preference;
+    use crate::presence::set_invisible_mode;
+    use crate::test_support::{count, file_pool, remove_db, test_pool};
+
+    #[tokio::test]
+    async fn renaming_to_a_taken_name_fails() {
+        let pool = test_pool().await;
+        apply_profile_update(&pool, 2, Some("bob"), None)
+            .await
+            .unwrap();
+        let err = apply_profile_update(&pool, 1, Some("bob"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "username_taken");
+
+        let renamed = apply_profile_update(&pool, 1, Some("neo"), None)
+            .await
+            .unwrap();
+        assert_eq!(renamed.username, "neo");
+        let updated = apply_profile_update(&pool, 1, None, Some("https://x.io/a.png"))
+            .await
+            .unwrap();
+        assert_eq!(updated.username, "neo");
+        assert_eq!(updated.avatar_url.as_deref(), Some("https://x.io/a.png"));
+
+        for avatar in ["ftp://x", "nope"] {
+            let err = apply_profile_update(&pool, 1, None, Some(avatar))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "validation");
+        }
+        let err = apply_profile_update(&pool, 99, Some("zed"), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn lookups_keep_input_order_and_collapse_duplicates() {
+        let pool = test_pool().await;
+        let users = users_by_ids(&pool, &[5, 2, 99, 5, 7, 2]).await.unwrap();
+        assert_eq!(users.iter().map(|u| u.id).collect::<Vec<_>>(), [5, 2, 7]);
+        assert!(users_by_ids(&pool, &[]).await.unwrap().is_empty());
+        let err = users_by_ids(&pool, &[1; MAX_USER_LOOKUP + 1])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "validation");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_get_or_create_makes_one_user() {
+        let pool = test_pool().await;
+        let first = find_or_create_user(&pool, "newbie", None).await.unwrap();
+        let again = find_or_create_user(&pool, "newbie", Some("https://x.io/a.png"))
+            .await
+            .unwrap();
+        assert_eq!(again.id, first.id);
+        assert!(again.avatar_url.is_none());
+        for name in ["ab", "bad name"] {
+            let err = find_or_create_user(&pool, name, None).await.unwrap_err();
+            assert_eq!(err.code(), "validation");
+        }
+
+        // Racing needs connections of its own, which one in-memory database
+        // can't give.
+        let (pool, path) = file_pool("racer").await;
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(
+                    async move { find_or_create_user(&pool, "racer", None).await.unwrap().id },
+                )
+            })
+            .collect();
+        let mut ids = Vec::new();
+        for task in tasks {
+            ids.push(task.await.unwrap());
+        }
+        ids.dedup();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM users WHERE username = 'racer'").await,
+            1
+        );
+        pool.close().await;
+        remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn prefix_matches_rank_before_substring_matches() {
+        let pool = test_pool().await;
+        for (id, name) in [
+            (1, "coralie"),
+            (2, "Alice"),
+            (3, "bob"),
+            (4, "a_b"),
+            (5, "axb"),
+        ] {
+            sqlx::query("UPDATE users SET username = ? WHERE id = ?")
+                .bind(name)
+                .bind(id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let names = |users: Vec<UserRow>| users.into_iter().map(|u| u.username).collect::<Vec<_>>();
+        assert_eq!(
+            names(search_usernames(&pool, "al", 10, None).await.unwrap()),
+            ["Alice", "coralie"]
+        );
+        // The searching user is left out.
+        assert_eq!(
+            names(search_usernames(&pool, "al", 10, Some(2)).await.unwrap()),
+            ["coralie"]
+        );
+        // LIKE wildcards in the query match literally.
+        assert_eq!(
+            names(search_usernames(&pool, "a_", 10, None).await.unwrap()),
+            ["a_b"]
+        );
+        assert!(search_usernames(&pool, "%", 10, None)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            search_usernames(&pool, "a", 1, None).await.unwrap().len(),
+            1
+        );
+    }
+
+    async fn message(pool: &SqlitePool, sender_id: i64, receiver_id: i64) -> i64 {
+        sqlx::query_scalar(
+            "INSERT INTO messages (sender_id, receiver_id, content) VALUES (?, ?, 'hi') RETURNING id",
+        )
+        .bind(sender_id)
+        .bind(receiver_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn merge_rejects_self_and_unknown_accounts() {
+        let pool = test_pool().await;
+        assert_eq!(
+            merge_accounts(&pool, 1, 1).await.unwrap_err().code(),
+            "validation"
+        );
+        assert_eq!(
+            merge_accounts(&pool, 1, 99).await.unwrap_err().code(),
+            "not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_moves_everything_to_the_primary() {
+        let pool = test_pool().await;
+        let to_third = message(&pool, 2, 3).await;
+        let from_third = message(&pool, 3, 2).await;
+        message(&pool, 2, 1).await;
+        message(&pool, 1, 2).await;
+        message(&pool, 2, 2).await;
+        insert_contact(&pool, 1, 3, false).await.unwrap();
+        insert_contact(&pool, 2, 3, true).await.unwrap();
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        let owned = insert_group(&pool, "g", None, 2, &[1, 3]).await.unwrap();
+        let joined = insert_group(&pool, "h", None, 4, &[2]).await.unwrap();
+
+        let report = merge_accounts(&pool, 1, 2).await.unwrap();
+        assert_eq!(report.self_messages_removed, 3);
+        let messages = report
+            .tables
+            .iter()
+            .find(|t| t.table == "messages")
+            .unwrap();
+        assert_eq!(messages.reassigned, 2);
+        let contacts = report
+            .tables
+            .iter()
+            .find(|t| t.table == "contacts")
+            .unwrap();
+        assert_eq!((contacts.reassigned, contacts.removed), (2, 3));
+
+        let remaining: Vec<(i64, i64, i64)> =
+            sqlx::query_as("SELECT id, sender_id, receiver_id FROM messages ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(remaining, [(to_third, 1, 3), (from_third, 3, 1)]);
+        let pairs: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT user_id, contact_id FROM contacts ORDER BY user_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(pairs, [(1, 3), (3, 1)]);
+
+        assert_eq!(
+            member_role(&pool, &owned.id, 1).await.unwrap().as_deref(),
+            Some("owner")
+        );
+        assert_eq!(
+            member_role(&pool, &joined.id, 1).await.unwrap().as_deref(),
+            Some("member")
+        );
+        let counts = "SELECT SUM(member_count) FROM groups";
+        assert_eq!(count(&pool, counts).await, 4);
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM users WHERE id = 2").await,
+            0
+        );
+        let violations = sqlx::query("PRAGMA foreign_key_check")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(violations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_reserved_accounts_cannot_be_erased() {
+        let pool = test_pool().await;
+        let err = erase_user(&pool, SYSTEM_USER_ID, true).await.unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        let err = erase_user(&pool, DELETED_USER_ID, false).await.unwrap_err();
+        assert_eq!(err.code(), "permission_denied");
+        assert_eq!(
+            erase_user(&pool, 99, false).await.unwrap_err().code(),
+            "not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn erasing_removes_the_user_and_anonymizes_what_others_keep() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let mine = insert_message(&pool, &encryption, 1, 2, "secret", MessageType::Text, None)
+            .await
+            .unwrap();
+        let theirs = insert_message(
+            &pool,
+            &encryption,
+            2,
+            1,
+            "re: secret",
+            MessageType::Text,
+            Some(mine.id),
+        )
+        .await
+        .unwrap();
+        insert_contact(&pool, 1, 2, true).await.unwrap();
+        upsert_preference(&pool, 1, "theme", "\"dark\"")
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO message_reactions (message_id, user_id, emoji) VALUES (?, 1, '👍')",
+        )
+        .bind(theirs.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let group = insert_group(&pool, "g", None, 1, &[2, 3]).await.unwrap();
+        sqlx::query("UPDATE group_members SET role = 'admin' WHERE user_id = 3")
+            .execute(&pool)
+            .await
+            .unwrap();
+        insert_group_message(
+            &pool,
+            &group.id,
+            1,
+            "hello group",
+            MessageType::Text,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let dry = erase_user(&pool, 1, false).await.unwrap();
+        assert!(dry.dry_run);
+        assert_eq!(
+            count(&pool, "SELECT COUNT(*) FROM users WHERE id = 1").await,
+            1
+        );
+        let real = erase_user(&pool, 1, true).await.unwrap();
+        assert!(!real.dry_run);
+        let counts = |report: &EraseReport| {
+            report
+                .tables
+                .iter()
+                .map(|t| (t.table.clone(), t.removed, t.anonymized))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(&dry), counts(&real));
+        let table = |name: &str| {
+            let t = real.tables.iter().find(|t| t.table == name).unwrap();
+            (t.removed, t.anonymized)
+        };
+        assert_eq!(table("messages"), (1, 1));
+        assert_eq!(table("contacts"), (2, 0));
+        assert_eq!(table("user_preferences"), (1, 0));
+        assert_eq!(table("message_reactions"), (1, 0));
+        assert_eq!(table("group_members"), (1, 0));
+        assert_eq!(table("groups"), (0, 1));
+        assert_eq!(table("users"), (1, 0));
+
+        let (receiver, snippet): (i64, Option<String>) =
+            sqlx::query_as("SELECT receiver_id, quoted_snippet FROM messages WHERE id = ?")
+                .bind(theirs.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(receiver, DELETED_USER_ID);
+        assert_eq!(snippet, None);
+        let tombstoned = format!(
+            "SELECT COUNT(*) FROM group_messages WHERE sender_id = {} AND content = 'hello group'",
+            DELETED_USER_ID
+        );
+        assert_eq!(count(&pool, &tombstoned).await, 1);
+        // The admin inherits the group.
+        assert_eq!(
+            member_role(&pool, &group.id, 3).await.unwrap().as_deref(),
+            Some("owner")
+        );
+        assert_eq!(count(&pool, "SELECT member_count FROM groups").await, 2);
+        let violations = sqlx::query("PRAGMA foreign_key_check")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(violations.is_empty());
+        assert!(search_usernames(&pool, "deleted", 10, None)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(search_usernames(&pool, "sys", 10, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn suggestions_are_valid_and_free() {
+        let pool = test_pool().await;
+        for username in ["alice", "alice1", "Alice2", "alice_app"] {
+            sqlx::query("INSERT INTO users (username) VALUES (?)")
+                .bind(username)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let suggestions = username_suggestions(&pool, " alice ", 5).await.unwrap();
+        assert_eq!(suggestions.len(), 5);
+        assert_eq!(&suggestions[..2], ["alice3", "alice4"]);
+        let unique: std::collections::HashSet<_> = suggestions.iter().collect();
+        assert_eq!(unique.len(), suggestions.len());
+        for name in &suggestions {
+            validate_username(name).unwrap();
+            let taken = format!(
+                "SELECT COUNT(*) FROM users WHERE username = '{}' COLLATE NOCASE",
+                name
+            );
+            assert_eq!(count(&pool, &taken).await, 0);
+        }
+
+        let long = "a".repeat(MAX_USERNAME_CHARS);
+        let suggestions = username_suggestions(&pool, &long, 3).await.unwrap();
+        assert!(suggestions
+            .iter()
+            .all(|name| name.len() <= MAX_USERNAME_CHARS && *name != long));
+    }
+
+    #[tokio::test]
+    async fn suggestion_count_is_capped() {
+        let pool = test_pool().await;
+        let err = username_suggestions(&pool, "a b", 3).await.unwrap_err();
+        assert_eq!(err.code(), "validation");
+        let suggestions = username_suggestions(&pool, "bob", 500).await.unwrap();
+        assert_eq!(suggestions.len(), MAX_USERNAME_SUGGESTIONS as usize);
+        assert!(username_suggestions(&pool, "bob", 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn invisible_users_look_offline_in_lookups() {
+        let pool = test_pool().await;
+        sqlx::query("UPDATE users SET status = 'online' WHERE id IN (1, 2)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        set_invisible_mode(&pool, 1, true).await.unwrap();
+
+        let found = search_usernames(&pool, "u1", 10, None).await.unwrap();
+        assert_eq!(found[0].id, 1);
+        assert_eq!(found[0].status.as_deref(), Some("offline"));
+
+        let users = users_by_ids(&pool, &[1, 2]).await.unwrap();
+        let statuses: Vec<_> = users.iter().map(|u| u.status.as_deref()).collect();
+        assert_eq!(statuses, [Some("offline"), Some("online")]);
+    }
+}
@@ -0,0 +1,902 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use rand::Rng;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+use tauri_plugin_sql::DbInstances;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::error::AppError;
+use crate::outbox::{self, OutboxFrame};
+use crate::protocol::{
+    parse_ws_frame, serialize_ws_frame, AuthEvent, NewMessageEvent, ProtocolError, WsEvent,
+};
+use crate::{db, messages, notify};
+
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+pub const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// A ping unanswered for this long marks the connection dead.
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the server has to answer the auth frame.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WsStatus {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Latency {
+    latency_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReconnectAttempt {
+    attempt: u32,
+    delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AuthRequired {
+    reason: Option<String>,
+}
+
+/// How the auth handshake on a fresh connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Handshake {
+    Accepted,
+    /// The server turned the token down. Reconnecting with the same token
+    /// would only be turned down again.
+    Rejected(Option<String>),
+    /// The socket closed or the server didn't answer in time; worth a retry.
+    Lost,
+}
+
+/// Connection bookkeeping shared between the commands and the socket task.
+///
+/// `generation` is bumped by every connect/disconnect; a socket task exits as
+/// soon as it notices it no longer owns the current generation.
+pub struct WsState {
+    status: WsStatus,
+    generation: u64,
+    outbound: Option<UnboundedSender<Message>>,
+    /// The signed-in user the connection was opened for.
+    user_id: Option<i64>,
+    /// Round-trip time of the latest answered ping on this connection.
+    last_latency_ms: Option<u64>,
+    /// Wakes the socket task when a frame is added to the outbox.
+    outbox_wake: Arc<Notify>,
+    /// When the socket last stopped being connected; `None` while it is.
+    down_since: Option<Instant>,
+    /// Highest local `messages.id` handed to the frontend, over the socket or
+    /// by polling.
+    last_delivered_id: Option<i64>,
+}
+
+impl Default for WsState {
+    fn default() -> Self {
+        Self {
+            status: WsStatus::Disconnected,
+            generation: 0,
+            outbound: None,
+            user_id: None,
+            last_latency_ms: None,
+            outbox_wake: Arc::new(Notify::new()),
+            down_since: Some(Instant::now()),
+            last_delivered_id: None,
+        }
+    }
+}
+
+impl WsState {
+    pub(crate) fn user_id(&self) -> Option<i64> {
+        self.user_id
+    }
+
+    fn set_status(&mut self, status: WsStatus) {
+        self.status = status;
+        if status == WsStatus::Connected {
+            self.down_since = None;
+        } else if self.down_since.is_none() {
+            self.down_since = Some(Instant::now());
+        }
+    }
+
+    /// How long the socket has been down, or `None` while it's connected.
+    pub(crate) fn offline_for(&self, now: Instant) -> Option<Duration> {
+        self.down_since
+            .map(|since| now.saturating_duration_since(since))
+    }
+
+    pub(crate) fn last_delivered_id(&self) -> Option<i64> {
+        self.last_delivered_id
+    }
+
+    /// Records `id` as delivered. Returns false if it, or a later message,
+    /// already was.
+    pub(crate) fn admit_message(&mut self, id: i64) -> bool {
+        if self.last_delivered_id.is_some_and(|last| id <= last) {
+            return false;
+        }
+        self.last_delivered_id = Some(id);
+        true
+    }
+}
+
+/// Ping/pong bookkeeping for one connection. At most one ping is in flight;
+/// the next is sent `PING_INTERVAL` after the previous one.
+pub(crate) struct Heartbeat {
+    last_ping_at: Instant,
+    in_flight: Option<(u64, Instant)>,
+    next_seq: u64,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            last_ping_at: now,
+            in_flight: None,
+            next_seq: 0,
+        }
+    }
+
+    /// When the heartbeat next needs attention: the pong deadline while a
+    /// ping is outstanding, otherwise the time for the next ping.
+    pub(crate) fn deadline(&self) -> Instant {
+        match self.in_flight {
+            Some((_, sent_at)) => sent_at + PONG_TIMEOUT,
+            None => self.last_ping_at + PING_INTERVAL,
+        }
+    }
+
+    pub(crate) fn is_dead(&self, now: Instant) -> bool {
+        self.in_flight
+            .is_some_and(|(_, sent_at)| now.duration_since(sent_at) >= PONG_TIMEOUT)
+    }
+
+    /// Records a ping and returns its payload.
+    pub(crate) fn ping(&mut self, now: Instant) -> Vec<u8> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.last_ping_at = now;
+        self.in_flight = Some((seq, now));
+        seq.to_be_bytes().to_vec()
+    }
+
+    /// Returns the round-trip time if `payload` answers the ping in flight.
+    pub(crate) fn pong(&mut self, payload: &[u8], now: Instant) -> Option<Duration> {
+        let (seq, sent_at) = self.in_flight?;
+        if payload != seq.to_be_bytes() {
+            return None;
+        }
+        self.in_flight = None;
+        Some(now.duration_since(sent_at))
+    }
+}
+
+/// Exponential backoff for the given (1-based) attempt: the delay doubles each
+/// time up to `MAX_BACKOFF`, and a random half of it is jittered away so
+/// clients that dropped together don't reconnect together.
+pub(crate) fn backoff_delay(attempt: u32) -> Duration {
+    let exp = BASE_BACKOFF.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    let capped = exp.min(MAX_BACKOFF);
+    let half = capped / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Queues a text frame on the live connection.
+pub(crate) fn send_text(state: &Mutex<WsState>, text: String) -> Result<(), AppError> {
+    let state = state.lock()?;
+    match (&state.outbound, state.status) {
+        (Some(tx), WsStatus::Connected) => tx
+            .send(Message::Text(text.into()))
+            .map_err(|_| AppError::Internal("websocket connection closed".into())),
+        _ => Err(AppError::Internal("websocket is not connected".into())),
+    }
+}
+
+fn set_status<R: Runtime>(app: &AppHandle<R>, generation: u64, status: WsStatus) -> bool {
+    let state = app.state::<Mutex<WsState>>();
+    let mut state = match state.lock() {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    if state.generation != generation {
+        return false;
+    }
+    state.set_status(status);
+    drop(state);
+    let _ = app.emit("ws-status", status);
+    true
+}
+
+/// Whether a message frame from the socket should reach the frontend. Its
+/// `id` is the server's, so the message is matched to its local row by
+/// client id and that row's id is checked against the delivery watermark.
+/// Messages the local database doesn't have go through untracked.
+pub(crate) async fn admit_socket_message(
+    pool: &SqlitePool,
+    state: &Mutex<WsState>,
+    message: &NewMessageEvent,
+) -> bool {
+    let Some(client_msg_id) = message.client_msg_id.as_deref() else {
+        return true;
+    };
+    let local_id = match messages::local_id_for(pool, message.sender_id, client_msg_id).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return true,
+        Err(e) => {
+            tracing::warn!("failed to match incoming message: {}", e);
+            return true;
+        }
+    };
+    state
+        .lock()
+        .map(|mut state| state.admit_message(local_id))
+        .unwrap_or(true)
+}
+
+/// Handles a frame from the server.
+pub(crate) async fn handle_incoming<R: Runtime>(app: &AppHandle<R>, text: &str) {
+    let frame = parse_ws_frame(text);
+    if let Ok(WsEvent::NewMessage(message)) = &frame {
+        if let Ok(pool) = db::pool(&app.state::<DbInstances>()).await {
+            if !admit_socket_message(&pool, &app.state::<Mutex<WsState>>(), message).await {
+                return;
+            }
+        }
+    }
+    dispatch(app, text, frame);
+}
+
+/// Handles the frame for a message the polling fallback read from the
+/// database as `local_id`, so it shows up the same way as one from the socket.
+pub(crate) fn handle_polled<R: Runtime>(app: &AppHandle<R>, local_id: i64, text: &str) {
+    let admitted = app
+        .state::<Mutex<WsState>>()
+        .lock()
+        .map(|mut state| state.admit_message(local_id))
+        .unwrap_or(true);
+    if admitted {
+        dispatch(app, text, parse_ws_frame(text));
+    }
+}
+
+fn dispatch<R: Runtime>(app: &AppHandle<R>, text: &str, frame: Result<WsEvent, ProtocolError>) {
+    let _ = app.emit("ws-message", text);
+
+    let message = match frame {
+        Ok(WsEvent::NewMessage(message)) => message,
+        Ok(WsEvent::Ack(ack)) => {
+            let app = app.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Ok(pool) = db::pool(&app.state::<DbInstances>()).await {
+                    let _ = outbox::ack(&pool, ack.id).await;
+                }
+            });
+            return;
+        }
+        _ => return,
+    };
+
+    let local_user = app
+        .state::<Mutex<WsState>>()
+        .lock()
+        .ok()
+        .and_then(|state| state.user_id);
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        notify::notify_new_message(
+            &app,
+            local_user,
+            message.sender_id,
+            message.sender_username,
+            &message.content,
+        )
+        .await;
+        if let Some(user_id) = local_user {
+            notify::refresh_badge(&app, user_id).await;
+        }
+    });
+}
+
+fn record_latency<R: Runtime>(app: &AppHandle<R>, generation: u64, rtt: Duration) {
+    let latency_ms = rtt.as_millis() as u64;
+    {
+        let state = app.state::<Mutex<WsState>>();
+        let Ok(mut state) = state.lock() else {
+            return;
+        };
+        if state.generation != generation {
+            return;
+        }
+        state.last_latency_ms = Some(latency_ms);
+    }
+    let _ = app.emit("ws-latency", Latency { latency_ms });
+}
+
+fn emit_error<R: Runtime>(app: &AppHandle<R>, message: &str) {
+    let _ = app.emit("ws-error", message);
+}
+
+/// Sends every due outbox frame, in order. Returns the frames that ran out
+/// of attempts, or an error once the sink stops accepting frames.
+pub(crate) async fn flush_outbox<S>(
+    pool: &SqlitePool,
+    sink: &mut S,
+) -> Result<Vec<OutboxFrame>, AppError>
+where
+    S: Sink<Message> + Unpin,
+{
+    let failed = outbox::fail_exhausted(pool).await?;
+    for frame in outbox::due(pool).await? {
+        // Counted before sending, so a frame that crashes the send can't be
+        // retried forever.
+        outbox::mark_attempted(pool, frame.id).await?;
+        if sink
+            .send(Message::Text(outbox::stamped(&frame).into()))
+            .await
+            .is_err()
+        {
+            return Err(AppError::Internal("websocket connection closed".into()));
+        }
+    }
+    Ok(failed)
+}
+
+/// Flushes the outbox on the live connection; `false` means the connection
+/// is gone. The pool is looked up each time, as `db::configure` may have
+/// swapped it since the connection opened.
+async fn deliver_outbox<R: Runtime, S>(app: &AppHandle<R>, sink: &mut S) -> bool
+where
+    S: Sink<Message> + Unpin,
+{
+    let Ok(pool) = db::pool(&app.state::<DbInstances>()).await else {
+        return true;
+    };
+    match flush_outbox(&pool, sink).await {
+        Ok(failed) => {
+            for frame in failed {
+                let _ = app.emit("ws-send-failed", frame);
+            }
+            true
+        }
+        Err(AppError::Internal(_)) => false,
+        Err(e) => {
+            emit_error(app, &format!("outbox delivery failed: {}", e));
+            true
+        }
+    }
+}
+
+/// Sends the auth frame and waits for the verdict. Other frames that arrive
+/// first are dropped; the server shouldn't send any before `auth-ok`.
+pub(crate) async fn authenticate<S, St, E>(sink: &mut S, source: &mut St, token: &str) -> Handshake
+where
+    S: Sink<Message> + Unpin,
+    St: Stream<Item = Result<Message, E>> + Unpin,
+{
+    let frame = serialize_ws_frame(&WsEvent::Auth(AuthEvent {
+        token: token.to_string(),
+    }));
+    if sink.send(Message::Text(frame.into())).await.is_err() {
+        return Handshake::Lost;
+    }
+
+    let verdict = async {
+        while let Some(Ok(message)) = source.next().await {
+            let Message::Text(text) = message else {
+                if matches!(message, Message::Close(_)) {
+                    break;
+                }
+                continue;
+            };
+            match parse_ws_frame(text.as_str()) {
+                Ok(WsEvent::AuthOk) => return Handshake::Accepted,
+                Ok(WsEvent::AuthFailed(failed)) => {
+                    return Handshake::Rejected(failed.and_then(|f| f.reason))
+                }
+                _ => {}
+            }
+        }
+        Handshake::Lost
+    };
+    tokio::time::timeout(AUTH_TIMEOUT, verdict)
+        .await
+        .unwrap_or(Handshake::Lost)
+}
+
+/// Ends this generation after the server rejected its token, leaving the
+/// socket down until `ws_connect` is called again with a fresh one.
+fn require_auth<R: Runtime>(app: &AppHandle<R>, generation: u64, reason: Option<String>) {
+    {
+        let state = app.state::<Mutex<WsState>>();
+        let Ok(mut state) = state.lock() else {
+            return;
+        };
+        if state.generation != generation {
+            return;
+        }
+        state.outbound = None;
+        state.set_status(WsStatus::Disconnected);
+    }
+    let _ = app.emit("ws-status", WsStatus::Disconnected);
+    let _ = app.emit("ws-auth-required", AuthRequired { reason });
+}
+
+async fn run_connection<R: Runtime>(
+    app: AppHandle<R>,
+    generation: u64,
+    url: String,
+    token: String,
+    mut outbound: UnboundedReceiver<Message>,
+    outbox_wake: Arc<Notify>,
+) {
+    let mut attempt: u32 = 0;
+
+    loop {
+        let status = if attempt == 0 {
+            WsStatus::Connecting
+        } else {
+            WsStatus::Reconnecting
+        };
+        if !set_status(&app, generation, status) {
+            return;
+        }
+
+        let mut request = match url.as_str().into_client_request() {
+            Ok(request) => request,
+            Err(e) => {
+                emit_error(&app, &format!("invalid websocket url: {}", e));
+                set_status(&app, generation, WsStatus::Disconnected);
+                return;
+            }
+        };
+        if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token)) {
+            request.headers_mut().insert("Authorization", value);
+        }
+
+        // Left early when the connect or the handshake fails, straight to
+        // the backoff below.
+        'session: {
+            let Ok((stream, _)) = tokio_tungstenite::connect_async(request).await else {
+                break 'session;
+            };
+            let (mut sink, mut source) = stream.split();
+            match authenticate(&mut sink, &mut source, &token).await {
+                Handshake::Accepted => {}
+                Handshake::Rejected(reason) => {
+                    let _ = sink.send(Message::Close(None)).await;
+                    require_auth(&app, generation, reason);
+                    return;
+                }
+                Handshake::Lost => {
+                    emit_error(&app, "websocket authentication got no answer");
+                    break 'session;
+                }
+            }
+
+            attempt = 0;
+            if !set_status(&app, generation, WsStatus::Connected) {
+                return;
+            }
+            let user_id = app
+                .state::<Mutex<WsState>>()
+                .lock()
+                .ok()
+                .and_then(|state| state.user_id);
+            let _ = app.emit("ws-authenticated", user_id);
+
+            let mut heartbeat = Heartbeat::new(Instant::now());
+            let mut outbox_retry = tokio::time::interval(outbox::ACK_TIMEOUT);
+            if let Ok(pool) = db::pool(&app.state::<DbInstances>()).await {
+                let _ = outbox::make_all_due(&pool).await;
+            }
+            loop {
+                tokio::select! {
+                    // The first tick fires straight away, flushing whatever
+                    // queued up while disconnected.
+                    _ = outbox_retry.tick() => {
+                        if !deliver_outbox(&app, &mut sink).await {
+                            break;
+                        }
+                    },
+                    _ = outbox_wake.notified() => {
+                        if !deliver_outbox(&app, &mut sink).await {
+                            break;
+                        }
+                    },
+                    _ = tokio::time::sleep_until(heartbeat.deadline().into()) => {
+                        let now = Instant::now();
+                        if heartbeat.is_dead(now) {
+                            emit_error(&app, "websocket heartbeat timed out");
+                            break;
+                        }
+                        let payload = heartbeat.ping(now);
+                        if sink.send(Message::Ping(payload.into())).await.is_err() {
+                            break;
+                        }
+                    },
+                    frame = outbound.recv() => match frame {
+                        Some(frame) => {
+                            if sink.send(frame).await.is_err() {
+                                break;
+                            }
+                        }
+                        // The sender was dropped by ws_disconnect.
+                        None => {
+                            let _ = sink.send(Message::Close(None)).await;
+                            return;
+                        }
+                    },
+                    incoming = source.next() => match incoming {
+                        Some(Ok(Message::Text(text))) => handle_incoming(&app, text.as_str()).await,
+                        Some(Ok(Message::Pong(payload))) => {
+                            if let Some(rtt) = heartbeat.pong(&payload, Instant::now()) {
+                                record_latency(&app, generation, rtt);
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    },
+                }
+            }
+        }
+
+        attempt += 1;
+        let delay = backoff_delay(attempt);
+        if !set_status(&app, generation, WsStatus::Reconnecting) {
+            return;
+        }
+        let _ = app.emit(
+            "ws-reconnect-attempt",
+            ReconnectAttempt {
+                attempt,
+                delay_ms: delay.as_millis() as u64,
+            },
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[tauri::command]
+pub fn ws_connect(
+    app: AppHandle,
+    state: State<'_, Mutex<WsState>>,
+    url: String,
+    token: String,
+    user_id: Option<i64>,
+) -> Result<(), AppError> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (generation, outbox_wake) = {
+        let mut state = state.lock()?;
+        state.generation += 1;
+        state.outbound = Some(tx);
+        if state.user_id != user_id {
+            state.last_delivered_id = None;
+        }
+        state.user_id = user_id;
+        state.last_latency_ms = None;
+        (state.generation, state.outbox_wake.clone())
+    };
+
+    tauri::async_runtime::spawn(run_connection(app, generation, url, token, rx, outbox_wake));
+    Ok(())
+}
+
+/// Drops the outbound channel, which makes the socket task send a close
+/// frame and exit.
+pub(crate) fn disconnect<R: Runtime>(app: &AppHandle<R>) -> Result<(), AppError> {
+    {
+        let state = app.state::<Mutex<WsState>>();
+        let mut state = state.lock()?;
+        state.generation += 1;
+        state.outbound = None;
+        state.user_id = None;
+        state.last_latency_ms = None;
+        state.set_status(WsStatus::Disconnected);
+    }
+    let _ = app.emit("ws-status", WsStatus::Disconnected);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn ws_disconnect(app: AppHandle) -> Result<(), AppError> {
+    disconnect(&app)
+}
+
+#[tauri::command]
+pub fn ws_last_latency_ms(state: State<'_, Mutex<WsState>>) -> Result<Option<u64>, AppError> {
+    Ok(state.lock()?.last_latency_ms)
+}
+
+/// Queues a frame for reliable delivery; it goes out as soon as the socket
+/// is connected and is retried until acknowledged.
+#[tauri::command]
+pub async fn ws_enqueue(
+    db: State<'_, DbInstances>,
+    state: State<'_, Mutex<WsState>>,
+    frame: String,
+) -> Result<i64, AppError> {
+    let pool = db::pool(&db).await?;
+    let id = outbox::enqueue(&pool, &frame).await?;
+    state.lock()?.outbox_wake.notify_one();
+    Ok(id)
+}
+
+#[tauri::command]
+pub async fn outbox_pending_count(db: State<'_, DbInstances>) -> Result<u32, AppError> {
+    let pool = db::pool(&db).await?;
+    outbox::pending_count(&pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context as TaskContext, Poll};
+
+    use super::*;
+    use crate::encryption::EncryptionState;
+    use crate::message_type::MessageType;
+    use crate::messages::insert_idempotent;
+    use crate::outbox::{ack, enqueue, make_all_due, pending_count, MAX_OUTBOX_ATTEMPTS};
+    use crate::polling::messages_since;
+    use crate::test_support::test_pool;
+
+    #[test]
+    fn a_missing_pong_marks_the_connection_dead() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(start);
+        assert_eq!(heartbeat.deadline(), start + PING_INTERVAL);
+        assert!(!heartbeat.is_dead(start + Duration::from_secs(60)));
+
+        let sent = start + PING_INTERVAL;
+        heartbeat.ping(sent);
+        assert_eq!(heartbeat.deadline(), sent + PONG_TIMEOUT);
+        assert!(!heartbeat.is_dead(sent + PONG_TIMEOUT - Duration::from_millis(1)));
+        assert!(heartbeat.is_dead(sent + PONG_TIMEOUT));
+    }
+
+    #[test]
+    fn a_matching_pong_reports_the_round_trip() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(start);
+        let first = heartbeat.ping(start);
+        assert!(heartbeat.pong(b"nope", start).is_none());
+        let rtt = heartbeat.pong(&first, start + Duration::from_millis(40));
+        assert_eq!(rtt, Some(Duration::from_millis(40)));
+        assert_eq!(heartbeat.deadline(), start + PING_INTERVAL);
+
+        // A late answer to an earlier ping doesn't clear the current one.
+        let next = start + PING_INTERVAL;
+        heartbeat.ping(next);
+        assert!(heartbeat.pong(&first, next).is_none());
+        assert!(heartbeat.is_dead(next + PONG_TIMEOUT));
+    }
+
+    /// A sink that keeps every frame sent to it.
+    #[derive(Default)]
+    struct Recorder(Vec<Message>);
+
+    impl Sink<Message> for Recorder {
+        type Error = ();
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), ()> {
+            self.0.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn queue(pool: &SqlitePool, n: usize) -> Vec<i64> {
+        let mut ids = Vec::new();
+        for i in 0..n {
+            let frame = format!(r#"{{"type":"message","data":{{"n":{}}}}}"#, i);
+            ids.push(enqueue(pool, &frame).await.unwrap());
+        }
+        ids
+    }
+
+    fn sent_ids(sent: &Recorder) -> Vec<i64> {
+        sent.0
+            .iter()
+            .map(|m| {
+                let frame: serde_json::Value = serde_json::from_str(m.to_text().unwrap()).unwrap();
+                frame["id"].as_i64().unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn enqueue_rejects_frames_without_a_type() {
+        let pool = test_pool().await;
+        assert_eq!(
+            enqueue(&pool, "nope").await.unwrap_err().code(),
+            "validation"
+        );
+        let untyped = enqueue(&pool, r#"{"data":1}"#).await.unwrap_err();
+        assert_eq!(untyped.code(), "validation");
+    }
+
+    #[tokio::test]
+    async fn reconnecting_resends_unacked_frames_in_order() {
+        let pool = test_pool().await;
+        let ids = queue(&pool, 3).await;
+        make_all_due(&pool).await.unwrap();
+
+        let mut sent = Recorder::default();
+        assert!(flush_outbox(&pool, &mut sent).await.unwrap().is_empty());
+        assert_eq!(sent_ids(&sent), ids);
+
+        // Not acked, but not timed out either.
+        let mut again = Recorder::default();
+        flush_outbox(&pool, &mut again).await.unwrap();
+        assert!(again.0.is_empty());
+
+        ack(&pool, ids[0]).await.unwrap();
+        assert_eq!(pending_count(&pool).await.unwrap(), 2);
+        make_all_due(&pool).await.unwrap();
+        let mut resent = Recorder::default();
+        flush_outbox(&pool, &mut resent).await.unwrap();
+        assert_eq!(sent_ids(&resent), ids[1..]);
+    }
+
+    #[tokio::test]
+    async fn exhausted_frames_fail_instead_of_resending() {
+        let pool = test_pool().await;
+        let ids = queue(&pool, 2).await;
+        sqlx::query("UPDATE outbox SET attempts = ? WHERE id = ?")
+            .bind(MAX_OUTBOX_ATTEMPTS)
+            .bind(ids[0])
+            .execute(&pool)
+            .await
+            .unwrap();
+        make_all_due(&pool).await.unwrap();
+
+        let mut sent = Recorder::default();
+        let failed = flush_outbox(&pool, &mut sent).await.unwrap();
+        assert_eq!(failed.iter().map(|f| f.id).collect::<Vec<_>>(), [ids[0]]);
+        assert_eq!(sent_ids(&sent), [ids[1]]);
+        assert_eq!(pending_count(&pool).await.unwrap(), 1);
+    }
+
+    fn frames(texts: &[&str]) -> impl Stream<Item = Result<Message, ()>> + Unpin {
+        let frames: Vec<_> = texts
+            .iter()
+            .map(|text| Ok(Message::Text(text.to_string().into())))
+            .collect();
+        futures_util::stream::iter(frames)
+    }
+
+    #[tokio::test]
+    async fn authenticate_sends_the_token_and_waits_for_a_verdict() {
+        let mut sent = Recorder::default();
+        let mut source = frames(&[
+            r#"{"type":"typing","data":{}}"#,
+            r#"{"type":"auth-failed","data":{"reason":"token expired"}}"#,
+            r#"{"type":"auth-ok"}"#,
+        ]);
+        let verdict = authenticate(&mut sent, &mut source, "tok").await;
+        assert_eq!(verdict, Handshake::Rejected(Some("token expired".into())));
+        assert_eq!(sent.0.len(), 1);
+        let frame: serde_json::Value = serde_json::from_str(sent.0[0].to_text().unwrap()).unwrap();
+        assert_eq!(
+            frame,
+            serde_json::json!({"type": "auth", "data": {"token": "tok"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticate_skips_unrelated_frames() {
+        let mut source = frames(&[
+            "garbage",
+            r#"{"type":"message","data":{"sender_id":1,"content":"x"}}"#,
+            r#"{"type":"auth-ok"}"#,
+        ]);
+        let verdict = authenticate(&mut Recorder::default(), &mut source, "tok").await;
+        assert_eq!(verdict, Handshake::Accepted);
+
+        let mut source = frames(&[r#"{"type":"auth-failed"}"#]);
+        let verdict = authenticate(&mut Recorder::default(), &mut source, "tok").await;
+        assert_eq!(verdict, Handshake::Rejected(None));
+    }
+
+    #[tokio::test]
+    async fn a_close_before_the_verdict_loses_the_connection() {
+        let mut source = futures_util::stream::iter(vec![
+            Ok::<_, ()>(Message::Close(None)),
+            Ok(Message::Text(r#"{"type":"auth-ok"}"#.into())),
+        ]);
+        let verdict = authenticate(&mut Recorder::default(), &mut source, "tok").await;
+        assert_eq!(verdict, Handshake::Lost);
+    }
+
+    #[tokio::test]
+    async fn the_socket_does_not_replay_what_polling_delivered() {
+        let pool = test_pool().await;
+        let encryption = EncryptionState::default();
+        let mut rows = Vec::new();
+        for i in 0..4 {
+            let client_msg_id = format!("c{}", i);
+            let row = insert_idempotent(
+                &pool,
+                &encryption,
+                2,
+                1,
+                &format!("m{}", i),
+                MessageType::Text,
+                None,
+                Some(&client_msg_id),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            rows.push(row);
+        }
+        let state = Mutex::new(WsState::default());
+
+        // Polling delivers the first three by their local ids.
+        let polled = messages_since(&pool, 1, 0).await.unwrap();
+        assert_eq!(polled.len(), 4);
+        for row in &polled[..3] {
+            assert!(state.lock().unwrap().admit_message(row.id));
+        }
+
+        // The socket comes back and the server replays from the second message,
+        // under its own ids.
+        let mut admitted = Vec::new();
+        for (i, row) in rows.iter().enumerate().skip(1) {
+            let frame = NewMessageEvent {
+                id: Some(9000 + i as i64),
+                sender_id: 2,
+                sender_username: None,
+                receiver_id: Some(1),
+                content: row.content.clone(),
+                message_type: None,
+                client_msg_id: row.client_msg_id.clone(),
+            };
+            admitted.push(admit_socket_message(&pool, &state, &frame).await);
+        }
+        assert_eq!(admitted, [false, false, true]);
+        let last = state.lock().unwrap().last_delivered_id().unwrap();
+        assert_eq!(last, rows[3].id);
+        assert!(messages_since(&pool, 1, last).await.unwrap().is_empty());
+
+        // A message the local database doesn't know goes through untracked.
+        let unknown = NewMessageEvent {
+            id: Some(1),
+            sender_id: 2,
+            sender_username: None,
+            receiver_id: Some(1),
+            content: "new".into(),
+            message_type: None,
+            client_msg_id: Some("elsewhere".into()),
+        };
+        assert!(admit_socket_message(&pool, &state, &unknown).await);
+        assert_eq!(state.lock().unwrap().last_delivered_id(), Some(last));
+    }
+}
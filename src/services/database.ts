@@ -8,7 +8,9 @@ class DatabaseService {
     try {
       // 检查是否在Tauri环境中
       if (typeof window !== 'undefined' && window.__TAURI__) {
-        this.db = await Database.load('sqlite:cereals.db');
+        // The database is preloaded and tuned on the Rust side; `load` would
+        // open a second, untuned pool and replace it.
+        this.db = await Database.get('sqlite:cereals.db');
         console.log('Database initialized successfully');
       } else {
         console.log('Running in browser mode, skipping database initialization');